@@ -0,0 +1,156 @@
+/*
+ * This file is part of ETL-Processor
+ *
+ * Copyright (c) 2024  Comprehensive Cancer Center Mainfranken
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published
+ * by the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::RwLock;
+
+use hmac::{Hmac, Mac};
+use reqwest::header::HeaderName;
+use sha2::Sha256;
+
+use crate::config::Config;
+use crate::AppError;
+use crate::AppError::{IoError, ValidationError};
+
+/// Bearer token used to authenticate requests.
+/// A token read from file is cached until it gets invalidated.
+pub struct BearerToken {
+    token: Option<String>,
+    token_file: Option<PathBuf>,
+    cached: RwLock<Option<String>>,
+}
+
+impl BearerToken {
+    pub fn new(config: &Config) -> Option<Self> {
+        if config.rest_bearer_token.is_none() && config.rest_bearer_token_file.is_none() {
+            return None;
+        }
+        Some(BearerToken {
+            token: config.rest_bearer_token.clone(),
+            token_file: config.rest_bearer_token_file.clone(),
+            cached: RwLock::new(None),
+        })
+    }
+
+    pub fn token(&self) -> Result<String, AppError> {
+        if let Some(token) = &self.token {
+            return Ok(token.clone());
+        }
+        if let Some(token) = self.cached.read().unwrap().as_ref() {
+            return Ok(token.clone());
+        }
+
+        let token = match &self.token_file {
+            Some(token_file) => fs::read_to_string(token_file)
+                .map_err(|e| IoError(format!("Cannot read bearer token file: {}", e)))?
+                .trim()
+                .to_string(),
+            None => String::new(),
+        };
+        *self.cached.write().unwrap() = Some(token.clone());
+        Ok(token)
+    }
+
+    /// Invalidates cached token. Returns `true` if a new token might be available.
+    pub fn invalidate(&self) -> bool {
+        *self.cached.write().unwrap() = None;
+        self.token_file.is_some()
+    }
+}
+
+/// Signs requests using HMAC-SHA256 with a shared secret
+pub struct HmacSigner {
+    secret: Vec<u8>,
+    header: HeaderName,
+}
+
+impl HmacSigner {
+    pub fn new(config: &Config) -> Result<Option<Self>, AppError> {
+        let secret = match (&config.rest_hmac_secret, &config.rest_hmac_secret_file) {
+            (Some(secret), _) => secret.clone(),
+            (None, Some(secret_file)) => fs::read_to_string(secret_file)
+                .map_err(|e| IoError(format!("Cannot read HMAC secret file: {}", e)))?
+                .trim()
+                .to_string(),
+            (None, None) => return Ok(None),
+        };
+        if secret.is_empty() {
+            return Err(ValidationError("Empty HMAC secret".into()));
+        }
+        let header = HeaderName::from_str(config.rest_hmac_header.trim()).map_err(|_| {
+            ValidationError(format!(
+                "Invalid HMAC signature header '{}'",
+                config.rest_hmac_header
+            ))
+        })?;
+        Ok(Some(HmacSigner {
+            secret: secret.into_bytes(),
+            header,
+        }))
+    }
+
+    pub fn header(&self) -> &HeaderName {
+        &self.header
+    }
+
+    /// Hex encoded HMAC-SHA256 of given data
+    pub fn sign(&self, data: &[u8]) -> String {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.secret).expect("HMAC accepts keys of any size");
+        mac.update(data);
+        mac.finalize()
+            .into_bytes()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::header::HeaderName;
+
+    use crate::auth::HmacSigner;
+
+    fn signer(secret: &str) -> HmacSigner {
+        HmacSigner {
+            secret: secret.as_bytes().to_vec(),
+            header: HeaderName::from_static("x-signature"),
+        }
+    }
+
+    // Test vectors of RFC 4231 and of requests sent by this application
+    #[test]
+    fn should_sign_test_vectors() {
+        assert_eq!(
+            signer("Jefe").sign(b"what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        assert_eq!(
+            signer("secret").sign(br#"{"consent":{}}"#),
+            "3bea8cb66e88d11cfa12c6902830987ff31d5e03f0d5aba8d46af457ae01a225"
+        );
+        assert_eq!(
+            signer("secret").sign(b"TESTPATIENT1234"),
+            "484dd599d3702db001505dace04f49c4878c97e5fbfe1345cf02b21779a7a415"
+        );
+    }
+}
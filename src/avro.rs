@@ -0,0 +1,701 @@
+/*
+ * This file is part of ETL-Processor
+ *
+ * Copyright (c) 2024  Comprehensive Cancer Center Mainfranken
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published
+ * by the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::{debug, info};
+use serde::Deserialize;
+use serde_json::{Map, Number, Value};
+
+use crate::config::Config;
+use crate::retry::RetryPolicy;
+use crate::AppError;
+use crate::AppError::{HttpConnectError, HttpError, HttpTimeout, ValidationError};
+
+/// Magic byte of the Confluent wire format, followed by the schema id and the Avro record
+const MAGIC_BYTE: u8 = 0;
+
+const HEADER_LENGTH: usize = 5;
+
+/// Checks if payload uses the Confluent wire format. JSON never starts with the magic byte.
+pub fn is_wire_format(payload: &[u8]) -> bool {
+    payload.len() >= HEADER_LENGTH && payload[0] == MAGIC_BYTE
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Schema {
+    Null,
+    Boolean,
+    Int,
+    Long,
+    Float,
+    Double,
+    Bytes,
+    String,
+    Record(Vec<(String, Schema)>),
+    Enum(Vec<String>),
+    Array(Box<Schema>),
+    Map(Box<Schema>),
+    Union(Vec<Schema>),
+    Fixed(usize),
+    /// Reference to a named record, enum or fixed type
+    Named(String),
+}
+
+/// Writer schema of Avro records. Logical types are decoded as their underlying type.
+#[derive(Debug, PartialEq)]
+pub struct AvroSchema {
+    root: Schema,
+    names: HashMap<String, Schema>,
+}
+
+impl FromStr for AvroSchema {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let value = serde_json::from_str::<Value>(s)
+            .map_err(|e| ValidationError(format!("Invalid Avro schema: {}", e)))?;
+        let mut names = HashMap::new();
+        let root = parse_schema(&value, None, &mut names)
+            .map_err(|e| ValidationError(format!("Invalid Avro schema: {}", e)))?;
+        Ok(AvroSchema { root, names })
+    }
+}
+
+fn full_name(name: &str, namespace: Option<&str>) -> String {
+    match namespace {
+        Some(namespace) if !name.contains('.') && !namespace.is_empty() => {
+            format!("{}.{}", namespace, name)
+        }
+        _ => name.to_string(),
+    }
+}
+
+fn parse_schema(
+    value: &Value,
+    namespace: Option<&str>,
+    names: &mut HashMap<String, Schema>,
+) -> Result<Schema, String> {
+    match value {
+        Value::String(name) => Ok(match name.as_str() {
+            "null" => Schema::Null,
+            "boolean" => Schema::Boolean,
+            "int" => Schema::Int,
+            "long" => Schema::Long,
+            "float" => Schema::Float,
+            "double" => Schema::Double,
+            "bytes" => Schema::Bytes,
+            "string" => Schema::String,
+            name => match full_name(name, namespace) {
+                full_name if names.contains_key(&full_name) => Schema::Named(full_name),
+                _ => Schema::Named(name.to_string()),
+            },
+        }),
+        Value::Array(branches) => branches
+            .iter()
+            .map(|branch| parse_schema(branch, namespace, names))
+            .collect::<Result<Vec<_>, _>>()
+            .map(Schema::Union),
+        Value::Object(fields) => {
+            let schema_type = fields.get("type").ok_or("missing type")?;
+            let name = fields.get("name").and_then(Value::as_str);
+            let namespace = fields
+                .get("namespace")
+                .and_then(Value::as_str)
+                .or(namespace);
+            let schema = match schema_type.as_str() {
+                Some("record" | "error") => {
+                    let name = full_name(name.ok_or("missing record name")?, namespace);
+                    // Namespace of nested types defaults to namespace of the record
+                    let namespace = name.rsplit_once('.').map(|(namespace, _)| namespace);
+                    // Registered before fields to support recursive records
+                    names.insert(name.clone(), Schema::Null);
+                    let fields = fields
+                        .get("fields")
+                        .and_then(Value::as_array)
+                        .ok_or("missing record fields")?
+                        .iter()
+                        .map(|field| {
+                            let field_name = field
+                                .get("name")
+                                .and_then(Value::as_str)
+                                .ok_or("missing field name")?;
+                            let field_type = field.get("type").ok_or("missing field type")?;
+                            parse_schema(field_type, namespace, names)
+                                .map(|schema| (field_name.to_string(), schema))
+                        })
+                        .collect::<Result<Vec<_>, _>>()?;
+                    let schema = Schema::Record(fields);
+                    names.insert(name.clone(), schema);
+                    return Ok(Schema::Named(name));
+                }
+                Some("enum") => Schema::Enum(
+                    fields
+                        .get("symbols")
+                        .and_then(Value::as_array)
+                        .ok_or("missing enum symbols")?
+                        .iter()
+                        .map(|symbol| symbol.as_str().map(String::from))
+                        .collect::<Option<Vec<_>>>()
+                        .ok_or("invalid enum symbols")?,
+                ),
+                Some("fixed") => Schema::Fixed(
+                    fields
+                        .get("size")
+                        .and_then(Value::as_u64)
+                        .ok_or("missing fixed size")? as usize,
+                ),
+                Some("array") => Schema::Array(Box::new(parse_schema(
+                    fields.get("items").ok_or("missing array items")?,
+                    namespace,
+                    names,
+                )?)),
+                Some("map") => Schema::Map(Box::new(parse_schema(
+                    fields.get("values").ok_or("missing map values")?,
+                    namespace,
+                    names,
+                )?)),
+                // Primitive type with attributes, e.g. logical types
+                _ => return parse_schema(schema_type, namespace, names),
+            };
+            match name {
+                Some(name) => {
+                    let name = full_name(name, namespace);
+                    names.insert(name.clone(), schema);
+                    Ok(Schema::Named(name))
+                }
+                None => Ok(schema),
+            }
+        }
+        _ => Err("invalid schema".into()),
+    }
+}
+
+impl AvroSchema {
+    /// Decodes Avro binary encoded record into JSON. Values of unions are not wrapped.
+    pub fn decode(&self, data: &[u8]) -> Result<Value, AppError> {
+        let mut input = data;
+        let value = self
+            .decode_value(&self.root, &mut input)
+            .map_err(ValidationError)?;
+        if !input.is_empty() {
+            return Err(ValidationError("trailing bytes after record".into()));
+        }
+        Ok(value)
+    }
+
+    fn decode_value(&self, schema: &Schema, input: &mut &[u8]) -> Result<Value, String> {
+        Ok(match schema {
+            Schema::Null => Value::Null,
+            Schema::Boolean => Value::Bool(read_bytes(input, 1)?[0] != 0),
+            Schema::Int => {
+                let value = read_long(input)?;
+                i32::try_from(value).map_err(|_| "int out of range")?;
+                Value::from(value)
+            }
+            Schema::Long => Value::from(read_long(input)?),
+            Schema::Float => {
+                let bytes = read_bytes(input, 4)?;
+                float_value(f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f64)
+            }
+            Schema::Double => {
+                let mut bytes = [0; 8];
+                bytes.copy_from_slice(read_bytes(input, 8)?);
+                float_value(f64::from_le_bytes(bytes))
+            }
+            Schema::Bytes => {
+                let length = read_length(input)?;
+                Value::String(String::from_utf8_lossy(read_bytes(input, length)?).into_owned())
+            }
+            Schema::String => {
+                let length = read_length(input)?;
+                Value::String(
+                    String::from_utf8(read_bytes(input, length)?.to_vec())
+                        .map_err(|_| "invalid UTF-8 string")?,
+                )
+            }
+            Schema::Record(fields) => {
+                let mut object = Map::new();
+                for (name, schema) in fields {
+                    object.insert(name.clone(), self.decode_value(schema, input)?);
+                }
+                Value::Object(object)
+            }
+            Schema::Enum(symbols) => {
+                let index = read_length(input)?;
+                Value::String(symbols.get(index).ok_or("invalid enum index")?.clone())
+            }
+            Schema::Array(items) => {
+                let mut array = Vec::new();
+                read_blocks(input, |input| {
+                    array.push(self.decode_value(items, input)?);
+                    Ok(())
+                })?;
+                Value::Array(array)
+            }
+            Schema::Map(values) => {
+                let mut object = Map::new();
+                read_blocks(input, |input| {
+                    let key = self.decode_value(&Schema::String, input)?;
+                    let value = self.decode_value(values, input)?;
+                    object.insert(key.as_str().unwrap_or_default().to_string(), value);
+                    Ok(())
+                })?;
+                Value::Object(object)
+            }
+            Schema::Union(branches) => {
+                let index = read_length(input)?;
+                let branch = branches.get(index).ok_or("invalid union index")?;
+                self.decode_value(branch, input)?
+            }
+            Schema::Fixed(size) => {
+                Value::String(String::from_utf8_lossy(read_bytes(input, *size)?).into_owned())
+            }
+            Schema::Named(name) => {
+                let schema = self
+                    .names
+                    .get(name)
+                    .ok_or_else(|| format!("unknown type '{}'", name))?;
+                self.decode_value(schema, input)?
+            }
+        })
+    }
+}
+
+fn float_value(value: f64) -> Value {
+    Number::from_f64(value).map_or(Value::Null, Value::Number)
+}
+
+fn read_bytes<'a>(input: &mut &'a [u8], length: usize) -> Result<&'a [u8], String> {
+    if input.len() < length {
+        return Err("unexpected end of record".into());
+    }
+    let (bytes, rest) = input.split_at(length);
+    *input = rest;
+    Ok(bytes)
+}
+
+/// Zigzag encoded variable-length long
+fn read_long(input: &mut &[u8]) -> Result<i64, String> {
+    let mut value: u64 = 0;
+    for shift in (0..64).step_by(7) {
+        let byte = read_bytes(input, 1)?[0];
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok((value >> 1) as i64 ^ -((value & 1) as i64));
+        }
+    }
+    Err("invalid long".into())
+}
+
+fn read_length(input: &mut &[u8]) -> Result<usize, String> {
+    usize::try_from(read_long(input)?).map_err(|_| "negative length".into())
+}
+
+/// Items of arrays and maps are encoded in blocks, a negative count is followed by the block size.
+/// Counts exceeding the remaining bytes are rejected, as items of e.g. type `null` take no bytes at all.
+fn read_blocks(
+    input: &mut &[u8],
+    mut read_item: impl FnMut(&mut &[u8]) -> Result<(), String>,
+) -> Result<(), String> {
+    loop {
+        let count = match read_long(input)? {
+            0 => return Ok(()),
+            count if count < 0 => {
+                read_long(input)?;
+                count.unsigned_abs()
+            }
+            count => count as u64,
+        };
+        if count > input.len() as u64 {
+            return Err("invalid block count".into());
+        }
+        for _ in 0..count {
+            read_item(input)?;
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct RegisteredSchema {
+    schema: String,
+}
+
+/// Client of a Confluent Schema Registry, caching fetched schemas by id
+pub struct SchemaRegistry {
+    uri: String,
+    username: Option<String>,
+    password: Option<String>,
+    client: reqwest::Client,
+    retry_policy: RetryPolicy,
+    schemas: Mutex<HashMap<u32, Arc<AvroSchema>>>,
+}
+
+impl SchemaRegistry {
+    /// Schema registry if `APP_SCHEMA_REGISTRY_URI` is configured
+    pub fn new(config: &Config) -> Result<Option<Self>, AppError> {
+        let Some(uri) = &config.schema_registry_uri else {
+            return Ok(None);
+        };
+        info!("Decoding Avro records using schema registry");
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(config.rest_timeout))
+            .build()
+            .map_err(|e| HttpError(e.to_string()))?;
+        Ok(Some(SchemaRegistry {
+            uri: uri.trim_end_matches('/').to_string(),
+            username: config.schema_registry_username.clone(),
+            password: config.schema_registry_password.clone(),
+            client,
+            retry_policy: RetryPolicy::new(config),
+            schemas: Mutex::new(HashMap::new()),
+        }))
+    }
+
+    /// Decodes record of Confluent wire format into JSON. If content is a string, it is parsed as JSON.
+    pub async fn decode(&self, payload: &[u8]) -> Result<String, AppError> {
+        if !is_wire_format(payload) {
+            return Err(ValidationError("missing magic byte and schema id".into()));
+        }
+        let schema_id = u32::from_be_bytes([payload[1], payload[2], payload[3], payload[4]]);
+        let schema = self.schema(schema_id).await?;
+        let mut record = schema.decode(&payload[HEADER_LENGTH..])?;
+        if let Some(Value::String(content)) = record.get("content") {
+            let content = serde_json::from_str::<Value>(content)
+                .map_err(|_| ValidationError("content is not valid JSON".into()))?;
+            record["content"] = content;
+        }
+        Ok(record.to_string())
+    }
+
+    async fn schema(&self, schema_id: u32) -> Result<Arc<AvroSchema>, AppError> {
+        if let Some(schema) = self.schemas.lock().unwrap().get(&schema_id) {
+            return Ok(schema.clone());
+        }
+        let schema = Arc::new(
+            self.retry_policy
+                .execute_on_http_error(|| self.fetch(schema_id))
+                .await?,
+        );
+        self.schemas
+            .lock()
+            .unwrap()
+            .insert(schema_id, schema.clone());
+        Ok(schema)
+    }
+
+    async fn fetch(&self, schema_id: u32) -> Result<AvroSchema, AppError> {
+        debug!("Fetching schema {} from schema registry", schema_id);
+        let mut request = self
+            .client
+            .get(format!("{}/schemas/ids/{}", self.uri, schema_id));
+        if let Some(username) = &self.username {
+            request = request.basic_auth(username, self.password.as_ref());
+        }
+        let response = request.send().await.map_err(|e| {
+            if e.is_timeout() {
+                HttpTimeout(e.to_string())
+            } else if e.is_connect() {
+                HttpConnectError(e.to_string())
+            } else {
+                HttpError(e.to_string())
+            }
+        })?;
+        let status = response.status();
+        // Registry errors might be temporary, unknown schema ids are not
+        if status.is_server_error() || status.as_u16() == 429 {
+            return Err(HttpError(format!(
+                "Schema registry responded with {}",
+                status
+            )));
+        }
+        if !status.is_success() {
+            return Err(ValidationError(format!(
+                "schema {} not available, schema registry responded with {}",
+                schema_id, status
+            )));
+        }
+        let body = response
+            .text()
+            .await
+            .map_err(|e| HttpError(e.to_string()))?;
+        let registered = serde_json::from_str::<RegisteredSchema>(&body)
+            .map_err(|e| ValidationError(format!("invalid schema {}: {}", schema_id, e)))?;
+        AvroSchema::from_str(&registered.schema)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use serde_json::json;
+
+    use crate::avro::{is_wire_format, AvroSchema, SchemaRegistry};
+    use crate::config::test_config;
+    use crate::AppError;
+
+    const REQUEST_SCHEMA: &str = r#"{
+        "type": "record",
+        "name": "Request",
+        "namespace": "de.ukw.ccc",
+        "fields": [
+            {"name": "requestId", "type": "string"},
+            {"name": "tenant", "type": ["null", "string"], "default": null},
+            {"name": "type", "type": {"type": "enum", "name": "RequestType", "symbols": ["MTB_FILE", "DELETE"]}},
+            {"name": "content", "type": "string"}
+        ]
+    }"#;
+
+    fn write_long(buffer: &mut Vec<u8>, value: i64) {
+        let mut value = ((value << 1) ^ (value >> 63)) as u64;
+        while value > 0x7f {
+            buffer.push((value as u8 & 0x7f) | 0x80);
+            value >>= 7;
+        }
+        buffer.push(value as u8);
+    }
+
+    fn write_string(buffer: &mut Vec<u8>, value: &str) {
+        write_long(buffer, value.len() as i64);
+        buffer.extend_from_slice(value.as_bytes());
+    }
+
+    fn request_record(schema_id: u32, content: &str) -> Vec<u8> {
+        let mut buffer = vec![0];
+        buffer.extend_from_slice(&schema_id.to_be_bytes());
+        write_string(&mut buffer, "request0123456789");
+        write_long(&mut buffer, 1);
+        write_string(&mut buffer, "tenant1");
+        write_long(&mut buffer, 0);
+        write_string(&mut buffer, content);
+        buffer
+    }
+
+    #[test]
+    fn should_detect_wire_format() {
+        assert!(is_wire_format(&[0, 0, 0, 0, 1, 2]));
+        assert!(!is_wire_format(br#"{"requestId": "request0123456789"}"#));
+        assert!(!is_wire_format(&[0, 0, 1]));
+    }
+
+    #[test]
+    fn should_decode_record() {
+        let schema = AvroSchema::from_str(REQUEST_SCHEMA).unwrap();
+
+        let actual = schema.decode(&request_record(1, "{}")[5..]).unwrap();
+
+        assert_eq!(
+            actual,
+            json!({
+                "requestId": "request0123456789",
+                "tenant": "tenant1",
+                "type": "MTB_FILE",
+                "content": "{}"
+            })
+        );
+    }
+
+    #[test]
+    fn should_decode_numbers_arrays_maps_and_recursive_records() {
+        let schema = AvroSchema::from_str(
+            r#"{
+                "type": "record",
+                "name": "Node",
+                "fields": [
+                    {"name": "value", "type": "int"},
+                    {"name": "ratio", "type": "double"},
+                    {"name": "date", "type": {"type": "int", "logicalType": "date"}},
+                    {"name": "tags", "type": {"type": "array", "items": "string"}},
+                    {"name": "counts", "type": {"type": "map", "values": "long"}},
+                    {"name": "next", "type": ["null", "Node"]}
+                ]
+            }"#,
+        )
+        .unwrap();
+        let mut data = Vec::new();
+        write_long(&mut data, -42);
+        data.extend_from_slice(&1.5_f64.to_le_bytes());
+        write_long(&mut data, 19000);
+        write_long(&mut data, 2);
+        write_string(&mut data, "a");
+        write_string(&mut data, "b");
+        write_long(&mut data, 0);
+        write_long(&mut data, -1);
+        write_long(&mut data, 3);
+        write_string(&mut data, "x");
+        write_long(&mut data, 1234567890123);
+        write_long(&mut data, 0);
+        write_long(&mut data, 1);
+        write_long(&mut data, 7);
+        data.extend_from_slice(&0.0_f64.to_le_bytes());
+        write_long(&mut data, 0);
+        write_long(&mut data, 0);
+        write_long(&mut data, 0);
+        write_long(&mut data, 0);
+
+        let actual = schema.decode(&data).unwrap();
+
+        assert_eq!(
+            actual,
+            json!({
+                "value": -42,
+                "ratio": 1.5,
+                "date": 19000,
+                "tags": ["a", "b"],
+                "counts": {"x": 1234567890123_i64},
+                "next": {
+                    "value": 7,
+                    "ratio": 0.0,
+                    "date": 0,
+                    "tags": [],
+                    "counts": {},
+                    "next": null
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn should_not_decode_block_count_exceeding_record() {
+        let schema = AvroSchema::from_str(
+            r#"{"type": "record", "name": "Values", "fields": [{"name": "values", "type": {"type": "array", "items": "null"}}]}"#,
+        )
+        .unwrap();
+        let mut data = Vec::new();
+        write_long(&mut data, i64::MAX);
+        write_long(&mut data, 0);
+
+        assert!(schema.decode(&data).is_err());
+
+        let mut data = Vec::new();
+        write_long(&mut data, 1);
+        write_long(&mut data, 0);
+
+        assert_eq!(schema.decode(&data).unwrap(), json!({"values": [null]}));
+    }
+
+    #[test]
+    fn should_not_decode_truncated_record() {
+        let schema = AvroSchema::from_str(REQUEST_SCHEMA).unwrap();
+        let record = request_record(1, "{}");
+
+        let actual = schema.decode(&record[5..record.len() - 1]);
+
+        assert!(
+            matches!(actual, Err(AppError::ValidationError(e)) if e == "unexpected end of record")
+        );
+    }
+
+    #[test]
+    fn should_not_parse_invalid_schema() {
+        assert!(AvroSchema::from_str(r#"{"type": "record", "fields": []}"#).is_err());
+        assert!(AvroSchema::from_str("no schema").is_err());
+    }
+
+    #[tokio::test]
+    async fn should_fetch_schema_once_using_basic_auth() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("GET", "/schemas/ids/42")
+            .match_header("authorization", "Basic dXNlcjpzZWNyZXQ=")
+            .with_status(200)
+            .with_body(json!({ "schema": REQUEST_SCHEMA }).to_string())
+            .expect(1)
+            .create_async()
+            .await;
+
+        let mut config = test_config("http://localhost:9000");
+        config.schema_registry_uri = Some(server.url());
+        config.schema_registry_username = Some("user".into());
+        config.schema_registry_password = Some("secret".into());
+        let registry = SchemaRegistry::new(&config).unwrap().unwrap();
+
+        for _ in 0..2 {
+            let actual = registry
+                .decode(&request_record(42, r#"{"consent": {"status": "active"}}"#))
+                .await
+                .unwrap();
+
+            assert_eq!(
+                serde_json::from_str::<serde_json::Value>(&actual).unwrap(),
+                json!({
+                    "requestId": "request0123456789",
+                    "tenant": "tenant1",
+                    "type": "MTB_FILE",
+                    "content": {"consent": {"status": "active"}}
+                })
+            );
+        }
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn should_retry_if_schema_registry_is_unavailable() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("GET", "/schemas/ids/42")
+            .with_status(503)
+            .expect(3)
+            .create_async()
+            .await;
+
+        let mut config = test_config("http://localhost:9000");
+        config.schema_registry_uri = Some(server.url());
+        config.rest_retries = 2;
+        config.rest_retry_delay_ms = 1;
+        let registry = SchemaRegistry::new(&config).unwrap().unwrap();
+
+        let actual = registry.decode(&request_record(42, "{}")).await;
+
+        assert!(matches!(actual, Err(AppError::HttpError(_))));
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn should_not_retry_unknown_schema() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("GET", "/schemas/ids/42")
+            .with_status(404)
+            .expect(1)
+            .create_async()
+            .await;
+
+        let mut config = test_config("http://localhost:9000");
+        config.schema_registry_uri = Some(server.url());
+        config.rest_retries = 2;
+        let registry = SchemaRegistry::new(&config).unwrap().unwrap();
+
+        let actual = registry.decode(&request_record(42, "{}")).await;
+
+        assert!(matches!(actual, Err(AppError::ValidationError(_))));
+        mock.assert_async().await;
+    }
+
+    #[test]
+    fn should_not_create_registry_without_uri() {
+        assert!(SchemaRegistry::new(&test_config("http://localhost:9000"))
+            .unwrap()
+            .is_none());
+    }
+}
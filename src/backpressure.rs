@@ -0,0 +1,169 @@
+/*
+ * This file is part of ETL-Processor
+ *
+ * Copyright (c) 2024  Comprehensive Cancer Center Mainfranken
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published
+ * by the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::collections::{HashMap, VecDeque};
+
+use log::{info, warn};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+/// Responses sent but not yet acknowledged by Kafka, limited to a maximum number
+pub struct PendingResponses {
+    max: usize,
+    pending: VecDeque<JoinHandle<()>>,
+}
+
+impl PendingResponses {
+    pub fn new(max: usize) -> Self {
+        PendingResponses {
+            max,
+            pending: VecDeque::new(),
+        }
+    }
+
+    pub fn push(&mut self, response: JoinHandle<()>) {
+        self.pending.retain(|response| !response.is_finished());
+        self.pending.push_back(response);
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.pending.len() >= self.max
+    }
+
+    /// Pauses consumption if maximum is reached and resumes after all pending responses are sent
+    pub async fn wait_if_full(&mut self, pause: impl FnOnce(), resume: impl FnOnce()) {
+        if !self.is_full() {
+            return;
+        }
+
+        warn!(
+            "{} pending responses - pausing consumption",
+            self.pending.len()
+        );
+        pause();
+        while let Some(response) = self.pending.pop_front() {
+            let _ = response.await;
+        }
+        resume();
+        info!("Pending responses sent - resuming consumption");
+    }
+}
+
+/// Partitions paused until their next record is due, e.g. of the delete retry topic
+#[derive(Default)]
+pub struct PausedPartitions {
+    resume_at: HashMap<i32, Instant>,
+}
+
+impl PausedPartitions {
+    pub fn pause(&mut self, partition: i32, until: Instant) {
+        self.resume_at.insert(partition, until);
+    }
+
+    /// Time the next partition is due to be resumed, none if no partition is paused
+    pub fn next_resume(&self) -> Option<Instant> {
+        self.resume_at.values().min().copied()
+    }
+
+    /// Removes and returns partitions due to be resumed at given time
+    pub fn take_due(&mut self, now: Instant) -> Vec<i32> {
+        let mut due = self
+            .resume_at
+            .iter()
+            .filter(|(_, resume_at)| **resume_at <= now)
+            .map(|(partition, _)| *partition)
+            .collect::<Vec<_>>();
+        due.sort_unstable();
+        self.resume_at
+            .retain(|partition, _| !due.contains(partition));
+        due
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::time::{Duration, Instant};
+
+    use crate::backpressure::{PausedPartitions, PendingResponses};
+
+    fn slow_response(delay: Duration) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(tokio::time::sleep(delay))
+    }
+
+    #[tokio::test]
+    async fn should_not_pause_below_maximum() {
+        let mut pending = PendingResponses::new(2);
+        pending.push(slow_response(Duration::from_millis(50)));
+
+        let paused = Cell::new(false);
+        pending
+            .wait_if_full(|| paused.set(true), || paused.set(false))
+            .await;
+
+        assert!(!paused.get());
+        assert_eq!(pending.pending.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn should_pause_and_resume_if_producer_is_slow() {
+        let mut pending = PendingResponses::new(2);
+        pending.push(slow_response(Duration::from_millis(50)));
+        pending.push(slow_response(Duration::from_millis(50)));
+
+        let paused = Cell::new(false);
+        let resumed = Cell::new(false);
+        let start = Instant::now();
+        pending
+            .wait_if_full(|| paused.set(true), || resumed.set(paused.get()))
+            .await;
+
+        assert!(paused.get());
+        assert!(resumed.get());
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert_eq!(pending.pending.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn should_not_count_sent_responses() {
+        let mut pending = PendingResponses::new(2);
+        pending.push(slow_response(Duration::ZERO));
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        pending.push(slow_response(Duration::from_millis(50)));
+
+        assert!(!pending.is_full());
+    }
+
+    #[test]
+    fn should_resume_partitions_when_due() {
+        let now = tokio::time::Instant::now();
+        let mut paused = PausedPartitions::default();
+        assert!(paused.next_resume().is_none());
+
+        paused.pause(2, now + Duration::from_secs(60));
+        paused.pause(1, now + Duration::from_secs(10));
+        paused.pause(0, now);
+
+        assert_eq!(paused.next_resume(), Some(now));
+        assert_eq!(paused.take_due(now), vec![0]);
+        assert_eq!(paused.next_resume(), Some(now + Duration::from_secs(10)));
+        assert_eq!(paused.take_due(now + Duration::from_secs(60)), vec![1, 2]);
+        assert!(paused.next_resume().is_none());
+    }
+}
@@ -17,51 +17,2254 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
-use std::env;
-use std::time::Duration;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+use bytes::Bytes;
+use log::{debug, info, warn};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE, LOCATION, RETRY_AFTER};
+use reqwest::{Method, RequestBuilder, Response, StatusCode, Url};
+use tracing::Span;
+
+use crate::auth::{BearerToken, HmacSigner};
+use crate::config::Config;
+use crate::rate_limit::RateLimiter;
+use crate::retry::RetryPolicy;
+use crate::telemetry;
+use crate::unix_socket::{UnixSocket, UNIX_BASE_URI};
 use crate::AppError;
-use crate::AppError::{HttpError, MissingConfig};
+use crate::AppError::{
+    HttpConnectError, HttpError, HttpTimeout, MissingConfig, PollingTimeout, ValidationError,
+};
 
 pub struct HttpResponse {
     pub status_code: u16,
     pub status_body: String,
+    pub headers: BTreeMap<String, String>,
+    /// Endpoint that handled the request if a fallback endpoint is configured
+    pub endpoint: Option<Endpoint>,
+    pub content_type: Option<String>,
+    /// Status code returned by the backend if status code has been overridden
+    pub original_status_code: Option<u16>,
+    /// Set if status body has been truncated
+    pub truncated: bool,
+    /// Delay requested by a `Retry-After` header of a response with status 429 or 503
+    pub retry_after: Option<Duration>,
+}
+
+impl HttpResponse {
+    async fn from_response(response: Response, header_names: &[String]) -> Self {
+        let mut headers = Self::selected_headers(response.headers(), header_names);
+        // Include target of redirects not followed and status URL of accepted requests
+        if (response.status().is_redirection() || response.status() == StatusCode::ACCEPTED)
+            && !headers
+                .keys()
+                .any(|name| name.eq_ignore_ascii_case(LOCATION.as_str()))
+        {
+            headers.extend(Self::selected_headers(
+                response.headers(),
+                &["Location".to_string()],
+            ));
+        }
+        let content_type = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.to_string());
+        let retry_after = matches!(response.status().as_u16(), 429 | 503)
+            .then(|| response.headers().get(RETRY_AFTER))
+            .flatten()
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| parse_retry_after(value, SystemTime::now()));
+        HttpResponse {
+            status_code: response.status().as_u16(),
+            status_body: response.text().await.unwrap_or_default(),
+            headers,
+            endpoint: None,
+            content_type,
+            original_status_code: None,
+            truncated: false,
+            retry_after,
+        }
+    }
+
+    /// Overrides status code and keeps the status code returned by the backend
+    pub fn override_status_code(&mut self, status_code: u16) {
+        self.original_status_code = self.original_status_code.or(Some(self.status_code));
+        self.status_code = status_code;
+    }
+
+    /// URL to poll the result of an accepted request using the `Location` header
+    /// or a `location`, `statusUrl` or `callbackUrl` field of the body
+    pub fn callback_url(&self) -> Option<String> {
+        if self.status_code != 202 {
+            return None;
+        }
+        self.headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(LOCATION.as_str()))
+            .map(|(_, location)| location.clone())
+            .or_else(|| {
+                let body = serde_json::from_str::<serde_json::Value>(&self.status_body).ok()?;
+                ["location", "statusUrl", "callbackUrl"]
+                    .iter()
+                    .find_map(|field| body.get(field).and_then(|url| url.as_str()))
+                    .filter(|url| !url.trim().is_empty())
+                    .map(|url| url.to_string())
+            })
+    }
+
+    /// Truncates status body to given number of bytes at a character boundary
+    pub fn truncate_body(&mut self, max_bytes: usize) {
+        if self.status_body.len() <= max_bytes {
+            return;
+        }
+        let mut end = max_bytes;
+        while !self.status_body.is_char_boundary(end) {
+            end -= 1;
+        }
+        self.status_body.truncate(end);
+        self.truncated = true;
+    }
+
+    /// Checks if body is empty or JSON. A response without content type is accepted if the body is JSON.
+    pub fn is_json(&self) -> bool {
+        if self.status_body.trim().is_empty() {
+            return true;
+        }
+        let json_content_type = self.content_type.as_ref().is_none_or(|content_type| {
+            let media_type = content_type.split(';').next().unwrap_or_default().trim();
+            media_type.eq_ignore_ascii_case("application/json") || media_type.ends_with("+json")
+        });
+        json_content_type && serde_json::from_str::<serde_json::Value>(&self.status_body).is_ok()
+    }
+
+    fn selected_headers(headers: &HeaderMap, header_names: &[String]) -> BTreeMap<String, String> {
+        header_names
+            .iter()
+            .map(|name| name.trim())
+            .filter(|name| !name.is_empty())
+            .filter_map(|name| {
+                headers
+                    .get(name)
+                    .and_then(|value| value.to_str().ok())
+                    .map(|value| (name.to_string(), value.to_string()))
+            })
+            .collect()
+    }
+}
+
+/// Parses a `Retry-After` value given in seconds or as HTTP date. Dates in the past result in no delay.
+fn parse_retry_after(value: &str, now: SystemTime) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    httpdate::parse_http_date(value)
+        .ok()
+        .map(|date| date.duration_since(now).unwrap_or_default())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Endpoint {
+    Primary,
+    Fallback,
+}
+
+impl Endpoint {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Endpoint::Primary => "primary",
+            Endpoint::Fallback => "fallback",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DeleteMode {
+    /// Send `DELETE {uri}/MTBFile/{patient_id}`
+    Delete,
+    /// Send the consent resource using `POST` to the configured consent path
+    PostConsent,
 }
 
-pub struct BwhcClient;
+impl FromStr for DeleteMode {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "delete" => Ok(DeleteMode::Delete),
+            "post-consent" => Ok(DeleteMode::PostConsent),
+            _ => Err(ValidationError(format!("Unknown delete mode '{}'", s))),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MtbFileMethod {
+    /// Send `POST {uri}/{path}`
+    Post,
+    /// Send `PUT {uri}/{path}`, e.g. to upsert MTB file using path `MTBFile/{patient_id}`
+    Put,
+}
+
+impl MtbFileMethod {
+    fn method(self) -> Method {
+        match self {
+            MtbFileMethod::Post => Method::POST,
+            MtbFileMethod::Put => Method::PUT,
+        }
+    }
+}
+
+impl FromStr for MtbFileMethod {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_uppercase().as_str() {
+            "POST" => Ok(MtbFileMethod::Post),
+            "PUT" => Ok(MtbFileMethod::Put),
+            _ => Err(ValidationError(format!("Unknown MTB file method '{}'", s))),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RedirectPolicy {
+    /// Do not follow redirects
+    None,
+    /// Follow up to given number of redirects
+    Limited(usize),
+    /// Follow redirects to the host of the original request only
+    SameHostOnly,
+}
+
+impl RedirectPolicy {
+    const MAX_REDIRECTS: usize = 10;
+
+    /// Redirect policy not following a redirect returns the redirect response.
+    /// Authorization headers are kept by reqwest for redirects to the same host.
+    fn policy(self) -> reqwest::redirect::Policy {
+        match self {
+            RedirectPolicy::None => reqwest::redirect::Policy::none(),
+            RedirectPolicy::Limited(max) => reqwest::redirect::Policy::custom(move |attempt| {
+                if attempt.previous().len() > max {
+                    attempt.stop()
+                } else {
+                    attempt.follow()
+                }
+            }),
+            RedirectPolicy::SameHostOnly => reqwest::redirect::Policy::custom(|attempt| {
+                let same_host = attempt.previous().first().is_some_and(|original| {
+                    original.host_str() == attempt.url().host_str()
+                        && original.port_or_known_default() == attempt.url().port_or_known_default()
+                });
+                if same_host && attempt.previous().len() <= Self::MAX_REDIRECTS {
+                    attempt.follow()
+                } else {
+                    attempt.stop()
+                }
+            }),
+        }
+    }
+}
+
+impl FromStr for RedirectPolicy {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "none" => Ok(RedirectPolicy::None),
+            "same-host-only" => Ok(RedirectPolicy::SameHostOnly),
+            policy => match policy.strip_prefix("limited:").map(|max| max.parse()) {
+                Some(Ok(max)) => Ok(RedirectPolicy::Limited(max)),
+                _ => Err(ValidationError(format!("Unknown redirect policy '{}'", s))),
+            },
+        }
+    }
+}
+
+/// Static address of a host used instead of DNS resolution, e.g. `bwhc.example.org:443=10.1.2.3`
+#[derive(Clone, Debug, PartialEq)]
+pub struct ResolveOverride {
+    host: String,
+    addr: SocketAddr,
+}
+
+impl FromStr for ResolveOverride {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ValidationError(format!("Invalid resolve entry '{}'", s));
+
+        let (host_port, ip) = s.trim().split_once('=').ok_or_else(invalid)?;
+        let (host, port) = host_port.rsplit_once(':').ok_or_else(invalid)?;
+        let port = port.parse::<u16>().map_err(|_| invalid())?;
+        let ip = ip
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse::<IpAddr>()
+            .map_err(|_| invalid())?;
+        if host.trim().is_empty() {
+            return Err(invalid());
+        }
+
+        Ok(ResolveOverride {
+            host: host.trim().to_string(),
+            addr: SocketAddr::new(ip, port),
+        })
+    }
+}
+
+const PATIENT_ID_PLACEHOLDER: &str = "{patient_id}";
+const SITE_ID_PLACEHOLDER: &str = "{site_id}";
+
+pub struct BwhcClient {
+    uri: String,
+    fallback_uri: Option<String>,
+    fallback_cooldown: Duration,
+    fallback_until: Mutex<Option<Instant>>,
+    fallback_on_server_error: bool,
+    tenant_routes: HashMap<String, String>,
+    delete_mode: DeleteMode,
+    consent_path: String,
+    delete_path_template: String,
+    mtbfile_method: MtbFileMethod,
+    mtbfile_path: String,
+    site_id: Option<String>,
+    response_headers: Vec<String>,
+    request_id_header: HeaderName,
+    client: reqwest::Client,
+    mtbfile_timeout: Duration,
+    delete_timeout: Duration,
+    retry_policy: RetryPolicy,
+    delete_retry_policy: RetryPolicy,
+    rate_limiter: Option<RateLimiter>,
+    bearer_token: Option<BearerToken>,
+    signer: Option<HmacSigner>,
+    poll_accepted: bool,
+    poll_interval: Duration,
+    poll_max_wait: Duration,
+    unix_socket: Option<UnixSocket>,
+}
 
 impl BwhcClient {
-    pub async fn send_mtb_file(content: &str) -> Result<HttpResponse, AppError> {
-        let uri = env::var("APP_REST_URI").map_err(|e| MissingConfig(e.to_string()))?;
-
-        let client = reqwest::Client::new();
-        let response = client
-            .post(format!("{}/MTBFile", uri))
-            .body(content.to_string())
-            .header("Content-Type", "application/json")
-            .timeout(Duration::from_secs(5))
-            .send()
+    pub fn new(config: &Config) -> Result<Self, AppError> {
+        let timeout = Duration::from_secs(config.rest_timeout);
+        let mtbfile_timeout = config
+            .rest_mtbfile_timeout
+            .map(Duration::from_secs)
+            .unwrap_or(timeout);
+        let delete_timeout = config
+            .rest_delete_timeout
+            .map(Duration::from_secs)
+            .unwrap_or(timeout);
+        info!(
+            "Using timeouts: MTB file {}s, delete {}s",
+            mtbfile_timeout.as_secs(),
+            delete_timeout.as_secs()
+        );
+
+        let tenant_routes = match &config.tenant_routes {
+            Some(routes) => Self::parse_tenant_routes(routes.as_str())?,
+            None => HashMap::new(),
+        };
+
+        // Location of accepted requests is required to poll the result
+        let mut response_headers = config.rest_response_headers.clone();
+        if config.rest_poll_accepted
+            && !response_headers
+                .iter()
+                .any(|name| name.trim().eq_ignore_ascii_case(LOCATION.as_str()))
+        {
+            response_headers.push("Location".into());
+        }
+
+        let rest_uri = config
+            .rest_uri
+            .clone()
+            .ok_or(MissingConfig("APP_REST_URI".into()))?;
+
+        // A single fallback URI would receive MTB files of all tenants
+        if config.rest_uri_fallback().is_some() && !tenant_routes.is_empty() {
+            return Err(ValidationError(
+                "Fallback URI cannot be used with tenant routes".into(),
+            ));
+        }
+
+        // Requests to a unix socket use a fixed base URI and the configured path prefix
+        let (uri, unix_socket) = match UnixSocket::path_of(&rest_uri) {
+            Some(path) => {
+                if config.rest_uri_fallback().is_some() || !tenant_routes.is_empty() {
+                    return Err(ValidationError(
+                        "Fallback URI and tenant routes cannot be used with unix socket".into(),
+                    ));
+                }
+                info!("Using unix socket '{}'", path.display());
+                let uri = match config.rest_uri_path_prefix.trim_matches('/') {
+                    "" => UNIX_BASE_URI.to_string(),
+                    prefix => format!("{}/{}", UNIX_BASE_URI, prefix),
+                };
+                (
+                    uri,
+                    Some(UnixSocket::new(path, Self::default_headers(config)?)),
+                )
+            }
+            None => (rest_uri, None),
+        };
+
+        Ok(BwhcClient {
+            uri,
+            fallback_uri: config.rest_uri_fallback(),
+            fallback_cooldown: Duration::from_secs(config.rest_fallback_cooldown),
+            fallback_until: Mutex::new(None),
+            fallback_on_server_error: config.rest_fallback_on_5xx,
+            tenant_routes,
+            delete_mode: config.delete_mode,
+            consent_path: config.rest_consent_path.clone(),
+            delete_path_template: Self::parse_delete_path_template(
+                &config.rest_delete_path_template,
+            )?,
+            mtbfile_method: config.rest_mtbfile_method,
+            mtbfile_path: Self::parse_mtbfile_path(
+                config.rest_mtbfile_method,
+                &config.rest_mtbfile_path,
+            )?,
+            site_id: config.rest_site_id.clone(),
+            response_headers,
+            request_id_header: HeaderName::from_str(config.rest_request_id_header.trim()).map_err(
+                |_| {
+                    ValidationError(format!(
+                        "Invalid request id header '{}'",
+                        config.rest_request_id_header
+                    ))
+                },
+            )?,
+            client: Self::build_client(config)?,
+            mtbfile_timeout,
+            delete_timeout,
+            retry_policy: RetryPolicy::new(config),
+            delete_retry_policy: RetryPolicy::for_delete(config),
+            rate_limiter: config
+                .rest_rate_limit
+                .map(|rate| RateLimiter::new(rate, config.rest_rate_limit_burst)),
+            bearer_token: BearerToken::new(config),
+            signer: HmacSigner::new(config)?,
+            poll_accepted: config.rest_poll_accepted,
+            poll_interval: Duration::from_millis(config.rest_poll_interval_ms),
+            poll_max_wait: Duration::from_secs(config.rest_poll_max_wait),
+            unix_socket,
+        })
+    }
+
+    /// Sends MTB file using `APP_REST_MTBFILE_METHOD` and `APP_REST_MTBFILE_PATH`
+    pub async fn send_mtb_file(
+        &self,
+        request_id: &str,
+        patient_id: Option<&str>,
+        content: &str,
+        tenant: Option<&str>,
+    ) -> Result<HttpResponse, AppError> {
+        // Do not even try to send a request without required patient id
+        self.mtbfile_url(self.uri_for(tenant), patient_id)?;
+
+        // Body is copied once and shared between attempts
+        let body = &Bytes::copy_from_slice(content.as_bytes());
+
+        let response = self
+            .execute(&self.retry_policy, self.uri_for(tenant), |uri| async move {
+                let request = self
+                    .client
+                    .request(
+                        self.mtbfile_method.method(),
+                        self.mtbfile_url(uri, patient_id)?,
+                    )
+                    .body(body.clone())
+                    .header("Content-Type", "application/json")
+                    .timeout(self.mtbfile_timeout);
+                let request = self.with_request_id(request, request_id);
+
+                self.send(self.signed(request, content.as_bytes())).await
+            })
+            .await?;
+
+        let location = response
+            .headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(LOCATION.as_str()))
+            .map(|(_, location)| location.clone());
+
+        match location {
+            Some(location) if self.poll_accepted && response.status_code == 202 => {
+                let uri = match (response.endpoint, &self.fallback_uri) {
+                    (Some(Endpoint::Fallback), Some(fallback_uri)) => fallback_uri.as_str(),
+                    _ => self.uri_for(tenant),
+                };
+                let url = self
+                    .mtbfile_url(uri, patient_id)?
+                    .join(location.as_str())
+                    .map_err(|e| HttpError(e.to_string()))?;
+                let result = self.poll_result(request_id, url).await?;
+                Ok(HttpResponse {
+                    endpoint: response.endpoint,
+                    ..result
+                })
+            }
+            _ => Ok(response),
+        }
+    }
+
+    /// Polls the URL of an accepted request until a status other than `202` is available
+    async fn poll_result(&self, request_id: &str, url: Url) -> Result<HttpResponse, AppError> {
+        let deadline = Instant::now() + self.poll_max_wait;
+        debug!("Request accepted - polling result using '{}'", url);
+        loop {
+            tokio::time::sleep(self.poll_interval).await;
+
+            let response = self
+                .retry_policy
+                .execute(|| async {
+                    let request = self.client.get(url.clone()).timeout(self.mtbfile_timeout);
+                    let request = self.with_request_id(request, request_id);
+
+                    self.send(request).await
+                })
+                .await?;
+
+            if response.status_code != 202 {
+                return Ok(response);
+            }
+            if Instant::now() >= deadline {
+                warn!("No result available using '{}'", url);
+                return Err(PollingTimeout(url.to_string()));
+            }
+        }
+    }
+
+    /// Sends delete request using `APP_REST_DELETE_PATH_TEMPLATE`. The site id of the request
+    /// takes precedence over the configured `APP_REST_SITE_ID`.
+    pub async fn send_delete(
+        &self,
+        request_id: &str,
+        patient_id: &str,
+        site_id: Option<&str>,
+        tenant: Option<&str>,
+    ) -> Result<HttpResponse, AppError> {
+        let uri = self.uri_for(tenant);
+        let site_id = site_id.or(self.site_id.as_deref());
+
+        // Do not even try to send a request with an invalid patient id
+        Self::template_url(uri, &self.delete_path_template, patient_id, site_id)?;
+
+        self.execute(&self.delete_retry_policy, uri, |uri| async move {
+            let request = self
+                .client
+                .delete(Self::template_url(
+                    uri,
+                    &self.delete_path_template,
+                    patient_id,
+                    site_id,
+                )?)
+                .header("Content-Type", "application/json")
+                .timeout(self.delete_timeout);
+            let request = self.with_request_id(request, request_id);
+
+            self.send(self.signed(request, patient_id.as_bytes())).await
+        })
+        .await
+    }
+
+    /// Checks if the bwHC-Backend is reachable and returns the HTTP status code
+    pub async fn check_connection(&self) -> Result<u16, AppError> {
+        self.check_health("").await
+    }
+
+    /// Sends `HEAD` request to given path relative to `APP_REST_URI` and returns the HTTP status code
+    pub async fn check_health(&self, path: &str) -> Result<u16, AppError> {
+        let uri = match path.trim_matches('/') {
+            "" => self.uri.clone(),
+            path => format!("{}/{}", self.uri.trim_end_matches('/'), path),
+        };
+        let request = self.client.head(uri).timeout(self.mtbfile_timeout);
+        let response = match &self.unix_socket {
+            Some(unix_socket) => unix_socket.send(request).await?,
+            None => request.send().await.map_err(|e| HttpError(e.to_string()))?,
+        };
+
+        Ok(response.status().as_u16())
+    }
+
+    pub fn delete_mode(&self) -> DeleteMode {
+        self.delete_mode
+    }
+
+    /// Sends consent revocation as `POST` with consent resource as body, signed like MTB files
+    pub async fn send_consent(
+        &self,
+        request_id: &str,
+        consent: &str,
+        tenant: Option<&str>,
+    ) -> Result<HttpResponse, AppError> {
+        self.execute(
+            &self.delete_retry_policy,
+            self.uri_for(tenant),
+            |uri| async move {
+                let request = self
+                    .client
+                    .post(format!("{}/{}", uri, self.consent_path))
+                    .body(consent.to_string())
+                    .header("Content-Type", "application/json")
+                    .timeout(self.delete_timeout);
+                let request = self.with_request_id(request, request_id);
+
+                self.send(self.signed(request, consent.as_bytes())).await
+            },
+        )
+        .await
+    }
+
+    /// URI of the endpoint configured for the tenant or the default `APP_REST_URI`
+    fn uri_for(&self, tenant: Option<&str>) -> &str {
+        match tenant.and_then(|tenant| self.tenant_routes.get(tenant)) {
+            Some(uri) => uri.as_str(),
+            None => self.uri.as_str(),
+        }
+    }
+
+    fn parse_tenant_routes(routes: &str) -> Result<HashMap<String, String>, AppError> {
+        serde_json::from_str(routes)
+            .map_err(|e| ValidationError(format!("Invalid tenant routes: {}", e)))
+    }
+
+    /// Sends request using retry policy to given endpoint and,
+    /// if the endpoint is unreachable, to the fallback endpoint if configured.
+    /// After a failover, the fallback endpoint is used first until the cooldown has expired.
+    async fn execute<'a, F, Fut>(
+        &'a self,
+        retry_policy: &RetryPolicy,
+        uri: &'a str,
+        request: F,
+    ) -> Result<HttpResponse, AppError>
+    where
+        F: Fn(&'a str) -> Fut,
+        Fut: Future<Output = Result<HttpResponse, AppError>>,
+    {
+        let fallback_uri = match &self.fallback_uri {
+            Some(fallback_uri) => fallback_uri.as_str(),
+            None => {
+                let result = retry_policy.execute(|| request(uri)).await;
+                if result.is_ok() {
+                    debug!("Request served by endpoint '{}'", uri);
+                }
+                return result;
+            }
+        };
+
+        let endpoints = if self.is_fallback_active() {
+            [(Endpoint::Fallback, fallback_uri), (Endpoint::Primary, uri)]
+        } else {
+            [(Endpoint::Primary, uri), (Endpoint::Fallback, fallback_uri)]
+        };
+
+        let mut result = Err(HttpError("No endpoint available".into()));
+        for (endpoint, uri) in endpoints {
+            result = retry_policy.execute(|| request(uri)).await;
+            if self.is_unavailable(&result) {
+                warn!("Request to {} endpoint failed", endpoint.as_str());
+                continue;
+            }
+
+            debug!("Request served by {} endpoint '{}'", endpoint.as_str(), uri);
+            if endpoint != endpoints[0].0 {
+                self.set_fallback_active(endpoint == Endpoint::Fallback);
+            }
+            return result.map(|response| HttpResponse {
+                endpoint: Some(endpoint),
+                ..response
+            });
+        }
+        result
+    }
+
+    fn is_fallback_active(&self) -> bool {
+        match *self.fallback_until.lock().unwrap() {
+            Some(until) => Instant::now() < until,
+            None => false,
+        }
+    }
+
+    fn set_fallback_active(&self, active: bool) {
+        let mut fallback_until = self.fallback_until.lock().unwrap();
+        if active && !self.fallback_cooldown.is_zero() {
+            info!(
+                "Using fallback endpoint for the next {}s",
+                self.fallback_cooldown.as_secs()
+            );
+            *fallback_until = Some(Instant::now() + self.fallback_cooldown);
+        } else {
+            *fallback_until = None;
+        }
+    }
+
+    /// Connection errors and timeouts result in a failover, HTTP status `5xx` only if configured
+    fn is_unavailable(&self, result: &Result<HttpResponse, AppError>) -> bool {
+        match result {
+            Ok(response) => self.fallback_on_server_error && response.status_code >= 500,
+            Err(e) => matches!(e, HttpConnectError(_) | HttpTimeout(_)),
+        }
+    }
+
+    /// Sends request. If the bearer token was rejected, the token will be refreshed
+    /// and the request will be sent once again.
+    #[tracing::instrument(name = "bwhc_request", skip_all, fields(status_code))]
+    async fn send(&self, request: RequestBuilder) -> Result<HttpResponse, AppError> {
+        self.acquire().await;
+
+        let retry = request.try_clone();
+        let response = self.send_once(request).await?;
+
+        let response = match (&self.bearer_token, retry) {
+            (Some(bearer_token), Some(retry))
+                if matches!(response.status().as_u16(), 401 | 403) && bearer_token.invalidate() =>
+            {
+                debug!("Bearer token rejected - refreshing token and sending request again");
+                self.send_once(retry).await?
+            }
+            _ => response,
+        };
+        Span::current().record("status_code", response.status().as_u16());
+        Ok(HttpResponse::from_response(response, &self.response_headers).await)
+    }
+
+    /// Sends request once. If the connection was closed by the remote endpoint while the request
+    /// was sent, e.g. an idle connection closed by a proxy, the request will be sent once again.
+    async fn send_once(&self, request: RequestBuilder) -> Result<Response, AppError> {
+        let request = match &self.bearer_token {
+            Some(bearer_token) => request.bearer_auth(bearer_token.token()?),
+            None => request,
+        }
+        .headers(telemetry::trace_context_headers());
+
+        if let Some(unix_socket) = &self.unix_socket {
+            return unix_socket.send(request).await;
+        }
+
+        let retry = request.try_clone();
+        match (request.send().await, retry) {
+            (Err(e), Some(retry)) if Self::is_connection_closed(&e) => {
+                debug!("Connection closed before message completed - sending request again");
+                retry.send().await
+            }
+            (result, _) => result,
+        }
+        .map_err(|e| {
+            if e.is_timeout() {
+                HttpTimeout(e.to_string())
+            } else if e.is_connect() {
+                HttpConnectError(e.to_string())
+            } else {
+                HttpError(e.to_string())
+            }
+        })
+    }
+
+    /// Checks if the connection was closed before the response was completely received
+    fn is_connection_closed(error: &reqwest::Error) -> bool {
+        let mut source: Option<&dyn Error> = Some(error);
+        while let Some(e) = source {
+            if e.downcast_ref::<hyper::Error>()
+                .is_some_and(hyper::Error::is_incomplete_message)
+            {
+                return true;
+            }
+            source = e.source();
+        }
+        false
+    }
+
+    fn build_client(config: &Config) -> Result<reqwest::Client, AppError> {
+        let mut builder = reqwest::Client::builder();
+
+        if let Some(timeout) = config.rest_pool_idle_timeout {
+            builder = builder.pool_idle_timeout(Duration::from_secs(timeout));
+        }
+        if let Some(max_idle) = config.rest_pool_max_idle {
+            builder = builder.pool_max_idle_per_host(max_idle);
+        }
+        if let Some(keepalive) = config.rest_tcp_keepalive {
+            builder = builder.tcp_keepalive(Duration::from_secs(keepalive));
+        }
+        if config.rest_http2 {
+            builder = builder.http2_prior_knowledge();
+        }
+        if config.rest_http1_only {
+            builder = builder.http1_only();
+        }
+        builder = builder.redirect(config.rest_redirect_policy.policy());
+        // System proxies of HTTP_PROXY and HTTPS_PROXY are used by default
+        if config.rest_no_proxy {
+            info!("Not using any proxy");
+            builder = builder.no_proxy();
+        } else if let Some(proxy) = &config.rest_proxy {
+            // Proxy URI might contain credentials and is not logged
+            info!("Using configured proxy");
+            let proxy = reqwest::Proxy::all(proxy.as_str())
+                .map_err(|e| ValidationError(format!("Invalid proxy: {}", e)))?
+                .no_proxy(reqwest::NoProxy::from_env());
+            builder = builder.proxy(proxy);
+        }
+        for entry in &config.rest_resolve {
+            info!("Using address {} for host '{}'", entry.addr, entry.host);
+            builder = builder.resolve(&entry.host, entry.addr);
+        }
+        builder = builder.default_headers(Self::default_headers(config)?);
+
+        builder.build().map_err(|e| HttpError(e.to_string()))
+    }
+
+    /// Headers sent with each request, e.g. API key
+    fn default_headers(config: &Config) -> Result<HeaderMap, AppError> {
+        match &config.rest_api_key {
+            Some(api_key) => Self::api_key_headers(&config.rest_api_key_header, api_key),
+            None => Ok(HeaderMap::new()),
+        }
+    }
+
+    /// Adds request id header. Request ids containing characters other than visible ASCII,
+    /// e.g. umlauts, are sent percent-encoded as UTF-8.
+    fn with_request_id(&self, request: RequestBuilder, request_id: &str) -> RequestBuilder {
+        let value = if request_id
+            .bytes()
+            .all(|b| b == b' ' || b.is_ascii_graphic())
+        {
+            HeaderValue::from_str(request_id)
+        } else {
+            let encoded = request_id
+                .bytes()
+                .map(|b| match b {
+                    b'%' => "%25".to_string(),
+                    b' ' => " ".to_string(),
+                    b if b.is_ascii_graphic() => (b as char).to_string(),
+                    b => format!("%{:02X}", b),
+                })
+                .collect::<String>();
+            HeaderValue::from_str(&encoded)
+        };
+        match value {
+            Ok(value) => request.header(&self.request_id_header, value),
+            Err(e) => {
+                warn!("Request id not sent as header: {}", e);
+                request
+            }
+        }
+    }
+
+    /// API key header marked as sensitive to be excluded from debug output
+    fn api_key_headers(header: &str, api_key: &str) -> Result<HeaderMap, AppError> {
+        let name = HeaderName::from_str(header.trim())
+            .map_err(|_| ValidationError(format!("Invalid API key header '{}'", header)))?;
+        let mut value = HeaderValue::from_str(api_key.trim())
+            .map_err(|_| ValidationError("Invalid API key".into()))?;
+        value.set_sensitive(true);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(name, value);
+        Ok(headers)
+    }
+
+    async fn acquire(&self) {
+        if let Some(rate_limiter) = &self.rate_limiter {
+            rate_limiter.acquire().await
+        }
+    }
+
+    fn parse_delete_path_template(template: &str) -> Result<String, AppError> {
+        if !template
+            .split('/')
+            .any(|segment| segment == PATIENT_ID_PLACEHOLDER)
+        {
+            return Err(ValidationError(format!(
+                "Delete path template '{}' does not contain '{}'",
+                template, PATIENT_ID_PLACEHOLDER
+            )));
+        }
+        Ok(template.trim_matches('/').to_string())
+    }
+
+    /// Adds HMAC signature of given data if a secret is configured
+    fn signed(&self, request: RequestBuilder, data: &[u8]) -> RequestBuilder {
+        match &self.signer {
+            Some(signer) => request.header(signer.header(), signer.sign(data)),
+            None => request,
+        }
+    }
+
+    fn parse_mtbfile_path(method: MtbFileMethod, path: &str) -> Result<String, AppError> {
+        if method == MtbFileMethod::Put
+            && !path
+                .split('/')
+                .any(|segment| segment == PATIENT_ID_PLACEHOLDER)
+        {
+            return Err(ValidationError(format!(
+                "MTB file path '{}' does not contain '{}' required for PUT",
+                path, PATIENT_ID_PLACEHOLDER
+            )));
+        }
+        Ok(path.trim_matches('/').to_string())
+    }
+
+    /// Builds MTB file URL, replacing path segment `{patient_id}` if present
+    fn mtbfile_url(&self, uri: &str, patient_id: Option<&str>) -> Result<Url, AppError> {
+        if self
+            .mtbfile_path
+            .split('/')
+            .any(|segment| segment == PATIENT_ID_PLACEHOLDER)
+        {
+            return Self::template_url(
+                uri,
+                &self.mtbfile_path,
+                patient_id.unwrap_or_default(),
+                None,
+            );
+        }
+        Url::parse(format!("{}/{}", uri, self.mtbfile_path).as_str())
+            .map_err(|e| HttpError(e.to_string()))
+    }
+
+    /// Builds URL by replacing path segments `{patient_id}` and `{site_id}` of the template
+    fn template_url(
+        uri: &str,
+        path_template: &str,
+        patient_id: &str,
+        site_id: Option<&str>,
+    ) -> Result<Url, AppError> {
+        if patient_id.trim().is_empty() {
+            return Err(ValidationError("Empty patient id".into()));
+        }
+
+        let mut url = Url::parse(uri).map_err(|e| HttpError(e.to_string()))?;
+        let mut segments = url
+            .path_segments_mut()
+            .map_err(|_| HttpError(format!("Cannot use '{}' as base URI", uri)))?;
+        segments.pop_if_empty();
+        for segment in path_template.split('/').filter(|s| !s.is_empty()) {
+            match segment {
+                PATIENT_ID_PLACEHOLDER => segments.push(patient_id),
+                SITE_ID_PLACEHOLDER => match site_id.filter(|id| !id.trim().is_empty()) {
+                    Some(site_id) => segments.push(site_id),
+                    None => return Err(ValidationError("Missing site id".into())),
+                },
+                _ => segments.push(segment),
+            };
+        }
+        drop(segments);
+        Ok(url)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+    use std::time::{Duration, SystemTime};
+
+    use reqwest::header::{HeaderMap, HeaderValue};
+
+    use crate::bwhc_client::{
+        parse_retry_after, BwhcClient, DeleteMode, Endpoint, HttpResponse, MtbFileMethod,
+        RedirectPolicy, ResolveOverride,
+    };
+    use crate::config::test_config;
+    use crate::AppError;
+
+    const URI: &str = "http://localhost:9000/bwhc/etl/api";
+    const DELETE_PATH: &str = "MTBFile/{patient_id}";
+
+    fn client(uri: &str) -> BwhcClient {
+        BwhcClient::new(&test_config(uri)).unwrap()
+    }
+
+    #[test]
+    fn should_build_template_url() {
+        let actual = BwhcClient::template_url(URI, DELETE_PATH, "TESTPATIENT1234", None);
+
+        assert_eq!(
+            actual.unwrap().as_str(),
+            "http://localhost:9000/bwhc/etl/api/MTBFile/TESTPATIENT1234"
+        )
+    }
+
+    #[test]
+    fn should_build_template_url_with_trailing_slash_in_uri() {
+        let actual = BwhcClient::template_url(
+            "http://localhost:9000/bwhc/etl/api/",
+            DELETE_PATH,
+            "TESTPATIENT1234",
+            None,
+        );
+
+        assert_eq!(
+            actual.unwrap().as_str(),
+            "http://localhost:9000/bwhc/etl/api/MTBFile/TESTPATIENT1234"
+        )
+    }
+
+    #[test]
+    fn should_encode_slashes_in_patient_id() {
+        let actual = BwhcClient::template_url(URI, DELETE_PATH, "TEST/PATIENT#1234", None);
+
+        assert_eq!(
+            actual.unwrap().as_str(),
+            "http://localhost:9000/bwhc/etl/api/MTBFile/TEST%2FPATIENT%231234"
+        )
+    }
+
+    #[test]
+    fn should_encode_spaces_in_patient_id() {
+        let actual = BwhcClient::template_url(URI, DELETE_PATH, "TEST PATIENT 1234", None);
+
+        assert_eq!(
+            actual.unwrap().as_str(),
+            "http://localhost:9000/bwhc/etl/api/MTBFile/TEST%20PATIENT%201234"
+        )
+    }
+
+    #[test]
+    fn should_encode_umlauts_in_patient_id() {
+        let actual = BwhcClient::template_url(URI, DELETE_PATH, "Müller1234", None);
+
+        assert_eq!(
+            actual.unwrap().as_str(),
+            "http://localhost:9000/bwhc/etl/api/MTBFile/M%C3%BCller1234"
+        )
+    }
+
+    #[test]
+    fn should_encode_percent_signs_in_patient_id() {
+        let actual = BwhcClient::template_url(URI, DELETE_PATH, "TEST%2F1234", None);
+
+        assert_eq!(
+            actual.unwrap().as_str(),
+            "http://localhost:9000/bwhc/etl/api/MTBFile/TEST%252F1234"
+        )
+    }
+
+    #[test]
+    fn should_not_build_template_url_for_empty_patient_id() {
+        assert!(BwhcClient::template_url(URI, DELETE_PATH, "", None).is_err());
+        assert!(BwhcClient::template_url(URI, DELETE_PATH, "   ", None).is_err());
+    }
+
+    #[test]
+    fn should_build_template_url_with_site_id() {
+        let actual = BwhcClient::template_url(
+            URI,
+            "MTBFile/{site_id}/{patient_id}",
+            "TESTPATIENT1234",
+            Some("TEST/SITE"),
+        );
+
+        assert_eq!(
+            actual.unwrap().as_str(),
+            "http://localhost:9000/bwhc/etl/api/MTBFile/TEST%2FSITE/TESTPATIENT1234"
+        )
+    }
+
+    #[test]
+    fn should_not_build_template_url_without_required_site_id() {
+        let template = "MTBFile/{site_id}/{patient_id}";
+
+        assert!(BwhcClient::template_url(URI, template, "TESTPATIENT1234", None).is_err());
+        assert!(BwhcClient::template_url(URI, template, "TESTPATIENT1234", Some(" ")).is_err());
+    }
+
+    #[test]
+    fn should_reject_delete_path_template_without_patient_id() {
+        let mut config = test_config(URI);
+        config.rest_delete_path_template = "MTBFile/{site_id}".into();
+
+        assert!(BwhcClient::new(&config).is_err());
+    }
+
+    #[tokio::test]
+    async fn should_send_delete_with_configured_site_id() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("DELETE", "/MTBFile/TESTSITE/TESTPATIENT1234")
+            .with_status(200)
+            .expect(1)
+            .create_async()
+            .await;
+
+        let mut config = test_config(server.url().as_str());
+        config.rest_delete_path_template = "MTBFile/{site_id}/{patient_id}".into();
+        config.rest_site_id = Some("TESTSITE".into());
+        let client = BwhcClient::new(&config).unwrap();
+
+        let actual = client
+            .send_delete("request0123456789", "TESTPATIENT1234", None, None)
+            .await;
+
+        assert_eq!(actual.unwrap().status_code, 200);
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn should_send_mtb_file_using_put_with_patient_id() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("PUT", "/MTBFile/TESTPATIENT1234")
+            .with_status(200)
+            .expect(1)
+            .create_async()
+            .await;
+
+        let mut config = test_config(server.url().as_str());
+        config.rest_mtbfile_method = MtbFileMethod::Put;
+        config.rest_mtbfile_path = "MTBFile/{patient_id}".into();
+        let client = BwhcClient::new(&config).unwrap();
+
+        let actual = client
+            .send_mtb_file("request0123456789", Some("TESTPATIENT1234"), "{}", None)
+            .await;
+
+        assert_eq!(actual.unwrap().status_code, 200);
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn should_not_send_mtb_file_without_required_patient_id() {
+        let mut config = test_config(unused_uri().as_str());
+        config.rest_mtbfile_method = MtbFileMethod::Put;
+        config.rest_mtbfile_path = "MTBFile/{patient_id}".into();
+        let client = BwhcClient::new(&config).unwrap();
+
+        let actual = client
+            .send_mtb_file("request0123456789", None, "{}", None)
+            .await;
+
+        assert!(matches!(actual, Err(AppError::ValidationError(_))));
+    }
+
+    #[test]
+    fn should_reject_mtb_file_path_without_patient_id_for_put() {
+        let mut config = test_config(URI);
+        config.rest_mtbfile_method = MtbFileMethod::Put;
+
+        assert!(BwhcClient::new(&config).is_err());
+    }
+
+    #[test]
+    fn should_parse_mtb_file_method() {
+        assert_eq!(
+            MtbFileMethod::from_str("POST").unwrap(),
+            MtbFileMethod::Post
+        );
+        assert_eq!(MtbFileMethod::from_str("put").unwrap(), MtbFileMethod::Put);
+        assert!(MtbFileMethod::from_str("PATCH").is_err());
+    }
+
+    fn response(content_type: Option<&str>, body: &str) -> HttpResponse {
+        HttpResponse {
+            status_code: 200,
+            status_body: body.to_string(),
+            headers: Default::default(),
+            endpoint: None,
+            content_type: content_type.map(|content_type| content_type.to_string()),
+            original_status_code: None,
+            truncated: false,
+            retry_after: None,
+        }
+    }
+
+    #[test]
+    fn should_accept_json_response() {
+        assert!(response(Some("application/json"), r#"{"issues":[]}"#).is_json());
+        assert!(response(Some("application/json; charset=utf-8"), "{}").is_json());
+        assert!(response(Some("application/problem+json"), "{}").is_json());
+        assert!(response(None, "{}").is_json());
+        assert!(response(Some("text/html"), "").is_json());
+    }
+
+    #[test]
+    fn should_not_accept_non_json_response() {
+        assert!(!response(Some("text/html"), "<html>Maintenance</html>").is_json());
+        assert!(!response(Some("text/plain"), "{}").is_json());
+        assert!(!response(Some("application/json"), "<html>Maintenance</html>").is_json());
+        assert!(!response(None, "<html>Maintenance</html>").is_json());
+    }
+
+    #[test]
+    fn should_truncate_body_at_character_boundary() {
+        let mut actual = response(None, "{\"message\": \"äöü\"}");
+        actual.truncate_body(16);
+
+        assert_eq!(actual.status_body, "{\"message\": \"ä");
+        assert!(actual.truncated);
+    }
+
+    #[test]
+    fn should_not_truncate_body_within_limit() {
+        let mut actual = response(None, "{}");
+        actual.truncate_body(2);
+
+        assert_eq!(actual.status_body, "{}");
+        assert!(!actual.truncated);
+    }
+
+    #[test]
+    fn should_return_callback_url_of_accepted_request() {
+        let mut with_header = HttpResponse {
+            status_code: 202,
+            ..response(None, r#"{"statusUrl": "/MTBFile/status/2"}"#)
+        };
+        with_header
+            .headers
+            .insert("Location".to_string(), "/MTBFile/status/1".to_string());
+        let with_body = HttpResponse {
+            status_code: 202,
+            ..response(None, r#"{"statusUrl": "/MTBFile/status/2"}"#)
+        };
+        let without_url = HttpResponse {
+            status_code: 202,
+            ..response(None, r#"{"statusUrl": " "}"#)
+        };
+        let not_accepted = response(None, r#"{"statusUrl": "/MTBFile/status/2"}"#);
+
+        assert_eq!(
+            with_header.callback_url(),
+            Some("/MTBFile/status/1".to_string())
+        );
+        assert_eq!(
+            with_body.callback_url(),
+            Some("/MTBFile/status/2".to_string())
+        );
+        assert_eq!(without_url.callback_url(), None);
+        assert_eq!(not_accepted.callback_url(), None);
+    }
+
+    #[test]
+    fn should_select_location_header() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "location",
+            HeaderValue::from_static("/bwhc/etl/api/MTBFile/TESTPATIENT1234"),
+        );
+        headers.insert("server", HeaderValue::from_static("nginx"));
+
+        let actual = HttpResponse::selected_headers(&headers, &["Location".to_string()]);
+
+        assert_eq!(actual.len(), 1);
+        assert_eq!(
+            actual.get("Location"),
+            Some(&"/bwhc/etl/api/MTBFile/TESTPATIENT1234".to_string())
+        )
+    }
+
+    #[tokio::test]
+    async fn should_select_location_and_request_id_headers_by_default() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("POST", "/MTBFile")
+            .with_status(201)
+            .with_header("location", "/MTBFile/TESTPATIENT1234")
+            .with_header("x-request-id", "request0123456789")
+            .with_header("server", "nginx")
+            .create_async()
+            .await;
+
+        let client = client(server.url().as_str());
+
+        let actual = client
+            .send_mtb_file("request0123456789", None, "{}", None)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            actual.headers.into_iter().collect::<Vec<_>>(),
+            vec![
+                (
+                    "Location".to_string(),
+                    "/MTBFile/TESTPATIENT1234".to_string()
+                ),
+                ("X-Request-ID".to_string(), "request0123456789".to_string())
+            ]
+        );
+        mock.assert_async().await;
+    }
+
+    #[test]
+    fn should_parse_retry_after_seconds() {
+        let now = SystemTime::now();
+
+        assert_eq!(
+            parse_retry_after("120", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(parse_retry_after(" 0 ", now), Some(Duration::ZERO));
+        assert_eq!(parse_retry_after("-1", now), None);
+        assert_eq!(parse_retry_after("soon", now), None);
+    }
+
+    #[test]
+    fn should_parse_retry_after_http_date() {
+        let now = httpdate::parse_http_date("Wed, 21 Oct 2026 07:28:00 GMT").unwrap();
+
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2026 07:30:00 GMT", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2026 07:00:00 GMT", now),
+            Some(Duration::ZERO)
+        );
+    }
+
+    #[tokio::test]
+    async fn should_use_retry_after_of_unavailable_backend_only() {
+        let mut server = mockito::Server::new_async().await;
+        let unavailable = server
+            .mock("POST", "/MTBFile")
+            .with_status(503)
+            .with_header("retry-after", "3")
+            .create_async()
+            .await;
+
+        let client = client(server.url().as_str());
+
+        let actual = client
+            .send_mtb_file("request0123456789", None, "{}", None)
+            .await
+            .unwrap();
+
+        assert_eq!(actual.retry_after, Some(Duration::from_secs(3)));
+        unavailable.assert_async().await;
+
+        server.reset();
+        server
+            .mock("POST", "/MTBFile")
+            .with_status(500)
+            .with_header("retry-after", "3")
+            .create_async()
+            .await;
+
+        let actual = client
+            .send_mtb_file("request0123456789", None, "{}", None)
             .await
-            .map_err(|e| HttpError(e.to_string()))?;
+            .unwrap();
+
+        assert_eq!(actual.retry_after, None);
+    }
+
+    fn unused_uri() -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        format!("http://{}", listener.local_addr().unwrap())
+    }
+
+    #[tokio::test]
+    async fn should_use_primary_endpoint() {
+        let mut primary = mockito::Server::new_async().await;
+        let primary_mock = primary
+            .mock("POST", "/MTBFile")
+            .with_status(201)
+            .create_async()
+            .await;
+        let mut fallback = mockito::Server::new_async().await;
+        let fallback_mock = fallback
+            .mock("POST", "/MTBFile")
+            .expect(0)
+            .create_async()
+            .await;
+
+        let mut client = client(primary.url().as_str());
+        client.fallback_uri = Some(fallback.url());
+
+        let actual = client
+            .send_mtb_file("request0123456789", None, "{}", None)
+            .await;
+
+        assert_eq!(actual.unwrap().status_code, 201);
+        primary_mock.assert_async().await;
+        fallback_mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn should_not_use_fallback_endpoint_on_server_error_by_default() {
+        let mut primary = mockito::Server::new_async().await;
+        let primary_mock = primary
+            .mock("DELETE", "/MTBFile/TESTPATIENT1234")
+            .with_status(503)
+            .create_async()
+            .await;
+        let mut fallback = mockito::Server::new_async().await;
+        let fallback_mock = fallback
+            .mock("DELETE", "/MTBFile/TESTPATIENT1234")
+            .expect(0)
+            .create_async()
+            .await;
+
+        let mut client = client(primary.url().as_str());
+        client.fallback_uri = Some(fallback.url());
+
+        let actual = client
+            .send_delete("request0123456789", "TESTPATIENT1234", None, None)
+            .await
+            .unwrap();
+
+        assert_eq!(actual.status_code, 503);
+        assert_eq!(actual.endpoint, Some(Endpoint::Primary));
+        primary_mock.assert_async().await;
+        fallback_mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn should_use_fallback_endpoint_on_server_error_if_configured() {
+        let mut primary = mockito::Server::new_async().await;
+        let primary_mock = primary
+            .mock("DELETE", "/MTBFile/TESTPATIENT1234")
+            .with_status(503)
+            .create_async()
+            .await;
+        let mut fallback = mockito::Server::new_async().await;
+        let fallback_mock = fallback
+            .mock("DELETE", "/MTBFile/TESTPATIENT1234")
+            .with_status(200)
+            .create_async()
+            .await;
+
+        let mut client = client(primary.url().as_str());
+        client.fallback_uri = Some(fallback.url());
+        client.fallback_on_server_error = true;
+
+        let actual = client
+            .send_delete("request0123456789", "TESTPATIENT1234", None, None)
+            .await;
+
+        assert_eq!(actual.unwrap().status_code, 200);
+        primary_mock.assert_async().await;
+        fallback_mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn should_use_fallback_endpoint_if_primary_unreachable() {
+        let mut fallback = mockito::Server::new_async().await;
+        let fallback_mock = fallback
+            .mock("POST", "/MTBFile")
+            .with_status(201)
+            .create_async()
+            .await;
+
+        let mut client = client(unused_uri().as_str());
+        client.fallback_uri = Some(fallback.url());
+
+        let actual = client
+            .send_mtb_file("request0123456789", None, "{}", None)
+            .await;
+
+        assert_eq!(actual.unwrap().status_code, 201);
+        fallback_mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn should_return_error_if_primary_and_fallback_unreachable() {
+        let mut client = client(unused_uri().as_str());
+        client.fallback_uri = Some(unused_uri());
+
+        let actual = client
+            .send_mtb_file("request0123456789", None, "{}", None)
+            .await;
 
-        Ok(
-            HttpResponse { status_code: response.status().as_u16(), status_body: response.text().await.unwrap_or(String::new()) }
+        assert!(actual.is_err())
+    }
+
+    #[tokio::test]
+    async fn should_report_endpoint_and_keep_using_fallback_during_cooldown() {
+        let mut primary = mockito::Server::new_async().await;
+        let primary_mock = primary
+            .mock("DELETE", "/MTBFile/TESTPATIENT1234")
+            .with_status(503)
+            .expect(1)
+            .create_async()
+            .await;
+        let mut fallback = mockito::Server::new_async().await;
+        let fallback_mock = fallback
+            .mock("DELETE", "/MTBFile/TESTPATIENT1234")
+            .with_status(200)
+            .expect(2)
+            .create_async()
+            .await;
+
+        let mut client = client(primary.url().as_str());
+        client.fallback_uri = Some(fallback.url());
+        client.fallback_on_server_error = true;
+        client.fallback_cooldown = Duration::from_secs(60);
+
+        for _ in 0..2 {
+            let actual = client
+                .send_delete("request0123456789", "TESTPATIENT1234", None, None)
+                .await
+                .unwrap();
+
+            assert_eq!(actual.status_code, 200);
+            assert_eq!(actual.endpoint, Some(Endpoint::Fallback));
+        }
+        primary_mock.assert_async().await;
+        fallback_mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn should_use_primary_endpoint_again_without_cooldown() {
+        let mut primary = mockito::Server::new_async().await;
+        let primary_mock = primary
+            .mock("POST", "/MTBFile")
+            .with_status(503)
+            .expect(2)
+            .create_async()
+            .await;
+        let mut fallback = mockito::Server::new_async().await;
+        let fallback_mock = fallback
+            .mock("POST", "/MTBFile")
+            .with_status(201)
+            .expect(2)
+            .create_async()
+            .await;
+
+        let mut client = client(primary.url().as_str());
+        client.fallback_uri = Some(fallback.url());
+        client.fallback_on_server_error = true;
+
+        for _ in 0..2 {
+            let actual = client
+                .send_mtb_file("request0123456789", None, "{}", None)
+                .await
+                .unwrap();
+
+            assert_eq!(actual.endpoint, Some(Endpoint::Fallback));
+        }
+        primary_mock.assert_async().await;
+        fallback_mock.assert_async().await;
+    }
+
+    #[test]
+    fn should_route_tenant_to_configured_uri() {
+        let mut client = client(URI);
+        client.tenant_routes = BwhcClient::parse_tenant_routes(
+            r#"{ "tenant1": "http://bwhc1:9000/bwhc/etl/api", "tenant2": "http://bwhc2:9000/bwhc/etl/api" }"#,
         )
+        .unwrap();
+
+        assert_eq!(
+            client.uri_for(Some("tenant1")),
+            "http://bwhc1:9000/bwhc/etl/api"
+        );
+        assert_eq!(
+            client.uri_for(Some("tenant2")),
+            "http://bwhc2:9000/bwhc/etl/api"
+        );
     }
 
-    pub async fn send_delete(patient_id: &str) -> Result<HttpResponse, AppError> {
-        let uri = env::var("APP_REST_URI").map_err(|e| MissingConfig(e.to_string()))?;
+    #[test]
+    fn should_route_unknown_or_missing_tenant_to_default_uri() {
+        let mut client = client(URI);
+        client.tenant_routes =
+            BwhcClient::parse_tenant_routes(r#"{ "tenant1": "http://bwhc1:9000/bwhc/etl/api" }"#)
+                .unwrap();
 
-        let client = reqwest::Client::new();
-        let response = client
-            .delete(format!("{}/MTBFile/{}", uri, patient_id))
-            .header("Content-Type", "application/json")
-            .timeout(Duration::from_secs(5))
-            .send()
+        assert_eq!(client.uri_for(Some("tenant3")), URI);
+        assert_eq!(client.uri_for(None), URI);
+    }
+
+    #[test]
+    fn should_reject_invalid_tenant_routes() {
+        assert!(BwhcClient::parse_tenant_routes(r#"["tenant1"]"#).is_err())
+    }
+
+    #[test]
+    fn should_require_rest_uri() {
+        let mut config = test_config(URI);
+        config.rest_uri = None;
+
+        assert!(BwhcClient::new(&config).is_err())
+    }
+
+    #[tokio::test]
+    async fn should_refresh_bearer_token_and_retry_once_on_401() {
+        let token_file =
+            std::env::temp_dir().join(format!("kafka-to-bwhc-token-{}", std::process::id()));
+        std::fs::write(&token_file, "expired-token").unwrap();
+
+        let mut server = mockito::Server::new_async().await;
+        let rejected = server
+            .mock("POST", "/MTBFile")
+            .match_header("authorization", "Bearer expired-token")
+            .with_status(401)
+            .expect(1)
+            .create_async()
+            .await;
+        let accepted = server
+            .mock("POST", "/MTBFile")
+            .match_header("authorization", "Bearer new-token")
+            .with_status(201)
+            .expect(1)
+            .create_async()
+            .await;
+
+        let mut config = test_config(server.url().as_str());
+        config.rest_bearer_token_file = Some(token_file.clone());
+        let client = BwhcClient::new(&config).unwrap();
+
+        // Cache expired token before token file is updated
+        client.bearer_token.as_ref().unwrap().token().unwrap();
+        std::fs::write(&token_file, "new-token").unwrap();
+
+        let actual = client
+            .send_mtb_file("request0123456789", None, "{}", None)
+            .await;
+
+        assert_eq!(actual.unwrap().status_code, 201);
+        rejected.assert_async().await;
+        accepted.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn should_not_retry_on_other_status_codes() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("DELETE", "/MTBFile/TESTPATIENT1234")
+            .with_status(500)
+            .expect(1)
+            .create_async()
+            .await;
+
+        let mut config = test_config(server.url().as_str());
+        config.rest_bearer_token = Some("token".into());
+        let client = BwhcClient::new(&config).unwrap();
+
+        let actual = client
+            .send_delete("request0123456789", "TESTPATIENT1234", None, None)
+            .await;
+
+        assert_eq!(actual.unwrap().status_code, 500);
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn should_follow_redirect_to_same_host_with_bearer_token() {
+        let mut server = mockito::Server::new_async().await;
+        let redirect = server
+            .mock("POST", "/MTBFile")
+            .with_status(307)
+            .with_header("Location", "/region/MTBFile")
+            .expect(1)
+            .create_async()
+            .await;
+        let target = server
+            .mock("POST", "/region/MTBFile")
+            .match_header("authorization", "Bearer token")
+            .with_status(201)
+            .expect(1)
+            .create_async()
+            .await;
+
+        let mut config = test_config(server.url().as_str());
+        config.rest_bearer_token = Some("token".into());
+        config.rest_redirect_policy = RedirectPolicy::SameHostOnly;
+        let client = BwhcClient::new(&config).unwrap();
+
+        let actual = client
+            .send_mtb_file("request0123456789", None, "{}", None)
+            .await;
+
+        assert_eq!(actual.unwrap().status_code, 201);
+        redirect.assert_async().await;
+        target.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn should_send_request_using_configured_proxy() {
+        let mut proxy = mockito::Server::new_async().await;
+        let upload = proxy
+            .mock("POST", "/bwhc/etl/api/MTBFile")
+            .match_header("host", "bwhc.example.invalid")
+            .with_status(201)
+            .expect(1)
+            .create_async()
+            .await;
+
+        let mut config = test_config("http://bwhc.example.invalid/bwhc/etl/api");
+        config.rest_proxy = Some(proxy.url());
+        let client = BwhcClient::new(&config).unwrap();
+
+        let actual = client
+            .send_mtb_file("request0123456789", None, "{}", None)
+            .await
+            .unwrap();
+
+        assert_eq!(actual.status_code, 201);
+        upload.assert_async().await;
+    }
+
+    #[test]
+    fn should_reject_invalid_proxy() {
+        let mut config = test_config(URI);
+        config.rest_proxy = Some("not a proxy".into());
+
+        assert!(matches!(
+            BwhcClient::new(&config),
+            Err(AppError::ValidationError(message)) if message.starts_with("Invalid proxy")
+        ));
+    }
+
+    #[tokio::test]
+    async fn should_return_redirect_with_location_if_not_followed() {
+        let other_host = unused_uri();
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("POST", "/MTBFile")
+            .with_status(307)
+            .with_header("Location", format!("{}/MTBFile", other_host).as_str())
+            .create_async()
+            .await;
+
+        for policy in [RedirectPolicy::None, RedirectPolicy::SameHostOnly] {
+            let mut config = test_config(server.url().as_str());
+            config.rest_redirect_policy = policy;
+            config.rest_response_headers = vec![];
+            let client = BwhcClient::new(&config).unwrap();
+
+            let actual = client
+                .send_mtb_file("request0123456789", None, "{}", None)
+                .await
+                .unwrap();
+
+            assert_eq!(actual.status_code, 307);
+            assert_eq!(
+                actual.headers.get("Location"),
+                Some(&format!("{}/MTBFile", other_host))
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn should_return_location_of_accepted_request_if_not_polled() {
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("POST", "/MTBFile")
+            .with_status(202)
+            .with_header("Location", "/MTBFile/status/1")
+            .create_async()
+            .await;
+
+        let mut config = test_config(server.url().as_str());
+        config.rest_response_headers = vec![];
+        let client = BwhcClient::new(&config).unwrap();
+
+        let actual = client
+            .send_mtb_file("request0123456789", None, "{}", None)
             .await
-            .map_err(|e| HttpError(e.to_string()))?;
+            .unwrap();
 
-        Ok(
-            HttpResponse { status_code: response.status().as_u16(), status_body: response.text().await.unwrap_or(String::new()) }
+        assert_eq!(actual.status_code, 202);
+        assert_eq!(actual.callback_url(), Some("/MTBFile/status/1".to_string()));
+    }
+
+    #[tokio::test]
+    async fn should_send_request_id_header() {
+        let mut server = mockito::Server::new_async().await;
+        let upload = server
+            .mock("POST", "/MTBFile")
+            .match_header("x-request-id", "request0123456789")
+            .with_status(201)
+            .expect(1)
+            .create_async()
+            .await;
+        let delete = server
+            .mock("DELETE", "/MTBFile/TESTPATIENT1234")
+            .match_header("x-request-id", "request0123456789")
+            .with_status(200)
+            .expect(1)
+            .create_async()
+            .await;
+
+        let client = client(server.url().as_str());
+
+        let actual = client
+            .send_mtb_file("request0123456789", None, "{}", None)
+            .await;
+        assert_eq!(actual.unwrap().status_code, 201);
+
+        let actual = client
+            .send_delete("request0123456789", "TESTPATIENT1234", None, None)
+            .await;
+        assert_eq!(actual.unwrap().status_code, 200);
+
+        upload.assert_async().await;
+        delete.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn should_send_percent_encoded_non_ascii_request_id() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("POST", "/MTBFile")
+            .match_header("x-request-id", "anfrage-%C3%A4-100%25")
+            .with_status(201)
+            .expect(1)
+            .create_async()
+            .await;
+
+        let client = client(server.url().as_str());
+
+        let actual = client
+            .send_mtb_file("anfrage-ä-100%", None, "{}", None)
+            .await;
+
+        assert_eq!(actual.unwrap().status_code, 201);
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn should_send_request_id_using_configured_header() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("POST", "/MTBFile")
+            .match_header("x-correlation-id", "request0123456789")
+            .with_status(201)
+            .expect(1)
+            .create_async()
+            .await;
+
+        let mut config = test_config(server.url().as_str());
+        config.rest_request_id_header = "X-Correlation-ID".into();
+        let client = BwhcClient::new(&config).unwrap();
+
+        let actual = client
+            .send_mtb_file("request0123456789", None, "{}", None)
+            .await;
+
+        assert_eq!(actual.unwrap().status_code, 201);
+        mock.assert_async().await;
+    }
+
+    #[test]
+    fn should_reject_invalid_request_id_header() {
+        let mut config = test_config(URI);
+        config.rest_request_id_header = "X Request ID".into();
+
+        assert!(BwhcClient::new(&config).is_err());
+    }
+
+    #[tokio::test]
+    async fn should_send_api_key_header() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("DELETE", "/MTBFile/TESTPATIENT1234")
+            .match_header("ocp-apim-subscription-key", "secret-key")
+            .with_status(200)
+            .expect(1)
+            .create_async()
+            .await;
+
+        let mut config = test_config(server.url().as_str());
+        config.rest_api_key = Some("secret-key".into());
+        config.rest_api_key_header = "Ocp-Apim-Subscription-Key".into();
+        let client = BwhcClient::new(&config).unwrap();
+
+        let actual = client
+            .send_delete("request0123456789", "TESTPATIENT1234", None, None)
+            .await;
+
+        assert_eq!(actual.unwrap().status_code, 200);
+        mock.assert_async().await;
+    }
+
+    #[test]
+    fn should_not_include_api_key_in_debug_output() {
+        let headers = BwhcClient::api_key_headers("X-API-Key", "secret-key").unwrap();
+
+        assert!(!format!("{:?}", headers).contains("secret-key"));
+    }
+
+    #[tokio::test]
+    async fn should_send_hmac_signature_header() {
+        let mut server = mockito::Server::new_async().await;
+        let upload = server
+            .mock("POST", "/MTBFile")
+            .match_header(
+                "x-signature",
+                "3bea8cb66e88d11cfa12c6902830987ff31d5e03f0d5aba8d46af457ae01a225",
+            )
+            .with_status(201)
+            .expect(1)
+            .create_async()
+            .await;
+        let delete = server
+            .mock("DELETE", "/MTBFile/TESTPATIENT1234")
+            .match_header(
+                "x-signature",
+                "484dd599d3702db001505dace04f49c4878c97e5fbfe1345cf02b21779a7a415",
+            )
+            .with_status(200)
+            .expect(1)
+            .create_async()
+            .await;
+        let consent = server
+            .mock("POST", "/Consent")
+            .match_header(
+                "x-signature",
+                "3bea8cb66e88d11cfa12c6902830987ff31d5e03f0d5aba8d46af457ae01a225",
+            )
+            .match_header("authorization", "Bearer secret-token")
+            .with_status(200)
+            .expect(1)
+            .create_async()
+            .await;
+
+        let mut config = test_config(server.url().as_str());
+        config.rest_hmac_secret = Some("secret".into());
+        config.rest_bearer_token = Some("secret-token".into());
+        let client = BwhcClient::new(&config).unwrap();
+
+        let actual = client
+            .send_mtb_file("request0123456789", None, r#"{"consent":{}}"#, None)
+            .await;
+        assert_eq!(actual.unwrap().status_code, 201);
+
+        let actual = client
+            .send_delete("request0123456789", "TESTPATIENT1234", None, None)
+            .await;
+        assert_eq!(actual.unwrap().status_code, 200);
+
+        let actual = client
+            .send_consent("request0123456789", r#"{"consent":{}}"#, None)
+            .await;
+        assert_eq!(actual.unwrap().status_code, 200);
+
+        upload.assert_async().await;
+        delete.assert_async().await;
+        consent.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn should_detect_connection_closed_before_response() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            if let Ok((mut stream, _)) = listener.accept() {
+                let _ = std::io::Read::read(&mut stream, &mut [0; 1024]);
+            }
+        });
+
+        let actual = reqwest::Client::new()
+            .delete(format!("http://{}/MTBFile/TESTPATIENT1234", addr))
+            .send()
+            .await;
+
+        assert!(BwhcClient::is_connection_closed(&actual.unwrap_err()));
+    }
+
+    #[tokio::test]
+    async fn should_not_detect_connection_closed_if_connection_refused() {
+        let actual = reqwest::Client::new().delete(unused_uri()).send().await;
+
+        assert!(!BwhcClient::is_connection_closed(&actual.unwrap_err()));
+    }
+
+    #[test]
+    fn should_reject_invalid_api_key_header() {
+        assert!(BwhcClient::api_key_headers("X API Key", "secret-key").is_err());
+    }
+
+    #[test]
+    fn should_build_client_with_connection_options() {
+        let mut config = test_config(URI);
+        config.rest_pool_idle_timeout = Some(30);
+        config.rest_pool_max_idle = Some(4);
+        config.rest_tcp_keepalive = Some(60);
+        config.rest_http2 = true;
+
+        assert!(BwhcClient::new(&config).is_ok());
+    }
+
+    #[test]
+    fn should_parse_resolve_override() {
+        let actual = ResolveOverride::from_str("bwhc.example.org:443=10.1.2.3").unwrap();
+
+        assert_eq!(actual.host, "bwhc.example.org");
+        assert_eq!(actual.addr, "10.1.2.3:443".parse().unwrap());
+
+        let actual = ResolveOverride::from_str("bwhc.example.org:8443=[::1]").unwrap();
+
+        assert_eq!(actual.addr, "[::1]:8443".parse().unwrap());
+    }
+
+    #[test]
+    fn should_not_parse_invalid_resolve_override() {
+        for entry in [
+            "bwhc.example.org",
+            "bwhc.example.org=10.1.2.3",
+            "bwhc.example.org:443=10.1.2",
+            "bwhc.example.org:port=10.1.2.3",
+            ":443=10.1.2.3",
+        ] {
+            assert!(ResolveOverride::from_str(entry).is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn should_use_resolve_override() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("POST", "/MTBFile")
+            .match_header("host", mockito::Matcher::Regex("^bwhc.example.org".into()))
+            .with_status(201)
+            .expect(1)
+            .create_async()
+            .await;
+        let port = server.socket_address().port();
+
+        let mut config = test_config(format!("http://bwhc.example.org:{}", port).as_str());
+        config.rest_resolve = vec![ResolveOverride::from_str(
+            format!("bwhc.example.org:{}=127.0.0.1", port).as_str(),
         )
+        .unwrap()];
+        let client = BwhcClient::new(&config).unwrap();
+
+        let actual = client
+            .send_mtb_file("request0123456789", None, "{}", None)
+            .await;
+
+        assert_eq!(actual.unwrap().status_code, 201);
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn should_poll_result_of_accepted_request() {
+        let mut server = mockito::Server::new_async().await;
+        let upload = server
+            .mock("POST", "/MTBFile")
+            .with_status(202)
+            .with_header("Location", "/jobs/1234")
+            .expect(1)
+            .create_async()
+            .await;
+        let job = server
+            .mock("GET", "/jobs/1234")
+            .match_header("x-request-id", "request0123456789")
+            .with_status(201)
+            .with_body(r#"{"issues":[]}"#)
+            .expect(1)
+            .create_async()
+            .await;
+
+        let mut config = test_config(server.url().as_str());
+        config.rest_poll_accepted = true;
+        config.rest_poll_interval_ms = 10;
+        let client = BwhcClient::new(&config).unwrap();
+
+        let actual = client
+            .send_mtb_file("request0123456789", None, "{}", None)
+            .await
+            .unwrap();
+
+        assert_eq!(actual.status_code, 201);
+        assert_eq!(actual.status_body, r#"{"issues":[]}"#);
+        upload.assert_async().await;
+        job.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn should_report_polling_timeout() {
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("POST", "/MTBFile")
+            .with_status(202)
+            .with_header("Location", "/jobs/1234")
+            .create_async()
+            .await;
+        server
+            .mock("GET", "/jobs/1234")
+            .with_status(202)
+            .create_async()
+            .await;
+
+        let mut config = test_config(server.url().as_str());
+        config.rest_poll_accepted = true;
+        config.rest_poll_interval_ms = 10;
+        config.rest_poll_max_wait = 0;
+        let client = BwhcClient::new(&config).unwrap();
+
+        let actual = client
+            .send_mtb_file("request0123456789", None, "{}", None)
+            .await;
+
+        assert!(
+            matches!(actual, Err(AppError::PollingTimeout(location)) if location.ends_with("/jobs/1234"))
+        );
+    }
+
+    #[tokio::test]
+    async fn should_not_poll_result_by_default() {
+        let mut server = mockito::Server::new_async().await;
+        let job = server
+            .mock("GET", "/jobs/1234")
+            .expect(0)
+            .create_async()
+            .await;
+        server
+            .mock("POST", "/MTBFile")
+            .with_status(202)
+            .with_header("Location", "/jobs/1234")
+            .create_async()
+            .await;
+
+        let client = client(server.url().as_str());
+
+        let actual = client
+            .send_mtb_file("request0123456789", None, "{}", None)
+            .await;
+
+        assert_eq!(actual.unwrap().status_code, 202);
+        job.assert_async().await;
+    }
+
+    #[test]
+    fn should_use_path_prefix_for_unix_socket() {
+        let mut config = test_config("unix:///run/bwhc/api.sock");
+        config.rest_uri_path_prefix = "/bwhc/etl/api/".into();
+
+        let client = BwhcClient::new(&config).unwrap();
+
+        assert!(client.unix_socket.is_some());
+        assert_eq!(
+            client
+                .mtbfile_url(client.uri_for(None), None)
+                .unwrap()
+                .as_str(),
+            "http://localhost/bwhc/etl/api/MTBFile"
+        );
+    }
+
+    #[test]
+    fn should_reject_fallback_uri_with_tenant_routes() {
+        let mut config = test_config(URI);
+        config.rest_uri_fallback = Some("http://bwhc2:9000/bwhc/etl/api".into());
+        config.tenant_routes = Some(r#"{ "tenant1": "http://bwhc1:9000/bwhc/etl/api" }"#.into());
+
+        assert!(BwhcClient::new(&config).is_err());
+    }
+
+    #[test]
+    fn should_reject_fallback_uri_for_unix_socket() {
+        let mut config = test_config("unix:///run/bwhc/api.sock");
+        config.rest_uri_fallback = Some(URI.into());
+
+        assert!(BwhcClient::new(&config).is_err());
+    }
+
+    #[tokio::test]
+    async fn should_return_connect_error_if_unix_socket_is_not_available() {
+        let socket = std::env::temp_dir().join(format!(
+            "kafka-to-bwhc-unavailable-{}.sock",
+            std::process::id()
+        ));
+        let client = client(format!("unix://{}", socket.display()).as_str());
+
+        let actual = client
+            .send_delete("request0123456789", "TESTPATIENT1234", None, None)
+            .await;
+
+        assert!(matches!(actual, Err(AppError::HttpConnectError(_))));
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn should_check_health_using_path() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("HEAD", "/health")
+            .with_status(204)
+            .expect(1)
+            .create_async()
+            .await;
+
+        let client = client(server.url().as_str());
+
+        let actual = client.check_health("/health").await;
+
+        assert_eq!(actual.unwrap(), 204);
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn should_use_delete_retries_for_deletes_only() {
+        let mut server = mockito::Server::new_async().await;
+        let delete = server
+            .mock("DELETE", "/MTBFile/TESTPATIENT1234")
+            .with_status(503)
+            .expect(3)
+            .create_async()
+            .await;
+        let upload = server
+            .mock("POST", "/MTBFile")
+            .with_status(503)
+            .expect(1)
+            .create_async()
+            .await;
+
+        let mut config = test_config(server.url().as_str());
+        config.rest_delete_retries = Some(2);
+        config.rest_retry_delay_ms = 1;
+        let client = BwhcClient::new(&config).unwrap();
+
+        let actual = client
+            .send_delete("request0123456789", "TESTPATIENT1234", None, None)
+            .await;
+        assert_eq!(actual.unwrap().status_code, 503);
+
+        let actual = client
+            .send_mtb_file("request0123456789", None, "{}", None)
+            .await;
+        assert_eq!(actual.unwrap().status_code, 503);
+
+        delete.assert_async().await;
+        upload.assert_async().await;
+    }
+
+    #[test]
+    fn should_parse_delete_mode() {
+        assert_eq!(DeleteMode::from_str("delete").unwrap(), DeleteMode::Delete);
+        assert_eq!(
+            DeleteMode::from_str("post-consent").unwrap(),
+            DeleteMode::PostConsent
+        );
+        assert!(DeleteMode::from_str("purge").is_err());
+    }
+
+    #[tokio::test]
+    async fn should_post_consent() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("POST", "/Consent")
+            .match_body(r#"{"id":"TESTID1234","patient":"TESTPATIENT1234","status":"rejected"}"#)
+            .with_status(200)
+            .create_async()
+            .await;
+
+        let client = client(server.url().as_str());
+
+        let actual = client
+            .send_consent(
+                "request0123456789",
+                r#"{"id":"TESTID1234","patient":"TESTPATIENT1234","status":"rejected"}"#,
+                None,
+            )
+            .await;
+
+        assert_eq!(actual.unwrap().status_code, 200);
+        mock.assert_async().await;
+    }
+}
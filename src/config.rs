@@ -0,0 +1,1576 @@
+/*
+ * This file is part of ETL-Processor
+ *
+ * Copyright (c) 2024  Comprehensive Cancer Center Mainfranken
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published
+ * by the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use clap::error::ErrorKind;
+use clap::{ArgAction, Args, CommandFactory, Parser, Subcommand, ValueEnum};
+use regex::Regex;
+
+use crate::bwhc_client::{DeleteMode, MtbFileMethod, RedirectPolicy, ResolveOverride};
+use crate::json_pointer::RECURSIVE_WILDCARD;
+use crate::key_template::KeyTemplate;
+use crate::pseudonym::DEFAULT_PSEUDONYM_PATHS;
+use crate::resources::issues::Severity;
+use crate::resources::mtbfile::{
+    PatientIdSource, DEFAULT_CONSENT_ACTIVE_STATUS, DEFAULT_CONSENT_DATE_FIELD,
+    DEFAULT_CONSENT_POINTER,
+};
+use crate::resources::request::{RequestIdFormat, DEFAULT_MAX_DECODED_CONTENT_BYTES};
+use crate::retry::RetryStatus;
+use crate::sink::SinkType;
+use crate::AppError;
+use crate::AppError::{MissingConfig, ValidationError};
+
+#[derive(Parser)]
+#[command(author, version, about, args_override_self = true)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    #[command(flatten)]
+    pub config: Config,
+}
+
+impl Cli {
+    /// Parses command line arguments, environment variables and settings of `APP_CONFIG_FILE`.
+    /// Settings of the configuration file take precedence over environment variables.
+    pub fn load() -> Result<Self, clap::Error> {
+        Self::load_from(env::args().collect())
+    }
+
+    fn load_from(args: Vec<String>) -> Result<Self, clap::Error> {
+        let cli = Cli::try_parse_from(&args)?;
+        let Some(config_file) = &cli.config.config_file else {
+            return Ok(cli);
+        };
+        let content = fs::read_to_string(config_file).map_err(|e| {
+            Cli::command().error(
+                ErrorKind::Io,
+                format!("Cannot read '{}': {}", config_file.display(), e),
+            )
+        })?;
+
+        // Explicit command line arguments still take precedence
+        let mut file_args = args.iter().take(1).cloned().collect::<Vec<_>>();
+        file_args.extend(config_file_args(&content)?);
+        file_args.extend(args.into_iter().skip(1));
+        Cli::try_parse_from(file_args)
+    }
+}
+
+/// Settings of the configuration file by name of environment variable, values are not validated
+pub fn config_file_settings(path: &Path) -> std::io::Result<BTreeMap<String, String>> {
+    Ok(fs::read_to_string(path)?
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let (key, value) = line.split_once('=').unwrap_or((line, ""));
+            (key.trim().to_string(), value.trim().to_string())
+        })
+        .collect())
+}
+
+/// Checks if setting is applied when reloading the configuration, i.e. used to create the sink.
+/// Health checks of the bwHC-Backend keep their settings of application start.
+pub fn is_sink_setting(key: &str) -> bool {
+    match key {
+        "APP_TENANT_ROUTES" | "APP_DELETE_MODE" => true,
+        key if key.starts_with("APP_REST_HEALTHCHECK_") => false,
+        "APP_REST_WARMUP" | "APP_REST_REQUIRE_REACHABLE" => false,
+        key => key.starts_with("APP_SINK") || key.starts_with("APP_REST_"),
+    }
+}
+
+/// Converts `KEY=VALUE` lines using names of environment variables into command line arguments
+fn config_file_args(content: &str) -> Result<Vec<String>, clap::Error> {
+    let command = Cli::command();
+    let mut args = vec![];
+    for line in content
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+    {
+        let (key, value) = line.split_once('=').unwrap_or((line, ""));
+        let arg = command
+            .get_arguments()
+            .find(|arg| arg.get_env().is_some_and(|env| env == key.trim()))
+            .ok_or_else(|| {
+                Cli::command().error(
+                    ErrorKind::UnknownArgument,
+                    format!("Unknown setting '{}' in configuration file", key.trim()),
+                )
+            })?;
+        let name = format!("--{}", arg.get_long().unwrap_or(arg.get_id().as_str()));
+        let value = value.trim();
+        match arg.get_action() {
+            ArgAction::SetTrue => {
+                if !matches!(value.to_lowercase().as_str(), "false" | "0" | "no" | "off") {
+                    args.push(name);
+                }
+            }
+            _ => args.extend([name, value.to_string()]),
+        }
+    }
+    Ok(args)
+}
+
+#[derive(Subcommand, Debug, PartialEq)]
+pub enum Command {
+    /// Consume requests and send them to bwHC-Backend (default)
+    Run,
+    /// Load and validate configuration
+    ValidateConfig,
+    /// Check connection to Kafka and bwHC-Backend
+    CheckConnection,
+    /// Check Kafka and bwHC-Backend health and exit with non-zero exit code on failure, e.g. for container healthchecks
+    Selftest,
+    /// Reprocess requests of the dead letter queue and send them to the dead letter queue again on failure
+    ReplayDlq {
+        /// Only log requests to be replayed without sending them to bwHC-Backend
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
+/// Handling of requests without consent status or with unknown consent status
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum UndeterminedConsentPolicy {
+    /// Log error and drop request
+    Drop,
+    /// Send error response
+    Respond,
+    /// Send error response and send request to dead letter queue
+    Dlq,
+}
+
+/// Handling of requests with consent status `entered-in-error`
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum EnteredInErrorPolicy {
+    /// Delete MTB file of patient
+    Delete,
+    /// Skip request and send response that it was ignored
+    Ignore,
+}
+
+/// Handling of requests with a consent whose validity period has ended
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum ExpiredConsentPolicy {
+    /// Delete MTB file of patient
+    Delete,
+    /// Skip request and send response that it was ignored
+    Ignore,
+}
+
+/// Time to check the validity period of consents against
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum ConsentValidityTime {
+    /// Timestamp of the Kafka record, the current time if not available
+    Record,
+    /// Current time
+    Now,
+}
+
+/// Mode to commit offsets of processed messages
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum KafkaCommitMode {
+    /// Commit offsets without waiting for the result
+    Async,
+    /// Wait for each commit to be completed
+    Sync,
+}
+
+/// Format of consumed records
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum PayloadFormat {
+    /// JSON request, or Avro record if a schema registry is configured
+    Json,
+    /// Protobuf message as defined in `docs/request.proto`
+    Protobuf,
+}
+
+/// Handling of requests with content without consent
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum MissingConsentPolicy {
+    /// Send error response
+    Reject,
+    /// Send MTB file as if consent is active
+    Upload,
+    /// Delete MTB file of patient given by patient id of content
+    Delete,
+}
+
+/// Method to replace patient ids by pseudonyms before sending them
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum PseudonymizeMode {
+    /// HMAC-SHA256 of the patient id using the configured salt, calculated by this application
+    Buildin,
+}
+
+/// Configuration using command line arguments or environment variables
+#[derive(Args, Clone, Debug)]
+pub struct Config {
+    /// File containing settings as KEY=VALUE lines using names of environment variables.
+    /// REST settings are reloaded from this file on SIGHUP
+    #[arg(long, env = "APP_CONFIG_FILE")]
+    pub config_file: Option<PathBuf>,
+
+    /// Log level (error, warn, info, debug, trace). Default: RUST_LOG or info
+    #[arg(long, env = "APP_LOG_LEVEL")]
+    pub log_level: Option<String>,
+
+    /// OTLP endpoint to export tracing spans to using HTTP, e.g. `http://localhost:4318/v1/traces`
+    #[arg(long, env = "APP_OTLP_ENDPOINT")]
+    pub otlp_endpoint: Option<String>,
+
+    /// Target of requests
+    #[arg(long, env = "APP_SINK", value_enum, default_value = "http")]
+    pub sink: SinkType,
+
+    /// JSON Schema file to validate MTB files before sending them. Not validated if not set
+    #[arg(long, env = "APP_MTBFILE_SCHEMA_FILE")]
+    pub mtbfile_schema_file: Option<PathBuf>,
+
+    /// Directory used by file sink
+    #[arg(long, env = "APP_SINK_DIR")]
+    pub sink_dir: Option<PathBuf>,
+
+    /// Status code returned by null sink
+    #[arg(long, env = "APP_SINK_NULL_STATUS", default_value_t = 200)]
+    pub sink_null_status: u16,
+
+    /// Delay in milliseconds before null sink returns
+    #[arg(long, env = "APP_SINK_NULL_DELAY_MS", default_value_t = 0)]
+    pub sink_null_delay_ms: u64,
+
+    /// URI of bwHC-Backend API, e.g. http://localhost:9000/bwhc/etl/api or unix:///run/bwhc/api.sock
+    #[arg(long, env = "APP_REST_URI")]
+    pub rest_uri: Option<String>,
+
+    /// Path of bwHC-Backend API if APP_REST_URI is a unix socket, e.g. /bwhc/etl/api
+    #[arg(long, env = "APP_REST_URI_PATH_PREFIX", default_value = "")]
+    pub rest_uri_path_prefix: String,
+
+    /// URI of bwHC-Backend API used if requests to primary URI fail
+    #[arg(long, env = "APP_REST_URI_FALLBACK")]
+    pub rest_uri_fallback: Option<String>,
+
+    /// Alternative name of APP_REST_URI_FALLBACK
+    #[arg(
+        long,
+        env = "APP_REST_FALLBACK_URI",
+        conflicts_with = "rest_uri_fallback"
+    )]
+    pub rest_fallback_uri: Option<String>,
+
+    /// Use fallback URI if primary URI responds with HTTP status 5xx, not only if it is unreachable
+    #[arg(long, env = "APP_REST_FALLBACK_ON_5XX")]
+    pub rest_fallback_on_5xx: bool,
+
+    /// Time in seconds to keep using the fallback URI after primary URI failed
+    #[arg(long, env = "APP_REST_FALLBACK_COOLDOWN", default_value_t = 0)]
+    pub rest_fallback_cooldown: u64,
+
+    /// Bearer token used to authenticate requests
+    #[arg(long, env = "APP_REST_BEARER_TOKEN", hide_env_values = true)]
+    pub rest_bearer_token: Option<String>,
+
+    /// File containing bearer token used to authenticate requests
+    #[arg(
+        long,
+        env = "APP_REST_BEARER_TOKEN_FILE",
+        conflicts_with = "rest_bearer_token"
+    )]
+    pub rest_bearer_token_file: Option<PathBuf>,
+
+    /// API key used to authenticate requests
+    #[arg(
+        long,
+        env = "APP_REST_API_KEY",
+        hide_env_values = true,
+        conflicts_with_all = ["rest_bearer_token", "rest_bearer_token_file"]
+    )]
+    pub rest_api_key: Option<String>,
+
+    /// Request header used to send API key
+    #[arg(long, env = "APP_REST_API_KEY_HEADER", default_value = "X-API-Key")]
+    pub rest_api_key_header: String,
+
+    /// Shared secret used to sign requests using HMAC-SHA256
+    #[arg(long, env = "APP_REST_HMAC_SECRET", hide_env_values = true)]
+    pub rest_hmac_secret: Option<String>,
+
+    /// File containing shared secret used to sign requests using HMAC-SHA256
+    #[arg(
+        long,
+        env = "APP_REST_HMAC_SECRET_FILE",
+        conflicts_with = "rest_hmac_secret"
+    )]
+    pub rest_hmac_secret_file: Option<PathBuf>,
+
+    /// Request header used to send HMAC signature
+    #[arg(long, env = "APP_REST_HMAC_HEADER", default_value = "X-Signature")]
+    pub rest_hmac_header: String,
+
+    /// Tenant to bwHC-Backend API URI mapping as JSON object
+    #[arg(long, env = "APP_TENANT_ROUTES")]
+    pub tenant_routes: Option<String>,
+
+    /// Kafka header containing the tenant
+    #[arg(long, env = "APP_TENANT_HEADER", default_value = "tenant")]
+    pub tenant_header: String,
+
+    /// Delete MTB file (delete) or send consent (post-consent) if consent is missing
+    #[arg(long, env = "APP_DELETE_MODE", default_value = "delete", value_parser = DeleteMode::from_str)]
+    pub delete_mode: DeleteMode,
+
+    /// Path used for post-consent delete mode
+    #[arg(long, env = "APP_REST_CONSENT_PATH", default_value = "Consent")]
+    pub rest_consent_path: String,
+
+    /// Path of delete requests relative to REST URI using placeholders `{patient_id}` and `{site_id}`
+    #[arg(
+        long,
+        env = "APP_REST_DELETE_PATH_TEMPLATE",
+        default_value = "MTBFile/{patient_id}"
+    )]
+    pub rest_delete_path_template: String,
+
+    /// HTTP method used to send MTB files (POST, PUT)
+    #[arg(long, env = "APP_REST_MTBFILE_METHOD", default_value = "POST", value_parser = MtbFileMethod::from_str)]
+    pub rest_mtbfile_method: MtbFileMethod,
+
+    /// Path of MTB file requests relative to REST URI using placeholder `{patient_id}`, required for PUT
+    #[arg(long, env = "APP_REST_MTBFILE_PATH", default_value = "MTBFile")]
+    pub rest_mtbfile_path: String,
+
+    /// Site id used for `{site_id}` if not contained in request
+    #[arg(long, env = "APP_REST_SITE_ID")]
+    pub rest_site_id: Option<String>,
+
+    /// Request header used to send request id
+    #[arg(
+        long,
+        env = "APP_REST_REQUEST_ID_HEADER",
+        default_value = "X-Request-ID"
+    )]
+    pub rest_request_id_header: String,
+
+    /// Poll result of requests accepted with status code 202 using its Location header
+    #[arg(long, env = "APP_REST_POLL_ACCEPTED")]
+    pub rest_poll_accepted: bool,
+
+    /// Interval in milliseconds to poll result of accepted requests
+    #[arg(long, env = "APP_REST_POLL_INTERVAL_MS", default_value_t = 1000)]
+    pub rest_poll_interval_ms: u64,
+
+    /// Maximum time in seconds to wait for result of accepted requests
+    #[arg(long, env = "APP_REST_POLL_MAX_WAIT", default_value_t = 300)]
+    pub rest_poll_max_wait: u64,
+
+    /// Use status code 502 in response if a successful response does not contain JSON
+    #[arg(long, env = "APP_REST_NON_JSON_AS_FAILURE")]
+    pub rest_non_json_as_failure: bool,
+
+    /// Response headers to be included in response
+    #[arg(
+        long,
+        env = "APP_REST_RESPONSE_HEADERS",
+        value_delimiter = ',',
+        default_value = "Location,X-Request-ID"
+    )]
+    pub rest_response_headers: Vec<String>,
+
+    /// Request timeout in seconds
+    #[arg(long, env = "APP_REST_TIMEOUT", default_value_t = 5, value_parser = clap::value_parser!(u64).range(1..))]
+    pub rest_timeout: u64,
+
+    /// MTB file request timeout in seconds
+    #[arg(long, env = "APP_REST_MTBFILE_TIMEOUT", value_parser = clap::value_parser!(u64).range(1..))]
+    pub rest_mtbfile_timeout: Option<u64>,
+
+    /// Delete request timeout in seconds
+    #[arg(long, env = "APP_REST_DELETE_TIMEOUT", value_parser = clap::value_parser!(u64).range(1..))]
+    pub rest_delete_timeout: Option<u64>,
+
+    /// Number of retries
+    #[arg(long, env = "APP_REST_RETRIES", default_value_t = 0)]
+    pub rest_retries: u32,
+
+    /// Delay before first retry in milliseconds
+    #[arg(long, env = "APP_REST_RETRY_DELAY_MS", default_value_t = 500)]
+    pub rest_retry_delay_ms: u64,
+
+    /// Maximum delay between retries in milliseconds
+    #[arg(long, env = "APP_REST_RETRY_MAX_DELAY_MS", default_value_t = 30_000)]
+    pub rest_retry_max_delay_ms: u64,
+
+    /// Randomize delay between retries
+    #[arg(long, env = "APP_REST_RETRY_JITTER", default_value_t = true, action = ArgAction::Set)]
+    pub rest_retry_jitter: bool,
+
+    /// Status codes of responses to be retried. Use an empty list to retry on network errors only
+    #[arg(long, env = "APP_REST_RETRY_STATUS", default_value = "429,502,503,504", value_parser = RetryStatus::from_str)]
+    pub rest_retry_status: RetryStatus,
+
+    /// Number of retries for delete requests. Default: APP_REST_RETRIES
+    #[arg(long, env = "APP_REST_DELETE_RETRIES")]
+    pub rest_delete_retries: Option<u32>,
+
+    /// Maximum delay in milliseconds between retries of delete requests. Default: APP_REST_RETRY_MAX_DELAY_MS
+    #[arg(long, env = "APP_REST_DELETE_RETRY_MAX_DELAY_MS")]
+    pub rest_delete_retry_max_delay_ms: Option<u64>,
+
+    /// Maximum delay in milliseconds requested by a Retry-After header. Default: APP_REST_RETRY_MAX_DELAY_MS
+    #[arg(long, env = "APP_REST_RETRY_AFTER_MAX_MS")]
+    pub rest_retry_after_max_ms: Option<u64>,
+
+    /// Maximum requests per second
+    #[arg(long, env = "APP_REST_RATE_LIMIT", value_parser = parse_rate_limit)]
+    pub rest_rate_limit: Option<f64>,
+
+    /// Requests sent without waiting
+    #[arg(long, env = "APP_REST_RATE_LIMIT_BURST", default_value_t = 1)]
+    pub rest_rate_limit_burst: u32,
+
+    /// Time in seconds idle connections will be kept open
+    #[arg(long, env = "APP_REST_POOL_IDLE_TIMEOUT", value_parser = clap::value_parser!(u64).range(1..))]
+    pub rest_pool_idle_timeout: Option<u64>,
+
+    /// Maximum number of idle connections per host
+    #[arg(long, env = "APP_REST_POOL_MAX_IDLE")]
+    pub rest_pool_max_idle: Option<usize>,
+
+    /// TCP keepalive interval in seconds
+    #[arg(long, env = "APP_REST_TCP_KEEPALIVE", value_parser = clap::value_parser!(u64).range(1..))]
+    pub rest_tcp_keepalive: Option<u64>,
+
+    /// Use HTTP/2 with prior knowledge
+    #[arg(long, env = "APP_REST_HTTP2", conflicts_with = "rest_http1_only")]
+    pub rest_http2: bool,
+
+    /// Use HTTP/1 only
+    #[arg(long, env = "APP_REST_HTTP1_ONLY")]
+    pub rest_http1_only: bool,
+
+    /// Proxy for requests to bwHC-Backend, e.g. http://proxy.example.org:3128.
+    /// Default: HTTP_PROXY, HTTPS_PROXY and NO_PROXY
+    #[arg(long, env = "APP_REST_PROXY")]
+    pub rest_proxy: Option<String>,
+
+    /// Do not use any proxy, including HTTP_PROXY and HTTPS_PROXY
+    #[arg(long, env = "APP_REST_NO_PROXY", conflicts_with = "rest_proxy")]
+    pub rest_no_proxy: bool,
+
+    /// Static addresses of hosts as comma separated list, e.g. bwhc.example.org:443=10.1.2.3
+    #[arg(long, env = "APP_REST_RESOLVE", value_delimiter = ',', value_parser = ResolveOverride::from_str)]
+    pub rest_resolve: Vec<ResolveOverride>,
+
+    /// Interval in seconds to probe bwHC-Backend health. Disabled if not set
+    #[arg(long, env = "APP_REST_HEALTHCHECK_INTERVAL", value_parser = clap::value_parser!(u64).range(1..))]
+    pub rest_healthcheck_interval: Option<u64>,
+
+    /// Pause consumption while bwHC-Backend health probe fails, resume once healthy again
+    #[arg(
+        long,
+        env = "APP_PAUSE_WHEN_UNHEALTHY",
+        requires = "rest_healthcheck_interval"
+    )]
+    pub pause_when_unhealthy: bool,
+
+    /// Path relative to REST URI used to probe bwHC-Backend health
+    #[arg(long, env = "APP_REST_HEALTHCHECK_PATH", default_value = "")]
+    pub rest_healthcheck_path: String,
+
+    /// Send `HEAD` request to REST health path on startup to establish connection before consuming
+    #[arg(long, env = "APP_REST_WARMUP")]
+    pub rest_warmup: bool,
+
+    /// Do not start if bwHC-Backend is not reachable on warm-up. Implies `APP_REST_WARMUP`
+    #[arg(long, env = "APP_REST_REQUIRE_REACHABLE")]
+    pub rest_require_reachable: bool,
+
+    /// Redirect policy (none, limited:<n>, same-host-only)
+    #[arg(long, env = "APP_REST_REDIRECT_POLICY", default_value = "limited:10", value_parser = RedirectPolicy::from_str)]
+    pub rest_redirect_policy: RedirectPolicy,
+
+    /// Sanitize content of requests before sending MTB files
+    #[arg(long, env = "APP_SANITIZE_CONTENT")]
+    pub sanitize_content: bool,
+
+    /// Top-level content fields to keep if content is sanitized. Default: all fields
+    #[arg(long, env = "APP_SANITIZE_CONTENT_ALLOW", value_delimiter = ',')]
+    pub sanitize_content_allow: Vec<String>,
+
+    /// Top-level content fields to remove if content is sanitized
+    #[arg(long, env = "APP_SANITIZE_CONTENT_DENY", value_delimiter = ',')]
+    pub sanitize_content_deny: Vec<String>,
+
+    /// JSON pointers to fields removed from the content before sending MTB files, `*` matches any array element
+    #[arg(long, env = "APP_STRIP_FIELDS", value_delimiter = ',', value_parser = parse_json_pointer)]
+    pub strip_fields: Vec<String>,
+
+    /// Include number of removed fields in response
+    #[arg(long, env = "APP_STRIP_FIELDS_REPORT")]
+    pub strip_fields_report: bool,
+
+    /// Replace patient ids by pseudonyms before sending MTB files and deletes. Disabled if not set
+    #[arg(long, env = "APP_PSEUDONYMIZE", value_enum)]
+    pub pseudonymize: Option<PseudonymizeMode>,
+
+    /// Site-specific salt used to derive pseudonyms
+    #[arg(long, env = "APP_PSEUDONYM_SALT", hide_env_values = true)]
+    pub pseudonym_salt: Option<String>,
+
+    /// File containing salt used to derive pseudonyms
+    #[arg(
+        long,
+        env = "APP_PSEUDONYM_SALT_FILE",
+        conflicts_with = "pseudonym_salt"
+    )]
+    pub pseudonym_salt_file: Option<PathBuf>,
+
+    /// JSON pointers to patient ids within the content, `*` matches any array element or object field
+    #[arg(long, env = "APP_PSEUDONYM_PATHS", value_delimiter = ',', default_value = DEFAULT_PSEUDONYM_PATHS)]
+    pub pseudonym_paths: Vec<String>,
+
+    /// Include SHA-256 hash of sent content in response for debugging
+    #[arg(long, env = "APP_ECHO_CONTENT")]
+    pub echo_content: bool,
+
+    /// Include full sent content instead of hash only if content echo is enabled
+    #[arg(long, env = "APP_ECHO_CONTENT_FULL")]
+    pub echo_content_full: bool,
+
+    /// Maximum size of response body in bytes included in responses. Larger bodies are truncated
+    #[arg(long, env = "APP_MAX_RESPONSE_BODY_BYTES", default_value_t = 1024 * 1024, value_parser = clap::value_parser!(u64).range(1..))]
+    pub max_response_body_bytes: u64,
+
+    /// Maximum size of decompressed content in bytes if content is sent encoded as `gzip+base64`
+    #[arg(long, env = "APP_MAX_DECODED_CONTENT_BYTES", default_value_t = DEFAULT_MAX_DECODED_CONTENT_BYTES, value_parser = clap::value_parser!(u64).range(1..))]
+    pub max_decoded_content_bytes: u64,
+
+    /// Message of response issue if there is no HTTP connection
+    #[arg(
+        long,
+        env = "APP_NO_CONNECTION_MESSAGE",
+        default_value = "No HTTP connection"
+    )]
+    pub no_connection_message: String,
+
+    /// Include detail of the underlying error in response if there is no HTTP connection
+    #[arg(long, env = "APP_INCLUDE_ERROR_DETAIL")]
+    pub include_error_detail: bool,
+
+    /// Interval in seconds to log counts of processed records. Use 0 to disable
+    #[arg(long, env = "APP_STATS_INTERVAL_SECONDS", default_value_t = 60)]
+    pub stats_interval_seconds: u64,
+
+    /// Maximum age of records in seconds. Older records are skipped and sent to DLQ if configured
+    #[arg(long, env = "APP_MAX_RECORD_AGE_SECONDS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub max_record_age_seconds: Option<u64>,
+
+    /// Maximum number of responses not yet acknowledged by Kafka before consumption is paused.
+    /// Responses are sent one by one if not set
+    #[arg(long, env = "APP_MAX_PENDING_RESPONSES", value_parser = clap::value_parser!(u64).range(1..))]
+    pub max_pending_responses: Option<u64>,
+
+    /// Maximum number of request ids of requests sent to bwHC-Backend to skip requests sent again.
+    /// Duplicates are not skipped if not set
+    #[arg(long, env = "APP_DEDUP_MAX_ENTRIES", value_parser = clap::value_parser!(u64).range(1..))]
+    pub dedup_max_entries: Option<u64>,
+
+    /// Time to live in seconds of request ids to skip requests sent again. Kept until evicted if not set
+    #[arg(long, env = "APP_DEDUP_TTL", requires = "dedup_max_entries", value_parser = clap::value_parser!(u64).range(1..))]
+    pub dedup_ttl: Option<u64>,
+
+    /// Interval in milliseconds to commit offsets of processed messages.
+    /// Offset of each processed message is committed using commit mode if not set
+    #[arg(long, env = "APP_COMMIT_INTERVAL_MS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub commit_interval_ms: Option<u64>,
+
+    /// Mode to commit offsets of processed messages manually
+    #[arg(long, env = "APP_KAFKA_COMMIT_MODE", value_enum, default_value_t = KafkaCommitMode::Async)]
+    pub kafka_commit_mode: KafkaCommitMode,
+
+    /// Minimum severity of issues in successful responses to report status code 422 instead
+    #[arg(long, env = "APP_SEVERITY_THRESHOLD", value_parser = Severity::from_str)]
+    pub severity_threshold: Option<Severity>,
+
+    /// Pattern request ids must match, e.g. ^[A-Za-z0-9-]+$
+    #[arg(long, env = "APP_REQUEST_ID_PATTERN", value_parser = Regex::new)]
+    pub request_id_pattern: Option<Regex>,
+
+    /// Maximum length of request ids in characters
+    #[arg(long, env = "APP_REQUEST_ID_MAX_LENGTH", default_value_t = 64, value_parser = clap::value_parser!(u64).range(1..))]
+    pub request_id_max_length: u64,
+
+    /// Format request ids must have, e.g. uuid
+    #[arg(long, env = "APP_REQUEST_ID_FORMAT", value_parser = RequestIdFormat::from_str)]
+    pub request_id_format: Option<RequestIdFormat>,
+
+    /// Accepted consent issuers as comma separated list, consent id is used if no issuer is given. Default: all issuers
+    #[arg(long, env = "APP_ALLOWED_CONSENT_ISSUERS", value_delimiter = ',')]
+    pub allowed_consent_issuers: Vec<String>,
+
+    /// Handling of requests without consent status or with unknown consent status
+    #[arg(
+        long,
+        env = "APP_UNDETERMINED_CONSENT_POLICY",
+        value_enum,
+        default_value = "respond"
+    )]
+    pub undetermined_consent_policy: UndeterminedConsentPolicy,
+
+    /// Handling of requests with content without consent
+    #[arg(
+        long,
+        env = "APP_MISSING_CONSENT",
+        value_enum,
+        default_value = "reject"
+    )]
+    pub missing_consent_policy: MissingConsentPolicy,
+
+    /// Purpose of the provision of a broad consent that decides about upload or delete
+    /// if content contains no consent status
+    #[arg(
+        long,
+        env = "APP_BROAD_CONSENT_PROVISION",
+        default_value = "sequencing"
+    )]
+    pub broad_consent_provision: String,
+
+    /// Consent statuses permitting to send MTB files as comma separated list
+    #[arg(long, env = "APP_CONSENT_ACTIVE_STATUSES", value_delimiter = ',', default_value = DEFAULT_CONSENT_ACTIVE_STATUS)]
+    pub consent_active_statuses: Vec<String>,
+
+    /// Field of `consents` entries used to select the most recent consent
+    #[arg(long, env = "APP_CONSENT_DATE_FIELD", default_value = DEFAULT_CONSENT_DATE_FIELD)]
+    pub consent_date_field: String,
+
+    /// JSON pointer (RFC 6901) locating the consent within the content
+    #[arg(long, env = "APP_CONSENT_POINTER", default_value = DEFAULT_CONSENT_POINTER, value_parser = parse_json_pointer)]
+    pub consent_pointer: String,
+
+    /// Primary source of the patient id, `consent` or `patient`. The other source is used as fallback
+    #[arg(long, env = "APP_PATIENT_ID_SOURCE", default_value = "consent", value_parser = PatientIdSource::from_str)]
+    pub patient_id_source: PatientIdSource,
+
+    /// Reject requests if patient ids of consent and patient differ instead of logging the mismatch
+    #[arg(long, env = "APP_STRICT_PATIENT_ID")]
+    pub strict_patient_id: bool,
+
+    /// Do not send MTB files if patient ids of consent and patient differ
+    #[arg(long, env = "APP_STRICT_PATIENT_MATCH")]
+    pub strict_patient_match: bool,
+
+    /// Handle records without value as delete of the patient given by the record key,
+    /// e.g. on a compacted topic. Records without value are skipped otherwise
+    #[arg(long, env = "APP_NULL_VALUE_DELETES")]
+    pub null_value_deletes: bool,
+
+    /// Handling of requests with consent status entered-in-error
+    #[arg(
+        long,
+        env = "APP_ENTERED_IN_ERROR_POLICY",
+        value_enum,
+        default_value = "delete"
+    )]
+    pub entered_in_error_policy: EnteredInErrorPolicy,
+
+    /// Handling of requests with a consent whose validity period has ended
+    #[arg(
+        long,
+        env = "APP_EXPIRED_CONSENT_POLICY",
+        value_enum,
+        default_value = "delete"
+    )]
+    pub expired_consent_policy: ExpiredConsentPolicy,
+
+    /// Time to check the validity period of consents against
+    #[arg(
+        long,
+        env = "APP_CONSENT_VALIDITY_TIME",
+        value_enum,
+        default_value = "record"
+    )]
+    pub consent_validity_time: ConsentValidityTime,
+
+    /// Kafka bootstrap servers as comma separated list
+    #[arg(long, env = "KAFKA_BOOTSTRAP_SERVERS", default_value = "kafka:9092")]
+    pub kafka_bootstrap_servers: String,
+
+    /// Topic to consume requests from
+    #[arg(long, env = "APP_KAFKA_TOPIC", default_value = "etl-processor")]
+    pub kafka_topic: String,
+
+    /// Topic to send responses to. Default: Request topic with suffix "_response"
+    #[arg(long, env = "APP_KAFKA_RESPONSE_TOPIC")]
+    pub kafka_response_topic: Option<String>,
+
+    /// Template of keys of response and DLQ records. Placeholders: `{request_id}`, `{patient_id}`, `{key}`, `{partition}`, `{offset}`
+    #[arg(long, env = "APP_KEY_TEMPLATE", default_value = "{key}", value_parser = KeyTemplate::from_str)]
+    pub key_template: KeyTemplate,
+
+    /// Topic to send responses of successful requests to instead of the response topic
+    #[arg(long, env = "APP_KAFKA_SUCCESS_TOPIC")]
+    pub kafka_success_topic: Option<String>,
+
+    /// Topic to send responses of failed requests to instead of the response topic
+    #[arg(long, env = "APP_KAFKA_FAILURE_TOPIC")]
+    pub kafka_failure_topic: Option<String>,
+
+    /// Kafka consumer group id. Default: Request topic with suffix "_group"
+    #[arg(long, env = "APP_KAFKA_GROUP_ID")]
+    pub kafka_group_id: Option<String>,
+
+    /// Kafka topic for delete requests to be retried later
+    #[arg(long, env = "APP_KAFKA_DELETE_RETRY_TOPIC")]
+    pub kafka_delete_retry_topic: Option<String>,
+
+    /// Delay in milliseconds before a delete request from retry topic is sent again
+    #[arg(long, env = "APP_KAFKA_DELETE_RETRY_DELAY_MS", default_value_t = 60000)]
+    pub kafka_delete_retry_delay_ms: u64,
+
+    /// Maximum number of times a delete request is sent to retry topic before it fails
+    #[arg(
+        long,
+        env = "APP_KAFKA_DELETE_RETRY_MAX_ATTEMPTS",
+        default_value_t = 10
+    )]
+    pub kafka_delete_retry_max_attempts: u32,
+
+    /// Kafka client id. Default: kafka-to-bwhc with hostname
+    #[arg(long, env = "APP_KAFKA_CLIENT_ID")]
+    pub kafka_client_id: Option<String>,
+
+    /// Number of retries if Kafka consumer or producer cannot be created
+    #[arg(long, env = "APP_KAFKA_CREATE_RETRIES", default_value_t = 5)]
+    pub kafka_create_retries: u32,
+
+    /// Delay in milliseconds before first retry to create Kafka consumer or producer
+    #[arg(long, env = "APP_KAFKA_CREATE_RETRY_DELAY_MS", default_value_t = 1000)]
+    pub kafka_create_retry_delay_ms: u64,
+
+    /// Topic to send requests to that cannot be processed (dead letter queue)
+    #[arg(long, env = "APP_KAFKA_DLQ_TOPIC")]
+    pub kafka_dlq_topic: Option<String>,
+
+    /// Directory to store records that cannot be sent to Kafka, e.g. responses and DLQ records. Disabled if not set
+    #[arg(long, env = "APP_SPOOL_DIR")]
+    pub spool_dir: Option<PathBuf>,
+
+    /// Interval in seconds to retry sending spooled records
+    #[arg(long, env = "APP_SPOOL_RETRY_INTERVAL", default_value_t = 30, value_parser = clap::value_parser!(u64).range(1..))]
+    pub spool_retry_interval: u64,
+
+    /// Minimum number of bytes the broker responds with to a fetch request. Default: librdkafka default
+    #[arg(long, env = "KAFKA_FETCH_MIN_BYTES", value_parser = clap::value_parser!(u32).range(1..))]
+    pub kafka_fetch_min_bytes: Option<u32>,
+
+    /// Maximum number of bytes the broker responds with to a fetch request. Default: librdkafka default
+    #[arg(long, env = "KAFKA_FETCH_MAX_BYTES", value_parser = clap::value_parser!(u32).range(1..))]
+    pub kafka_fetch_max_bytes: Option<u32>,
+
+    /// Maximum number of bytes per partition the broker responds with. Default: librdkafka default
+    #[arg(long, env = "KAFKA_MAX_PARTITION_FETCH_BYTES", value_parser = clap::value_parser!(u32).range(1..))]
+    pub kafka_max_partition_fetch_bytes: Option<u32>,
+
+    /// Maximum time in milliseconds between polls before the consumer leaves the group. Default: librdkafka default
+    #[arg(long, env = "KAFKA_MAX_POLL_INTERVAL_MS", value_parser = clap::value_parser!(u32).range(1..))]
+    pub kafka_max_poll_interval_ms: Option<u32>,
+
+    /// Format of consumed records
+    #[arg(long, env = "APP_PAYLOAD_FORMAT", value_enum, default_value_t = PayloadFormat::Json)]
+    pub payload_format: PayloadFormat,
+
+    /// URI of Confluent Schema Registry to decode Avro records. Records are JSON only if not set
+    #[arg(long, env = "APP_SCHEMA_REGISTRY_URI")]
+    pub schema_registry_uri: Option<String>,
+
+    /// Username to authenticate requests to schema registry
+    #[arg(
+        long,
+        env = "APP_SCHEMA_REGISTRY_USERNAME",
+        requires = "schema_registry_uri"
+    )]
+    pub schema_registry_username: Option<String>,
+
+    /// Password to authenticate requests to schema registry
+    #[arg(
+        long,
+        env = "APP_SCHEMA_REGISTRY_PASSWORD",
+        hide_env_values = true,
+        requires = "schema_registry_username"
+    )]
+    pub schema_registry_password: Option<String>,
+}
+
+impl Config {
+    /// Validates dependencies between configuration values
+    pub fn validate(&self) -> Result<(), AppError> {
+        if self.undetermined_consent_policy == UndeterminedConsentPolicy::Dlq
+            && self.kafka_dlq_topic.is_none()
+        {
+            return Err(MissingConfig("APP_KAFKA_DLQ_TOPIC".into()));
+        }
+        if self.pseudonymize.is_some() {
+            match (&self.pseudonym_salt, &self.pseudonym_salt_file) {
+                (None, None) => return Err(MissingConfig("APP_PSEUDONYM_SALT".into())),
+                (Some(salt), _) if salt.trim().is_empty() => {
+                    return Err(ValidationError("Empty pseudonym salt".into()))
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    pub fn rest_uri_fallback(&self) -> Option<String> {
+        self.rest_uri_fallback
+            .clone()
+            .or(self.rest_fallback_uri.clone())
+            .filter(|uri| !uri.trim().is_empty())
+    }
+
+    pub fn kafka_response_topic(&self) -> String {
+        self.kafka_response_topic
+            .clone()
+            .unwrap_or(format!("{}_response", self.kafka_topic))
+    }
+
+    pub fn kafka_client_id(&self) -> String {
+        self.kafka_client_id
+            .clone()
+            .unwrap_or(default_kafka_client_id(env::var("HOSTNAME").ok()))
+    }
+
+    pub fn kafka_group_id(&self) -> String {
+        self.kafka_group_id
+            .clone()
+            .unwrap_or(format!("{}_group", self.kafka_topic))
+    }
+}
+
+/// Default client id containing hostname, e.g. name of pod, to distinguish instances
+fn default_kafka_client_id(hostname: Option<String>) -> String {
+    match hostname.filter(|hostname| !hostname.trim().is_empty()) {
+        Some(hostname) => format!("kafka-to-bwhc-{}", hostname.trim()),
+        None => "kafka-to-bwhc".into(),
+    }
+}
+
+fn parse_rate_limit(value: &str) -> Result<f64, String> {
+    value
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|rate| rate.is_finite() && *rate > 0.0)
+        .ok_or(format!("Invalid rate limit '{}'", value))
+}
+
+fn parse_json_pointer(value: &str) -> Result<String, String> {
+    let pointer = value.trim();
+    if !pointer.starts_with('/') {
+        Err(format!(
+            "Invalid JSON pointer '{}', must start with '/'",
+            value
+        ))
+    } else if pointer.rsplit('/').next() == Some(RECURSIVE_WILDCARD) {
+        Err(format!(
+            "Invalid JSON pointer '{}', must not end with '{}'",
+            value, RECURSIVE_WILDCARD
+        ))
+    } else {
+        Ok(pointer.to_string())
+    }
+}
+
+#[cfg(test)]
+pub fn test_config(uri: &str) -> Config {
+    Cli::parse_from(["kafka-to-bwhc", "--rest-uri", uri]).config
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use std::path::PathBuf;
+    use std::str::FromStr;
+
+    use crate::bwhc_client::{DeleteMode, MtbFileMethod, RedirectPolicy};
+    use crate::config::{
+        config_file_settings, default_kafka_client_id, is_sink_setting, Cli, Command,
+        ConsentValidityTime, EnteredInErrorPolicy, ExpiredConsentPolicy, KafkaCommitMode,
+        MissingConsentPolicy, PayloadFormat, PseudonymizeMode, UndeterminedConsentPolicy,
+    };
+    use crate::key_template::KeyTemplate;
+    use crate::resources::mtbfile::PatientIdSource;
+    use crate::resources::request::RequestIdFormat;
+    use crate::retry::RetryStatus;
+    use crate::sink::SinkType;
+
+    const URI: &str = "http://localhost:9000/bwhc/etl/api";
+
+    #[test]
+    fn should_parse_without_subcommand() {
+        let cli = Cli::try_parse_from(["kafka-to-bwhc", "--rest-uri", URI]).unwrap();
+
+        assert_eq!(cli.command, None);
+        assert_eq!(cli.config.rest_uri, Some(URI.to_string()));
+    }
+
+    #[test]
+    fn should_parse_replay_dlq_dry_run() {
+        let cli = Cli::try_parse_from([
+            "kafka-to-bwhc",
+            "--rest-uri",
+            URI,
+            "replay-dlq",
+            "--dry-run",
+        ])
+        .unwrap();
+
+        assert_eq!(cli.command, Some(Command::ReplayDlq { dry_run: true }));
+    }
+
+    #[test]
+    fn should_parse_subcommands() {
+        let commands = [
+            ("run", Command::Run),
+            ("validate-config", Command::ValidateConfig),
+            ("check-connection", Command::CheckConnection),
+            ("replay-dlq", Command::ReplayDlq { dry_run: false }),
+        ];
+
+        for (arg, command) in commands {
+            let cli = Cli::try_parse_from(["kafka-to-bwhc", "--rest-uri", URI, arg]).unwrap();
+            assert_eq!(cli.command, Some(command));
+        }
+    }
+
+    #[test]
+    fn should_use_defaults() {
+        let config = Cli::try_parse_from(["kafka-to-bwhc", "--rest-uri", URI])
+            .unwrap()
+            .config;
+
+        assert_eq!(config.kafka_topic, "etl-processor");
+        assert_eq!(config.kafka_response_topic(), "etl-processor_response");
+        assert_eq!(config.kafka_success_topic, None);
+        assert_eq!(config.key_template, KeyTemplate::from_str("{key}").unwrap());
+        assert_eq!(config.kafka_failure_topic, None);
+        assert_eq!(config.kafka_group_id(), "etl-processor_group");
+        assert_eq!(config.rest_timeout, 5);
+        assert_eq!(config.delete_mode, DeleteMode::Delete);
+        assert_eq!(
+            config.rest_response_headers,
+            vec!["Location".to_string(), "X-Request-ID".to_string()]
+        );
+        assert!(config.rest_retry_jitter);
+        assert_eq!(
+            config.rest_retry_status,
+            RetryStatus::from_str("429,502,503,504").unwrap()
+        );
+        assert_eq!(config.rest_redirect_policy, RedirectPolicy::Limited(10));
+        assert_eq!(config.rest_mtbfile_method, MtbFileMethod::Post);
+        assert_eq!(config.rest_mtbfile_path, "MTBFile");
+        assert_eq!(config.no_connection_message, "No HTTP connection");
+        assert!(!config.include_error_detail);
+        assert_eq!(config.request_id_max_length, 64);
+        assert_eq!(config.entered_in_error_policy, EnteredInErrorPolicy::Delete);
+        assert_eq!(config.expired_consent_policy, ExpiredConsentPolicy::Delete);
+        assert_eq!(config.consent_validity_time, ConsentValidityTime::Record);
+        assert!(!config.null_value_deletes);
+        assert_eq!(config.missing_consent_policy, MissingConsentPolicy::Reject);
+        assert_eq!(config.broad_consent_provision, "sequencing");
+        assert_eq!(config.consent_date_field, "dateTime");
+        assert_eq!(config.consent_active_statuses, vec!["active".to_string()]);
+        assert_eq!(config.patient_id_source, PatientIdSource::Consent);
+        assert!(!config.strict_patient_id);
+        assert!(!config.strict_patient_match);
+        assert!(!config.pause_when_unhealthy);
+        assert_eq!(config.kafka_commit_mode, KafkaCommitMode::Async);
+        assert_eq!(config.max_response_body_bytes, 1024 * 1024);
+        assert_eq!(config.max_decoded_content_bytes, 16 * 1024 * 1024);
+        assert!(config.request_id_format.is_none());
+        assert!(config.schema_registry_uri.is_none());
+        assert_eq!(config.payload_format, PayloadFormat::Json);
+    }
+
+    #[test]
+    fn should_parse_flags() {
+        let config = Cli::try_parse_from([
+            "kafka-to-bwhc",
+            "--rest-uri",
+            URI,
+            "--kafka-topic",
+            "test",
+            "--delete-mode",
+            "post-consent",
+            "--rest-response-headers",
+            "Location,X-Request-ID",
+            "--rest-retry-jitter",
+            "false",
+            "--rest-http2",
+            "--no-connection-message",
+            "Backend unavailable",
+            "--include-error-detail",
+            "--request-id-format",
+            "uuid",
+            "--entered-in-error-policy",
+            "ignore",
+            "--expired-consent-policy",
+            "ignore",
+            "--consent-validity-time",
+            "now",
+            "--broad-consent-provision",
+            "case-identification",
+            "--patient-id-source",
+            "patient",
+            "--strict-patient-id",
+            "--strict-patient-match",
+            "--kafka-commit-mode",
+            "sync",
+            "--payload-format",
+            "protobuf",
+        ])
+        .unwrap()
+        .config;
+
+        assert_eq!(config.kafka_response_topic(), "test_response");
+        assert_eq!(config.payload_format, PayloadFormat::Protobuf);
+        assert_eq!(config.delete_mode, DeleteMode::PostConsent);
+        assert_eq!(
+            config.rest_response_headers,
+            vec!["Location".to_string(), "X-Request-ID".to_string()]
+        );
+        assert!(!config.rest_retry_jitter);
+        assert!(config.rest_http2);
+        assert_eq!(config.no_connection_message, "Backend unavailable");
+        assert!(config.include_error_detail);
+        assert_eq!(config.request_id_format, Some(RequestIdFormat::Uuid));
+        assert_eq!(config.entered_in_error_policy, EnteredInErrorPolicy::Ignore);
+        assert_eq!(config.expired_consent_policy, ExpiredConsentPolicy::Ignore);
+        assert_eq!(config.consent_validity_time, ConsentValidityTime::Now);
+        assert_eq!(config.broad_consent_provision, "case-identification");
+        assert_eq!(config.patient_id_source, PatientIdSource::Patient);
+        assert!(config.strict_patient_id);
+        assert!(config.strict_patient_match);
+        assert_eq!(config.kafka_commit_mode, KafkaCommitMode::Sync);
+    }
+
+    #[test]
+    fn should_parse_consent_active_statuses() {
+        let config = Cli::try_parse_from([
+            "kafka-to-bwhc",
+            "--consent-active-statuses",
+            "active,provisional",
+        ])
+        .unwrap()
+        .config;
+
+        assert_eq!(
+            config.consent_active_statuses,
+            vec!["active".to_string(), "provisional".to_string()]
+        );
+    }
+
+    #[test]
+    fn should_parse_proxy_and_reject_no_proxy_with_proxy() {
+        let proxy = "http://proxy.example.org:3128";
+        let config = Cli::try_parse_from(["kafka-to-bwhc", "--rest-proxy", proxy])
+            .unwrap()
+            .config;
+        let conflicting =
+            Cli::try_parse_from(["kafka-to-bwhc", "--rest-proxy", proxy, "--rest-no-proxy"]);
+
+        assert_eq!(config.rest_proxy, Some(proxy.to_string()));
+        assert!(!config.rest_no_proxy);
+        assert!(conflicting.is_err());
+    }
+
+    #[test]
+    fn should_require_schema_registry_uri_for_credentials() {
+        let config = Cli::try_parse_from([
+            "kafka-to-bwhc",
+            "--schema-registry-uri",
+            "http://registry:8081",
+            "--schema-registry-username",
+            "user",
+            "--schema-registry-password",
+            "secret",
+        ])
+        .unwrap()
+        .config;
+        let missing_uri =
+            Cli::try_parse_from(["kafka-to-bwhc", "--schema-registry-username", "user"]);
+
+        assert_eq!(
+            config.schema_registry_uri,
+            Some("http://registry:8081".to_string())
+        );
+        assert_eq!(config.schema_registry_username, Some("user".to_string()));
+        assert_eq!(config.schema_registry_password, Some("secret".to_string()));
+        assert!(missing_uri.is_err());
+    }
+
+    #[test]
+    fn should_reject_key_template_with_unknown_placeholder() {
+        let actual =
+            Cli::try_parse_from(["kafka-to-bwhc", "--key-template", "{request_id}:{topic}"]);
+
+        assert!(actual.is_err());
+    }
+
+    #[test]
+    fn should_require_healthcheck_interval_to_pause_when_unhealthy() {
+        let config = Cli::try_parse_from([
+            "kafka-to-bwhc",
+            "--rest-healthcheck-interval",
+            "10",
+            "--pause-when-unhealthy",
+        ])
+        .unwrap()
+        .config;
+        let missing_interval = Cli::try_parse_from(["kafka-to-bwhc", "--pause-when-unhealthy"]);
+
+        assert!(config.pause_when_unhealthy);
+        assert!(missing_interval.is_err());
+    }
+
+    #[test]
+    fn should_require_dedup_max_entries_for_ttl() {
+        let config = Cli::try_parse_from([
+            "kafka-to-bwhc",
+            "--dedup-max-entries",
+            "1000",
+            "--dedup-ttl",
+            "300",
+        ])
+        .unwrap()
+        .config;
+        let missing_max_entries = Cli::try_parse_from(["kafka-to-bwhc", "--dedup-ttl", "300"]);
+
+        assert_eq!(config.dedup_max_entries, Some(1000));
+        assert_eq!(config.dedup_ttl, Some(300));
+        assert!(missing_max_entries.is_err());
+    }
+
+    #[test]
+    fn should_parse_file_sink() {
+        let config = Cli::try_parse_from([
+            "kafka-to-bwhc",
+            "--sink",
+            "file",
+            "--sink-dir",
+            "/tmp/kafka-to-bwhc",
+        ])
+        .unwrap()
+        .config;
+
+        assert_eq!(config.sink, SinkType::File);
+        assert_eq!(config.sink_dir, Some(PathBuf::from("/tmp/kafka-to-bwhc")));
+    }
+
+    #[test]
+    fn should_require_dlq_topic_for_dlq_policy() {
+        let config = Cli::try_parse_from([
+            "kafka-to-bwhc",
+            "--rest-uri",
+            URI,
+            "--undetermined-consent-policy",
+            "dlq",
+        ])
+        .unwrap()
+        .config;
+
+        assert_eq!(
+            config.undetermined_consent_policy,
+            UndeterminedConsentPolicy::Dlq
+        );
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn should_parse_strip_fields() {
+        let config = Cli::try_parse_from([
+            "kafka-to-bwhc",
+            "--strip-fields",
+            "/episode/note,/specimens/*/note",
+        ])
+        .unwrap()
+        .config;
+
+        assert_eq!(
+            config.strip_fields,
+            vec!["/episode/note", "/specimens/*/note"]
+        );
+        assert!(!config.strip_fields_report);
+        assert!(Cli::try_parse_from(["kafka-to-bwhc", "--strip-fields", "episode/note"]).is_err());
+        assert!(Cli::try_parse_from(["kafka-to-bwhc", "--strip-fields", "/specimens/**"]).is_err());
+    }
+
+    #[test]
+    fn should_require_salt_to_pseudonymize() {
+        let config = Cli::try_parse_from(["kafka-to-bwhc", "--pseudonymize", "buildin"])
+            .unwrap()
+            .config;
+
+        assert_eq!(config.pseudonymize, Some(PseudonymizeMode::Buildin));
+        assert!(config.validate().is_err());
+
+        let config = Cli::try_parse_from([
+            "kafka-to-bwhc",
+            "--pseudonymize",
+            "buildin",
+            "--pseudonym-salt",
+            " ",
+        ])
+        .unwrap()
+        .config;
+
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn should_accept_pseudonym_salt_file_instead_of_salt() {
+        // Salt file is read when pseudonymizer is created, like other secret files
+        let config = Cli::try_parse_from([
+            "kafka-to-bwhc",
+            "--pseudonymize",
+            "buildin",
+            "--pseudonym-salt-file",
+            "/run/secrets/pseudonym-salt",
+        ])
+        .unwrap()
+        .config;
+
+        assert_eq!(
+            config.pseudonym_salt_file,
+            Some(PathBuf::from("/run/secrets/pseudonym-salt"))
+        );
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn should_reject_zero_negative_and_invalid_timeout() {
+        for timeout in ["0", "-5", "five"] {
+            assert!(Cli::try_parse_from([
+                "kafka-to-bwhc",
+                "--rest-uri",
+                URI,
+                "--rest-timeout",
+                timeout
+            ])
+            .is_err());
+        }
+    }
+
+    #[test]
+    fn should_reject_invalid_rate_limit() {
+        for rate_limit in ["0", "-1", "fast"] {
+            assert!(Cli::try_parse_from([
+                "kafka-to-bwhc",
+                "--rest-uri",
+                URI,
+                "--rest-rate-limit",
+                rate_limit
+            ])
+            .is_err());
+        }
+    }
+
+    #[test]
+    fn should_parse_consent_pointer() {
+        let cli = Cli::parse_from(["kafka-to-bwhc", "--rest-uri", URI]);
+        assert_eq!(cli.config.consent_pointer, "/consent");
+
+        let cli = Cli::parse_from([
+            "kafka-to-bwhc",
+            "--rest-uri",
+            URI,
+            "--consent-pointer",
+            "/episodesOfCare/0/consent",
+        ]);
+        assert_eq!(cli.config.consent_pointer, "/episodesOfCare/0/consent");
+
+        for pointer in ["", "consent", "#/consent"] {
+            assert!(Cli::try_parse_from([
+                "kafka-to-bwhc",
+                "--rest-uri",
+                URI,
+                "--consent-pointer",
+                pointer
+            ])
+            .is_err());
+        }
+    }
+
+    #[test]
+    fn should_reject_http2_and_http1_only() {
+        assert!(Cli::try_parse_from([
+            "kafka-to-bwhc",
+            "--rest-uri",
+            URI,
+            "--rest-http2",
+            "--rest-http1-only"
+        ])
+        .is_err())
+    }
+
+    #[test]
+    fn should_accept_both_fallback_uri_names() {
+        for arg in ["--rest-uri-fallback", "--rest-fallback-uri"] {
+            let config = Cli::try_parse_from(["kafka-to-bwhc", "--rest-uri", URI, arg, URI])
+                .unwrap()
+                .config;
+
+            assert_eq!(config.rest_uri_fallback(), Some(URI.to_string()));
+        }
+    }
+
+    #[test]
+    fn should_parse_redirect_policies() {
+        let policies = [
+            ("none", RedirectPolicy::None),
+            ("limited:3", RedirectPolicy::Limited(3)),
+            ("same-host-only", RedirectPolicy::SameHostOnly),
+        ];
+
+        for (arg, policy) in policies {
+            let config = Cli::try_parse_from([
+                "kafka-to-bwhc",
+                "--rest-uri",
+                URI,
+                "--rest-redirect-policy",
+                arg,
+            ])
+            .unwrap()
+            .config;
+
+            assert_eq!(config.rest_redirect_policy, policy);
+        }
+    }
+
+    #[test]
+    fn should_reject_invalid_redirect_policy() {
+        for arg in ["always", "limited:", "limited:-1"] {
+            assert!(Cli::try_parse_from([
+                "kafka-to-bwhc",
+                "--rest-uri",
+                URI,
+                "--rest-redirect-policy",
+                arg,
+            ])
+            .is_err());
+        }
+    }
+
+    #[test]
+    fn should_reject_multiple_auth_mechanisms() {
+        for arg in ["--rest-bearer-token", "--rest-bearer-token-file"] {
+            assert!(Cli::try_parse_from([
+                "kafka-to-bwhc",
+                "--rest-uri",
+                URI,
+                "--rest-api-key",
+                "secret-key",
+                arg,
+                "token",
+            ])
+            .is_err());
+        }
+    }
+
+    #[test]
+    fn should_reject_unknown_request_id_format_and_zero_maximum_length() {
+        assert!(Cli::try_parse_from([
+            "kafka-to-bwhc",
+            "--rest-uri",
+            URI,
+            "--request-id-format",
+            "ulid"
+        ])
+        .is_err());
+        assert!(Cli::try_parse_from([
+            "kafka-to-bwhc",
+            "--rest-uri",
+            URI,
+            "--request-id-max-length",
+            "0"
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn should_reject_invalid_request_id_pattern() {
+        assert!(Cli::try_parse_from([
+            "kafka-to-bwhc",
+            "--rest-uri",
+            URI,
+            "--request-id-pattern",
+            "[A-Z",
+        ])
+        .is_err())
+    }
+
+    #[test]
+    fn should_reject_invalid_resolve_entries() {
+        assert!(Cli::try_parse_from([
+            "kafka-to-bwhc",
+            "--rest-uri",
+            URI,
+            "--rest-resolve",
+            "bwhc.example.org:443=10.1.2.3,bwhc.example.org:443=invalid",
+        ])
+        .is_err())
+    }
+
+    #[test]
+    fn should_use_configured_kafka_client_id() {
+        let config = Cli::try_parse_from([
+            "kafka-to-bwhc",
+            "--rest-uri",
+            URI,
+            "--kafka-client-id",
+            "kafka-to-bwhc-1",
+        ])
+        .unwrap()
+        .config;
+
+        assert_eq!(config.kafka_client_id(), "kafka-to-bwhc-1");
+    }
+
+    #[test]
+    fn should_use_hostname_in_default_kafka_client_id() {
+        assert_eq!(
+            default_kafka_client_id(Some("worker-7d9f8b".into())),
+            "kafka-to-bwhc-worker-7d9f8b"
+        );
+        assert_eq!(default_kafka_client_id(Some(" ".into())), "kafka-to-bwhc");
+        assert_eq!(default_kafka_client_id(None), "kafka-to-bwhc");
+    }
+
+    #[test]
+    fn should_parse_empty_retry_status() {
+        let config = Cli::try_parse_from(["kafka-to-bwhc", "--rest-retry-status", ""])
+            .unwrap()
+            .config;
+
+        assert_eq!(config.rest_retry_status, RetryStatus::from_str("").unwrap());
+    }
+
+    #[test]
+    fn should_use_settings_of_config_file() {
+        let config_file =
+            std::env::temp_dir().join(format!("kafka-to-bwhc-config-{}", std::process::id()));
+        std::fs::write(
+            &config_file,
+            "# REST settings\nAPP_REST_URI=http://bwhc:8080/api\nAPP_REST_TIMEOUT = 10\nAPP_REST_HTTP2=true\n",
+        )
+        .unwrap();
+
+        let cli = Cli::load_from(vec![
+            "kafka-to-bwhc".into(),
+            "--config-file".into(),
+            config_file.display().to_string(),
+            "--rest-timeout".into(),
+            "20".into(),
+            "run".into(),
+        ])
+        .unwrap();
+
+        assert_eq!(cli.command, Some(Command::Run));
+        assert_eq!(cli.config.rest_uri, Some("http://bwhc:8080/api".into()));
+        assert_eq!(cli.config.rest_timeout, 20);
+        assert!(cli.config.rest_http2);
+    }
+
+    #[test]
+    fn should_read_settings_of_config_file() {
+        let config_file =
+            std::env::temp_dir().join(format!("kafka-to-bwhc-settings-{}", std::process::id()));
+        std::fs::write(
+            &config_file,
+            "# REST settings\nAPP_REST_URI=http://bwhc:8080/api\nAPP_REST_TIMEOUT = 10\nAPP_REST_HTTP2\n",
+        )
+        .unwrap();
+
+        let actual = config_file_settings(&config_file).unwrap();
+
+        assert_eq!(
+            actual.into_iter().collect::<Vec<_>>(),
+            vec![
+                ("APP_REST_HTTP2".to_string(), String::new()),
+                ("APP_REST_TIMEOUT".to_string(), "10".to_string()),
+                (
+                    "APP_REST_URI".to_string(),
+                    "http://bwhc:8080/api".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn should_only_reload_sink_settings() {
+        for key in [
+            "APP_SINK",
+            "APP_SINK_DIR",
+            "APP_REST_URI",
+            "APP_REST_BEARER_TOKEN_FILE",
+            "APP_TENANT_ROUTES",
+            "APP_DELETE_MODE",
+        ] {
+            assert!(is_sink_setting(key), "{}", key);
+        }
+        for key in [
+            "APP_REST_HEALTHCHECK_INTERVAL",
+            "APP_REST_HEALTHCHECK_PATH",
+            "APP_REST_WARMUP",
+            "APP_STRIP_FIELDS",
+            "APP_MAX_RESPONSE_BODY_BYTES",
+            "APP_KAFKA_TOPIC",
+        ] {
+            assert!(!is_sink_setting(key), "{}", key);
+        }
+    }
+
+    #[test]
+    fn should_reject_unknown_setting_in_config_file() {
+        let config_file = std::env::temp_dir().join(format!(
+            "kafka-to-bwhc-invalid-config-{}",
+            std::process::id()
+        ));
+        std::fs::write(&config_file, "APP_UNKNOWN=test\n").unwrap();
+
+        let actual = Cli::load_from(vec![
+            "kafka-to-bwhc".into(),
+            "--config-file".into(),
+            config_file.display().to_string(),
+        ]);
+
+        assert!(actual.is_err());
+    }
+}
@@ -0,0 +1,179 @@
+/*
+ * This file is part of ETL-Processor
+ *
+ * Copyright (c) 2024  Comprehensive Cancer Center Mainfranken
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published
+ * by the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Request ids of requests sent to bwHC-Backend, limited to a maximum number of entries and
+/// optionally expiring after a time to live. The oldest request id is removed first.
+/// Disabled without maximum number of entries. Entries are kept in memory only.
+pub struct RecentRequestIds {
+    max_entries: Option<usize>,
+    ttl: Option<Duration>,
+    entries: Mutex<Entries>,
+}
+
+#[derive(Default)]
+struct Entries {
+    order: VecDeque<(String, Instant)>,
+    first_seen: HashMap<String, SystemTime>,
+}
+
+impl Entries {
+    fn remove_expired(&mut self, ttl: Option<Duration>) {
+        let Some(ttl) = ttl else {
+            return;
+        };
+        while let Some((request_id, inserted)) = self.order.front() {
+            if inserted.elapsed() < ttl {
+                break;
+            }
+            self.first_seen.remove(request_id);
+            self.order.pop_front();
+        }
+    }
+}
+
+impl RecentRequestIds {
+    pub fn new(max_entries: Option<usize>, ttl: Option<Duration>) -> Self {
+        RecentRequestIds {
+            max_entries,
+            ttl,
+            entries: Mutex::new(Entries::default()),
+        }
+    }
+
+    /// Time the request id was first inserted, if not expired
+    pub fn first_seen(&self, request_id: &str) -> Option<SystemTime> {
+        self.max_entries?;
+        let mut entries = self.entries.lock().unwrap();
+        entries.remove_expired(self.ttl);
+        entries.first_seen.get(request_id).copied()
+    }
+
+    pub fn insert(&self, request_id: &str) {
+        let Some(max_entries) = self.max_entries else {
+            return;
+        };
+        let mut entries = self.entries.lock().unwrap();
+        entries.remove_expired(self.ttl);
+        if entries.first_seen.contains_key(request_id) {
+            return;
+        }
+        entries
+            .first_seen
+            .insert(request_id.to_string(), SystemTime::now());
+        entries
+            .order
+            .push_back((request_id.to_string(), Instant::now()));
+        while entries.order.len() > max_entries {
+            if let Some((oldest, _)) = entries.order.pop_front() {
+                entries.first_seen.remove(&oldest);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::dedup::RecentRequestIds;
+
+    #[test]
+    fn should_contain_inserted_request_ids_up_to_maximum() {
+        let recent = RecentRequestIds::new(Some(2), None);
+        for request_id in ["request1", "request2", "request2", "request3"] {
+            recent.insert(request_id);
+        }
+
+        assert!(recent.first_seen("request1").is_none());
+        assert!(recent.first_seen("request2").is_some());
+        assert!(recent.first_seen("request3").is_some());
+    }
+
+    #[test]
+    fn should_not_contain_request_ids_if_disabled() {
+        let recent = RecentRequestIds::new(None, Some(Duration::from_secs(60)));
+        recent.insert("request1");
+
+        assert!(recent.first_seen("request1").is_none());
+    }
+
+    #[test]
+    fn should_keep_time_of_first_insert() {
+        let recent = RecentRequestIds::new(Some(2), None);
+        recent.insert("request1");
+        let first_seen = recent.first_seen("request1");
+
+        thread::sleep(Duration::from_millis(10));
+        recent.insert("request1");
+
+        assert_eq!(recent.first_seen("request1"), first_seen);
+    }
+
+    #[test]
+    fn should_not_contain_expired_request_ids() {
+        let recent = RecentRequestIds::new(Some(10), Some(Duration::from_millis(50)));
+        recent.insert("request1");
+        assert!(recent.first_seen("request1").is_some());
+
+        thread::sleep(Duration::from_millis(60));
+        recent.insert("request2");
+
+        assert!(recent.first_seen("request1").is_none());
+        assert!(recent.first_seen("request2").is_some());
+
+        recent.insert("request1");
+        assert!(recent.first_seen("request1").is_some());
+    }
+
+    #[test]
+    fn should_evict_oldest_request_ids_if_shared_across_threads() {
+        let recent = Arc::new(RecentRequestIds::new(
+            Some(100),
+            Some(Duration::from_secs(60)),
+        ));
+
+        let workers = (0..4)
+            .map(|worker| {
+                let recent = recent.clone();
+                thread::spawn(move || {
+                    for index in 0..100 {
+                        recent.insert(&format!("request{}-{}", worker, index));
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+
+        let entries = recent.entries.lock().unwrap();
+        assert_eq!(entries.order.len(), 100);
+        assert_eq!(entries.first_seen.len(), 100);
+        assert!(entries
+            .order
+            .iter()
+            .all(|(request_id, _)| entries.first_seen.contains_key(request_id)));
+    }
+}
@@ -0,0 +1,210 @@
+/*
+ * This file is part of ETL-Processor
+ *
+ * Copyright (c) 2024  Comprehensive Cancer Center Mainfranken
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published
+ * by the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+/// Errors reported in responses, each with a stable name, status code and default message
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorCode {
+    NoConnection,
+    Timeout,
+    ConnectionRefused,
+    SchemaRegistryUnavailable,
+    ParseError,
+    Duplicate,
+    InvalidRequestId,
+    InvalidPatientId,
+    PatientIdMismatch,
+    UnsupportedVersion,
+    UndeterminedConsent,
+    DisallowedConsentIssuer,
+    ConsentRefused,
+    ValidationError,
+    DeleteFailed,
+}
+
+impl ErrorCode {
+    /// Name sent as `error_code` of the response
+    pub fn name(&self) -> &'static str {
+        match self {
+            ErrorCode::NoConnection => "NO_CONNECTION",
+            ErrorCode::Timeout => "TIMEOUT",
+            ErrorCode::ConnectionRefused => "CONNECTION_REFUSED",
+            ErrorCode::SchemaRegistryUnavailable => "SCHEMA_REGISTRY_UNAVAILABLE",
+            ErrorCode::ParseError => "PARSE_ERROR",
+            ErrorCode::Duplicate => "DUPLICATE",
+            ErrorCode::InvalidRequestId => "INVALID_REQUEST_ID",
+            ErrorCode::InvalidPatientId => "INVALID_PATIENT_ID",
+            ErrorCode::PatientIdMismatch => "PATIENT_ID_MISMATCH",
+            ErrorCode::UnsupportedVersion => "UNSUPPORTED_VERSION",
+            ErrorCode::UndeterminedConsent => "UNDETERMINED_CONSENT",
+            ErrorCode::DisallowedConsentIssuer => "DISALLOWED_CONSENT_ISSUER",
+            ErrorCode::ConsentRefused => "CONSENT_REFUSED",
+            ErrorCode::ValidationError => "VALIDATION_ERROR",
+            ErrorCode::DeleteFailed => "DELETE_FAILED",
+        }
+    }
+
+    /// Numeric code sent as `status_code` of the response
+    pub fn status_code(&self) -> u16 {
+        match self {
+            ErrorCode::NoConnection => 900,
+            ErrorCode::Timeout => 901,
+            ErrorCode::ConnectionRefused => 902,
+            ErrorCode::SchemaRegistryUnavailable => 903,
+            ErrorCode::ParseError => 904,
+            ErrorCode::Duplicate => 905,
+            ErrorCode::DeleteFailed => 906,
+            ErrorCode::InvalidRequestId
+            | ErrorCode::InvalidPatientId
+            | ErrorCode::PatientIdMismatch
+            | ErrorCode::UnsupportedVersion
+            | ErrorCode::UndeterminedConsent => 400,
+            ErrorCode::DisallowedConsentIssuer | ErrorCode::ConsentRefused => 403,
+            ErrorCode::ValidationError => 422,
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        match self {
+            ErrorCode::NoConnection => "No HTTP connection",
+            ErrorCode::Timeout => "HTTP request timed out",
+            ErrorCode::ConnectionRefused => "HTTP connection refused",
+            ErrorCode::SchemaRegistryUnavailable => "Schema registry unavailable",
+            ErrorCode::ParseError => "Cannot parse request",
+            ErrorCode::Duplicate => "Request skipped",
+            ErrorCode::InvalidRequestId => "Invalid request id",
+            ErrorCode::InvalidPatientId => "Invalid patient id",
+            ErrorCode::PatientIdMismatch => "Patient ids of consent and patient differ",
+            ErrorCode::UnsupportedVersion => "Unsupported request version",
+            ErrorCode::UndeterminedConsent => "Could not determine consent",
+            ErrorCode::DisallowedConsentIssuer => "Consent issuer not allowed",
+            ErrorCode::ConsentRefused => "Consent does not permit MTB file",
+            ErrorCode::ValidationError => "MTB file violates schema",
+            ErrorCode::DeleteFailed => "Delete failed after all retries",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use crate::error_code::ErrorCode;
+
+    const EXPECTED: [(ErrorCode, &str, u16, &str); 15] = [
+        (
+            ErrorCode::NoConnection,
+            "NO_CONNECTION",
+            900,
+            "No HTTP connection",
+        ),
+        (ErrorCode::Timeout, "TIMEOUT", 901, "HTTP request timed out"),
+        (
+            ErrorCode::ConnectionRefused,
+            "CONNECTION_REFUSED",
+            902,
+            "HTTP connection refused",
+        ),
+        (
+            ErrorCode::SchemaRegistryUnavailable,
+            "SCHEMA_REGISTRY_UNAVAILABLE",
+            903,
+            "Schema registry unavailable",
+        ),
+        (
+            ErrorCode::ParseError,
+            "PARSE_ERROR",
+            904,
+            "Cannot parse request",
+        ),
+        (ErrorCode::Duplicate, "DUPLICATE", 905, "Request skipped"),
+        (
+            ErrorCode::InvalidRequestId,
+            "INVALID_REQUEST_ID",
+            400,
+            "Invalid request id",
+        ),
+        (
+            ErrorCode::InvalidPatientId,
+            "INVALID_PATIENT_ID",
+            400,
+            "Invalid patient id",
+        ),
+        (
+            ErrorCode::PatientIdMismatch,
+            "PATIENT_ID_MISMATCH",
+            400,
+            "Patient ids of consent and patient differ",
+        ),
+        (
+            ErrorCode::UnsupportedVersion,
+            "UNSUPPORTED_VERSION",
+            400,
+            "Unsupported request version",
+        ),
+        (
+            ErrorCode::UndeterminedConsent,
+            "UNDETERMINED_CONSENT",
+            400,
+            "Could not determine consent",
+        ),
+        (
+            ErrorCode::DisallowedConsentIssuer,
+            "DISALLOWED_CONSENT_ISSUER",
+            403,
+            "Consent issuer not allowed",
+        ),
+        (
+            ErrorCode::ConsentRefused,
+            "CONSENT_REFUSED",
+            403,
+            "Consent does not permit MTB file",
+        ),
+        (
+            ErrorCode::ValidationError,
+            "VALIDATION_ERROR",
+            422,
+            "MTB file violates schema",
+        ),
+        (
+            ErrorCode::DeleteFailed,
+            "DELETE_FAILED",
+            906,
+            "Delete failed after all retries",
+        ),
+    ];
+
+    #[test]
+    fn should_map_error_codes() {
+        for (code, name, status_code, message) in EXPECTED {
+            assert_eq!(code.name(), name);
+            assert_eq!(code.status_code(), status_code);
+            assert_eq!(code.message(), message);
+        }
+    }
+
+    #[test]
+    fn should_use_unique_names() {
+        let names = EXPECTED
+            .iter()
+            .map(|(code, _, _, _)| code.name())
+            .collect::<HashSet<_>>();
+
+        assert_eq!(names.len(), EXPECTED.len());
+    }
+}
@@ -0,0 +1,225 @@
+/*
+ * This file is part of ETL-Processor
+ *
+ * Copyright (c) 2024  Comprehensive Cancer Center Mainfranken
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published
+ * by the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+use std::time::{Duration, Instant, SystemTime};
+
+use log::{info, warn};
+
+use crate::bwhc_client::BwhcClient;
+use crate::AppError;
+
+/// Result of the last backend health probe
+pub static BACKEND_HEALTH: HealthState = HealthState::new();
+
+/// Set once Kafka and bwHC-Backend connections are warmed up
+pub static READINESS: Readiness = Readiness::new();
+
+pub struct Readiness {
+    ready: AtomicBool,
+}
+
+impl Readiness {
+    pub const fn new() -> Self {
+        Readiness {
+            ready: AtomicBool::new(false),
+        }
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::Acquire)
+    }
+
+    pub fn set_ready(&self) {
+        self.ready.store(true, Ordering::Release)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct HealthStatus {
+    pub healthy: bool,
+    /// HTTP status code or `None` if backend was not reachable
+    pub status_code: Option<u16>,
+    pub latency: Duration,
+    pub timestamp: SystemTime,
+}
+
+pub struct HealthState {
+    last: RwLock<Option<HealthStatus>>,
+}
+
+impl HealthState {
+    pub const fn new() -> Self {
+        HealthState {
+            last: RwLock::new(None),
+        }
+    }
+
+    /// Last health status or `None` if no probe was run
+    pub fn last(&self) -> Option<HealthStatus> {
+        self.last.read().unwrap().clone()
+    }
+
+    /// Stores probe result. Backend is healthy if reachable without server error.
+    pub fn update(&self, result: Result<u16, AppError>, latency: Duration) -> HealthStatus {
+        let status = HealthStatus {
+            healthy: matches!(result, Ok(status_code) if status_code < 500),
+            status_code: result.as_ref().ok().copied(),
+            latency,
+            timestamp: SystemTime::now(),
+        };
+
+        let mut last = self.last.write().unwrap();
+        let was_healthy = last.as_ref().map(|last| last.healthy);
+        match (was_healthy, status.healthy, &result) {
+            (Some(true) | None, false, Ok(status_code)) => {
+                warn!("bwHC-Backend unhealthy: HTTP {}", status_code)
+            }
+            (Some(true) | None, false, Err(e)) => warn!("bwHC-Backend unhealthy: {}", e),
+            (Some(false), true, _) => info!("bwHC-Backend healthy again"),
+            _ => {}
+        }
+        *last = Some(status.clone());
+        status
+    }
+}
+
+/// Opens while bwHC-Backend is unhealthy to pause consumption and closes once healthy again.
+/// Unknown health keeps the circuit closed.
+pub struct BackendCircuit {
+    open: bool,
+}
+
+impl BackendCircuit {
+    pub fn new() -> Self {
+        BackendCircuit { open: false }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.open
+    }
+
+    /// Updates state by last health status and pauses consumption while open, also to pause
+    /// partitions assigned after opening. Resumes consumption once closed.
+    pub fn update(&mut self, status: Option<&HealthStatus>, pause: impl FnOnce(bool)) {
+        let open = status.is_some_and(|status| !status.healthy);
+        match (self.open, open) {
+            (false, true) => warn!("bwHC-Backend unhealthy - pausing consumption"),
+            (true, false) => info!("bwHC-Backend healthy again - resuming consumption"),
+            (true, true) => {}
+            (false, false) => return,
+        }
+        self.open = open;
+        pause(open);
+    }
+}
+
+/// Probes backend health in given interval
+pub async fn probe_periodically(client: BwhcClient, path: String, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        let start = Instant::now();
+        let result = client.check_health(path.as_str()).await;
+        BACKEND_HEALTH.update(result, start.elapsed());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use std::cell::RefCell;
+
+    use crate::health::{BackendCircuit, HealthState, Readiness};
+    use crate::AppError::HttpError;
+
+    #[test]
+    fn should_not_have_status_without_probe() {
+        assert!(HealthState::new().last().is_none())
+    }
+
+    #[test]
+    fn should_store_last_status() {
+        let state = HealthState::new();
+
+        state.update(Ok(200), Duration::from_millis(10));
+        assert!(state.last().unwrap().healthy);
+
+        state.update(Ok(503), Duration::from_millis(20));
+        let actual = state.last().unwrap();
+        assert!(!actual.healthy);
+        assert_eq!(actual.status_code, Some(503));
+        assert_eq!(actual.latency, Duration::from_millis(20));
+
+        state.update(Err(HttpError("Connection refused".into())), Duration::ZERO);
+        let actual = state.last().unwrap();
+        assert!(!actual.healthy);
+        assert_eq!(actual.status_code, None);
+    }
+
+    #[test]
+    fn should_not_be_ready_until_set() {
+        let readiness = Readiness::new();
+        assert!(!readiness.is_ready());
+
+        readiness.set_ready();
+        assert!(readiness.is_ready());
+    }
+
+    #[test]
+    fn should_pause_consumption_while_circuit_is_open() {
+        let state = HealthState::new();
+        let mut circuit = BackendCircuit::new();
+        let calls = RefCell::new(vec![]);
+
+        circuit.update(state.last().as_ref(), |pause| {
+            calls.borrow_mut().push(pause)
+        });
+        assert!(!circuit.is_open());
+
+        state.update(Ok(200), Duration::ZERO);
+        circuit.update(state.last().as_ref(), |pause| {
+            calls.borrow_mut().push(pause)
+        });
+        assert!(!circuit.is_open());
+        assert!(calls.borrow().is_empty());
+
+        state.update(Ok(503), Duration::ZERO);
+        circuit.update(state.last().as_ref(), |pause| {
+            calls.borrow_mut().push(pause)
+        });
+        assert!(circuit.is_open());
+
+        state.update(Err(HttpError("Connection refused".into())), Duration::ZERO);
+        circuit.update(state.last().as_ref(), |pause| {
+            calls.borrow_mut().push(pause)
+        });
+        assert!(circuit.is_open());
+
+        state.update(Ok(200), Duration::ZERO);
+        circuit.update(state.last().as_ref(), |pause| {
+            calls.borrow_mut().push(pause)
+        });
+        assert!(!circuit.is_open());
+
+        assert_eq!(*calls.borrow(), vec![true, true, false]);
+    }
+}
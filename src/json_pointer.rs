@@ -0,0 +1,293 @@
+/*
+ * This file is part of ETL-Processor
+ *
+ * Copyright (c) 2024  Comprehensive Cancer Center Mainfranken
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published
+ * by the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::collections::BTreeSet;
+
+use serde_json::Value;
+
+/// Token of JSON pointers (RFC 6901) matching any array element or object field
+const WILDCARD: &str = "*";
+
+/// Token matching any number of nested array elements or object fields, including none
+pub const RECURSIVE_WILDCARD: &str = "**";
+
+/// Unescaped tokens of the pointer
+fn tokens(pointer: &str) -> Vec<String> {
+    pointer
+        .trim()
+        .split('/')
+        .skip(1)
+        .map(|token| token.replace("~1", "/").replace("~0", "~"))
+        .collect()
+}
+
+fn escape(token: &str) -> String {
+    token.replace('~', "~0").replace('/', "~1")
+}
+
+/// Pointers without wildcards of all values matching the pointer
+pub fn matching_pointers(value: &Value, pointer: &str) -> BTreeSet<String> {
+    let mut pointers = BTreeSet::new();
+    collect_pointers(value, &tokens(pointer), String::new(), &mut pointers);
+    pointers
+}
+
+fn collect_pointers(
+    value: &Value,
+    tokens: &[String],
+    pointer: String,
+    pointers: &mut BTreeSet<String>,
+) {
+    let Some((token, rest)) = tokens.split_first() else {
+        pointers.insert(pointer);
+        return;
+    };
+    let child = |key: &str| format!("{}/{}", pointer, escape(key));
+    if token == RECURSIVE_WILDCARD {
+        collect_pointers(value, rest, pointer.clone(), pointers);
+        match value {
+            Value::Object(fields) => fields
+                .iter()
+                .for_each(|(key, field)| collect_pointers(field, tokens, child(key), pointers)),
+            Value::Array(elements) => elements.iter().enumerate().for_each(|(index, element)| {
+                collect_pointers(element, tokens, child(&index.to_string()), pointers)
+            }),
+            _ => {}
+        }
+        return;
+    }
+    match value {
+        Value::Object(fields) if token == WILDCARD => fields
+            .iter()
+            .for_each(|(key, field)| collect_pointers(field, rest, child(key), pointers)),
+        Value::Array(elements) if token == WILDCARD => {
+            elements.iter().enumerate().for_each(|(index, element)| {
+                collect_pointers(element, rest, child(&index.to_string()), pointers)
+            })
+        }
+        Value::Object(fields) => {
+            if let Some(field) = fields.get(token) {
+                collect_pointers(field, rest, child(token), pointers)
+            }
+        }
+        Value::Array(elements) => {
+            if let Some((index, element)) = token
+                .parse::<usize>()
+                .ok()
+                .and_then(|index| elements.get(index).map(|element| (index, element)))
+            {
+                collect_pointers(element, rest, child(&index.to_string()), pointers)
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Removes all values matching the pointer and returns the number of removed values.
+/// A wildcard as last token removes all elements or fields, a recursive wildcard as last token removes nothing.
+pub fn remove_matching(value: &mut Value, pointer: &str) -> usize {
+    remove(value, &tokens(pointer))
+}
+
+fn remove(value: &mut Value, tokens: &[String]) -> usize {
+    let Some((token, rest)) = tokens.split_first() else {
+        return 0;
+    };
+    if token == RECURSIVE_WILDCARD {
+        let removed = remove(value, rest);
+        return removed
+            + match value {
+                Value::Object(fields) => {
+                    fields.values_mut().map(|field| remove(field, tokens)).sum()
+                }
+                Value::Array(elements) => elements
+                    .iter_mut()
+                    .map(|element| remove(element, tokens))
+                    .sum(),
+                _ => 0,
+            };
+    }
+    match value {
+        Value::Object(fields) if token == WILDCARD && rest.is_empty() => {
+            let removed = fields.len();
+            fields.clear();
+            removed
+        }
+        Value::Array(elements) if token == WILDCARD && rest.is_empty() => {
+            let removed = elements.len();
+            elements.clear();
+            removed
+        }
+        Value::Object(fields) if token == WILDCARD => {
+            fields.values_mut().map(|field| remove(field, rest)).sum()
+        }
+        Value::Array(elements) if token == WILDCARD => elements
+            .iter_mut()
+            .map(|element| remove(element, rest))
+            .sum(),
+        Value::Object(fields) if rest.is_empty() => usize::from(fields.remove(token).is_some()),
+        Value::Object(fields) => fields.get_mut(token).map_or(0, |field| remove(field, rest)),
+        Value::Array(elements) => match token.parse::<usize>() {
+            Ok(index) if index < elements.len() && rest.is_empty() => {
+                elements.remove(index);
+                1
+            }
+            Ok(index) => elements
+                .get_mut(index)
+                .map_or(0, |element| remove(element, rest)),
+            Err(_) => 0,
+        },
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use serde_json::json;
+
+    use crate::json_pointer::{matching_pointers, remove_matching};
+
+    #[test]
+    fn should_match_pointers_with_wildcards() {
+        let value = json!({
+            "patient": "TESTPATIENT1234",
+            "specimens": [{"patient": "TESTPATIENT1234"}, {"id": "2"}],
+            "a/b": {"c~d": 1}
+        });
+
+        assert_eq!(
+            matching_pointers(&value, "/specimens/*/patient"),
+            BTreeSet::from(["/specimens/0/patient".to_string()])
+        );
+        assert_eq!(
+            matching_pointers(&value, "/*/*/patient"),
+            BTreeSet::from(["/specimens/0/patient".to_string()])
+        );
+        assert!(matching_pointers(&value, "/*/patient").is_empty());
+        assert_eq!(
+            matching_pointers(&value, "/a~1b/c~0d"),
+            BTreeSet::from(["/a~1b/c~0d".to_string()])
+        );
+        assert!(matching_pointers(&value, "/specimens/2/patient").is_empty());
+        assert!(matching_pointers(&value, "/patient/id").is_empty());
+    }
+
+    #[test]
+    fn should_match_pointers_at_any_depth_using_recursive_wildcard() {
+        let value = json!({
+            "patient": "TESTPATIENT1234",
+            "episode": {"patient": "TESTPATIENT1234"},
+            "therapies": [{"history": [{"patient": "TESTPATIENT1234"}]}]
+        });
+
+        assert_eq!(
+            matching_pointers(&value, "/**/patient"),
+            BTreeSet::from([
+                "/episode/patient".to_string(),
+                "/patient".to_string(),
+                "/therapies/0/history/0/patient".to_string()
+            ])
+        );
+        assert_eq!(
+            matching_pointers(&value, "/therapies/**/patient"),
+            BTreeSet::from(["/therapies/0/history/0/patient".to_string()])
+        );
+    }
+
+    #[test]
+    fn should_remove_fields_at_any_depth_using_recursive_wildcard() {
+        let mut value = json!({
+            "note": "free text",
+            "specimens": [{"id": "1", "note": "free text", "collection": {"note": "nested"}}]
+        });
+
+        assert_eq!(remove_matching(&mut value, "/specimens/**/note"), 2);
+        assert_eq!(
+            value,
+            json!({"note": "free text", "specimens": [{"id": "1", "collection": {}}]})
+        );
+        assert_eq!(remove_matching(&mut value, "/**/note"), 1);
+        assert_eq!(remove_matching(&mut value, "/**"), 0);
+    }
+
+    #[test]
+    fn should_remove_nested_fields() {
+        let mut value = json!({
+            "patient": {"id": "TESTPATIENT1234", "managingZPM": "internal"},
+            "episode": {"id": "1", "note": "free text"}
+        });
+
+        assert_eq!(remove_matching(&mut value, "/episode/note"), 1);
+        assert_eq!(remove_matching(&mut value, "/patient/managingZPM"), 1);
+        assert_eq!(remove_matching(&mut value, "/patient/missing"), 0);
+        assert_eq!(remove_matching(&mut value, "/missing/note"), 0);
+        assert_eq!(
+            value,
+            json!({"patient": {"id": "TESTPATIENT1234"}, "episode": {"id": "1"}})
+        );
+    }
+
+    #[test]
+    fn should_remove_fields_of_all_array_elements_using_wildcard() {
+        let mut value = json!({
+            "specimens": [
+                {"id": "1", "note": "free text"},
+                {"id": "2"},
+                {"id": "3", "note": "free text", "collection": {"note": "nested"}}
+            ]
+        });
+
+        assert_eq!(remove_matching(&mut value, "/specimens/*/note"), 2);
+        assert_eq!(
+            remove_matching(&mut value, "/specimens/*/collection/note"),
+            1
+        );
+        assert_eq!(
+            value,
+            json!({"specimens": [{"id": "1"}, {"id": "2"}, {"id": "3", "collection": {}}]})
+        );
+    }
+
+    #[test]
+    fn should_remove_array_elements_by_index_or_wildcard() {
+        let mut value = json!({"notes": ["first", "second"], "cases": ["1", "2", "3"]});
+
+        assert_eq!(remove_matching(&mut value, "/notes/0"), 1);
+        assert_eq!(remove_matching(&mut value, "/notes/5"), 0);
+        assert_eq!(remove_matching(&mut value, "/cases/*"), 3);
+        assert_eq!(value, json!({"notes": ["second"], "cases": []}));
+    }
+
+    #[test]
+    fn should_remove_fields_of_all_object_fields_using_wildcard() {
+        let mut value = json!({
+            "episode": {"id": "1", "caseNumber": "internal"},
+            "consent": {"id": "2", "caseNumber": "internal"},
+            "caseNumber": "internal"
+        });
+
+        assert_eq!(remove_matching(&mut value, "/*/caseNumber"), 2);
+        assert_eq!(
+            value,
+            json!({"episode": {"id": "1"}, "consent": {"id": "2"}, "caseNumber": "internal"})
+        );
+    }
+}
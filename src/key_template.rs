@@ -0,0 +1,175 @@
+/*
+ * This file is part of ETL-Processor
+ *
+ * Copyright (c) 2024  Comprehensive Cancer Center Mainfranken
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published
+ * by the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::str::FromStr;
+
+use crate::AppError;
+use crate::AppError::ValidationError;
+
+const PLACEHOLDERS: [&str; 5] = ["request_id", "patient_id", "key", "partition", "offset"];
+
+/// Values of a consumed record to render keys of response and DLQ records
+pub struct KeyValues<'a> {
+    pub request_id: &'a str,
+    /// SHA-256 hash of the patient id, if known
+    pub patient_id: Option<&'a str>,
+    pub key: &'a str,
+    pub partition: i32,
+    pub offset: i64,
+}
+
+/// Template of record keys with placeholders, e.g. `{request_id}:{partition}:{offset}`
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeyTemplate(String);
+
+impl KeyTemplate {
+    pub fn uses(&self, placeholder: &str) -> bool {
+        self.0.contains(&format!("{{{}}}", placeholder))
+    }
+
+    /// Key with placeholders replaced, missing values are rendered empty
+    pub fn render(&self, values: &KeyValues) -> String {
+        let mut rendered = String::with_capacity(self.0.len());
+        let mut rest = self.0.as_str();
+        while let Some(start) = rest.find('{') {
+            rendered.push_str(&rest[..start]);
+            let Some(end) = rest[start..].find('}').map(|end| start + end) else {
+                break;
+            };
+            match &rest[start + 1..end] {
+                "request_id" => rendered.push_str(values.request_id),
+                "patient_id" => rendered.push_str(values.patient_id.unwrap_or_default()),
+                "key" => rendered.push_str(values.key),
+                "partition" => rendered.push_str(&values.partition.to_string()),
+                "offset" => rendered.push_str(&values.offset.to_string()),
+                _ => rendered.push_str(&rest[start..=end]),
+            }
+            rest = &rest[end + 1..];
+        }
+        rendered.push_str(rest);
+        rendered
+    }
+}
+
+impl FromStr for KeyTemplate {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut rest = s;
+        while let Some(start) = rest.find('{') {
+            let Some(end) = rest[start..].find('}').map(|end| start + end) else {
+                return Err(ValidationError(format!(
+                    "Unclosed placeholder in key template '{}'",
+                    s
+                )));
+            };
+            let placeholder = &rest[start + 1..end];
+            if !PLACEHOLDERS.contains(&placeholder) {
+                return Err(ValidationError(format!(
+                    "Unknown placeholder '{{{}}}' in key template, expected one of {}",
+                    placeholder,
+                    PLACEHOLDERS
+                        .iter()
+                        .map(|placeholder| format!("{{{}}}", placeholder))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )));
+            }
+            rest = &rest[end + 1..];
+        }
+        Ok(KeyTemplate(s.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use crate::key_template::{KeyTemplate, KeyValues};
+
+    const VALUES: KeyValues = KeyValues {
+        request_id: "request0123456789",
+        patient_id: Some("3bea8cb6"),
+        key: "TESTPATIENT1234",
+        partition: 2,
+        offset: 42,
+    };
+
+    #[test]
+    fn should_render_key_by_default() {
+        let actual = KeyTemplate::from_str("{key}").unwrap().render(&VALUES);
+
+        assert_eq!(actual, "TESTPATIENT1234");
+    }
+
+    #[test]
+    fn should_render_placeholders() {
+        for (template, expected) in [
+            (
+                "{request_id}:{partition}:{offset}",
+                "request0123456789:2:42",
+            ),
+            ("{patient_id}", "3bea8cb6"),
+            ("{key}-{key}", "TESTPATIENT1234-TESTPATIENT1234"),
+            (
+                "prefix/{request_id}/suffix",
+                "prefix/request0123456789/suffix",
+            ),
+            ("static", "static"),
+            ("", ""),
+        ] {
+            let actual = KeyTemplate::from_str(template).unwrap().render(&VALUES);
+
+            assert_eq!(actual, expected, "template '{}'", template);
+        }
+    }
+
+    #[test]
+    fn should_render_missing_patient_id_empty() {
+        let values = KeyValues {
+            patient_id: None,
+            ..VALUES
+        };
+
+        let actual = KeyTemplate::from_str("{patient_id}:{offset}")
+            .unwrap()
+            .render(&values);
+
+        assert_eq!(actual, ":42");
+    }
+
+    #[test]
+    fn should_reject_invalid_templates() {
+        for template in ["{unknown}", "{request_id", "{}", "{Key}"] {
+            assert!(
+                KeyTemplate::from_str(template).is_err(),
+                "template '{}'",
+                template
+            );
+        }
+    }
+
+    #[test]
+    fn should_check_used_placeholders() {
+        let template = KeyTemplate::from_str("{request_id}:{patient_id}").unwrap();
+
+        assert!(template.uses("patient_id"));
+        assert!(!template.uses("offset"));
+    }
+}
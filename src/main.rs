@@ -17,26 +17,81 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::env;
 use std::error::Error;
 use std::fmt::{Debug as FmtDebug, Display, Formatter};
+use std::future::Future;
+use std::process;
 use std::str::FromStr;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use log::{debug, error, info, warn};
-use rdkafka::consumer::{Consumer, ConsumerContext, Rebalance, StreamConsumer};
+use log::{debug, error, info, warn, LevelFilter};
+use metrics::{counter, histogram};
+use rdkafka::consumer::{
+    BaseConsumer, CommitMode, Consumer, ConsumerContext, Rebalance, StreamConsumer,
+};
 use rdkafka::error::KafkaResult;
+use rdkafka::message::{
+    BorrowedHeaders, BorrowedMessage, Header, Headers, OwnedHeaders, Timestamp, ToBytes,
+};
 use rdkafka::producer::{FutureProducer, FutureRecord};
-use rdkafka::{ClientConfig, ClientContext, Message, TopicPartitionList};
+use rdkafka::{ClientConfig, ClientContext, Message, Offset, TopicPartitionList};
+use serde_json::value::RawValue;
 use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
 use simple_logger::SimpleLogger;
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::field::Empty;
+use tracing::{Instrument, Span};
 
-use crate::bwhc_client::{BwhcClient, HttpResponse};
-use crate::resources::request::Request;
-use crate::AppError::{ConnectionError, HttpError, MissingConfig};
+use crate::avro::SchemaRegistry;
+use crate::backpressure::{PausedPartitions, PendingResponses};
+use crate::bwhc_client::{BwhcClient, DeleteMode, HttpResponse};
+use crate::config::{
+    config_file_settings, is_sink_setting, Cli, Command, Config, ConsentValidityTime,
+    EnteredInErrorPolicy, ExpiredConsentPolicy, KafkaCommitMode, MissingConsentPolicy,
+    PayloadFormat, UndeterminedConsentPolicy,
+};
+use crate::dedup::RecentRequestIds;
+use crate::error_code::ErrorCode;
+use crate::health::{BackendCircuit, Readiness, BACKEND_HEALTH, READINESS};
+use crate::key_template::{KeyTemplate, KeyValues};
+use crate::pseudonym::Pseudonymizer;
+use crate::resources::issues::{Issues, Severity};
+use crate::resources::mtbfile::{ConsentDecision, ConsentValidity, PatientMatch};
+use crate::resources::request::{sanitize_content, Request, RequestType};
+use crate::schema::MtbFileSchema;
+use crate::sink::Sink;
+use crate::spool::{Spool, SpooledRecord};
+use crate::stats::{Outcome, STATS};
+use crate::AppError::{
+    ConnectionError, HttpConnectError, HttpError, HttpTimeout, IoError, MissingConfig,
+    PollingTimeout, ValidationError,
+};
 
+mod auth;
+mod avro;
+mod backpressure;
 mod bwhc_client;
+mod config;
+mod dedup;
+mod error_code;
+mod health;
+mod json_pointer;
+mod key_template;
+mod protobuf;
+mod pseudonym;
+mod rate_limit;
 mod resources;
+mod retry;
+mod schema;
+mod sink;
+mod spool;
+mod stats;
+mod telemetry;
+mod unix_socket;
 
 struct CustomContext;
 
@@ -62,6 +117,18 @@ pub enum AppError {
     ConnectionError(String),
     MissingConfig(String),
     HttpError(String),
+    HttpTimeout(String),
+    HttpConnectError(String),
+    IoError(String),
+    ValidationError(String),
+    PollingTimeout(String),
+}
+
+impl AppError {
+    /// Any error sending an HTTP request, including timeouts and refused connections
+    fn is_http_error(&self) -> bool {
+        matches!(self, HttpError(_) | HttpTimeout(_) | HttpConnectError(_))
+    }
 }
 
 impl Error for AppError {}
@@ -78,175 +145,6430 @@ impl Display for AppError {
             ConnectionError(s) => write!(f, "ConnectionError: {}", s),
             MissingConfig(s) => write!(f, "Missing config: {}", s),
             HttpError(s) => write!(f, "HTTP error: {}", s),
+            HttpTimeout(s) => write!(f, "HTTP timeout: {}", s),
+            HttpConnectError(s) => write!(f, "HTTP connect error: {}", s),
+            IoError(s) => write!(f, "IO error: {}", s),
+            ValidationError(s) => write!(f, "Validation error: {}", s),
+            PollingTimeout(s) => write!(f, "Polling timeout: {}", s),
         }
     }
 }
 
+/// Maximum number of characters of a non-JSON response body included in the response
+const MAX_BODY_EXCERPT: usize = 200;
+
+/// Header counting the attempts of a delete request using the retry topic
+const DELETE_RETRY_ATTEMPT_HEADER: &str = "delete-retry-attempt";
+
 enum KafkaResponsePayload {
-    SuccessfulConnection(HttpResponse),
-    NoConnection,
+    /// Response of bwHC-Backend and additional fields of the response, e.g. the echoed content
+    SuccessfulConnection(HttpResponse, Option<Value>),
+    /// Message and, if enabled, detail of the underlying error
+    NoConnection(String, Option<String>),
+    Timeout,
+    ConnectionRefused,
+    InvalidPatientId,
+    /// SHA-256 hashes of differing patient ids of consent and patient
+    PatientIdMismatch(String, String),
+    DeletePending,
+    /// Delete still failing after given number of attempts using retry topic
+    DeleteFailed(u32),
+    /// Status codes of deletes by SHA-256 hash of patient id, `900` to `902` if no connection
+    MultiPatientDelete(Vec<(String, u16)>),
+    /// Invalid request id, truncated and sanitized
+    InvalidRequestId(String),
+    /// Request cannot be parsed, containing the reason without any values of the request
+    InvalidRequest(String),
+    DisallowedConsentIssuer,
+    UnsupportedVersion(u32),
+    UndeterminedConsent,
+    /// Request skipped due to consent status, e.g. `draft`
+    Ignored(String),
+    /// Request skipped as the consent has expired or is not yet valid
+    InvalidConsentPeriod(ConsentValidity),
+    /// MTB file not sent as it violates the schema, containing the violations
+    SchemaViolation(Vec<String>),
+    /// Request not processed due to duplicate request id, containing the reason and the time
+    /// the request id was first seen since start
+    Skipped(String, SystemTime),
+    /// MTB file refused as consent requires delete, containing the consent status if present
+    ConsentRefused(Option<String>),
+    /// Avro record not decoded as schema could not be fetched from schema registry
+    SchemaRegistryUnavailable,
+    PollingTimeout(String),
 }
 
 impl KafkaResponsePayload {
-    fn to_payload(&self, request_id: &str) -> String {
+    fn from_response(response: HttpResponse) -> Self {
+        if let Ok(issues) = Issues::from_str(response.status_body.as_str()) {
+            histogram!("bwhc_response_issues").record(issues.count() as f64);
+            for severity in [
+                Severity::Fatal,
+                Severity::Error,
+                Severity::Warning,
+                Severity::Info,
+            ] {
+                counter!("bwhc_response_issues_total", "severity" => severity.as_str().to_string())
+                    .increment(issues.count_by_severity(&severity) as u64);
+            }
+        }
+        KafkaResponsePayload::SuccessfulConnection(response, None)
+    }
+
+    /// Adds field to the response of bwHC-Backend, other responses are kept as is
+    fn with_field(self, name: &str, value: Value) -> Self {
+        match self {
+            KafkaResponsePayload::SuccessfulConnection(response, fields) => {
+                let mut fields = fields.unwrap_or(json!({}));
+                fields[name] = value;
+                KafkaResponsePayload::SuccessfulConnection(response, Some(fields))
+            }
+            payload => payload,
+        }
+    }
+
+    /// Includes SHA-256 hash and, if `full` is set, the content sent to the backend
+    fn with_content(self, content: &str, full: bool) -> Self {
+        let mut echo = json!({
+            "sha256": format!("{:x}", Sha256::digest(content.as_bytes()))
+        });
+        if full {
+            echo["full"] = serde_json::from_str(content).unwrap_or(json!(content));
+        }
+        self.with_field("content", echo)
+    }
+
+    /// Includes number of fields removed from the content before sending it
+    fn with_stripped_fields(self, count: usize) -> Self {
+        self.with_field("stripped_fields", json!(count))
+    }
+
+    /// Error code of responses not received from bwHC-Backend
+    fn error_code(&self) -> Option<ErrorCode> {
         match self {
-            KafkaResponsePayload::SuccessfulConnection(s) => json!({
+            KafkaResponsePayload::NoConnection(_, _) => Some(ErrorCode::NoConnection),
+            KafkaResponsePayload::Timeout => Some(ErrorCode::Timeout),
+            KafkaResponsePayload::ConnectionRefused => Some(ErrorCode::ConnectionRefused),
+            KafkaResponsePayload::InvalidPatientId => Some(ErrorCode::InvalidPatientId),
+            KafkaResponsePayload::PatientIdMismatch(_, _) => Some(ErrorCode::PatientIdMismatch),
+            KafkaResponsePayload::InvalidRequestId(_) => Some(ErrorCode::InvalidRequestId),
+            KafkaResponsePayload::InvalidRequest(_) => Some(ErrorCode::ParseError),
+            KafkaResponsePayload::DisallowedConsentIssuer => {
+                Some(ErrorCode::DisallowedConsentIssuer)
+            }
+            KafkaResponsePayload::UnsupportedVersion(_) => Some(ErrorCode::UnsupportedVersion),
+            KafkaResponsePayload::UndeterminedConsent => Some(ErrorCode::UndeterminedConsent),
+            KafkaResponsePayload::DeleteFailed(_) => Some(ErrorCode::DeleteFailed),
+            KafkaResponsePayload::SchemaViolation(_) => Some(ErrorCode::ValidationError),
+            KafkaResponsePayload::Skipped(_, _) => Some(ErrorCode::Duplicate),
+            KafkaResponsePayload::ConsentRefused(_) => Some(ErrorCode::ConsentRefused),
+            KafkaResponsePayload::SchemaRegistryUnavailable => {
+                Some(ErrorCode::SchemaRegistryUnavailable)
+            }
+            KafkaResponsePayload::SuccessfulConnection(_, _)
+            | KafkaResponsePayload::DeletePending
+            | KafkaResponsePayload::MultiPatientDelete(_)
+            | KafkaResponsePayload::Ignored(_)
+            | KafkaResponsePayload::InvalidConsentPeriod(_)
+            | KafkaResponsePayload::PollingTimeout(_) => None,
+        }
+    }
+
+    /// Request failed if not processed or bwHC-Backend responded with an error status code
+    fn is_failure(&self) -> bool {
+        self.error_code().is_some()
+            || match self {
+                KafkaResponsePayload::SuccessfulConnection(response, _) => {
+                    response.status_code >= 400
+                }
+                KafkaResponsePayload::MultiPatientDelete(results) => {
+                    results.iter().any(|(_, status_code)| *status_code >= 400)
+                }
+                _ => false,
+            }
+    }
+
+    fn to_payload(&self, request_id: &str) -> String {
+        let mut payload = match self {
+            KafkaResponsePayload::SuccessfulConnection(s, fields) => {
+                let mut payload = json!({
+                    "request_id": request_id,
+                    "status_code": s.status_code,
+                    "status_body" : if s.status_body.trim().is_empty() {
+                        json!({})
+                    } else if s.truncated {
+                        json!({
+                            "issues": [{
+                                "severity": "warning",
+                                "message": "Response body truncated"
+                            }]
+                        })
+                    } else if !s.is_json() {
+                        json!({
+                            "issues": [{
+                                "severity": "error",
+                                "message": "Non-JSON response from backend",
+                                "details": s.status_body.chars().take(MAX_BODY_EXCERPT).collect::<String>()
+                            }]
+                        })
+                    } else {
+                        serde_json::from_str::<Value>(&s.status_body).unwrap_or(json!({}))
+                    }
+                });
+                if !s.headers.is_empty() {
+                    payload["headers"] = json!(s.headers);
+                }
+                if let Some(original_status_code) = s.original_status_code {
+                    payload["http_status_code"] = json!(original_status_code);
+                }
+                if let Some(endpoint) = s.endpoint {
+                    payload["endpoint"] = json!(endpoint.as_str());
+                }
+                if !s.is_json() || s.truncated {
+                    payload["raw_body"] = json!(s.status_body);
+                }
+                if s.truncated {
+                    payload["truncated"] = json!(true);
+                }
+                if s.status_code == 202 {
+                    payload["async"] = json!(true);
+                    if let Some(callback_url) = s.callback_url() {
+                        payload["callback_url"] = json!(callback_url);
+                    }
+                }
+                if let Some(Value::Object(fields)) = fields {
+                    for (name, value) in fields {
+                        payload[name] = value.clone();
+                    }
+                }
+                payload
+            }
+            KafkaResponsePayload::NoConnection(message, detail) => {
+                let mut issue = json!({
+                    "severity": "error",
+                    "message": message
+                });
+                if let Some(detail) = detail {
+                    issue["details"] = json!(detail);
+                }
+                json!({
+                    "request_id": request_id,
+                    "status_code": ErrorCode::NoConnection.status_code(),
+                    "status_body" : {
+                        "issues": [issue]
+                    }
+                })
+            }
+            KafkaResponsePayload::Timeout => json!({
+                "request_id": request_id,
+                "status_code": ErrorCode::Timeout.status_code(),
+                "status_body" : {
+                    "issues": [{
+                        "severity": "error",
+                        "message": ErrorCode::Timeout.message()
+                    }]
+                }
+            }),
+            KafkaResponsePayload::ConnectionRefused => json!({
                 "request_id": request_id,
-                "status_code": s.status_code,
-                "status_body" : if s.status_body.trim().is_empty() {
-                    json!({})
-                } else {
-                    serde_json::from_str::<Value>(&s.status_body).unwrap_or(json!({}))
+                "status_code": ErrorCode::ConnectionRefused.status_code(),
+                "status_body" : {
+                    "issues": [{
+                        "severity": "error",
+                        "message": ErrorCode::ConnectionRefused.message()
+                    }]
                 }
-            })
-            .to_string(),
-            KafkaResponsePayload::NoConnection => json!({
+            }),
+            KafkaResponsePayload::InvalidPatientId => json!({
                 "request_id": request_id,
-                "status_code": 900,
+                "status_code": ErrorCode::InvalidPatientId.status_code(),
                 "status_body" : {
                     "issues": [{
                         "severity": "error",
-                        "message": "No HTTP connection"
+                        "message": ErrorCode::InvalidPatientId.message()
                     }]
                 }
-            })
-            .to_string(),
+            }),
+            KafkaResponsePayload::PatientIdMismatch(consent, patient) => json!({
+                "request_id": request_id,
+                "status_code": ErrorCode::PatientIdMismatch.status_code(),
+                "status_body" : {
+                    "issues": [{
+                        "severity": "error",
+                        "message": ErrorCode::PatientIdMismatch.message(),
+                        "details": format!(
+                            "SHA-256 of consent.patient '{}' and patient '{}'",
+                            consent, patient
+                        )
+                    }]
+                }
+            }),
+            KafkaResponsePayload::DeletePending => json!({
+                "request_id": request_id,
+                "status_code": 202,
+                "status_body" : {
+                    "issues": [{
+                        "severity": "info",
+                        "message": "Delete pending - request will be retried"
+                    }]
+                }
+            }),
+            KafkaResponsePayload::MultiPatientDelete(results) => json!({
+                "request_id": request_id,
+                "status_code": results.iter().map(|(_, status_code)| *status_code).max().unwrap_or(200),
+                "status_body" : {
+                    "issues": results
+                        .iter()
+                        .filter(|(_, status_code)| *status_code >= 400)
+                        .map(|(patient_id, status_code)| json!({
+                            "severity": "error",
+                            "message": format!("Delete of patient '{}' failed with status {}", patient_id, status_code)
+                        }))
+                        .collect::<Vec<_>>()
+                },
+                "patients": results
+                    .iter()
+                    .map(|(patient_id, status_code)| json!({
+                        "patient_id": patient_id,
+                        "status_code": status_code
+                    }))
+                    .collect::<Vec<_>>()
+            }),
+            KafkaResponsePayload::InvalidRequestId(value) => json!({
+                "request_id": request_id,
+                "status_code": ErrorCode::InvalidRequestId.status_code(),
+                "status_body" : {
+                    "issues": [{
+                        "severity": "error",
+                        "message": ErrorCode::InvalidRequestId.message(),
+                        "details": value
+                    }]
+                }
+            }),
+            KafkaResponsePayload::InvalidRequest(reason) => json!({
+                "request_id": request_id,
+                "status_code": ErrorCode::ParseError.status_code(),
+                "status_body" : {
+                    "issues": [{
+                        "severity": "error",
+                        "message": ErrorCode::ParseError.message(),
+                        "details": reason
+                    }]
+                }
+            }),
+            KafkaResponsePayload::DisallowedConsentIssuer => json!({
+                "request_id": request_id,
+                "status_code": ErrorCode::DisallowedConsentIssuer.status_code(),
+                "status_body" : {
+                    "issues": [{
+                        "severity": "error",
+                        "message": ErrorCode::DisallowedConsentIssuer.message()
+                    }]
+                }
+            }),
+            KafkaResponsePayload::PollingTimeout(location) => json!({
+                "request_id": request_id,
+                "status_code": 202,
+                "status_body" : {
+                    "issues": [{
+                        "severity": "warning",
+                        "message": "Timeout waiting for final result"
+                    }]
+                },
+                "headers": {
+                    "Location": location
+                }
+            }),
+            KafkaResponsePayload::UnsupportedVersion(version) => json!({
+                "request_id": request_id,
+                "status_code": ErrorCode::UnsupportedVersion.status_code(),
+                "status_body" : {
+                    "issues": [{
+                        "severity": "error",
+                        "message": format!("{} {}", ErrorCode::UnsupportedVersion.message(), version)
+                    }]
+                }
+            }),
+            KafkaResponsePayload::UndeterminedConsent => json!({
+                "request_id": request_id,
+                "status_code": ErrorCode::UndeterminedConsent.status_code(),
+                "status_body" : {
+                    "issues": [{
+                        "severity": "error",
+                        "message": ErrorCode::UndeterminedConsent.message()
+                    }]
+                }
+            }),
+            KafkaResponsePayload::DeleteFailed(attempts) => json!({
+                "request_id": request_id,
+                "status_code": ErrorCode::DeleteFailed.status_code(),
+                "status_body" : {
+                    "issues": [{
+                        "severity": "error",
+                        "message": ErrorCode::DeleteFailed.message(),
+                        "details": format!("Delete not successful after {} retries", attempts)
+                    }]
+                }
+            }),
+            KafkaResponsePayload::SchemaViolation(violations) => json!({
+                "request_id": request_id,
+                "status_code": ErrorCode::ValidationError.status_code(),
+                "status_body" : {
+                    "issues": violations
+                        .iter()
+                        .map(|violation| json!({
+                            "severity": "error",
+                            "message": violation
+                        }))
+                        .collect::<Vec<_>>()
+                }
+            }),
+            KafkaResponsePayload::Skipped(reason, first_seen) => {
+                let first_seen_epoch_ms = first_seen
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_millis() as u64;
+                json!({
+                    "request_id": request_id,
+                    "status_code": ErrorCode::Duplicate.status_code(),
+                    "status_body" : {
+                        "issues": [{
+                            "severity": "info",
+                            "message": ErrorCode::Duplicate.message(),
+                            // Request ids are kept in memory only and not recognized after a restart
+                            "details": format!(
+                                "{}, first seen at {} - request ids are not kept across restarts",
+                                reason,
+                                httpdate::fmt_http_date(*first_seen)
+                            )
+                        }]
+                    },
+                    "skipped": reason,
+                    "first_seen_epoch_ms": first_seen_epoch_ms
+                })
+            }
+            KafkaResponsePayload::ConsentRefused(status) => {
+                let mut issue = json!({
+                    "severity": "error",
+                    "message": ErrorCode::ConsentRefused.message()
+                });
+                if let Some(status) = status {
+                    issue["details"] = json!(format!("Consent status '{}'", status));
+                }
+                json!({
+                    "request_id": request_id,
+                    "status_code": ErrorCode::ConsentRefused.status_code(),
+                    "status_body" : {
+                        "issues": [issue]
+                    }
+                })
+            }
+            KafkaResponsePayload::SchemaRegistryUnavailable => json!({
+                "request_id": request_id,
+                "status_code": ErrorCode::SchemaRegistryUnavailable.status_code(),
+                "status_body" : {
+                    "issues": [{
+                        "severity": "error",
+                        "message": ErrorCode::SchemaRegistryUnavailable.message()
+                    }]
+                }
+            }),
+            KafkaResponsePayload::Ignored(status) => json!({
+                "request_id": request_id,
+                "status_code": 200,
+                "status_body" : {
+                    "issues": [{
+                        "severity": "info",
+                        "message": "Request ignored",
+                        "details": format!("Consent status '{}'", status)
+                    }]
+                }
+            }),
+            KafkaResponsePayload::InvalidConsentPeriod(validity) => json!({
+                "request_id": request_id,
+                "status_code": 200,
+                "status_body" : {
+                    "issues": [{
+                        "severity": "info",
+                        "message": "Request ignored",
+                        "details": match validity {
+                            ConsentValidity::NotYetValid => "Consent not yet valid",
+                            _ => "Consent expired",
+                        }
+                    }]
+                }
+            }),
+        };
+        if let Some(code) = self.error_code() {
+            payload["error_code"] = json!(code.name());
+        }
+        payload.to_string()
+    }
+}
+
+/// Topics to send responses to, separated by success and failure if configured
+#[derive(Clone)]
+struct ResponseTopics {
+    response: String,
+    success: Option<String>,
+    failure: Option<String>,
+}
+
+impl ResponseTopics {
+    fn new(config: &Config) -> Self {
+        ResponseTopics {
+            response: config.kafka_response_topic(),
+            success: config.kafka_success_topic.clone(),
+            failure: config.kafka_failure_topic.clone(),
+        }
+    }
+
+    /// Success or failure topic if configured, response topic otherwise
+    fn topic_for(&self, payload: &KafkaResponsePayload) -> &str {
+        let topic = if payload.is_failure() {
+            &self.failure
+        } else {
+            &self.success
+        };
+        topic.as_deref().unwrap_or(&self.response)
+    }
+}
+
+/// Hashed patient id of the request, only parsed if used by the key template
+fn key_patient_id(config: &Config, payload: &str) -> Option<String> {
+    if !config.key_template.uses("patient_id") {
+        return None;
+    }
+    Request::parse(
+        payload,
+        config.max_decoded_content_bytes,
+        &config.consent_date_field,
+        &config.consent_active_statuses,
+        &config.consent_pointer,
+    )
+    .ok()?
+    .patient_id(config.patient_id_source)
+    .map(|patient_id| hashed_patient_id(&patient_id))
+}
+
+/// Stores record that could not be sent to Kafka in spool directory if configured
+async fn spool_record(spool: Option<&Spool>, record: SpooledRecord) {
+    if let Some(spool) = spool {
+        match spool.store(&record).await {
+            Ok(path) => info!(
+                "Record for topic '{}' spooled to '{}'",
+                record.topic,
+                path.display()
+            ),
+            Err(e) => error!(
+                "Cannot spool record for topic '{}', record is lost: {}",
+                record.topic, e
+            ),
         }
     }
 }
 
 async fn send_kafka_response(
     producer: &FutureProducer,
-    topic: &str,
-    request_id: &str,
-    key: &str,
+    spool: Option<&Spool>,
+    topics: &ResponseTopics,
+    key_template: &KeyTemplate,
+    values: &KeyValues<'_>,
     payload: KafkaResponsePayload,
 ) {
+    let topic = topics.topic_for(&payload);
+    let key = key_template.render(values);
+    let payload = payload.to_payload(values.request_id);
     if let Err(e) = producer
         .send(
-            FutureRecord::to(topic)
-                .key(key)
-                .payload(payload.to_payload(request_id).as_str()),
+            FutureRecord::to(topic).key(&key).payload(payload.as_str()),
             Duration::from_secs(1),
         )
         .await
     {
-        warn!("Response not sent: {}", e.0)
+        warn!("Response not sent: {}", e.0);
+        let record = SpooledRecord::new::<OwnedHeaders>(topic, &key, payload.as_bytes(), None);
+        spool_record(spool, record).await
     };
 }
 
-async fn handle_message(producer: &FutureProducer, topic: &str, key: &str, payload: &str) {
-    if Request::can_parse(payload) {
-        if let Ok(request) = Request::from_str(payload) {
-            if request.has_consent() {
-                match BwhcClient::send_mtb_file(request.content_string().as_str()).await {
-                    Ok(response) => {
-                        send_kafka_response(
-                            producer,
-                            topic,
-                            request.request_id().as_str(),
-                            key,
-                            KafkaResponsePayload::SuccessfulConnection(response),
-                        )
-                        .await
-                    }
-                    Err(_) => {
-                        send_kafka_response(
-                            producer,
-                            topic,
-                            request.request_id().as_str(),
-                            key,
-                            KafkaResponsePayload::NoConnection,
-                        )
-                        .await
-                    }
-                }
-            } else {
-                match BwhcClient::send_delete(request.patient_id().as_str()).await {
-                    Ok(response) => {
-                        send_kafka_response(
-                            producer,
-                            topic,
-                            request.request_id().as_str(),
-                            key,
-                            KafkaResponsePayload::SuccessfulConnection(response),
-                        )
-                        .await
-                    }
-                    Err(_) => {
-                        send_kafka_response(
-                            producer,
-                            topic,
-                            request.request_id().as_str(),
-                            key,
-                            KafkaResponsePayload::NoConnection,
-                        )
-                        .await
-                    }
-                }
+/// Sends consumed message including its headers to given topic, e.g. DLQ
+async fn forward_kafka_message<P: ToBytes + ?Sized>(
+    producer: &FutureProducer,
+    spool: Option<&Spool>,
+    topic: &str,
+    key: &str,
+    payload: &P,
+    headers: Option<OwnedHeaders>,
+) {
+    let mut record = FutureRecord::to(topic).key(key).payload(payload);
+    if let Some(headers) = &headers {
+        record = record.headers(headers.clone());
+    }
+    if let Err(e) = producer.send(record, Duration::from_secs(1)).await {
+        warn!("Request not sent to topic '{}': {}", topic, e.0);
+        let record = SpooledRecord::new(topic, key, payload.to_bytes(), headers.as_ref());
+        spool_record(spool, record).await
+    };
+}
+
+/// Payload as JSON, decoded from protobuf if configured, or from Avro if a schema registry
+/// is configured and the payload uses Confluent wire format.
+/// Fails without response if a JSON payload is not UTF-8.
+async fn decode_payload<'a>(
+    format: PayloadFormat,
+    registry: Option<&SchemaRegistry>,
+    payload: &'a [u8],
+    consent_pointer: &str,
+) -> Result<Cow<'a, str>, Option<KafkaResponsePayload>> {
+    if format == PayloadFormat::Protobuf {
+        return match protobuf::decode(payload, consent_pointer) {
+            Ok(json) => Ok(Cow::Owned(json)),
+            Err(reason) => {
+                error!("Cannot decode protobuf message: {}", reason);
+                STATS.record(Outcome::ParseError);
+                Err(Some(KafkaResponsePayload::InvalidRequest(format!(
+                    "Invalid protobuf message: {}",
+                    reason
+                ))))
+            }
+        };
+    }
+    match registry {
+        Some(registry) if avro::is_wire_format(payload) => match registry.decode(payload).await {
+            Ok(json) => Ok(Cow::Owned(json)),
+            Err(ValidationError(reason)) => {
+                error!("Cannot decode Avro record: {}", reason);
+                STATS.record(Outcome::ParseError);
+                Err(Some(KafkaResponsePayload::InvalidRequest(format!(
+                    "Invalid Avro record: {}",
+                    reason
+                ))))
+            }
+            Err(e) => {
+                error!("Schema registry unavailable: {}", e);
+                STATS.record(Outcome::Failed);
+                Err(Some(KafkaResponsePayload::SchemaRegistryUnavailable))
             }
+        },
+        _ => std::str::from_utf8(payload)
+            .map(Cow::Borrowed)
+            .map_err(|_| None),
+    }
+}
+
+/// Checks if the request is sent to the dead letter queue, if configured, due to the response
+fn is_dlq_response(config: &Config, response: &KafkaResponsePayload) -> bool {
+    match response {
+        KafkaResponsePayload::UndeterminedConsent => {
+            config.undetermined_consent_policy == UndeterminedConsentPolicy::Dlq
         }
-    } else {
-        error!("Cannot parse message content!")
+        KafkaResponsePayload::InvalidRequestId(_)
+        | KafkaResponsePayload::InvalidRequest(_)
+        | KafkaResponsePayload::UnsupportedVersion(_)
+        | KafkaResponsePayload::DeleteFailed(_)
+        | KafkaResponsePayload::SchemaRegistryUnavailable => true,
+        _ => false,
     }
 }
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() -> Result<(), Box<dyn Error>> {
-    #[cfg(debug_assertions)]
-    {
-        use log::LevelFilter::Debug;
-        SimpleLogger::new().with_level(Debug).init().unwrap();
+/// Patient ids are only included in responses as SHA-256 hash
+fn hashed_patient_id(patient_id: &str) -> String {
+    format!("{:x}", Sha256::digest(patient_id.as_bytes()))
+}
+
+/// Span covering parsing, sending to bwHC-Backend and producing the response of a record.
+/// Request id and outcome are recorded while handling the record.
+fn record_span(partition: i32, offset: i64) -> Span {
+    tracing::info_span!(
+        "record",
+        partition,
+        offset,
+        request_id = Empty,
+        outcome = Empty
+    )
+}
+
+/// Outcome of handling a request with the request id and response to be sent.
+/// The caller decides on forwarding to the dead letter queue or retry topic and commits the offset.
+enum ProcessOutcome {
+    /// MTB file sent and accepted by bwHC-Backend
+    Posted(String, KafkaResponsePayload),
+    /// Delete or consent revocation sent and accepted by bwHC-Backend
+    Deleted(String, KafkaResponsePayload),
+    /// Request not sent, e.g. duplicate or ignored. None if dropped without response.
+    Skipped(Option<(String, KafkaResponsePayload)>),
+    /// Request invalid, refused or not accepted by bwHC-Backend
+    Failed(String, KafkaResponsePayload),
+}
+
+impl ProcessOutcome {
+    /// Request id and response to be sent, none if dropped without response
+    fn response(&self) -> Option<(&str, &KafkaResponsePayload)> {
+        match self {
+            ProcessOutcome::Posted(request_id, response)
+            | ProcessOutcome::Deleted(request_id, response)
+            | ProcessOutcome::Failed(request_id, response)
+            | ProcessOutcome::Skipped(Some((request_id, response))) => Some((request_id, response)),
+            ProcessOutcome::Skipped(None) => None,
+        }
     }
 
-    #[cfg(not(debug_assertions))]
-    {
-        use log::LevelFilter::Info;
-        SimpleLogger::new().with_level(Info).init().unwrap();
+    fn into_response(self) -> Option<(String, KafkaResponsePayload)> {
+        match self {
+            ProcessOutcome::Posted(request_id, response)
+            | ProcessOutcome::Deleted(request_id, response)
+            | ProcessOutcome::Failed(request_id, response)
+            | ProcessOutcome::Skipped(Some((request_id, response))) => Some((request_id, response)),
+            ProcessOutcome::Skipped(None) => None,
+        }
+    }
+}
+
+/// Records outcome in stats and span and returns it together with the response
+fn processed(
+    outcome: Outcome,
+    request_id: String,
+    response: KafkaResponsePayload,
+) -> ProcessOutcome {
+    STATS.record(outcome);
+    match outcome {
+        Outcome::Posted => ProcessOutcome::Posted(request_id, response),
+        Outcome::Deleted => ProcessOutcome::Deleted(request_id, response),
+        Outcome::Ignored => ProcessOutcome::Skipped(Some((request_id, response))),
+        Outcome::Failed | Outcome::ParseError => ProcessOutcome::Failed(request_id, response),
     }
+}
 
-    let context = CustomContext;
+/// Handles a request without sending the response. Errors of the request or the bwHC-Backend are
+/// returned as failed outcome with response, leaving forwarding to the dead letter queue or retry
+/// topic to the caller. Infrastructure failures, e.g. a file sink that cannot be written, are
+/// returned as error, the record has to be processed again and its offset must not be committed.
+async fn handle_message(
+    config: &Config,
+    sink: &Sink,
+    recent: &RecentRequestIds,
+    transforms: &Transforms,
+    payload: &str,
+    tenant: Option<&str>,
+    timestamp: Timestamp,
+) -> Result<ProcessOutcome, AppError> {
+    STATS.record_consumed();
+
+    let request = match Request::parse(
+        payload,
+        config.max_decoded_content_bytes,
+        &config.consent_date_field,
+        &config.consent_active_statuses,
+        &config.consent_pointer,
+    ) {
+        Ok(request) => request,
+        Err(e) => {
+            error!("Cannot parse message content: {}", e);
+            if let Some(request_id) = e.request_id() {
+                Span::current().record("request_id", request_id.as_str());
+            }
+            return Ok(processed(
+                Outcome::ParseError,
+                e.request_id().unwrap_or_default(),
+                KafkaResponsePayload::InvalidRequest(e.to_string()),
+            ));
+        }
+    };
 
-    match env::var("APP_REST_URI") {
-        Ok(_) => { /* OK */ }
-        Err(_) => panic!("Missing configuration 'APP_REST_URI'"),
+    if !request.has_valid_request_id(
+        config.request_id_max_length,
+        config.request_id_format,
+        config.request_id_pattern.as_ref(),
+    ) {
+        let request_id = request.sanitized_request_id();
+        error!("Invalid request id '{}'!", request_id);
+        Span::current().record("request_id", request_id.as_str());
+        return Ok(processed(
+            Outcome::ParseError,
+            request_id.clone(),
+            KafkaResponsePayload::InvalidRequestId(request_id),
+        ));
     }
+    Span::current().record("request_id", request.request_id().as_str());
 
-    let boostrap_servers = env::var("KAFKA_BOOTSTRAP_SERVERS").unwrap_or("kafka:9092".into());
-    let src_topic = env::var("APP_KAFKA_TOPIC").unwrap_or("etl-processor".into());
-    let dst_topic =
-        env::var("APP_KAFKA_RESPONSE_TOPIC").unwrap_or(format!("{}_response", src_topic));
-    let group_id = env::var("APP_KAFKA_GROUP_ID").unwrap_or(format!("{}_group", src_topic));
+    if !request.has_allowed_consent_issuer(&config.allowed_consent_issuers) {
+        error!("Consent issuer not allowed!");
+        return Ok(processed(
+            Outcome::ParseError,
+            request.request_id(),
+            KafkaResponsePayload::DisallowedConsentIssuer,
+        ));
+    }
 
-    let consumer: LoggingConsumer = ClientConfig::new()
-        .set("group.id", group_id)
-        .set("bootstrap.servers", boostrap_servers.as_str())
-        .set("auto.offset.reset", "earliest")
-        .create_with_context(context)
-        .expect("Kafka consumer created");
+    if let Some(first_seen) = recent.first_seen(&request.request_id()) {
+        info!("Skipping duplicate request");
+        return Ok(processed(
+            Outcome::Ignored,
+            request.request_id(),
+            KafkaResponsePayload::Skipped("duplicate request_id".into(), first_seen),
+        ));
+    }
 
-    consumer
-        .subscribe([src_topic.as_str()].as_ref())
-        .map_err(|e| ConnectionError(e.to_string()))?;
+    let result = match request.version() {
+        1 => {
+            let at = consent_validity_time(config, timestamp);
+            handle_request_v1(config, sink, transforms, request, tenant, at).await?
+        }
+        version => {
+            error!("Unsupported request version {}!", version);
+            processed(
+                Outcome::ParseError,
+                request.request_id(),
+                KafkaResponsePayload::UnsupportedVersion(version),
+            )
+        }
+    };
+    // Only requests accepted by bwHC-Backend are skipped if sent again
+    if let Some((request_id, KafkaResponsePayload::SuccessfulConnection(response, _))) =
+        result.response()
+    {
+        if response.status_code < 300 {
+            recent.insert(request_id);
+        }
+    }
+    Ok(result)
+}
 
-    let producer: &FutureProducer = &ClientConfig::new()
-        .set("bootstrap.servers", boostrap_servers.as_str())
-        .set("message.timeout.ms", "5000")
-        .create()
-        .expect("Producer creation error");
+async fn handle_request_v1(
+    config: &Config,
+    sink: &Sink,
+    transforms: &Transforms,
+    request: Request<'_>,
+    tenant: Option<&str>,
+    at: SystemTime,
+) -> Result<ProcessOutcome, AppError> {
+    let tenant = request.tenant().or(tenant.map(|tenant| tenant.to_string()));
 
-    info!("Application started");
+    let entered_in_error = match config.entered_in_error_policy {
+        EnteredInErrorPolicy::Delete => ConsentDecision::Delete,
+        EnteredInErrorPolicy::Ignore => ConsentDecision::Ignore,
+    };
 
-    loop {
-        match consumer.recv().await {
-            Ok(msg) => match msg.payload_view::<str>() {
-                Some(Ok(s)) => match msg.key_view::<str>() {
-                    Some(Ok(key)) => handle_message(producer, dst_topic.as_str(), key, s).await,
-                    _ => error!("Unable to use key!"),
-                },
-                _ => error!("Unable to use payload!"),
-            },
-            _ => error!("Unable to consume message"),
+    // Multiple patients are deleted only if explicitly requested without consent or if consent is rejected,
+    // never for MTB files or active consents
+    let bulk_delete = matches!(
+        (
+            request.request_type(),
+            request.consent_decision(entered_in_error, &config.broad_consent_provision),
+        ),
+        (Some(RequestType::Delete), Ok(None)) | (None, Ok(Some(ConsentDecision::Delete)))
+    );
+    match request.patient_ids() {
+        Ok(Some(patient_ids)) if bulk_delete => {
+            return handle_multi_patient_delete(
+                config,
+                sink,
+                transforms.pseudonymizer.as_ref(),
+                request,
+                patient_ids,
+                tenant,
+            )
+            .await;
+        }
+        Err(e) if bulk_delete => {
+            warn!("Cannot delete MTB files: {}", e);
+            return Ok(processed(
+                Outcome::Failed,
+                request.request_id(),
+                KafkaResponsePayload::InvalidPatientId,
+            ));
+        }
+        _ => {}
+    }
+    // Only a known consent status may result in sending or deleting an MTB file,
+    // unless the request explicitly is a delete
+    let decision = match request.consent_decision(entered_in_error, &config.broad_consent_provision)
+    {
+        _ if request.request_type() == Some(RequestType::Delete) => ConsentDecision::Delete,
+        Ok(Some(decision)) => decision,
+        Ok(None) => match config.missing_consent_policy {
+            MissingConsentPolicy::Reject => {
+                error!("Content contains no consent");
+                return Ok(processed(
+                    Outcome::ParseError,
+                    request.request_id(),
+                    KafkaResponsePayload::UndeterminedConsent,
+                ));
+            }
+            MissingConsentPolicy::Upload => ConsentDecision::Upload,
+            MissingConsentPolicy::Delete => ConsentDecision::Delete,
+        },
+        Err(e) => {
+            error!("Cannot determine consent: {}", e);
+            return match config.undetermined_consent_policy {
+                UndeterminedConsentPolicy::Drop => {
+                    STATS.record(Outcome::ParseError);
+                    Ok(ProcessOutcome::Skipped(None))
+                }
+                UndeterminedConsentPolicy::Respond | UndeterminedConsentPolicy::Dlq => {
+                    Ok(processed(
+                        Outcome::ParseError,
+                        request.request_id(),
+                        KafkaResponsePayload::UndeterminedConsent,
+                    ))
+                }
+            };
+        }
+    };
+    // Consent permits sending MTB files within its validity period only
+    let decision = match (decision, request.consent_validity(at)) {
+        (ConsentDecision::Upload, ConsentValidity::Expired) => {
+            match config.expired_consent_policy {
+                ExpiredConsentPolicy::Delete => {
+                    info!("Consent expired - deleting MTB file");
+                    ConsentDecision::Delete
+                }
+                ExpiredConsentPolicy::Ignore => {
+                    info!("Ignoring request with expired consent");
+                    return Ok(processed(
+                        Outcome::Ignored,
+                        request.request_id(),
+                        KafkaResponsePayload::InvalidConsentPeriod(ConsentValidity::Expired),
+                    ));
+                }
+            }
         }
+        (ConsentDecision::Upload, ConsentValidity::NotYetValid) => {
+            info!("Ignoring request with consent not yet valid");
+            return Ok(processed(
+                Outcome::Ignored,
+                request.request_id(),
+                KafkaResponsePayload::InvalidConsentPeriod(ConsentValidity::NotYetValid),
+            ));
+        }
+        (decision, _) => decision,
+    };
+    // Consent acts as safety net for requests explicitly containing an MTB file
+    if request.request_type() == Some(RequestType::MtbFile) && decision == ConsentDecision::Delete {
+        error!("Consent does not permit sending MTB file");
+        return Ok(processed(
+            Outcome::Failed,
+            request.request_id(),
+            KafkaResponsePayload::ConsentRefused(request.consent_status().map(String::from)),
+        ));
+    }
+    let outcome = match decision {
+        ConsentDecision::Upload => Outcome::Posted,
+        ConsentDecision::Delete => Outcome::Deleted,
+        ConsentDecision::Ignore => {
+            let status = request.consent_status().unwrap_or_default();
+            info!("Ignoring request with consent status '{}'", status);
+            return Ok(processed(
+                Outcome::Ignored,
+                request.request_id(),
+                KafkaResponsePayload::Ignored(status.to_string()),
+            ));
+        }
+    };
+
+    // Patient ids must not be ambiguous to not send or delete MTB files of other patients
+    if let PatientMatch::Mismatch { consent, patient } = request.patient_match() {
+        warn!("Patient ids of consent and patient differ");
+        if config.strict_patient_id || (config.strict_patient_match && outcome == Outcome::Posted) {
+            return Ok(processed(
+                Outcome::Failed,
+                request.request_id(),
+                KafkaResponsePayload::PatientIdMismatch(
+                    hashed_patient_id(&consent),
+                    hashed_patient_id(&patient),
+                ),
+            ));
+        }
+    }
+    let patient_id = request.patient_id(config.patient_id_source);
+
+    // MTB files without patient id could not be deleted later
+    if outcome == Outcome::Posted && patient_id.is_none() {
+        warn!("Cannot send MTB file without patient id");
+        return Ok(processed(
+            Outcome::Failed,
+            request.request_id(),
+            KafkaResponsePayload::InvalidPatientId,
+        ));
+    }
+
+    // Content is borrowed from consumed message unless transformed.
+    // Content is parsed once for sanitizing, stripping fields, pseudonymization and schema validation.
+    let pseudonymizer = transforms.pseudonymizer.as_ref();
+    let transform =
+        config.sanitize_content || !config.strip_fields.is_empty() || pseudonymizer.is_some();
+    let mut stripped_fields = 0;
+    let content = if outcome == Outcome::Deleted {
+        None
+    } else if transform || transforms.schema.is_some() {
+        let mut value = match request.content_value() {
+            Ok(value) => value,
+            Err(e) => {
+                error!("Cannot parse content: {}", e);
+                return Ok(processed(
+                    Outcome::Failed,
+                    request.request_id(),
+                    KafkaResponsePayload::InvalidRequest("Cannot parse content".into()),
+                ));
+            }
+        };
+        if config.sanitize_content {
+            sanitize_content(
+                &mut value,
+                &config.sanitize_content_allow,
+                &config.sanitize_content_deny,
+            );
+        }
+
+        // Fields not to be transmitted are removed from the content only, consent was evaluated before
+        stripped_fields = config
+            .strip_fields
+            .iter()
+            .map(|pointer| json_pointer::remove_matching(&mut value, pointer))
+            .sum();
+        if !config.strip_fields.is_empty() {
+            debug!("Removed {} field(s) from content", stripped_fields);
+        }
+
+        // Patient ids leave the clinical network as pseudonyms only, deletes use the same pseudonyms
+        if let Some(pseudonymizer) = pseudonymizer {
+            pseudonymizer.pseudonymize_content(&mut value);
+        }
+
+        // Content violating the schema is not sent to save a round trip to bwHC-Backend
+        if let Some(schema) = &transforms.schema {
+            let violations = schema.validate(&value);
+            if !violations.is_empty() {
+                warn!(
+                    "MTB file violates schema: {} violation(s)",
+                    violations.len()
+                );
+                return Ok(processed(
+                    Outcome::Failed,
+                    request.request_id(),
+                    KafkaResponsePayload::SchemaViolation(violations),
+                ));
+            }
+        }
+
+        if transform {
+            Some(Cow::Owned(value.to_string()))
+        } else {
+            Some(Cow::Borrowed(request.content_str()))
+        }
+    } else {
+        Some(Cow::Borrowed(request.content_str()))
+    };
+    let patient_id = match pseudonymizer {
+        Some(pseudonymizer) => patient_id.map(|patient_id| pseudonymizer.pseudonym(&patient_id)),
+        None => patient_id,
+    };
+
+    let response = if let Some(content) = &content {
+        sink.send_mtb_file(
+            request.request_id().as_str(),
+            patient_id.as_deref(),
+            content.as_ref(),
+            tenant.as_deref(),
+        )
+        .await
+    } else if sink.delete_mode() == DeleteMode::PostConsent {
+        // Consent is never sent with the original patient id if pseudonymization fails
+        let consent = match (request.consent_string(), pseudonymizer) {
+            (Some(consent), Some(pseudonymizer)) => {
+                match pseudonymizer.pseudonymize_consent(&consent) {
+                    Ok(consent) => Some(consent),
+                    Err(_) => {
+                        return Ok(processed(
+                            Outcome::Failed,
+                            request.request_id(),
+                            KafkaResponsePayload::InvalidRequest(
+                                "Cannot pseudonymize consent".into(),
+                            ),
+                        ));
+                    }
+                }
+            }
+            (consent, _) => consent,
+        };
+        match consent {
+            Some(consent) => {
+                sink.send_consent(
+                    request.request_id().as_str(),
+                    consent.as_str(),
+                    tenant.as_deref(),
+                )
+                .await
+            }
+            None => {
+                warn!("Cannot revoke consent without consent");
+                return Ok(processed(
+                    Outcome::Failed,
+                    request.request_id(),
+                    KafkaResponsePayload::InvalidRequest("Content contains no consent".into()),
+                ));
+            }
+        }
+    } else {
+        match patient_id {
+            Some(patient_id) => {
+                sink.send_delete(
+                    request.request_id().as_str(),
+                    patient_id.as_str(),
+                    request.site_id().as_deref(),
+                    tenant.as_deref(),
+                )
+                .await
+            }
+            None => {
+                warn!("Cannot delete MTB file without patient id");
+                return Ok(processed(
+                    Outcome::Failed,
+                    request.request_id(),
+                    KafkaResponsePayload::InvalidPatientId,
+                ));
+            }
+        }
+    };
+
+    // Deletes are idempotent and will be retried later using retry topic
+    let retry_later = outcome == Outcome::Deleted && config.kafka_delete_retry_topic.is_some();
+
+    match response {
+        Ok(response) if retry_later && response.status_code >= 500 => {
+            warn!(
+                "Delete failed with HTTP {} - retry later",
+                response.status_code
+            );
+            Ok(processed(
+                Outcome::Failed,
+                request.request_id(),
+                KafkaResponsePayload::DeletePending,
+            ))
+        }
+        Err(e) if retry_later && e.is_http_error() => {
+            warn!("Delete failed: {} - retry later", e);
+            Ok(processed(
+                Outcome::Failed,
+                request.request_id(),
+                KafkaResponsePayload::DeletePending,
+            ))
+        }
+        Ok(mut response) => {
+            if config.rest_non_json_as_failure && response.status_code < 300 && !response.is_json()
+            {
+                warn!("Non-JSON response from backend - using status code 502");
+                response.override_status_code(502);
+            }
+            if let Some(threshold) = &config.severity_threshold {
+                let has_issues = Issues::from_str(&response.status_body)
+                    .is_ok_and(|issues| issues.has_severity_at_least(threshold));
+                if response.status_code < 300 && has_issues {
+                    warn!(
+                        "Response contains issues with severity {} or higher - using status code 422",
+                        threshold.as_str()
+                    );
+                    response.override_status_code(422);
+                }
+            }
+            let outcome = if response.status_code < 400 {
+                outcome
+            } else {
+                Outcome::Failed
+            };
+            response.truncate_body(config.max_response_body_bytes as usize);
+            let mut payload = KafkaResponsePayload::from_response(response);
+            if let (Some(content), true) = (&content, config.echo_content) {
+                payload = payload.with_content(content, config.echo_content_full);
+            }
+            if content.is_some() && config.strip_fields_report {
+                payload = payload.with_stripped_fields(stripped_fields);
+            }
+            Ok(processed(outcome, request.request_id(), payload))
+        }
+        Err(ValidationError(e)) if outcome == Outcome::Posted => {
+            warn!("Cannot send MTB file: {}", e);
+            Ok(processed(
+                Outcome::Failed,
+                request.request_id(),
+                KafkaResponsePayload::InvalidPatientId,
+            ))
+        }
+        Err(PollingTimeout(location)) => Ok(processed(
+            Outcome::Failed,
+            request.request_id(),
+            KafkaResponsePayload::PollingTimeout(location),
+        )),
+        Err(HttpTimeout(e)) => {
+            warn!("Request timed out: {}", e);
+            Ok(processed(
+                Outcome::Failed,
+                request.request_id(),
+                KafkaResponsePayload::Timeout,
+            ))
+        }
+        Err(HttpConnectError(e)) => {
+            warn!("Cannot connect: {}", e);
+            Ok(processed(
+                Outcome::Failed,
+                request.request_id(),
+                KafkaResponsePayload::ConnectionRefused,
+            ))
+        }
+        Err(e @ IoError(_)) => Err(e),
+        Err(e) => {
+            warn!("No connection: {}", e);
+            Ok(processed(
+                Outcome::Failed,
+                request.request_id(),
+                KafkaResponsePayload::NoConnection(
+                    config.no_connection_message.clone(),
+                    config.include_error_detail.then(|| e.to_string()),
+                ),
+            ))
+        }
+    }
+}
+
+/// Sends a delete request for each patient of a bulk delete request and aggregates the results
+async fn handle_multi_patient_delete(
+    config: &Config,
+    sink: &Sink,
+    pseudonymizer: Option<&Pseudonymizer>,
+    request: Request<'_>,
+    patient_ids: Vec<String>,
+    tenant: Option<String>,
+) -> Result<ProcessOutcome, AppError> {
+    if patient_ids.is_empty() {
+        warn!("Cannot delete MTB files without patient ids");
+        return Ok(processed(
+            Outcome::Failed,
+            request.request_id(),
+            KafkaResponsePayload::InvalidPatientId,
+        ));
+    }
+
+    let mut results = vec![];
+    for patient_id in patient_ids {
+        let sent_patient_id = match pseudonymizer {
+            Some(pseudonymizer) => pseudonymizer.pseudonym(&patient_id),
+            None => patient_id.clone(),
+        };
+        let status_code = match sink
+            .send_delete(
+                request.request_id().as_str(),
+                sent_patient_id.as_str(),
+                None,
+                tenant.as_deref(),
+            )
+            .await
+        {
+            Ok(response) => response.status_code,
+            Err(ValidationError(e)) => {
+                warn!("Cannot delete MTB file: {}", e);
+                400
+            }
+            Err(HttpTimeout(e)) => {
+                warn!("Delete timed out: {}", e);
+                ErrorCode::Timeout.status_code()
+            }
+            Err(HttpConnectError(e)) => {
+                warn!("Delete failed: {}", e);
+                ErrorCode::ConnectionRefused.status_code()
+            }
+            Err(e @ IoError(_)) => return Err(e),
+            Err(e) => {
+                warn!("Delete failed: {}", e);
+                ErrorCode::NoConnection.status_code()
+            }
+        };
+        results.push((hashed_patient_id(&patient_id), status_code));
+    }
+
+    let failed = results.iter().any(|(_, status_code)| *status_code >= 400);
+    let outcome = if failed {
+        Outcome::Failed
+    } else {
+        Outcome::Deleted
+    };
+
+    // Deletes are idempotent, therefore all deletes will be retried later using retry topic
+    if config.kafka_delete_retry_topic.is_some()
+        && results.iter().any(|(_, status_code)| *status_code >= 500)
+    {
+        warn!("Delete of multiple patients failed - retry later");
+        return Ok(processed(
+            outcome,
+            request.request_id(),
+            KafkaResponsePayload::DeletePending,
+        ));
+    }
+
+    Ok(processed(
+        outcome,
+        request.request_id(),
+        KafkaResponsePayload::MultiPatientDelete(results),
+    ))
+}
+
+/// Handles a record without value, e.g. on a compacted topic, as delete of the patient given by
+/// the record key. There is no request id, therefore the response contains an empty request id.
+async fn handle_tombstone(
+    config: &Config,
+    sink: &Sink,
+    pseudonymizer: Option<&Pseudonymizer>,
+    key: &str,
+    tenant: Option<&str>,
+) -> Result<ProcessOutcome, AppError> {
+    STATS.record_consumed();
+
+    if !config.null_value_deletes {
+        warn!("Skipping record without value");
+        return Ok(ProcessOutcome::Skipped(None));
+    }
+
+    if key.trim().is_empty() {
+        warn!("Cannot delete MTB file of record without value and patient id as key");
+        return Ok(processed(
+            Outcome::Failed,
+            String::new(),
+            KafkaResponsePayload::InvalidPatientId,
+        ));
+    }
+
+    let patient_id = match pseudonymizer {
+        Some(pseudonymizer) => pseudonymizer.pseudonym(key),
+        None => key.to_string(),
+    };
+    let payload = match sink.send_delete("", &patient_id, None, tenant).await {
+        Ok(mut response) => {
+            response.truncate_body(config.max_response_body_bytes as usize);
+            let outcome = if response.status_code < 400 {
+                Outcome::Deleted
+            } else {
+                Outcome::Failed
+            };
+            return Ok(processed(
+                outcome,
+                String::new(),
+                KafkaResponsePayload::from_response(response),
+            ));
+        }
+        Err(HttpTimeout(e)) => {
+            warn!("Delete timed out: {}", e);
+            KafkaResponsePayload::Timeout
+        }
+        Err(HttpConnectError(e)) => {
+            warn!("Delete failed: {}", e);
+            KafkaResponsePayload::ConnectionRefused
+        }
+        Err(e @ IoError(_)) => return Err(e),
+        Err(e) => {
+            warn!("Delete failed: {}", e);
+            KafkaResponsePayload::NoConnection(
+                config.no_connection_message.clone(),
+                config.include_error_detail.then(|| e.to_string()),
+            )
+        }
+    };
+    Ok(processed(Outcome::Failed, String::new(), payload))
+}
+
+/// Tenant given by configured header of consumed record
+fn tenant_of<'a>(config: &Config, headers: Option<&'a BorrowedHeaders>) -> Option<&'a str> {
+    headers.and_then(|headers| {
+        headers
+            .iter()
+            .find(|header| header.key == config.tenant_header)
+            .and_then(|header| header.value)
+            .and_then(|value| std::str::from_utf8(value).ok())
+    })
+}
+
+fn check_kafka_connection(config: &Config) -> Result<(), AppError> {
+    let consumer: BaseConsumer = ClientConfig::new()
+        .set("bootstrap.servers", config.kafka_bootstrap_servers.as_str())
+        .create()
+        .map_err(|e| ConnectionError(e.to_string()))?;
+
+    let metadata = consumer
+        .fetch_metadata(None, Duration::from_secs(5))
+        .map_err(|e| ConnectionError(e.to_string()))?;
+
+    info!(
+        "Kafka connection available: {} broker(s)",
+        metadata.brokers().len()
+    );
+    Ok(())
+}
+
+async fn check_connection(config: &Config) -> Result<(), AppError> {
+    check_kafka_connection(config)?;
+
+    match Sink::new(config)? {
+        Sink::Http(client) => {
+            let status_code = client.check_connection().await?;
+            info!("bwHC-Backend connection available: HTTP {}", status_code);
+        }
+        Sink::File(_) | Sink::Null(_) => {
+            info!("Not using HTTP sink - no bwHC-Backend connection required")
+        }
+    }
+
+    Ok(())
+}
+
+/// Exit code of `selftest` if Kafka is not available
+const SELFTEST_KAFKA_UNAVAILABLE: i32 = 2;
+/// Exit code of `selftest` if bwHC-Backend is not reachable or responds with server error
+const SELFTEST_BACKEND_UNAVAILABLE: i32 = 3;
+
+/// Checks Kafka and bwHC-Backend health without consuming requests and returns exit code
+async fn selftest(config: &Config) -> i32 {
+    if let Err(e) = check_kafka_connection(config) {
+        error!("Kafka not available: {}", e);
+        return SELFTEST_KAFKA_UNAVAILABLE;
+    }
+    selftest_backend(config).await
+}
+
+async fn selftest_backend(config: &Config) -> i32 {
+    let client = match Sink::new(config) {
+        Ok(Sink::Http(client)) => client,
+        Ok(Sink::File(_)) | Ok(Sink::Null(_)) => return 0,
+        Err(e) => {
+            error!("Invalid configuration: {}", e);
+            return 1;
+        }
+    };
+
+    match client
+        .check_health(config.rest_healthcheck_path.as_str())
+        .await
+    {
+        Ok(status_code) if status_code < 500 => {
+            info!("bwHC-Backend available: HTTP {}", status_code);
+            0
+        }
+        Ok(status_code) => {
+            error!("bwHC-Backend not healthy: HTTP {}", status_code);
+            SELFTEST_BACKEND_UNAVAILABLE
+        }
+        Err(e) => {
+            error!("bwHC-Backend not reachable: {}", e);
+            SELFTEST_BACKEND_UNAVAILABLE
+        }
+    }
+}
+
+async fn create_producer(config: &Config) -> Result<FutureProducer, AppError> {
+    create_with_retry(config, "producer", || {
+        ClientConfig::new()
+            .set("client.id", config.kafka_client_id())
+            .set("bootstrap.servers", config.kafka_bootstrap_servers.as_str())
+            .set("message.timeout.ms", "5000")
+            .create()
+    })
+    .await
+}
+
+/// Result of replaying a request of the dead letter queue
+enum ReplayResult {
+    /// Request has been processed, containing the response to be sent
+    Reprocessed(Option<(String, KafkaResponsePayload)>),
+    /// Request still cannot be processed, containing the response to be sent
+    Failed(Option<(String, KafkaResponsePayload)>),
+    /// Request has not been processed due to dry run
+    Skipped,
+}
+
+async fn replay_record(
+    config: &Config,
+    sink: &Sink,
+    transforms: &Transforms,
+    payload: &str,
+    tenant: Option<&str>,
+    dry_run: bool,
+) -> Result<ReplayResult, AppError> {
+    if dry_run {
+        match Request::parse(
+            payload,
+            config.max_decoded_content_bytes,
+            &config.consent_date_field,
+            &config.consent_active_statuses,
+            &config.consent_pointer,
+        ) {
+            Ok(request) => info!(
+                "Dry run - request '{}' would be replayed",
+                request.sanitized_request_id()
+            ),
+            Err(e) => info!("Dry run - request still cannot be parsed: {}", e),
+        }
+        return Ok(ReplayResult::Skipped);
+    }
+
+    // Replayed requests are not skipped as duplicates
+    let result = handle_message(
+        config,
+        sink,
+        &RecentRequestIds::new(None, None),
+        transforms,
+        payload,
+        tenant,
+        // Replayed requests are checked against the current time
+        Timestamp::NotAvailable,
+    )
+    .await?;
+    let failed = match &result {
+        ProcessOutcome::Failed(_, response) => {
+            is_dlq_response(config, response)
+                || match response {
+                    KafkaResponsePayload::SuccessfulConnection(response, _) => {
+                        response.status_code >= 500
+                    }
+                    KafkaResponsePayload::NoConnection(_, _)
+                    | KafkaResponsePayload::Timeout
+                    | KafkaResponsePayload::ConnectionRefused
+                    | KafkaResponsePayload::DeletePending
+                    | KafkaResponsePayload::PollingTimeout(_) => true,
+                    _ => false,
+                }
+        }
+        _ => false,
+    };
+    if failed {
+        Ok(ReplayResult::Failed(result.into_response()))
+    } else {
+        Ok(ReplayResult::Reprocessed(result.into_response()))
+    }
+}
+
+/// Offsets to start replaying each partition of the dead letter queue from and offsets to stop at.
+/// Requests sent to the dead letter queue again during the replay are not replayed again.
+fn replay_partitions(
+    consumer: &LoggingConsumer,
+    topic: &str,
+) -> Result<HashMap<i32, (i64, i64)>, AppError> {
+    let timeout = Duration::from_secs(5);
+    let metadata = consumer
+        .fetch_metadata(Some(topic), timeout)
+        .map_err(|e| ConnectionError(e.to_string()))?;
+    let mut committed = TopicPartitionList::new();
+    for partition in metadata
+        .topics()
+        .iter()
+        .flat_map(|topic| topic.partitions())
+    {
+        committed.add_partition(topic, partition.id());
+    }
+    let committed = consumer
+        .committed_offsets(committed, timeout)
+        .map_err(|e| ConnectionError(e.to_string()))?;
+
+    let mut partitions = HashMap::new();
+    for element in committed.elements() {
+        let (low, high) = consumer
+            .fetch_watermarks(topic, element.partition(), timeout)
+            .map_err(|e| ConnectionError(e.to_string()))?;
+        let start = match element.offset() {
+            Offset::Offset(offset) => offset.max(low),
+            _ => low,
+        };
+        if start < high {
+            partitions.insert(element.partition(), (start, high));
+        }
+    }
+    Ok(partitions)
+}
+
+/// Mode used to commit offsets manually
+fn commit_mode(config: &Config) -> CommitMode {
+    match config.kafka_commit_mode {
+        KafkaCommitMode::Async => CommitMode::Async,
+        KafkaCommitMode::Sync => CommitMode::Sync,
+    }
+}
+
+/// Reprocesses requests of the dead letter queue. Offsets of processed requests are committed
+/// using a separate consumer group, requests that still fail are sent to the dead letter queue again.
+async fn replay_dlq(config: &Config, dry_run: bool) -> Result<(), AppError> {
+    let dlq_topic = config
+        .kafka_dlq_topic
+        .as_deref()
+        .ok_or(MissingConfig("APP_KAFKA_DLQ_TOPIC".into()))?;
+    let sink = Sink::new(config)?;
+    let transforms = Transforms::load(config)?;
+    let registry = SchemaRegistry::new(config)?;
+    let response_topics = ResponseTopics::new(config);
+    let spool = config.spool_dir.clone().map(Spool::new).transpose()?;
+
+    let consumer: LoggingConsumer = create_with_retry(config, "consumer", || {
+        consumer_config(config)
+            .set("group.id", format!("{}_replay", config.kafka_group_id()))
+            .set("enable.auto.commit", "false")
+            .create_with_context(CustomContext)
+    })
+    .await?;
+    let producer = create_producer(config).await?;
+
+    let mut partitions = replay_partitions(&consumer, dlq_topic)?;
+    if partitions.is_empty() {
+        info!("No requests to replay in '{}'", dlq_topic);
+        return Ok(());
+    }
+    let mut assignment = TopicPartitionList::new();
+    for (partition, (start, _)) in &partitions {
+        assignment
+            .add_partition_offset(dlq_topic, *partition, Offset::Offset(*start))
+            .map_err(|e| ConnectionError(e.to_string()))?;
+    }
+    consumer
+        .assign(&assignment)
+        .map_err(|e| ConnectionError(e.to_string()))?;
+
+    let (mut reprocessed, mut failed, mut skipped) = (0, 0, 0);
+    while !partitions.is_empty() {
+        let msg = consumer
+            .recv()
+            .await
+            .map_err(|e| ConnectionError(e.to_string()))?;
+        match (msg.payload(), msg.key_view::<str>()) {
+            (Some(payload), Some(Ok(key))) => {
+                let tenant = tenant_of(config, msg.headers());
+                let mut patient_id = None;
+                let result = match decode_payload(
+                    config.payload_format,
+                    registry.as_ref(),
+                    payload,
+                    &config.consent_pointer,
+                )
+                .await
+                {
+                    Ok(json) => {
+                        patient_id = key_patient_id(config, &json);
+                        // Offset is not committed if the request cannot be processed due to infrastructure failure
+                        replay_record(config, &sink, &transforms, &json, tenant, dry_run).await?
+                    }
+                    Err(_) if dry_run => {
+                        info!("Dry run - request still cannot be decoded");
+                        ReplayResult::Skipped
+                    }
+                    Err(response) => {
+                        ReplayResult::Failed(response.map(|response| (String::new(), response)))
+                    }
+                };
+                match result {
+                    ReplayResult::Reprocessed(response) => {
+                        reprocessed += 1;
+                        if let Some((request_id, response)) = response {
+                            let values = KeyValues {
+                                request_id: &request_id,
+                                patient_id: patient_id.as_deref(),
+                                key,
+                                partition: msg.partition(),
+                                offset: msg.offset(),
+                            };
+                            send_kafka_response(
+                                &producer,
+                                spool.as_ref(),
+                                &response_topics,
+                                &config.key_template,
+                                &values,
+                                response,
+                            )
+                            .await
+                        }
+                    }
+                    ReplayResult::Failed(response) => {
+                        failed += 1;
+                        forward_kafka_message(
+                            &producer,
+                            spool.as_ref(),
+                            dlq_topic,
+                            key,
+                            payload,
+                            msg.headers().map(BorrowedHeaders::detach),
+                        )
+                        .await;
+                        if let Some((request_id, response)) = response {
+                            let values = KeyValues {
+                                request_id: &request_id,
+                                patient_id: patient_id.as_deref(),
+                                key,
+                                partition: msg.partition(),
+                                offset: msg.offset(),
+                            };
+                            send_kafka_response(
+                                &producer,
+                                spool.as_ref(),
+                                &response_topics,
+                                &config.key_template,
+                                &values,
+                                response,
+                            )
+                            .await
+                        }
+                    }
+                    ReplayResult::Skipped => skipped += 1,
+                }
+            }
+            _ => error!("Unable to use key or payload!"),
+        }
+        if !dry_run {
+            if let Err(e) = consumer.commit_message(&msg, commit_mode(config)) {
+                error!("Unable to commit offset: {}", e);
+            }
+        }
+        if partitions
+            .get(&msg.partition())
+            .is_some_and(|(_, high)| msg.offset() + 1 >= *high)
+        {
+            partitions.remove(&msg.partition());
+        }
+    }
+
+    info!(
+        "Replayed requests of '{}' - reprocessed: {}, failed: {}, skipped: {}",
+        dlq_topic, reprocessed, failed, skipped
+    );
+    Ok(())
+}
+
+/// Creates Kafka client. Failed attempts are retried with exponential backoff
+/// up to `APP_KAFKA_CREATE_RETRIES` times.
+async fn create_with_retry<T, F>(config: &Config, name: &str, create: F) -> Result<T, AppError>
+where
+    F: Fn() -> KafkaResult<T>,
+{
+    let mut delay = Duration::from_millis(config.kafka_create_retry_delay_ms);
+    let mut attempt = 0;
+    loop {
+        match create() {
+            Ok(client) => return Ok(client),
+            Err(e) if attempt < config.kafka_create_retries => {
+                attempt += 1;
+                warn!(
+                    "Cannot create Kafka {}: {} - retry {}/{} in {}ms",
+                    name,
+                    e,
+                    attempt,
+                    config.kafka_create_retries,
+                    delay.as_millis()
+                );
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+            Err(e) => {
+                return Err(ConnectionError(format!(
+                    "Cannot create Kafka {}: {}",
+                    name, e
+                )))
+            }
+        }
+    }
+}
+
+/// Time elapsed since message was sent, zero if message has no timestamp
+fn message_age(timestamp: Timestamp) -> Duration {
+    timestamp
+        .to_millis()
+        .and_then(|millis| {
+            let sent = UNIX_EPOCH + Duration::from_millis(millis.max(0) as u64);
+            SystemTime::now().duration_since(sent).ok()
+        })
+        .unwrap_or_default()
+}
+
+/// Time to check the consent validity period against, the record timestamp if available or the current time
+fn consent_validity_time(config: &Config, timestamp: Timestamp) -> SystemTime {
+    match (config.consent_validity_time, timestamp.to_millis()) {
+        (ConsentValidityTime::Record, Some(millis)) => {
+            UNIX_EPOCH + Duration::from_millis(millis.max(0) as u64)
+        }
+        _ => SystemTime::now(),
+    }
+}
+
+/// Elements of a payload containing a JSON array of requests, None for a single request
+fn split_requests(payload: &str) -> Option<Vec<&str>> {
+    if !payload.trim_start().starts_with('[') {
+        return None;
+    }
+    serde_json::from_str::<Vec<&RawValue>>(payload)
+        .ok()
+        .map(|requests| requests.into_iter().map(RawValue::get).collect())
+}
+
+/// Checks if message is older than configured maximum age.
+/// Delete requests of the retry topic are retried regardless of their age.
+fn is_too_old(config: &Config, topic: &str, timestamp: Timestamp) -> bool {
+    Some(topic) != config.kafka_delete_retry_topic.as_deref()
+        && config
+            .max_record_age_seconds
+            .is_some_and(|max_age| message_age(timestamp) > Duration::from_secs(max_age))
+}
+
+/// Remaining delay before a delete request of the retry topic is sent again, none if due
+fn delete_retry_delay(config: &Config, timestamp: Timestamp) -> Option<Duration> {
+    Duration::from_millis(config.kafka_delete_retry_delay_ms)
+        .checked_sub(message_age(timestamp))
+        .filter(|delay| !delay.is_zero())
+}
+
+/// Pauses the partition of the retry topic and sets it back to the record until the delete request
+/// is due, other partitions and topics are consumed meanwhile. Returns false if the record is due
+/// or cannot be deferred and has to be processed now. Offsets are never stored automatically,
+/// therefore the offset of a deferred record is not committed until it has been processed.
+fn defer_delete_retry(
+    config: &Config,
+    consumer: &LoggingConsumer,
+    msg: &BorrowedMessage,
+    paused: &mut PausedPartitions,
+) -> bool {
+    if Some(msg.topic()) != config.kafka_delete_retry_topic.as_deref() {
+        return false;
+    }
+    let Some(delay) = delete_retry_delay(config, msg.timestamp()) else {
+        return false;
+    };
+    let mut partition = TopicPartitionList::new();
+    partition.add_partition(msg.topic(), msg.partition());
+    let result = consumer.pause(&partition).and_then(|_| {
+        consumer.seek(
+            msg.topic(),
+            msg.partition(),
+            Offset::Offset(msg.offset()),
+            Duration::from_secs(1),
+        )
+    });
+    match result {
+        Ok(_) => {
+            debug!(
+                "Retrying delete request of partition {} in {}ms",
+                msg.partition(),
+                delay.as_millis()
+            );
+            paused.pause(msg.partition(), tokio::time::Instant::now() + delay);
+            true
+        }
+        Err(e) => {
+            warn!("Unable to defer delete request - retrying now: {}", e);
+            let _ = consumer.resume(&partition);
+            false
+        }
+    }
+}
+
+/// Resumes partitions of the retry topic whose next delete request is due
+fn resume_delete_retries(
+    config: &Config,
+    consumer: &LoggingConsumer,
+    paused: &mut PausedPartitions,
+) {
+    let Some(retry_topic) = config.kafka_delete_retry_topic.as_deref() else {
+        return;
+    };
+    let mut partitions = TopicPartitionList::new();
+    for partition in paused.take_due(tokio::time::Instant::now()) {
+        partitions.add_partition(retry_topic, partition);
+    }
+    if let Err(e) = consumer.resume(&partitions) {
+        // Partition might have been revoked meanwhile
+        debug!("Unable to resume delete retries: {}", e);
+    }
+}
+
+/// Number of times a request has been sent to the retry topic, zero if never sent
+fn delete_retry_attempt(headers: Option<&BorrowedHeaders>) -> u32 {
+    headers
+        .and_then(|headers| {
+            headers
+                .iter()
+                .find(|header| header.key == DELETE_RETRY_ATTEMPT_HEADER)
+        })
+        .and_then(|header| header.value)
+        .and_then(|value| std::str::from_utf8(value).ok())
+        .and_then(|value| value.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+/// Headers of consumed record with attempt counter replaced by given attempt
+fn delete_retry_headers(headers: Option<&BorrowedHeaders>, attempt: u32) -> OwnedHeaders {
+    let mut retry_headers = OwnedHeaders::new();
+    for header in headers.iter().flat_map(|headers| headers.iter()) {
+        if header.key != DELETE_RETRY_ATTEMPT_HEADER {
+            retry_headers = retry_headers.insert(header);
+        }
+    }
+    retry_headers.insert(Header {
+        key: DELETE_RETRY_ATTEMPT_HEADER,
+        value: Some(attempt.to_string().as_str()),
+    })
+}
+
+/// Consumer configuration. If a commit interval is configured, offsets of processed messages
+/// are stored explicitly and committed using this interval. Fetch sizes are applied if configured.
+fn consumer_config(config: &Config) -> ClientConfig {
+    let mut client_config = ClientConfig::new();
+    client_config
+        .set("group.id", config.kafka_group_id())
+        .set("client.id", config.kafka_client_id())
+        .set("bootstrap.servers", config.kafka_bootstrap_servers.as_str())
+        .set("auto.offset.reset", "earliest")
+        // Offsets are only stored or committed after a record has been processed
+        .set("enable.auto.offset.store", "false");
+    match config.commit_interval_ms {
+        Some(interval) => client_config
+            .set("enable.auto.commit", "true")
+            .set("auto.commit.interval.ms", interval.to_string()),
+        None => client_config.set("enable.auto.commit", "false"),
+    };
+    // Fetch sizes are only set if configured to keep librdkafka defaults
+    for (key, value) in [
+        ("fetch.min.bytes", config.kafka_fetch_min_bytes),
+        ("fetch.max.bytes", config.kafka_fetch_max_bytes),
+        (
+            "max.partition.fetch.bytes",
+            config.kafka_max_partition_fetch_bytes,
+        ),
+        ("max.poll.interval.ms", config.kafka_max_poll_interval_ms),
+    ] {
+        if let Some(value) = value {
+            client_config.set(key, value.to_string());
+        }
+    }
+    client_config
+}
+
+/// Processing time of a record after which leaving the consumer group is imminent,
+/// 80% of `max.poll.interval.ms` or its librdkafka default
+fn poll_interval_warning(config: &Config) -> Duration {
+    Duration::from_millis(u64::from(config.kafka_max_poll_interval_ms.unwrap_or(300_000)) * 4 / 5)
+}
+
+/// Processes a record and calls `on_deadline` once if processing is still running after `warn_after`
+async fn with_poll_deadline<F, D>(warn_after: Duration, on_deadline: D, processing: F) -> F::Output
+where
+    F: Future,
+    D: FnOnce(Duration),
+{
+    let start = Instant::now();
+    tokio::pin!(processing);
+    tokio::select! {
+        biased;
+        output = &mut processing => return output,
+        _ = tokio::time::sleep(warn_after) => on_deadline(start.elapsed()),
+    }
+    processing.await
+}
+
+/// Warns about slow processing. Offsets of processed records waiting for the commit interval are
+/// committed early to not process them again after a rebalance. This does not keep the consumer
+/// in the group, only polling does. Without commit interval processed records are already committed.
+fn approaching_poll_deadline(config: &Config, consumer: &LoggingConsumer, elapsed: Duration) {
+    warn!(
+        "Processing record for {} ms - approaching max.poll.interval.ms",
+        elapsed.as_millis()
+    );
+    counter!("kafka_poll_interval_warnings_total").increment(1);
+    if config.commit_interval_ms.is_none() {
+        return;
+    }
+    // Offset of record still being processed is not stored yet
+    match consumer.commit_consumer_state(commit_mode(config)) {
+        Ok(_) => info!("Committed offsets of processed records"),
+        Err(e) => debug!("Unable to commit offsets: {}", e),
+    }
+}
+
+/// Schema and pseudonymizer applied to the content of requests, loaded once at startup
+#[derive(Default)]
+struct Transforms {
+    schema: Option<MtbFileSchema>,
+    pseudonymizer: Option<Pseudonymizer>,
+}
+
+impl Transforms {
+    /// Invalid schemas and salt files that cannot be read fail startup
+    fn load(config: &Config) -> Result<Self, AppError> {
+        Ok(Transforms {
+            schema: load_schema(config)?,
+            pseudonymizer: Pseudonymizer::new(config)?,
+        })
+    }
+}
+
+/// Loads JSON Schema of MTB files if configured. Invalid schemas fail startup.
+fn load_schema(config: &Config) -> Result<Option<MtbFileSchema>, AppError> {
+    config
+        .mtbfile_schema_file
+        .as_deref()
+        .map(|path| {
+            info!("Validating MTB files using schema '{}'", path.display());
+            MtbFileSchema::load(path)
+        })
+        .transpose()
+}
+
+/// Establishes connection to bwHC-Backend before first request is sent.
+/// Failures are only returned if `APP_REST_REQUIRE_REACHABLE` is set.
+async fn warm_up(config: &Config, sink: &Sink) -> Result<(), AppError> {
+    let Sink::Http(client) = sink else {
+        return Ok(());
+    };
+    if !config.rest_warmup && !config.rest_require_reachable {
+        return Ok(());
+    }
+
+    match client
+        .check_health(config.rest_healthcheck_path.as_str())
+        .await
+    {
+        Ok(status_code) => {
+            info!("bwHC-Backend warm-up completed: HTTP {}", status_code);
+            Ok(())
+        }
+        Err(e) if config.rest_require_reachable => {
+            error!("bwHC-Backend not reachable: {}", e);
+            Err(e)
+        }
+        Err(e) => {
+            warn!("bwHC-Backend warm-up failed: {}", e);
+            Ok(())
+        }
+    }
+}
+
+/// Fetches Kafka metadata of consumed topics to establish broker connections.
+/// Failures are logged only, the consumer will connect on first poll.
+fn warm_up_kafka(consumer: &LoggingConsumer, topics: &[&str], timeout: Duration) {
+    for topic in topics {
+        match consumer.fetch_metadata(Some(topic), timeout) {
+            Ok(metadata) => info!(
+                "Kafka warm-up completed for topic '{}': {} broker(s)",
+                topic,
+                metadata.brokers().len()
+            ),
+            Err(e) => warn!("Kafka warm-up failed for topic '{}': {}", topic, e),
+        }
+    }
+}
+
+/// Warms up Kafka and bwHC-Backend connections and marks application ready afterwards
+async fn warm_up_and_set_ready(
+    config: &Config,
+    sink: &Sink,
+    consumer: &LoggingConsumer,
+    topics: &[&str],
+    timeout: Duration,
+    readiness: &Readiness,
+) -> Result<(), AppError> {
+    warm_up_kafka(consumer, topics, timeout);
+    warm_up(config, sink).await?;
+    readiness.set_ready();
+    info!("Application ready");
+    Ok(())
+}
+
+async fn run(config: &Config) -> Result<(), AppError> {
+    let mut sink = Sink::new(config)?;
+    let transforms = Transforms::load(config)?;
+    let registry = SchemaRegistry::new(config)?;
+
+    let response_topics = ResponseTopics::new(config);
+    let spool = config.spool_dir.clone().map(Spool::new).transpose()?;
+
+    let consumer: LoggingConsumer = create_with_retry(config, "consumer", || {
+        consumer_config(config).create_with_context(CustomContext)
+    })
+    .await?;
+
+    let mut topics = vec![config.kafka_topic.as_str()];
+    if let Some(retry_topic) = &config.kafka_delete_retry_topic {
+        topics.push(retry_topic.as_str());
+    }
+    consumer
+        .subscribe(&topics)
+        .map_err(|e| ConnectionError(e.to_string()))?;
+
+    let producer: &FutureProducer = &create_producer(config).await?;
+
+    if let Some(spool) = &spool {
+        tokio::spawn(spool::replay_periodically(
+            spool.clone(),
+            producer.clone(),
+            Duration::from_secs(config.spool_retry_interval),
+        ));
+    }
+
+    if let (Some(interval), Sink::Http(_)) = (config.rest_healthcheck_interval, &sink) {
+        tokio::spawn(health::probe_periodically(
+            BwhcClient::new(config)?,
+            config.rest_healthcheck_path.clone(),
+            Duration::from_secs(interval),
+        ));
+    }
+
+    if config.stats_interval_seconds > 0 {
+        tokio::spawn(stats::log_periodically(Duration::from_secs(
+            config.stats_interval_seconds,
+        )));
+    }
+
+    warm_up_and_set_ready(
+        config,
+        &sink,
+        &consumer,
+        &topics,
+        Duration::from_secs(5),
+        &READINESS,
+    )
+    .await?;
+
+    let recent = RecentRequestIds::new(
+        config
+            .dedup_max_entries
+            .map(|max_entries| max_entries as usize),
+        config.dedup_ttl.map(Duration::from_secs),
+    );
+
+    let mut pending_responses = config
+        .max_pending_responses
+        .map(|max| PendingResponses::new(max as usize));
+
+    let mut hangup = signal(SignalKind::hangup())
+        .map_err(|e| IoError(format!("Cannot handle SIGHUP: {}", e)))?;
+    // Settings of configuration file at start to report changed settings on reload
+    let mut file_settings = config
+        .config_file
+        .as_deref()
+        .map(config_file_settings)
+        .transpose()
+        .map_err(|e| IoError(format!("Cannot read configuration file: {}", e)))?
+        .unwrap_or_default();
+
+    let mut circuit =
+        (config.pause_when_unhealthy && matches!(sink, Sink::Http(_))).then(BackendCircuit::new);
+    let mut circuit_check = tokio::time::interval(Duration::from_secs(
+        config.rest_healthcheck_interval.unwrap_or(1),
+    ));
+
+    let poll_warning = poll_interval_warning(config);
+
+    // Partitions of retry topic paused until their next delete request is due
+    let mut paused_retries = PausedPartitions::default();
+
+    info!("Application started");
+
+    loop {
+        let message = tokio::select! {
+            message = consumer.recv() => message,
+            _ = hangup.recv() => {
+                reload_sink(config, &mut sink, &mut file_settings);
+                continue;
+            }
+            _ = tokio::time::sleep_until(
+                paused_retries.next_resume().unwrap_or_else(tokio::time::Instant::now)
+            ), if paused_retries.next_resume().is_some() => {
+                // Partitions paused by open circuit are resumed when the circuit closes
+                if !circuit.as_ref().is_some_and(BackendCircuit::is_open) {
+                    resume_delete_retries(config, &consumer, &mut paused_retries);
+                }
+                continue;
+            }
+            _ = circuit_check.tick(), if circuit.is_some() => {
+                if let Some(circuit) = &mut circuit {
+                    circuit.update(BACKEND_HEALTH.last().as_ref(), |pause| {
+                        pause_consumer(&consumer, pause)
+                    });
+                }
+                continue;
+            }
+        };
+        let processing = async {
+            match message {
+                Ok(msg) => {
+                    // Deferred record is consumed again and its offset must not be committed
+                    if defer_delete_retry(config, &consumer, &msg, &mut paused_retries) {
+                        return Ok(());
+                    }
+                    let payload = match msg.payload() {
+                        Some(payload) => Some(
+                            decode_payload(
+                                config.payload_format,
+                                registry.as_ref(),
+                                payload,
+                                &config.consent_pointer,
+                            )
+                            .await,
+                        ),
+                        None => None,
+                    };
+                    match payload {
+                        Some(Ok(ref s)) => match msg.key_view::<str>() {
+                            Some(Ok(key)) if is_too_old(config, msg.topic(), msg.timestamp()) => {
+                                warn!("Skipping record older than maximum record age");
+                                if let Some(dlq_topic) = &config.kafka_dlq_topic {
+                                    let patient_id = key_patient_id(config, s);
+                                    let values = KeyValues {
+                                        request_id: "",
+                                        patient_id: patient_id.as_deref(),
+                                        key,
+                                        partition: msg.partition(),
+                                        offset: msg.offset(),
+                                    };
+                                    forward_kafka_message(
+                                        producer,
+                                        spool.as_ref(),
+                                        dlq_topic,
+                                        &config.key_template.render(&values),
+                                        msg.payload().unwrap_or_default(),
+                                        msg.headers().map(BorrowedHeaders::detach),
+                                    )
+                                    .await
+                                }
+                            }
+                            Some(Ok(key)) => {
+                                let requests = split_requests(s);
+                                if requests.as_ref().is_some_and(Vec::is_empty) {
+                                    warn!("Skipping record containing an empty array of requests");
+                                }
+                                for request in requests.clone().unwrap_or_else(|| vec![s]) {
+                                    // Requests of an array are forwarded individually
+                                    let forwarded = match requests {
+                                        Some(_) => request.as_bytes(),
+                                        None => msg.payload().unwrap_or_default(),
+                                    };
+                                    async {
+                                        let tenant = tenant_of(config, msg.headers());
+                                        let outcome = handle_message(
+                                            config,
+                                            &sink,
+                                            &recent,
+                                            &transforms,
+                                            request,
+                                            tenant,
+                                            msg.timestamp(),
+                                        )
+                                        .await?;
+                                        // Only failed requests are forwarded to dead letter queue or retry topic
+                                        let failed = matches!(outcome, ProcessOutcome::Failed(..));
+                                        if let Some((request_id, response)) =
+                                            outcome.into_response()
+                                        {
+                                            let patient_id = key_patient_id(config, request);
+                                            let values = KeyValues {
+                                                request_id: &request_id,
+                                                patient_id: patient_id.as_deref(),
+                                                key,
+                                                partition: msg.partition(),
+                                                offset: msg.offset(),
+                                            };
+                                            let response = match (
+                                                failed,
+                                                response,
+                                                &config.kafka_delete_retry_topic,
+                                            ) {
+                                                (
+                                                    true,
+                                                    KafkaResponsePayload::DeletePending,
+                                                    Some(retry_topic),
+                                                ) => {
+                                                    let attempt =
+                                                        delete_retry_attempt(msg.headers()) + 1;
+                                                    if attempt
+                                                        <= config.kafka_delete_retry_max_attempts
+                                                    {
+                                                        forward_kafka_message(
+                                                            producer,
+                                                            spool.as_ref(),
+                                                            retry_topic,
+                                                            key,
+                                                            forwarded,
+                                                            Some(delete_retry_headers(
+                                                                msg.headers(),
+                                                                attempt,
+                                                            )),
+                                                        )
+                                                        .await;
+                                                        KafkaResponsePayload::DeletePending
+                                                    } else {
+                                                        warn!(
+                                                            "Delete request failed after {} retries",
+                                                            attempt - 1
+                                                        );
+                                                        KafkaResponsePayload::DeleteFailed(
+                                                            attempt - 1,
+                                                        )
+                                                    }
+                                                }
+                                                (_, response, _) => response,
+                                            };
+                                            if let (true, Some(dlq_topic)) = (
+                                                failed && is_dlq_response(config, &response),
+                                                &config.kafka_dlq_topic,
+                                            ) {
+                                                forward_kafka_message(
+                                                    producer,
+                                                    spool.as_ref(),
+                                                    dlq_topic,
+                                                    &config.key_template.render(&values),
+                                                    forwarded,
+                                                    msg.headers().map(BorrowedHeaders::detach),
+                                                )
+                                                .await
+                                            }
+                                            match &mut pending_responses {
+                                                Some(pending_responses) => {
+                                                    let producer = producer.clone();
+                                                    let spool = spool.clone();
+                                                    let response_topics = response_topics.clone();
+                                                    let key_template = config.key_template.clone();
+                                                    let key = key.to_string();
+                                                    let (partition, offset) =
+                                                        (msg.partition(), msg.offset());
+                                                    pending_responses.push(tokio::spawn(
+                                                        async move {
+                                                            let values = KeyValues {
+                                                                request_id: &request_id,
+                                                                patient_id: patient_id.as_deref(),
+                                                                key: &key,
+                                                                partition,
+                                                                offset,
+                                                            };
+                                                            send_kafka_response(
+                                                                &producer,
+                                                                spool.as_ref(),
+                                                                &response_topics,
+                                                                &key_template,
+                                                                &values,
+                                                                response,
+                                                            )
+                                                            .await
+                                                        }
+                                                        .in_current_span(),
+                                                    ));
+                                                    pending_responses
+                                                        .wait_if_full(
+                                                            || pause_consumer(&consumer, true),
+                                                            // Consumption stays paused while bwHC-Backend is unhealthy
+                                                            || {
+                                                                if !circuit.as_ref().is_some_and(
+                                                                    BackendCircuit::is_open,
+                                                                ) {
+                                                                    pause_consumer(&consumer, false)
+                                                                }
+                                                            },
+                                                        )
+                                                        .await
+                                                }
+                                                None => {
+                                                    send_kafka_response(
+                                                        producer,
+                                                        spool.as_ref(),
+                                                        &response_topics,
+                                                        &config.key_template,
+                                                        &values,
+                                                        response,
+                                                    )
+                                                    .await
+                                                }
+                                            }
+                                        }
+                                        Ok::<(), AppError>(())
+                                    }
+                                    .instrument(record_span(msg.partition(), msg.offset()))
+                                    .await?
+                                }
+                            }
+                            _ => error!("Unable to use key!"),
+                        },
+                        // Avro record or protobuf message that cannot be decoded is not dropped
+                        Some(Err(Some(response))) => match msg.key_view::<str>() {
+                            Some(Ok(key)) => {
+                                let values = KeyValues {
+                                    request_id: "",
+                                    patient_id: None,
+                                    key,
+                                    partition: msg.partition(),
+                                    offset: msg.offset(),
+                                };
+                                if let (true, Some(dlq_topic)) =
+                                    (is_dlq_response(config, &response), &config.kafka_dlq_topic)
+                                {
+                                    forward_kafka_message(
+                                        producer,
+                                        spool.as_ref(),
+                                        dlq_topic,
+                                        &config.key_template.render(&values),
+                                        msg.payload().unwrap_or_default(),
+                                        msg.headers().map(BorrowedHeaders::detach),
+                                    )
+                                    .await
+                                }
+                                send_kafka_response(
+                                    producer,
+                                    spool.as_ref(),
+                                    &response_topics,
+                                    &config.key_template,
+                                    &values,
+                                    response,
+                                )
+                                .await
+                            }
+                            _ => error!("Unable to use key!"),
+                        },
+                        // Null value, e.g. to delete by key on a compacted topic, unlike an empty value
+                        None => match msg.key_view::<str>() {
+                            Some(Ok(key)) => {
+                                async {
+                                    let tenant = tenant_of(config, msg.headers());
+                                    if let Some((request_id, response)) = handle_tombstone(
+                                        config,
+                                        &sink,
+                                        transforms.pseudonymizer.as_ref(),
+                                        key,
+                                        tenant,
+                                    )
+                                    .await?
+                                    .into_response()
+                                    {
+                                        // Key of record without value is the patient id
+                                        let patient_id = config
+                                            .key_template
+                                            .uses("patient_id")
+                                            .then(|| hashed_patient_id(key));
+                                        let values = KeyValues {
+                                            request_id: &request_id,
+                                            patient_id: patient_id.as_deref(),
+                                            key,
+                                            partition: msg.partition(),
+                                            offset: msg.offset(),
+                                        };
+                                        send_kafka_response(
+                                            producer,
+                                            spool.as_ref(),
+                                            &response_topics,
+                                            &config.key_template,
+                                            &values,
+                                            response,
+                                        )
+                                        .await
+                                    }
+                                    Ok::<(), AppError>(())
+                                }
+                                .instrument(record_span(msg.partition(), msg.offset()))
+                                .await?
+                            }
+                            _ => error!("Unable to use key!"),
+                        },
+                        _ => error!("Unable to use payload!"),
+                    }
+                    commit_record(config, &consumer, &msg);
+                }
+                _ => error!("Unable to consume message"),
+            }
+            Ok(())
+        };
+        // Offset of record not processed due to infrastructure failure is not committed,
+        // the record is processed again after restart
+        if let Err(e) = with_poll_deadline(
+            poll_warning,
+            |elapsed| approaching_poll_deadline(config, &consumer, elapsed),
+            processing,
+        )
+        .await
+        {
+            error!("Cannot process record - stopping: {}", e);
+            return Err(e);
+        }
+    }
+}
+
+/// Commits offset of processed record using configured commit mode or stores it to be committed
+/// on commit interval
+fn commit_record(config: &Config, consumer: &LoggingConsumer, msg: &BorrowedMessage) {
+    if config.commit_interval_ms.is_some() {
+        if let Err(e) = consumer.store_offset_from_message(msg) {
+            error!("Unable to store offset: {}", e);
+        }
+    } else if let Err(e) = consumer.commit_message(msg, commit_mode(config)) {
+        error!("Unable to commit offset: {}", e);
+    }
+}
+
+/// Replaces sink using reloaded `APP_CONFIG_FILE`. Command line arguments and environment variables
+/// cannot change at runtime, therefore only changes of the configuration file take effect and only
+/// for sink settings. All other settings, the health probe and the circuit keep their values of
+/// application start. Invalid configuration is rejected and the current sink is kept.
+fn reload_sink(config: &Config, sink: &mut Sink, file_settings: &mut BTreeMap<String, String>) {
+    let Some(config_file) = &config.config_file else {
+        warn!("Configuration not reloaded - APP_CONFIG_FILE is not set");
+        return;
+    };
+    info!("Reloading configuration '{}'", config_file.display());
+    let result = config_file_settings(config_file)
+        .map_err(|e| IoError(format!("Cannot read '{}': {}", config_file.display(), e)))
+        .and_then(|new_settings| {
+            let cli = Cli::load().map_err(|e| ValidationError(e.to_string()))?;
+            cli.config.validate()?;
+            Sink::new(&cli.config).map(|new_sink| (new_settings, new_sink))
+        });
+    match result {
+        Ok((new_settings, new_sink)) => {
+            // Values are not logged as settings may contain secrets
+            let (applied, ignored): (Vec<_>, Vec<_>) =
+                changed_settings(file_settings, &new_settings)
+                    .into_iter()
+                    .partition(|key| is_sink_setting(key));
+            if !ignored.is_empty() {
+                warn!(
+                    "Changed settings require a restart and are not applied: {}",
+                    ignored.join(", ")
+                );
+            }
+            *sink = new_sink;
+            *file_settings = new_settings;
+            if applied.is_empty() {
+                info!("Configuration reloaded - no sink settings changed");
+            } else {
+                info!(
+                    "Configuration reloaded - changed sink settings: {}",
+                    applied.join(", ")
+                );
+            }
+        }
+        Err(e) => error!(
+            "Invalid configuration - keeping current configuration: {}",
+            e
+        ),
+    }
+}
+
+/// Names of settings added, removed or changed
+fn changed_settings(old: &BTreeMap<String, String>, new: &BTreeMap<String, String>) -> Vec<String> {
+    old.keys()
+        .chain(new.keys())
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .filter(|key| old.get(*key) != new.get(*key))
+        .cloned()
+        .collect()
+}
+
+/// Pauses or resumes consumption of all assigned partitions
+fn pause_consumer(consumer: &LoggingConsumer, pause: bool) {
+    let result = consumer.assignment().and_then(|assignment| {
+        if pause {
+            consumer.pause(&assignment)
+        } else {
+            consumer.resume(&assignment)
+        }
+    });
+    if let Err(e) = result {
+        error!("Unable to pause or resume consumption: {}", e);
+    }
+}
+
+/// Log level to be used or default log level if not set or invalid
+fn parse_log_level(level: Option<&str>) -> LevelFilter {
+    #[cfg(debug_assertions)]
+    let default = LevelFilter::Debug;
+
+    #[cfg(not(debug_assertions))]
+    let default = LevelFilter::Info;
+
+    level
+        .and_then(|level| LevelFilter::from_str(level.trim()).ok())
+        .unwrap_or(default)
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<(), Box<dyn Error>> {
+    // Use exit code 1 for invalid configuration
+    let cli = Cli::load().unwrap_or_else(|e| {
+        let _ = e.print();
+        process::exit(if e.use_stderr() { 1 } else { 0 })
+    });
+    cli.config.validate()?;
+
+    let log_level = cli.config.log_level.clone().or(env::var("RUST_LOG").ok());
+    SimpleLogger::new()
+        .with_level(parse_log_level(log_level.as_deref()))
+        .init()
+        .unwrap();
+    telemetry::init(&cli.config)?;
+
+    match cli.command.unwrap_or(Command::Run) {
+        Command::Run => run(&cli.config).await?,
+        Command::ValidateConfig => {
+            Sink::new(&cli.config)?;
+            Transforms::load(&cli.config)?;
+            info!("Configuration is valid");
+        }
+        Command::CheckConnection => check_connection(&cli.config).await?,
+        Command::Selftest => process::exit(selftest(&cli.config).await),
+        Command::ReplayDlq { dry_run } => replay_dlq(&cli.config, dry_run).await?,
+    }
+
+    telemetry::shutdown();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+    use std::cell::Cell;
+    use std::collections::BTreeMap;
+    use std::env;
+    use std::fmt::Debug;
+    use std::io::Write;
+    use std::str::FromStr;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use base64::engine::general_purpose::STANDARD as BASE64;
+    use base64::Engine;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use regex::Regex;
+    use serde_json::{json, Value};
+    use sha2::{Digest, Sha256};
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Instrument, Subscriber};
+    use tracing_subscriber::layer::{Context, SubscriberExt};
+    use tracing_subscriber::registry::LookupSpan;
+    use tracing_subscriber::Layer;
+
+    use crate::avro::SchemaRegistry;
+    use crate::bwhc_client::{DeleteMode, Endpoint, HttpResponse};
+    use crate::config::test_config;
+    use crate::config::{
+        Config, ConsentValidityTime, EnteredInErrorPolicy, ExpiredConsentPolicy, KafkaCommitMode,
+        MissingConsentPolicy, PayloadFormat, PseudonymizeMode, UndeterminedConsentPolicy,
+    };
+    use crate::dedup::RecentRequestIds;
+    use crate::health::Readiness;
+    use crate::key_template::KeyTemplate;
+    use crate::protobuf::ProtoRequest;
+    use crate::pseudonym::Pseudonymizer;
+    use crate::resources::issues::Severity;
+    use crate::resources::mtbfile::{ConsentValidity, PatientIdSource};
+    use crate::resources::request::{Request, RequestIdFormat};
+    use crate::schema::MtbFileSchema;
+    use crate::sink::{Sink, SinkType};
+    use crate::{
+        approaching_poll_deadline, changed_settings, commit_mode, commit_record,
+        consent_validity_time, consumer_config, create_with_retry, decode_payload,
+        delete_retry_attempt, delete_retry_headers, handle_message, handle_tombstone,
+        hashed_patient_id, is_dlq_response, is_too_old, key_patient_id, load_schema,
+        parse_log_level, poll_interval_warning, record_span, replay_dlq, replay_record, run,
+        selftest, selftest_backend, split_requests, warm_up, warm_up_and_set_ready,
+        with_poll_deadline, AppError, CustomContext, KafkaResponsePayload, LoggingConsumer,
+        ProcessOutcome, ReplayResult, ResponseTopics, Transforms, SELFTEST_BACKEND_UNAVAILABLE,
+        SELFTEST_KAFKA_UNAVAILABLE,
+    };
+    use log::LevelFilter;
+    use prost::Message;
+    use rdkafka::consumer::{BaseConsumer, CommitMode, Consumer};
+    use rdkafka::error::KafkaError;
+    use rdkafka::message::{Header, Headers, OwnedHeaders, Timestamp};
+    use rdkafka::mocking::MockCluster;
+    use rdkafka::producer::{FutureProducer, FutureRecord};
+    use rdkafka::{ClientConfig, Offset, TopicPartitionList};
+
+    const URI: &str = "http://localhost:9000/bwhc/etl/api";
+
+    async fn process(config: &Config, payload: &str) -> ProcessOutcome {
+        handle_message(
+            config,
+            &Sink::new(config).unwrap(),
+            &RecentRequestIds::new(None, None),
+            &Transforms::load(config).unwrap(),
+            payload,
+            None,
+            Timestamp::NotAvailable,
+        )
+        .await
+        .unwrap()
+    }
+
+    async fn handle(config: Config, payload: &str) -> Option<(String, KafkaResponsePayload)> {
+        process(&config, payload).await.into_response()
+    }
+
+    #[test]
+    fn should_parse_log_level() {
+        assert_eq!(parse_log_level(Some("warn")), LevelFilter::Warn);
+        assert_eq!(parse_log_level(Some("DEBUG")), LevelFilter::Debug);
+        assert_eq!(parse_log_level(Some(" trace ")), LevelFilter::Trace);
+        assert_eq!(parse_log_level(Some("off")), LevelFilter::Off);
+    }
+
+    #[test]
+    fn should_use_default_log_level_if_invalid_or_missing() {
+        let default = parse_log_level(None);
+
+        assert_eq!(parse_log_level(Some("verbose")), default);
+        assert_eq!(parse_log_level(Some("info,rdkafka=debug")), default);
+    }
+
+    #[test]
+    fn should_include_location_header_in_payload() {
+        let payload = KafkaResponsePayload::SuccessfulConnection(
+            HttpResponse {
+                status_code: 201,
+                status_body: String::new(),
+                headers: BTreeMap::from([(
+                    "Location".to_string(),
+                    "/bwhc/etl/api/MTBFile/TESTPATIENT1234".to_string(),
+                )]),
+                endpoint: None,
+                content_type: None,
+                original_status_code: None,
+                truncated: false,
+                retry_after: None,
+            },
+            None,
+        );
+
+        let actual =
+            serde_json::from_str::<Value>(&payload.to_payload("request0123456789")).unwrap();
+
+        assert_eq!(
+            actual["headers"],
+            json!({ "Location": "/bwhc/etl/api/MTBFile/TESTPATIENT1234" })
+        )
+    }
+
+    fn response_with_status(status_code: u16) -> KafkaResponsePayload {
+        KafkaResponsePayload::SuccessfulConnection(
+            HttpResponse {
+                status_code,
+                status_body: String::new(),
+                headers: BTreeMap::new(),
+                endpoint: None,
+                content_type: None,
+                original_status_code: None,
+                truncated: false,
+                retry_after: None,
+            },
+            None,
+        )
+    }
+
+    fn response_topics(success: Option<&str>, failure: Option<&str>) -> ResponseTopics {
+        ResponseTopics {
+            response: "test_response".to_string(),
+            success: success.map(str::to_string),
+            failure: failure.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn should_route_responses_by_status_code() {
+        let topics = response_topics(Some("test_success"), Some("test_failure"));
+
+        for (status_code, expected) in [
+            (200, "test_success"),
+            (201, "test_success"),
+            (400, "test_failure"),
+            (404, "test_failure"),
+            (422, "test_failure"),
+            (500, "test_failure"),
+        ] {
+            assert_eq!(
+                topics.topic_for(&response_with_status(status_code)),
+                expected,
+                "status code {}",
+                status_code
+            );
+        }
+    }
+
+    #[test]
+    fn should_route_error_responses_to_failure_topic() {
+        let topics = response_topics(Some("test_success"), Some("test_failure"));
+
+        for payload in [
+            KafkaResponsePayload::NoConnection("Connection error".to_string(), None),
+            KafkaResponsePayload::Timeout,
+            KafkaResponsePayload::ConnectionRefused,
+            KafkaResponsePayload::SchemaRegistryUnavailable,
+        ] {
+            assert_eq!(topics.topic_for(&payload), "test_failure");
+        }
+        assert_eq!(
+            topics.topic_for(&KafkaResponsePayload::MultiPatientDelete(vec![
+                ("TESTPATIENT1234".to_string(), 200),
+                ("TESTPATIENT5678".to_string(), 404),
+            ])),
+            "test_failure"
+        );
+    }
+
+    #[test]
+    fn should_route_non_error_responses_to_success_topic() {
+        let topics = response_topics(Some("test_success"), Some("test_failure"));
+
+        for payload in [
+            KafkaResponsePayload::DeletePending,
+            KafkaResponsePayload::Ignored("ignored".to_string()),
+            KafkaResponsePayload::MultiPatientDelete(vec![("TESTPATIENT1234".to_string(), 200)]),
+        ] {
+            assert_eq!(topics.topic_for(&payload), "test_success");
+        }
+    }
+
+    #[test]
+    fn should_route_to_response_topic_if_not_configured() {
+        let topics = response_topics(None, None);
+
+        assert_eq!(
+            topics.topic_for(&response_with_status(200)),
+            "test_response"
+        );
+        assert_eq!(
+            topics.topic_for(&response_with_status(500)),
+            "test_response"
+        );
+        assert_eq!(
+            topics.topic_for(&KafkaResponsePayload::Timeout),
+            "test_response"
+        );
+
+        let topics = response_topics(None, Some("test_failure"));
+
+        assert_eq!(
+            topics.topic_for(&response_with_status(200)),
+            "test_response"
+        );
+        assert_eq!(topics.topic_for(&response_with_status(500)), "test_failure");
+    }
+
+    #[test]
+    fn should_not_include_headers_in_payload_if_none_selected() {
+        let payload = KafkaResponsePayload::SuccessfulConnection(
+            HttpResponse {
+                status_code: 200,
+                status_body: String::new(),
+                headers: BTreeMap::new(),
+                endpoint: None,
+                content_type: None,
+                original_status_code: None,
+                truncated: false,
+                retry_after: None,
+            },
+            None,
+        );
+
+        let actual =
+            serde_json::from_str::<Value>(&payload.to_payload("request0123456789")).unwrap();
+
+        assert!(actual.get("headers").is_none());
+        assert!(actual.get("endpoint").is_none())
+    }
+
+    #[test]
+    fn should_include_endpoint_in_payload() {
+        let payload = KafkaResponsePayload::SuccessfulConnection(
+            HttpResponse {
+                status_code: 201,
+                status_body: String::new(),
+                headers: BTreeMap::new(),
+                endpoint: Some(Endpoint::Fallback),
+                content_type: None,
+                original_status_code: None,
+                truncated: false,
+                retry_after: None,
+            },
+            None,
+        );
+
+        let actual =
+            serde_json::from_str::<Value>(&payload.to_payload("request0123456789")).unwrap();
+
+        assert_eq!(actual["endpoint"], json!("fallback"))
+    }
+
+    #[test]
+    fn should_use_default_no_connection_message_without_detail() {
+        let config = test_config(URI);
+        let payload = KafkaResponsePayload::NoConnection(config.no_connection_message, None);
+
+        let actual =
+            serde_json::from_str::<Value>(&payload.to_payload("request0123456789")).unwrap();
+
+        assert_eq!(actual["status_code"], json!(900));
+        assert_eq!(
+            actual["status_body"]["issues"],
+            json!([{ "severity": "error", "message": "No HTTP connection" }])
+        )
+    }
+
+    #[test]
+    fn should_use_overridden_no_connection_message_with_detail() {
+        let mut config = test_config(URI);
+        config.no_connection_message = "Backend unavailable".into();
+        config.include_error_detail = true;
+        let error = AppError::HttpError("error sending request".into());
+        let payload = KafkaResponsePayload::NoConnection(
+            config.no_connection_message,
+            config.include_error_detail.then(|| error.to_string()),
+        );
+
+        let actual =
+            serde_json::from_str::<Value>(&payload.to_payload("request0123456789")).unwrap();
+
+        assert_eq!(
+            actual["status_body"]["issues"],
+            json!([{
+                "severity": "error",
+                "message": "Backend unavailable",
+                "details": "HTTP error: error sending request"
+            }])
+        )
+    }
+
+    #[test]
+    fn should_serialize_error_code_with_status_code() {
+        for (payload, status_code, error_code) in [
+            (
+                KafkaResponsePayload::NoConnection("No HTTP connection".into(), None),
+                900,
+                "NO_CONNECTION",
+            ),
+            (KafkaResponsePayload::Timeout, 901, "TIMEOUT"),
+            (
+                KafkaResponsePayload::ConnectionRefused,
+                902,
+                "CONNECTION_REFUSED",
+            ),
+            (
+                KafkaResponsePayload::SchemaRegistryUnavailable,
+                903,
+                "SCHEMA_REGISTRY_UNAVAILABLE",
+            ),
+            (
+                KafkaResponsePayload::InvalidRequest("reason".into()),
+                904,
+                "PARSE_ERROR",
+            ),
+            (
+                KafkaResponsePayload::Skipped("duplicate request_id".into(), UNIX_EPOCH),
+                905,
+                "DUPLICATE",
+            ),
+            (
+                KafkaResponsePayload::InvalidRequestId("***".into()),
+                400,
+                "INVALID_REQUEST_ID",
+            ),
+            (
+                KafkaResponsePayload::InvalidPatientId,
+                400,
+                "INVALID_PATIENT_ID",
+            ),
+            (
+                KafkaResponsePayload::PatientIdMismatch("a".into(), "b".into()),
+                400,
+                "PATIENT_ID_MISMATCH",
+            ),
+            (
+                KafkaResponsePayload::UnsupportedVersion(3),
+                400,
+                "UNSUPPORTED_VERSION",
+            ),
+            (
+                KafkaResponsePayload::UndeterminedConsent,
+                400,
+                "UNDETERMINED_CONSENT",
+            ),
+            (
+                KafkaResponsePayload::DisallowedConsentIssuer,
+                403,
+                "DISALLOWED_CONSENT_ISSUER",
+            ),
+            (
+                KafkaResponsePayload::ConsentRefused(None),
+                403,
+                "CONSENT_REFUSED",
+            ),
+            (
+                KafkaResponsePayload::SchemaViolation(vec!["/: invalid".into()]),
+                422,
+                "VALIDATION_ERROR",
+            ),
+            (KafkaResponsePayload::DeleteFailed(10), 906, "DELETE_FAILED"),
+        ] {
+            let actual =
+                serde_json::from_str::<Value>(&payload.to_payload("request0123456789")).unwrap();
+
+            assert_eq!(actual["status_code"], json!(status_code));
+            assert_eq!(actual["error_code"], json!(error_code));
+        }
+    }
+
+    /// Avro record of Confluent wire format with string fields `requestId` and `content`
+    fn avro_record(schema_id: u32, request_id: &str, content: &str) -> Vec<u8> {
+        let mut record = vec![0];
+        record.extend_from_slice(&schema_id.to_be_bytes());
+        for value in [request_id, content] {
+            // Zigzag encoded length, sufficient for short strings
+            record.push((value.len() * 2) as u8);
+            record.extend_from_slice(value.as_bytes());
+        }
+        record
+    }
+
+    const AVRO_SCHEMA: &str = r#"{"type": "record", "name": "Request", "fields": [{"name": "requestId", "type": "string"}, {"name": "content", "type": "string"}]}"#;
+
+    #[tokio::test]
+    async fn should_decode_avro_payload_using_schema_registry() {
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("GET", "/schemas/ids/1")
+            .with_status(200)
+            .with_body(json!({ "schema": AVRO_SCHEMA }).to_string())
+            .create_async()
+            .await;
+        let mut config = test_config(URI);
+        config.schema_registry_uri = Some(server.url());
+        let registry = SchemaRegistry::new(&config).unwrap();
+
+        let payload = avro_record(1, "request0123456789", r#"{"consent": {}}"#);
+        let actual =
+            decode_payload(PayloadFormat::Json, registry.as_ref(), &payload, "/consent").await;
+
+        assert!(matches!(
+            actual,
+            Ok(json) if serde_json::from_str::<Value>(&json).unwrap()
+                == json!({ "requestId": "request0123456789", "content": { "consent": {} } })
+        ));
+    }
+
+    #[tokio::test]
+    async fn should_respond_if_avro_payload_cannot_be_decoded() {
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("GET", "/schemas/ids/1")
+            .with_status(503)
+            .create_async()
+            .await;
+        server
+            .mock("GET", "/schemas/ids/2")
+            .with_status(404)
+            .create_async()
+            .await;
+        let mut config = test_config(URI);
+        config.schema_registry_uri = Some(server.url());
+        config.rest_retries = 0;
+        let registry = SchemaRegistry::new(&config).unwrap();
+
+        let payload = avro_record(1, "request0123456789", "{}");
+        assert!(matches!(
+            decode_payload(PayloadFormat::Json, registry.as_ref(), &payload, "/consent").await,
+            Err(Some(KafkaResponsePayload::SchemaRegistryUnavailable))
+        ));
+        assert!(is_dlq_response(
+            &config,
+            &KafkaResponsePayload::SchemaRegistryUnavailable
+        ));
+
+        let payload = avro_record(2, "request0123456789", "{}");
+        assert!(matches!(
+            decode_payload(PayloadFormat::Json, registry.as_ref(), &payload, "/consent").await,
+            Err(Some(KafkaResponsePayload::InvalidRequest(reason))) if reason.starts_with("Invalid Avro record: schema 2 not available")
+        ));
+    }
+
+    #[tokio::test]
+    async fn should_use_json_payload_as_is() {
+        let payload = br#"{"requestId": "request0123456789", "content": {}}"#;
+
+        for registry in [
+            None,
+            SchemaRegistry::new(&{
+                let mut config = test_config(URI);
+                config.schema_registry_uri = Some("http://localhost:8081".into());
+                config
+            })
+            .unwrap(),
+        ] {
+            assert!(matches!(
+                decode_payload(PayloadFormat::Json, registry.as_ref(), payload, "/consent").await,
+                Ok(Cow::Borrowed(json)) if json.as_bytes() == payload
+            ));
+        }
+        assert!(matches!(
+            decode_payload(
+                PayloadFormat::Json,
+                None,
+                &[0, 0, 0, 0, 1, 0xff],
+                "/consent"
+            )
+            .await,
+            Err(None)
+        ));
+    }
+
+    #[tokio::test]
+    async fn should_decode_protobuf_payload() {
+        let content = r#"{"consent": {"id": "TESTID1234", "patient": "TESTPATIENT1234", "status": "active"}}"#;
+        let payload = ProtoRequest {
+            request_id: "request0123456789".into(),
+            content: content.into(),
+            consent_status: "active".into(),
+        }
+        .encode_to_vec();
+
+        let Ok(json) = decode_payload(PayloadFormat::Protobuf, None, &payload, "/consent").await
+        else {
+            panic!("protobuf payload not decoded");
+        };
+
+        let request = Request::try_from(json.as_ref()).unwrap();
+        assert_eq!(request.request_id(), "request0123456789");
+        assert_eq!(request.content_str(), content);
+    }
+
+    #[tokio::test]
+    async fn should_respond_to_invalid_protobuf_payload_as_invalid_request() {
+        let payload = br#"{"requestId": "request0123456789", "content": {}}"#;
+
+        let actual = decode_payload(PayloadFormat::Protobuf, None, payload, "/consent").await;
+
+        assert!(matches!(
+            actual,
+            Err(Some(KafkaResponsePayload::InvalidRequest(reason))) if reason.starts_with("Invalid protobuf message")
+        ));
+    }
+
+    #[test]
+    fn should_not_serialize_error_code_without_error() {
+        let payload = KafkaResponsePayload::Ignored("draft".into());
+
+        let actual =
+            serde_json::from_str::<Value>(&payload.to_payload("request0123456789")).unwrap();
+
+        assert_eq!(actual.get("error_code"), None);
+    }
+
+    #[tokio::test]
+    async fn should_not_delete_without_patient_id() {
+        let jsonstr = r#"
+           {
+                "requestId": "request0123456789",
+                "content": {
+                    "consent": {
+                        "id": "TESTID1234",
+                        "status": "rejected"
+                    }
+                }
+           }
+        "#;
+
+        let actual = handle(test_config(URI), jsonstr).await;
+
+        assert!(matches!(
+            actual,
+            Some((request_id, KafkaResponsePayload::InvalidPatientId)) if request_id == "request0123456789"
+        ))
+    }
+
+    #[tokio::test]
+    async fn should_not_delete_with_blank_patient_id() {
+        let jsonstr = r#"
+           {
+                "requestId": "request0123456789",
+                "content": {
+                    "consent": {
+                        "id": "TESTID1234",
+                        "patient": "  ",
+                        "status": "rejected"
+                    }
+                }
+           }
+        "#;
+
+        let actual = handle(test_config(URI), jsonstr).await;
+
+        assert!(matches!(
+            actual,
+            Some((_, KafkaResponsePayload::InvalidPatientId))
+        ))
+    }
+
+    #[tokio::test]
+    async fn should_not_send_mtb_file_with_empty_patient_id() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("POST", "/MTBFile")
+            .expect(0)
+            .create_async()
+            .await;
+
+        for patient in ["", r#""patient": "","#, r#""patient": "  ","#] {
+            let jsonstr = format!(
+                r#"{{
+                    "requestId": "request0123456789",
+                    "content": {{
+                        "consent": {{ "id": "TESTID1234", {} "status": "active" }}
+                    }}
+                }}"#,
+                patient
+            );
+
+            let actual = handle(test_config(server.url().as_str()), &jsonstr).await;
+
+            assert!(matches!(
+                actual,
+                Some((request_id, KafkaResponsePayload::InvalidPatientId)) if request_id == "request0123456789"
+            ))
+        }
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn should_not_delete_with_empty_patient_id() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("DELETE", mockito::Matcher::Any)
+            .expect(0)
+            .create_async()
+            .await;
+
+        let jsonstr = r#"
+           {
+                "requestId": "request0123456789",
+                "content": {
+                    "consent": {
+                        "id": "TESTID1234",
+                        "patient": "",
+                        "status": "rejected"
+                    }
+                }
+           }
+        "#;
+
+        let actual = handle(test_config(server.url().as_str()), jsonstr).await;
+
+        assert!(matches!(
+            actual,
+            Some((_, KafkaResponsePayload::InvalidPatientId))
+        ));
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn should_respond_to_request_without_consent_status() {
+        let jsonstr = r#"
+           {
+                "requestId": "request0123456789",
+                "content": {
+                    "consent": {
+                        "id": "TESTID1234",
+                        "patient": "TESTPATIENT1234"
+                    }
+                }
+           }
+        "#;
+
+        let mut config = test_config(URI);
+        config.undetermined_consent_policy = UndeterminedConsentPolicy::Respond;
+
+        let actual = handle(config, jsonstr).await;
+
+        assert!(matches!(
+            actual,
+            Some((request_id, KafkaResponsePayload::UndeterminedConsent)) if request_id == "request0123456789"
+        ))
+    }
+
+    #[tokio::test]
+    async fn should_respond_to_request_with_invalid_consent_status() {
+        let jsonstr = r#"
+           {
+                "requestId": "request0123456789",
+                "content": {
+                    "consent": {
+                        "id": "TESTID1234",
+                        "patient": "TESTPATIENT1234",
+                        "status": "unknown"
+                    }
+                }
+           }
+        "#;
+
+        let mut config = test_config(URI);
+        config.undetermined_consent_policy = UndeterminedConsentPolicy::Dlq;
+
+        let actual = handle(config, jsonstr).await;
+
+        assert!(matches!(
+            actual,
+            Some((_, KafkaResponsePayload::UndeterminedConsent))
+        ))
+    }
+
+    #[tokio::test]
+    async fn should_drop_request_with_invalid_consent_status() {
+        let jsonstr = r#"
+           {
+                "requestId": "request0123456789",
+                "content": {
+                    "consent": {
+                        "id": "TESTID1234",
+                        "patient": "TESTPATIENT1234",
+                        "status": "unknown"
+                    }
+                }
+           }
+        "#;
+
+        let mut config = test_config(URI);
+        config.undetermined_consent_policy = UndeterminedConsentPolicy::Drop;
+
+        let actual = handle(config, jsonstr).await;
+
+        assert!(actual.is_none())
+    }
+
+    fn request_with_consent_status(status: &str) -> String {
+        format!(
+            r#"{{ "requestId": "request0123456789", "content": {{ "consent": {{ "id": "TESTID1234", "patient": "TESTPATIENT1234", "status": "{}" }} }} }}"#,
+            status
+        )
+    }
+
+    #[tokio::test]
+    async fn should_return_posted_outcome_if_mtb_file_accepted() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("POST", "/MTBFile")
+            .with_status(201)
+            .create_async()
+            .await;
+
+        let actual = process(
+            &test_config(server.url().as_str()),
+            &request_with_consent_status("active"),
+        )
+        .await;
+
+        assert!(matches!(
+            actual,
+            ProcessOutcome::Posted(request_id, KafkaResponsePayload::SuccessfulConnection(response, _)) if request_id == "request0123456789" && response.status_code == 201
+        ));
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn should_return_deleted_outcome_if_delete_accepted() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("DELETE", "/MTBFile/TESTPATIENT1234")
+            .with_status(200)
+            .create_async()
+            .await;
+
+        let actual = process(
+            &test_config(server.url().as_str()),
+            &request_with_consent_status("rejected"),
+        )
+        .await;
+
+        assert!(matches!(
+            actual,
+            ProcessOutcome::Deleted(_, KafkaResponsePayload::SuccessfulConnection(response, _)) if response.status_code == 200
+        ));
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn should_return_skipped_outcome_if_request_not_sent() {
+        let mut config = test_config(URI);
+
+        let ignored = process(&config, &request_with_consent_status("draft")).await;
+        config.undetermined_consent_policy = UndeterminedConsentPolicy::Drop;
+        let dropped = process(&config, &request_with_consent_status("unknown")).await;
+
+        assert!(matches!(
+            ignored,
+            ProcessOutcome::Skipped(Some((_, KafkaResponsePayload::Ignored(_))))
+        ));
+        assert!(matches!(dropped, ProcessOutcome::Skipped(None)));
+    }
+
+    #[tokio::test]
+    async fn should_return_failed_outcome_if_request_invalid_or_not_accepted() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("POST", "/MTBFile")
+            .with_status(500)
+            .create_async()
+            .await;
+        let config = test_config(server.url().as_str());
+
+        let invalid = process(&config, r#"{ "requestId": "request0123456789" }"#).await;
+        let not_accepted = process(&config, &request_with_consent_status("active")).await;
+
+        assert!(matches!(
+            invalid,
+            ProcessOutcome::Failed(_, KafkaResponsePayload::InvalidRequest(_))
+        ));
+        assert!(matches!(
+            not_accepted,
+            ProcessOutcome::Failed(_, KafkaResponsePayload::SuccessfulConnection(response, _)) if response.status_code == 500
+        ));
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn should_return_error_on_infrastructure_failure() {
+        let mut config = test_config(URI);
+        config.sink = SinkType::File;
+        config.sink_dir = Some(env::temp_dir().join("kafka-to-bwhc-missing").join("sink"));
+
+        let actual = handle_message(
+            &config,
+            &Sink::new(&config).unwrap(),
+            &RecentRequestIds::new(None, None),
+            &Transforms::default(),
+            &request_with_consent_status("active"),
+            None,
+            Timestamp::NotAvailable,
+        )
+        .await;
+
+        assert!(matches!(actual, Err(AppError::IoError(_))));
+    }
+
+    #[test]
+    fn should_parse_hashed_patient_id_only_if_used_by_key_template() {
+        let payload = request_with_consent_status("active");
+        let mut config = test_config(URI);
+
+        assert_eq!(key_patient_id(&config, &payload), None);
+
+        config.key_template = KeyTemplate::from_str("{patient_id}:{offset}").unwrap();
+
+        assert_eq!(
+            key_patient_id(&config, &payload),
+            Some(hashed_patient_id("TESTPATIENT1234"))
+        );
+        assert_eq!(key_patient_id(&config, "invalid"), None);
+    }
+
+    #[tokio::test]
+    async fn should_send_consent_in_post_consent_mode() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("POST", "/Consent")
+            .match_body(mockito::Matcher::Json(json!({
+                "id": "TESTID1234", "patient": "TESTPATIENT1234", "status": "rejected"
+            })))
+            .with_status(200)
+            .create_async()
+            .await;
+        let mut config = test_config(server.url().as_str());
+        config.delete_mode = DeleteMode::PostConsent;
+
+        let actual = handle(config, &request_with_consent_status("rejected")).await;
+
+        assert!(matches!(
+            actual,
+            Some((_, KafkaResponsePayload::SuccessfulConnection(response, _))) if response.status_code == 200
+        ));
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn should_respond_with_error_in_post_consent_mode_without_consent() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("POST", mockito::Matcher::Any)
+            .expect(0)
+            .create_async()
+            .await;
+        let mut config = test_config(server.url().as_str());
+        config.delete_mode = DeleteMode::PostConsent;
+
+        let actual = handle(
+            config,
+            r#"{ "requestId": "request0123456789", "type": "DELETE", "content": { "patient": { "id": "TESTPATIENT1234" } } }"#,
+        )
+        .await;
+
+        assert!(matches!(
+            actual,
+            Some((_, KafkaResponsePayload::InvalidRequest(reason))) if reason == "Content contains no consent"
+        ));
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn should_not_send_mtb_file_if_content_cannot_be_sanitized() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("POST", mockito::Matcher::Any)
+            .expect(0)
+            .create_async()
+            .await;
+        let mut config = test_config(server.url().as_str());
+        config.sanitize_content = true;
+        let payload = format!(
+            r#"{{ "requestId": "request0123456789", "content": {{ "consent": {{ "patient": "TESTPATIENT1234", "status": "active" }}, "patient": {{ "id": "TESTPATIENT1234" }}, "nested": {}{} }} }}"#,
+            "[".repeat(200),
+            "]".repeat(200)
+        );
+
+        let actual = handle(config, &payload).await;
+
+        assert!(matches!(
+            actual,
+            Some((_, KafkaResponsePayload::InvalidRequest(reason))) if reason == "Cannot parse content"
+        ));
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn should_strip_fields_before_sending_mtb_file_and_report_count() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("POST", "/MTBFile")
+            .match_body(mockito::Matcher::Json(json!({
+                "patient": {"id": "TESTPATIENT1234"},
+                "specimens": [{"id": "1"}, {"id": "2"}]
+            })))
+            .with_status(201)
+            .create_async()
+            .await;
+        let mut config = test_config(server.url().as_str());
+        // Consent is evaluated before fields are stripped
+        config.strip_fields = vec!["/consent".into(), "/specimens/*/note".into()];
+        config.strip_fields_report = true;
+        let payload = r#"{
+            "requestId": "request0123456789",
+            "content": {
+                "consent": {"id": "TESTID1234", "patient": "TESTPATIENT1234", "status": "active"},
+                "patient": {"id": "TESTPATIENT1234"},
+                "specimens": [{"id": "1", "note": "free text"}, {"id": "2", "note": "free text"}]
+            }
+        }"#;
+
+        let (request_id, actual) = handle(config, payload).await.unwrap();
+
+        let actual = serde_json::from_str::<Value>(&actual.to_payload(&request_id)).unwrap();
+        assert_eq!(actual["status_code"], 201);
+        assert_eq!(actual["stripped_fields"], 3);
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn should_sanitize_strip_and_pseudonymize_content_in_one_pass() {
+        let mut server = mockito::Server::new_async().await;
+        let mut config = test_config(server.url().as_str());
+        config.sanitize_content = true;
+        config.sanitize_content_deny = vec!["debug".into()];
+        config.strip_fields = vec!["/specimens/*/note".into()];
+        config.pseudonymize = Some(PseudonymizeMode::Buildin);
+        config.pseudonym_salt = Some("site-salt".into());
+        let pseudonym = Pseudonymizer::new(&config)
+            .unwrap()
+            .unwrap()
+            .pseudonym("TESTPATIENT1234");
+        let mock = server
+            .mock("POST", "/MTBFile")
+            .match_body(mockito::Matcher::Json(json!({
+                "consent": {"id": "TESTID1234", "patient": pseudonym, "status": "active"},
+                "patient": {"id": pseudonym},
+                "specimens": [{"id": "1", "patient": pseudonym}]
+            })))
+            .with_status(201)
+            .create_async()
+            .await;
+        let payload = r#"{
+            "requestId": "request0123456789",
+            "content": {
+                "consent": {"id": "TESTID1234", "patient": "TESTPATIENT1234", "status": "active"},
+                "patient": {"id": "TESTPATIENT1234"},
+                "specimens": [{"id": "1", "patient": "TESTPATIENT1234", "note": "free text"}],
+                "debug": {"trace": "0123456789"}
+            }
+        }"#;
+
+        let actual = handle(config, payload).await;
+
+        assert!(matches!(
+            actual,
+            Some((_, KafkaResponsePayload::SuccessfulConnection(response, _))) if response.status_code == 201
+        ));
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn should_not_strip_fields_when_deleting() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("DELETE", "/MTBFile/TESTPATIENT1234")
+            .with_status(200)
+            .create_async()
+            .await;
+        let mut config = test_config(server.url().as_str());
+        config.strip_fields = vec!["/consent/patient".into()];
+        config.strip_fields_report = true;
+
+        let (request_id, actual) = handle(config, &request_with_consent_status("rejected"))
+            .await
+            .unwrap();
+
+        let actual = serde_json::from_str::<Value>(&actual.to_payload(&request_id)).unwrap();
+        assert_eq!(actual["status_code"], 200);
+        assert!(actual.get("stripped_fields").is_none());
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn should_send_and_delete_using_same_pseudonym() {
+        let mut server = mockito::Server::new_async().await;
+        let mut config = test_config(server.url().as_str());
+        config.pseudonymize = Some(PseudonymizeMode::Buildin);
+        config.pseudonym_salt = Some("site-salt".into());
+        let pseudonym = Pseudonymizer::new(&config)
+            .unwrap()
+            .unwrap()
+            .pseudonym("TESTPATIENT1234");
+        let sent = server
+            .mock("POST", "/MTBFile")
+            .match_body(mockito::Matcher::Json(json!({
+                "consent": {"id": "TESTID1234", "patient": pseudonym, "status": "active"}
+            })))
+            .with_status(201)
+            .create_async()
+            .await;
+        let deleted = server
+            .mock("DELETE", format!("/MTBFile/{}", pseudonym).as_str())
+            .with_status(200)
+            .create_async()
+            .await;
+
+        for status in ["active", "rejected"] {
+            let actual = handle(config.clone(), &request_with_consent_status(status)).await;
+
+            assert!(matches!(
+                actual,
+                Some((_, KafkaResponsePayload::SuccessfulConnection(response, _))) if response.status_code < 300
+            ));
+        }
+        sent.assert_async().await;
+        deleted.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn should_delete_pseudonym_of_record_without_value() {
+        let mut server = mockito::Server::new_async().await;
+        let mut config = test_config(server.url().as_str());
+        config.null_value_deletes = true;
+        config.pseudonymize = Some(PseudonymizeMode::Buildin);
+        config.pseudonym_salt = Some("site-salt".into());
+        let pseudonym = Pseudonymizer::new(&config)
+            .unwrap()
+            .unwrap()
+            .pseudonym("TESTPATIENT1234");
+        let mock = server
+            .mock("DELETE", format!("/MTBFile/{}", pseudonym).as_str())
+            .with_status(200)
+            .create_async()
+            .await;
+
+        handle_tombstone(
+            &config,
+            &Sink::new(&config).unwrap(),
+            Transforms::load(&config).unwrap().pseudonymizer.as_ref(),
+            "TESTPATIENT1234",
+            None,
+        )
+        .await
+        .unwrap()
+        .into_response();
+
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn should_respond_with_numeric_request_id_as_string() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("POST", "/MTBFile")
+            .with_status(201)
+            .create_async()
+            .await;
+        let payload = r#"{ "requestId": 4711, "content": { "consent": { "patient": "TESTPATIENT1234", "status": "active" } } }"#;
+
+        let (request_id, response) = handle(test_config(server.url().as_str()), payload)
+            .await
+            .unwrap();
+        let actual = serde_json::from_str::<Value>(&response.to_payload(&request_id)).unwrap();
+
+        assert_eq!(actual["request_id"], json!("4711"));
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn should_delete_mtb_file_with_inactive_consent() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("DELETE", "/MTBFile/TESTPATIENT1234")
+            .with_status(200)
+            .create_async()
+            .await;
+
+        let actual = handle(
+            test_config(server.url().as_str()),
+            &request_with_consent_status("inactive"),
+        )
+        .await;
+
+        assert!(matches!(
+            actual,
+            Some((_, KafkaResponsePayload::SuccessfulConnection(response, _))) if response.status_code == 200
+        ));
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn should_send_mtb_file_with_configured_active_consent_status() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("POST", "/MTBFile")
+            .with_status(201)
+            .create_async()
+            .await;
+        let mut config = test_config(server.url().as_str());
+        config.consent_active_statuses = vec!["active".into(), "provisional".into()];
+
+        let actual = handle(config, &request_with_consent_status("provisional")).await;
+
+        assert!(matches!(
+            actual,
+            Some((_, KafkaResponsePayload::SuccessfulConnection(response, _))) if response.status_code == 201
+        ));
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn should_ignore_request_with_draft_or_proposed_consent() {
+        for status in ["draft", "proposed"] {
+            let actual = handle(test_config(URI), &request_with_consent_status(status)).await;
+
+            assert!(matches!(
+                actual,
+                Some((request_id, KafkaResponsePayload::Ignored(actual_status))) if request_id == "request0123456789" && actual_status == status
+            ))
+        }
+    }
+
+    #[tokio::test]
+    async fn should_delete_mtb_file_with_consent_entered_in_error_by_default() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("DELETE", "/MTBFile/TESTPATIENT1234")
+            .with_status(200)
+            .create_async()
+            .await;
+
+        let actual = handle(
+            test_config(server.url().as_str()),
+            &request_with_consent_status("entered-in-error"),
+        )
+        .await;
+
+        assert!(matches!(
+            actual,
+            Some((_, KafkaResponsePayload::SuccessfulConnection(response, _))) if response.status_code == 200
+        ));
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn should_ignore_request_with_consent_entered_in_error_if_configured() {
+        let mut config = test_config(URI);
+        config.entered_in_error_policy = EnteredInErrorPolicy::Ignore;
+
+        let actual = handle(config, &request_with_consent_status("entered-in-error")).await;
+
+        assert!(matches!(
+            actual,
+            Some((_, KafkaResponsePayload::Ignored(status))) if status == "entered-in-error"
+        ))
+    }
+
+    #[test]
+    fn should_include_consent_status_in_ignored_payload() {
+        let payload = KafkaResponsePayload::Ignored("draft".into());
+
+        let actual =
+            serde_json::from_str::<Value>(&payload.to_payload("request0123456789")).unwrap();
+
+        assert_eq!(actual["status_code"], json!(200));
+        assert_eq!(
+            actual["status_body"]["issues"],
+            json!([{
+                "severity": "info",
+                "message": "Request ignored",
+                "details": "Consent status 'draft'"
+            }])
+        )
+    }
+
+    #[test]
+    fn should_split_array_of_requests_only() {
+        assert_eq!(
+            split_requests(r#" [{"requestId": "request1"}, {"requestId": "request2"}]"#),
+            Some(vec![
+                r#"{"requestId": "request1"}"#,
+                r#"{"requestId": "request2"}"#
+            ])
+        );
+        assert_eq!(split_requests("[]"), Some(vec![]));
+        assert_eq!(split_requests(r#"{"requestId": "request1"}"#), None);
+        assert_eq!(split_requests(r#"[{"requestId": "request1""#), None);
+    }
+
+    #[tokio::test]
+    async fn should_handle_each_request_of_array_with_mixed_consent_states() {
+        let mut server = mockito::Server::new_async().await;
+        let post = server
+            .mock("POST", "/MTBFile")
+            .with_status(201)
+            .create_async()
+            .await;
+        let delete = server
+            .mock("DELETE", "/MTBFile/TESTPATIENT5678")
+            .with_status(200)
+            .create_async()
+            .await;
+        let config = test_config(server.url().as_str());
+        let sink = Sink::new(&config).unwrap();
+        let recent = RecentRequestIds::new(None, None);
+        let payload = r#"[
+            { "requestId": "request1", "content": { "consent": { "patient": "TESTPATIENT1234", "status": "active" } } },
+            { "requestId": "request2", "content": { "consent": { "patient": "TESTPATIENT5678", "status": "rejected" } } },
+            { "requestId": "request3", "content": { "consent": { "patient": "TESTPATIENT9012", "status": "draft" } } }
+        ]"#;
+
+        let mut actual = vec![];
+        for request in split_requests(payload).unwrap() {
+            actual.push(
+                handle_message(
+                    &config,
+                    &sink,
+                    &recent,
+                    &Transforms::default(),
+                    request,
+                    None,
+                    Timestamp::NotAvailable,
+                )
+                .await
+                .unwrap()
+                .into_response()
+                .unwrap(),
+            );
+        }
+
+        assert!(matches!(
+            &actual[..],
+            [
+                (first, KafkaResponsePayload::SuccessfulConnection(posted, _)),
+                (second, KafkaResponsePayload::SuccessfulConnection(deleted, _)),
+                (third, KafkaResponsePayload::Ignored(status)),
+            ] if first == "request1" && posted.status_code == 201
+                && second == "request2" && deleted.status_code == 200
+                && third == "request3" && status == "draft"
+        ));
+        post.assert_async().await;
+        delete.assert_async().await;
+    }
+
+    fn request_with_consent_period(period: &str) -> String {
+        format!(
+            r#"{{ "requestId": "request0123456789", "content": {{ "consent": {{ "id": "TESTID1234", "patient": "TESTPATIENT1234", "status": "active", "provision": {{ "period": {} }} }} }} }}"#,
+            period
+        )
+    }
+
+    #[tokio::test]
+    async fn should_delete_mtb_file_with_expired_consent_by_default() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("DELETE", "/MTBFile/TESTPATIENT1234")
+            .with_status(200)
+            .create_async()
+            .await;
+
+        let actual = handle(
+            test_config(server.url().as_str()),
+            &request_with_consent_period(r#"{ "start": "2020-01-01", "end": "2020-12-31" }"#),
+        )
+        .await;
+
+        assert!(matches!(
+            actual,
+            Some((_, KafkaResponsePayload::SuccessfulConnection(response, _))) if response.status_code == 200
+        ));
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn should_ignore_request_with_expired_consent_if_configured() {
+        let mut config = test_config(URI);
+        config.expired_consent_policy = ExpiredConsentPolicy::Ignore;
+
+        let actual = handle(
+            config,
+            &request_with_consent_period(r#"{ "end": "2020-12-31T23:59:59+01:00" }"#),
+        )
+        .await;
+
+        assert!(matches!(
+            actual,
+            Some((
+                _,
+                KafkaResponsePayload::InvalidConsentPeriod(ConsentValidity::Expired)
+            ))
+        ))
+    }
+
+    #[tokio::test]
+    async fn should_ignore_request_with_consent_not_yet_valid() {
+        let actual = handle(
+            test_config(URI),
+            &request_with_consent_period(r#"{ "start": "2999-01-01" }"#),
+        )
+        .await;
+
+        assert!(matches!(
+            actual,
+            Some((
+                _,
+                KafkaResponsePayload::InvalidConsentPeriod(ConsentValidity::NotYetValid)
+            ))
+        ))
+    }
+
+    #[tokio::test]
+    async fn should_check_consent_period_against_record_timestamp() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("POST", "/MTBFile")
+            .with_status(201)
+            .create_async()
+            .await;
+        let config = test_config(server.url().as_str());
+        // 2024-06-15T12:00:00Z
+        let timestamp = Timestamp::CreateTime(1_718_452_800_000);
+
+        let actual = handle_message(
+            &config,
+            &Sink::new(&config).unwrap(),
+            &RecentRequestIds::new(None, None),
+            &Transforms::default(),
+            &request_with_consent_period(r#"{ "start": "2024-01-01", "end": "2024-06-30" }"#),
+            None,
+            timestamp,
+        )
+        .await
+        .unwrap()
+        .into_response();
+
+        assert!(matches!(
+            actual,
+            Some((_, KafkaResponsePayload::SuccessfulConnection(response, _))) if response.status_code == 201
+        ));
+        mock.assert_async().await;
+    }
+
+    #[test]
+    fn should_use_current_time_for_consent_period_if_configured_or_without_timestamp() {
+        let mut config = test_config(URI);
+        let timestamp = Timestamp::CreateTime(1_718_452_800_000);
+
+        assert_eq!(
+            consent_validity_time(&config, timestamp),
+            UNIX_EPOCH + Duration::from_millis(1_718_452_800_000)
+        );
+        assert!(
+            consent_validity_time(&config, Timestamp::NotAvailable)
+                > SystemTime::now() - Duration::from_secs(60)
+        );
+
+        config.consent_validity_time = ConsentValidityTime::Now;
+
+        assert!(
+            consent_validity_time(&config, timestamp) > SystemTime::now() - Duration::from_secs(60)
+        );
+    }
+
+    #[test]
+    fn should_include_reason_in_invalid_consent_period_payload() {
+        for (validity, details) in [
+            (ConsentValidity::Expired, "Consent expired"),
+            (ConsentValidity::NotYetValid, "Consent not yet valid"),
+        ] {
+            let payload = KafkaResponsePayload::InvalidConsentPeriod(validity);
+
+            let actual =
+                serde_json::from_str::<Value>(&payload.to_payload("request0123456789")).unwrap();
+
+            assert_eq!(actual["status_code"], json!(200));
+            assert_eq!(actual["error_code"], Value::Null);
+            assert_eq!(
+                actual["status_body"]["issues"][0]["details"],
+                json!(details)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn should_delete_patient_of_record_without_value_if_enabled() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("DELETE", "/MTBFile/TESTPATIENT1234")
+            .with_status(200)
+            .create_async()
+            .await;
+
+        let mut config = test_config(server.url().as_str());
+        config.null_value_deletes = true;
+
+        let actual = handle_tombstone(
+            &config,
+            &Sink::new(&config).unwrap(),
+            None,
+            "TESTPATIENT1234",
+            None,
+        )
+        .await
+        .unwrap()
+        .into_response();
+
+        assert!(matches!(
+            actual,
+            Some((request_id, KafkaResponsePayload::SuccessfulConnection(response, _))) if request_id.is_empty() && response.status_code == 200
+        ));
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn should_skip_record_without_value_by_default() {
+        let config = test_config(URI);
+
+        let actual = handle_tombstone(
+            &config,
+            &Sink::new(&config).unwrap(),
+            None,
+            "TESTPATIENT1234",
+            None,
+        )
+        .await
+        .unwrap()
+        .into_response();
+
+        assert!(actual.is_none())
+    }
+
+    #[tokio::test]
+    async fn should_not_delete_record_without_value_and_blank_key() {
+        let mut config = test_config(URI);
+        config.null_value_deletes = true;
+
+        let actual = handle_tombstone(&config, &Sink::new(&config).unwrap(), None, "  ", None)
+            .await
+            .unwrap()
+            .into_response();
+
+        assert!(matches!(
+            actual,
+            Some((_, KafkaResponsePayload::InvalidPatientId))
+        ))
+    }
+
+    #[tokio::test]
+    async fn should_respond_to_content_that_is_not_an_object_as_invalid_request() {
+        for content in [
+            r#""\"TESTPATIENT1234\"""#,
+            r#"[{ "id": "TESTPATIENT1234" }]"#,
+            "42",
+        ] {
+            let payload = format!(
+                r#"{{ "requestId": "request0123456789", "content": {} }}"#,
+                content
+            );
+
+            let actual = handle(test_config(URI), payload.as_str()).await;
+
+            assert!(matches!(
+                actual,
+                Some((request_id, KafkaResponsePayload::InvalidRequest(reason)))
+                    if request_id == "request0123456789"
+                        && reason.starts_with("Invalid request: content must be a JSON object")
+            ))
+        }
+    }
+
+    #[tokio::test]
+    async fn should_respond_to_empty_value_as_invalid_request_even_if_null_value_deletes() {
+        let mut config = test_config(URI);
+        config.null_value_deletes = true;
+
+        let actual = handle(config, "").await;
+
+        assert!(matches!(
+            actual,
+            Some((_, KafkaResponsePayload::InvalidRequest(_)))
+        ))
+    }
+
+    #[tokio::test]
+    async fn should_neither_send_nor_delete_mtb_file_with_unknown_consent_status() {
+        let mut server = mockito::Server::new_async().await;
+        let post = server
+            .mock("POST", mockito::Matcher::Any)
+            .expect(0)
+            .create_async()
+            .await;
+        let delete = server
+            .mock("DELETE", mockito::Matcher::Any)
+            .expect(0)
+            .create_async()
+            .await;
+
+        let mut config = test_config(server.url().as_str());
+        let responded = handle(config.clone(), &request_with_consent_status("withdrawn")).await;
+        config.undetermined_consent_policy = UndeterminedConsentPolicy::Drop;
+        let dropped = handle(config, &request_with_consent_status("withdrawn")).await;
+
+        // Unknown consent status results in an error response unless configured to drop
+        assert!(matches!(
+            responded,
+            Some((_, KafkaResponsePayload::UndeterminedConsent))
+        ));
+        assert!(dropped.is_none());
+        post.assert_async().await;
+        delete.assert_async().await;
+    }
+
+    fn request_with_broad_consent(provision_type: &str) -> String {
+        format!(
+            r#"{{ "requestId": "request0123456789", "content": {{ "patient": {{ "id": "TESTPATIENT1234" }}, "metadata": {{ "modelProjectConsent": {{ "provisions": [{{ "date": "2025-01-01", "purpose": "sequencing", "type": "{}" }}] }} }} }} }}"#,
+            provision_type
+        )
+    }
+
+    #[tokio::test]
+    async fn should_send_mtb_file_with_permitted_broad_consent_provision() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("POST", "/MTBFile")
+            .with_status(201)
+            .create_async()
+            .await;
+
+        let actual = handle(
+            test_config(server.url().as_str()),
+            &request_with_broad_consent("permit"),
+        )
+        .await;
+
+        assert!(matches!(
+            actual,
+            Some((_, KafkaResponsePayload::SuccessfulConnection(response, _))) if response.status_code == 201
+        ));
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn should_delete_mtb_file_with_denied_broad_consent_provision() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("DELETE", "/MTBFile/TESTPATIENT1234")
+            .with_status(200)
+            .create_async()
+            .await;
+
+        let actual = handle(
+            test_config(server.url().as_str()),
+            &request_with_broad_consent("deny"),
+        )
+        .await;
+
+        assert!(matches!(
+            actual,
+            Some((_, KafkaResponsePayload::SuccessfulConnection(response, _))) if response.status_code == 200
+        ));
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn should_reject_broad_consent_without_configured_provision() {
+        let mut config = test_config(URI);
+        config.broad_consent_provision = "reidentification".into();
+
+        let actual = handle(config, &request_with_broad_consent("permit")).await;
+
+        assert!(matches!(
+            actual,
+            Some((_, KafkaResponsePayload::UndeterminedConsent))
+        ))
+    }
+
+    fn request_with_type(request_type: &str, status: &str) -> String {
+        format!(
+            r#"{{ "requestId": "request0123456789", "type": "{}", "content": {{ "consent": {{ "id": "TESTID1234", "patient": "TESTPATIENT1234", "status": "{}" }} }} }}"#,
+            request_type, status
+        )
+    }
+
+    #[tokio::test]
+    async fn should_decide_by_request_type_and_consent_status() {
+        // Request type, consent status, expected uploads and deletes
+        for (request_type, status, uploads, deletes) in [
+            ("MTB_FILE", "active", 1, 0),
+            ("MTB_FILE", "rejected", 0, 0),
+            ("MTB_FILE", "draft", 0, 0),
+            ("DELETE", "active", 0, 1),
+            ("DELETE", "rejected", 0, 1),
+            ("DELETE", "draft", 0, 1),
+        ] {
+            let mut server = mockito::Server::new_async().await;
+            let upload = server
+                .mock("POST", "/MTBFile")
+                .with_status(201)
+                .expect(uploads)
+                .create_async()
+                .await;
+            let delete = server
+                .mock("DELETE", "/MTBFile/TESTPATIENT1234")
+                .with_status(200)
+                .expect(deletes)
+                .create_async()
+                .await;
+
+            handle(
+                test_config(server.url().as_str()),
+                &request_with_type(request_type, status),
+            )
+            .await;
+
+            upload.assert_async().await;
+            delete.assert_async().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn should_refuse_mtb_file_request_with_rejected_consent() {
+        let (request_id, payload) =
+            handle(test_config(URI), &request_with_type("MTB_FILE", "rejected"))
+                .await
+                .unwrap();
+        let actual = serde_json::from_str::<Value>(&payload.to_payload(&request_id)).unwrap();
+
+        assert_eq!(actual["status_code"], json!(403));
+        assert_eq!(
+            actual["status_body"]["issues"],
+            json!([{
+                "severity": "error",
+                "message": "Consent does not permit MTB file",
+                "details": "Consent status 'rejected'"
+            }])
+        );
+    }
+
+    #[tokio::test]
+    async fn should_refuse_mtb_file_request_without_consent_if_configured_to_delete() {
+        let mut config = test_config(URI);
+        config.missing_consent_policy = MissingConsentPolicy::Delete;
+
+        let actual = handle(
+            config,
+            r#"{ "requestId": "request0123456789", "type": "MTB_FILE", "content": { "patient": "TESTPATIENT1234" } }"#,
+        )
+        .await;
+
+        assert!(matches!(
+            actual,
+            Some((_, KafkaResponsePayload::ConsentRefused(None)))
+        ))
+    }
+
+    const REQUEST_WITH_DIFFERENT_PATIENT_IDS: &str = r#"{ "requestId": "request0123456789", "content": { "consent": { "patient": "CONSENTPATIENT", "status": "rejected" }, "patient": { "id": "TESTPATIENT1234" } } }"#;
+
+    #[tokio::test]
+    async fn should_delete_using_patient_id_of_configured_source() {
+        for (source, patient_id) in [
+            (PatientIdSource::Consent, "CONSENTPATIENT"),
+            (PatientIdSource::Patient, "TESTPATIENT1234"),
+        ] {
+            let mut server = mockito::Server::new_async().await;
+            let mock = server
+                .mock("DELETE", format!("/MTBFile/{}", patient_id).as_str())
+                .with_status(200)
+                .create_async()
+                .await;
+
+            let mut config = test_config(server.url().as_str());
+            config.patient_id_source = source;
+
+            let actual = handle(config, REQUEST_WITH_DIFFERENT_PATIENT_IDS).await;
+
+            assert!(matches!(
+                actual,
+                Some((_, KafkaResponsePayload::SuccessfulConnection(response, _))) if response.status_code == 200
+            ));
+            mock.assert_async().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn should_not_delete_with_different_patient_ids_if_strict() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("DELETE", mockito::Matcher::Any)
+            .expect(0)
+            .create_async()
+            .await;
+
+        let mut config = test_config(server.url().as_str());
+        config.strict_patient_id = true;
+
+        let actual = handle(config, REQUEST_WITH_DIFFERENT_PATIENT_IDS).await;
+
+        assert!(matches!(
+            actual,
+            Some((_, KafkaResponsePayload::PatientIdMismatch(_, _)))
+        ));
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn should_delete_with_different_patient_ids_if_strict_patient_match() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("DELETE", "/MTBFile/CONSENTPATIENT")
+            .with_status(200)
+            .create_async()
+            .await;
+
+        let mut config = test_config(server.url().as_str());
+        config.strict_patient_match = true;
+
+        let actual = handle(config, REQUEST_WITH_DIFFERENT_PATIENT_IDS).await;
+
+        assert!(matches!(
+            actual,
+            Some((_, KafkaResponsePayload::SuccessfulConnection(response, _))) if response.status_code == 200
+        ));
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn should_not_send_mtb_file_with_different_patient_ids_if_strict_patient_match() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("POST", mockito::Matcher::Any)
+            .expect(0)
+            .create_async()
+            .await;
+
+        let mut config = test_config(server.url().as_str());
+        config.strict_patient_match = true;
+
+        let actual = handle(
+            config,
+            &REQUEST_WITH_DIFFERENT_PATIENT_IDS.replace("rejected", "active"),
+        )
+        .await;
+
+        let Some((request_id, payload)) = actual else {
+            panic!("Expected response")
+        };
+        let response = payload.to_payload(&request_id);
+        assert!(!response.contains("CONSENTPATIENT"));
+        assert_eq!(
+            serde_json::from_str::<Value>(&response).unwrap(),
+            json!({
+                "request_id": "request0123456789",
+                "status_code": 400,
+                "error_code": "PATIENT_ID_MISMATCH",
+                "status_body": {
+                    "issues": [{
+                        "severity": "error",
+                        "message": "Patient ids of consent and patient differ",
+                        "details": format!(
+                            "SHA-256 of consent.patient '{:x}' and patient '{:x}'",
+                            Sha256::digest("CONSENTPATIENT"),
+                            Sha256::digest("TESTPATIENT1234")
+                        )
+                    }]
+                }
+            })
+        );
+        mock.assert_async().await;
+    }
+
+    const REQUEST_WITHOUT_CONSENT: &str = r#"{ "requestId": "request0123456789", "content": { "patient": { "id": "TESTPATIENT1234" } } }"#;
+
+    #[tokio::test]
+    async fn should_reject_request_without_consent_by_default() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("POST", mockito::Matcher::Any)
+            .expect(0)
+            .create_async()
+            .await;
+
+        let actual = handle(test_config(server.url().as_str()), REQUEST_WITHOUT_CONSENT).await;
+
+        assert!(matches!(
+            actual,
+            Some((request_id, KafkaResponsePayload::UndeterminedConsent)) if request_id == "request0123456789"
+        ));
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn should_upload_request_without_consent_if_configured() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("POST", "/MTBFile")
+            .match_body(r#"{ "patient": { "id": "TESTPATIENT1234" } }"#)
+            .with_status(201)
+            .create_async()
+            .await;
+
+        let mut config = test_config(server.url().as_str());
+        config.missing_consent_policy = MissingConsentPolicy::Upload;
+
+        let actual = handle(config, REQUEST_WITHOUT_CONSENT).await;
+
+        assert!(matches!(
+            actual,
+            Some((_, KafkaResponsePayload::SuccessfulConnection(response, _))) if response.status_code == 201
+        ));
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn should_delete_using_patient_id_of_request_without_consent_if_configured() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("DELETE", "/MTBFile/TESTPATIENT1234")
+            .with_status(200)
+            .create_async()
+            .await;
+
+        let mut config = test_config(server.url().as_str());
+        config.missing_consent_policy = MissingConsentPolicy::Delete;
+
+        let actual = handle(config, REQUEST_WITHOUT_CONSENT).await;
+
+        assert!(matches!(
+            actual,
+            Some((_, KafkaResponsePayload::SuccessfulConnection(response, _))) if response.status_code == 200
+        ));
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn should_not_delete_request_without_consent_and_patient_id() {
+        let mut config = test_config(URI);
+        config.missing_consent_policy = MissingConsentPolicy::Delete;
+
+        let actual = handle(
+            config,
+            r#"{ "requestId": "request0123456789", "content": { "patient": {} } }"#,
+        )
+        .await;
+
+        assert!(matches!(
+            actual,
+            Some((_, KafkaResponsePayload::InvalidPatientId))
+        ))
+    }
+
+    async fn parse_error_payload(payload: &str) -> Value {
+        match handle(test_config(URI), payload).await {
+            Some((request_id, response @ KafkaResponsePayload::InvalidRequest(_))) => {
+                serde_json::from_str::<Value>(&response.to_payload(&request_id)).unwrap()
+            }
+            _ => panic!("No parse error response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn should_respond_to_request_without_request_id() {
+        let actual = parse_error_payload(
+            r#"{"content": {"consent": {"patient": "TESTPATIENT1234", "status": "active"}}}"#,
+        )
+        .await;
+
+        assert_eq!(actual["request_id"], json!(""));
+        assert_eq!(actual["status_code"], json!(904));
+        assert_eq!(
+            actual["status_body"]["issues"][0]["details"],
+            json!("Invalid request: missing field `request_id` at line 1 column 76")
+        );
+    }
+
+    #[tokio::test]
+    async fn should_respond_to_request_without_content() {
+        let actual = parse_error_payload(r#"{"requestId": "request0123456789"}"#).await;
+
+        assert_eq!(actual["request_id"], json!("request0123456789"));
+        assert_eq!(actual["status_code"], json!(904));
+        assert_eq!(
+            actual["status_body"]["issues"][0]["details"],
+            json!("Invalid request: missing field `content` at line 1 column 34")
+        );
+    }
+
+    #[tokio::test]
+    async fn should_respond_to_invalid_json() {
+        let payload =
+            r#"{"requestId": "request0123456789", "content": {"patient": "TESTPATIENT1234""#;
+
+        let actual = parse_error_payload(payload).await;
+
+        assert_eq!(actual["request_id"], json!(""));
+        assert_eq!(actual["status_code"], json!(904));
+        assert!(actual["status_body"]["issues"][0]["details"]
+            .as_str()
+            .unwrap()
+            .starts_with("Invalid request: EOF while parsing an object"));
+        assert!(!actual.to_string().contains("TESTPATIENT1234"));
+    }
+
+    #[tokio::test]
+    async fn should_respond_to_request_with_empty_request_id() {
+        let jsonstr = r#"
+           {
+                "requestId": "  ",
+                "content": {
+                    "consent": {
+                        "id": "TESTID1234",
+                        "patient": "TESTPATIENT1234",
+                        "status": "active"
+                    }
+                }
+           }
+        "#;
+
+        let actual = handle(test_config(URI), jsonstr).await;
+
+        assert!(matches!(
+            actual,
+            Some((_, KafkaResponsePayload::InvalidRequestId(_)))
+        ))
+    }
+
+    #[tokio::test]
+    async fn should_respond_to_request_with_malformed_request_id() {
+        let jsonstr = r#"
+           {
+                "requestId": "request/0123456789",
+                "content": {
+                    "consent": {
+                        "id": "TESTID1234",
+                        "patient": "TESTPATIENT1234",
+                        "status": "active"
+                    }
+                }
+           }
+        "#;
+
+        let mut config = test_config(URI);
+        config.request_id_pattern = Some(Regex::new("^[A-Za-z0-9-]+$").unwrap());
+
+        let actual = handle(config, jsonstr).await;
+
+        assert!(matches!(
+            actual,
+            Some((request_id, KafkaResponsePayload::InvalidRequestId(value))) if request_id == "request/0123456789" && value == request_id
+        ))
+    }
+
+    #[tokio::test]
+    async fn should_respond_to_request_with_too_long_request_id_using_truncated_value() {
+        let jsonstr = format!(
+            r#"{{ "requestId": "{}", "content": {{ "consent": {{ "id": "TESTID1234", "patient": "TESTPATIENT1234", "status": "active" }} }} }}"#,
+            "A".repeat(2_000_000)
+        );
+
+        let (request_id, payload) = handle(test_config(URI), &jsonstr).await.unwrap();
+        let actual = serde_json::from_str::<Value>(&payload.to_payload(&request_id)).unwrap();
+
+        let truncated = format!("{}...", "A".repeat(64));
+        assert_eq!(actual["request_id"], json!(truncated));
+        assert_eq!(actual["status_code"], json!(400));
+        assert_eq!(
+            actual["status_body"]["issues"][0]["details"],
+            json!(truncated)
+        );
+    }
+
+    #[tokio::test]
+    async fn should_respond_to_request_with_request_id_not_in_uuid_format() {
+        let jsonstr = r#"
+           {
+                "requestId": "request0123456789",
+                "content": {
+                    "consent": {
+                        "id": "TESTID1234",
+                        "patient": "TESTPATIENT1234",
+                        "status": "active"
+                    }
+                }
+           }
+        "#;
+
+        let mut config = test_config(URI);
+        config.request_id_format = Some(RequestIdFormat::Uuid);
+
+        let actual = handle(config, jsonstr).await;
+
+        assert!(matches!(
+            actual,
+            Some((_, KafkaResponsePayload::InvalidRequestId(_)))
+        ))
+    }
+
+    #[test]
+    fn should_include_issue_for_non_json_response() {
+        let payload = KafkaResponsePayload::SuccessfulConnection(
+            HttpResponse {
+                status_code: 200,
+                status_body: format!("<html>{}</html>", "Maintenance".repeat(100)),
+                headers: BTreeMap::new(),
+                endpoint: None,
+                content_type: Some("text/html".into()),
+                original_status_code: None,
+                truncated: false,
+                retry_after: None,
+            },
+            None,
+        );
+
+        let actual =
+            serde_json::from_str::<Value>(&payload.to_payload("request0123456789")).unwrap();
+
+        assert_eq!(actual["status_code"], json!(200));
+        assert_eq!(
+            actual["status_body"]["issues"][0]["message"],
+            json!("Non-JSON response from backend")
+        );
+        assert_eq!(
+            actual["status_body"]["issues"][0]["details"]
+                .as_str()
+                .unwrap()
+                .chars()
+                .count(),
+            200
+        );
+    }
+
+    #[tokio::test]
+    async fn should_use_failure_status_for_non_json_response_if_configured() {
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("POST", "/MTBFile")
+            .with_status(200)
+            .with_header("Content-Type", "text/html")
+            .with_body("<html>Maintenance</html>")
+            .create_async()
+            .await;
+        let jsonstr = r#"
+           {
+                "requestId": "request0123456789",
+                "content": {
+                    "consent": {
+                        "id": "TESTID1234",
+                        "patient": "TESTPATIENT1234",
+                        "status": "active"
+                    }
+                }
+           }
+        "#;
+
+        for (non_json_as_failure, status_code) in [(false, 200), (true, 502)] {
+            let mut config = test_config(server.url().as_str());
+            config.rest_non_json_as_failure = non_json_as_failure;
+
+            let actual = handle(config, jsonstr).await;
+
+            assert!(matches!(
+                actual,
+                Some((_, KafkaResponsePayload::SuccessfulConnection(response, _))) if response.status_code == status_code
+            ))
+        }
+    }
+
+    #[tokio::test]
+    async fn should_retry_creating_kafka_client() {
+        let attempts = Cell::new(0);
+        let mut config = test_config(URI);
+        config.kafka_create_retry_delay_ms = 1;
+
+        let actual = create_with_retry(&config, "producer", || {
+            attempts.set(attempts.get() + 1);
+            if attempts.get() < 3 {
+                Err(KafkaError::ClientCreation("Broker unavailable".into()))
+            } else {
+                Ok(attempts.get())
+            }
+        })
+        .await;
+
+        assert_eq!(actual.unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn should_return_connection_error_if_kafka_client_cannot_be_created() {
+        let attempts = Cell::new(0);
+        let mut config = test_config(URI);
+        config.kafka_create_retries = 2;
+        config.kafka_create_retry_delay_ms = 1;
+
+        let actual: Result<(), AppError> = create_with_retry(&config, "producer", || {
+            attempts.set(attempts.get() + 1);
+            Err(KafkaError::ClientCreation("Broker unavailable".into()))
+        })
+        .await;
+
+        assert!(matches!(actual, Err(AppError::ConnectionError(_))));
+        assert_eq!(attempts.get(), 3);
+    }
+
+    #[tokio::test]
+    async fn should_handle_request_version_1() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("POST", "/MTBFile")
+            .with_status(201)
+            .expect(2)
+            .create_async()
+            .await;
+
+        for version in ["", r#""version": 1,"#] {
+            let jsonstr = format!(
+                r#"{{
+                    {}
+                    "requestId": "request0123456789",
+                    "content": {{
+                        "consent": {{ "id": "TESTID1234", "patient": "TESTPATIENT1234", "status": "active" }}
+                    }}
+                }}"#,
+                version
+            );
+
+            let actual = handle(test_config(server.url().as_str()), &jsonstr).await;
+
+            assert!(matches!(
+                actual,
+                Some((_, KafkaResponsePayload::SuccessfulConnection(response, _))) if response.status_code == 201
+            ))
+        }
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn should_respond_to_request_with_unknown_version() {
+        let jsonstr = r#"
+           {
+                "version": 2,
+                "requestId": "request0123456789",
+                "content": {
+                    "consent": {
+                        "id": "TESTID1234",
+                        "patient": "TESTPATIENT1234",
+                        "status": "active"
+                    }
+                }
+           }
+        "#;
+
+        let actual = handle(test_config(URI), jsonstr).await;
+
+        assert!(matches!(
+            actual,
+            Some((_, KafkaResponsePayload::UnsupportedVersion(2)))
+        ))
+    }
+
+    #[tokio::test]
+    async fn should_respond_pending_if_delete_fails_and_retry_topic_is_configured() {
+        let jsonstr = r#"
+           {
+                "requestId": "request0123456789",
+                "content": {
+                    "consent": {
+                        "id": "TESTID1234",
+                        "patient": "TESTPATIENT1234",
+                        "status": "rejected"
+                    }
+                }
+           }
+        "#;
+
+        let mut config = test_config("http://localhost:1/bwhc/etl/api");
+        config.kafka_delete_retry_topic = Some("etl-processor_delete-retry".into());
+
+        let actual = handle(config, jsonstr).await;
+
+        assert!(matches!(
+            actual,
+            Some((_, KafkaResponsePayload::DeletePending))
+        ))
+    }
+
+    #[tokio::test]
+    async fn should_not_respond_pending_without_retry_topic() {
+        let jsonstr = r#"
+           {
+                "requestId": "request0123456789",
+                "content": {
+                    "consent": {
+                        "id": "TESTID1234",
+                        "patient": "TESTPATIENT1234",
+                        "status": "rejected"
+                    }
+                }
+           }
+        "#;
+
+        let actual = handle(test_config("http://localhost:1/bwhc/etl/api"), jsonstr).await;
+
+        assert!(matches!(
+            actual,
+            Some((_, KafkaResponsePayload::ConnectionRefused))
+        ))
+    }
+
+    #[test]
+    fn should_count_delete_retry_attempts_in_header() {
+        let headers = OwnedHeaders::new().insert(Header {
+            key: "tenant",
+            value: Some("ukw"),
+        });
+        let headers = headers.as_borrowed();
+
+        assert_eq!(delete_retry_attempt(None), 0);
+        assert_eq!(delete_retry_attempt(Some(headers)), 0);
+
+        let retry_headers = delete_retry_headers(Some(headers), 1);
+        let retry_headers = delete_retry_headers(Some(retry_headers.as_borrowed()), 2);
+        let retry_headers = retry_headers.as_borrowed();
+
+        assert_eq!(delete_retry_attempt(Some(retry_headers)), 2);
+        assert_eq!(retry_headers.count(), 2);
+        assert_eq!(
+            retry_headers.get_as::<str>(0).map(|header| header.value),
+            Ok(Some("ukw"))
+        );
+    }
+
+    #[test]
+    fn should_respond_delete_failed_after_all_retries() {
+        let actual = serde_json::from_str::<Value>(
+            &KafkaResponsePayload::DeleteFailed(10).to_payload("request0123456789"),
+        )
+        .unwrap();
+
+        assert_eq!(actual["status_code"], json!(906));
+        assert_eq!(
+            actual["status_body"]["issues"][0]["details"],
+            json!("Delete not successful after 10 retries")
+        );
+    }
+
+    #[test]
+    fn should_include_raw_body_for_non_json_body_only() {
+        let bodies = [
+            (r#"{"issues":[]}"#, None),
+            ("", None),
+            (
+                "<html><body>502 Bad Gateway</body></html>",
+                Some("<html><body>502 Bad Gateway</body></html>"),
+            ),
+        ];
+
+        for (body, raw_body) in bodies {
+            let payload = KafkaResponsePayload::SuccessfulConnection(
+                HttpResponse {
+                    status_code: 502,
+                    status_body: body.to_string(),
+                    headers: BTreeMap::new(),
+                    endpoint: None,
+                    content_type: None,
+                    original_status_code: None,
+                    truncated: false,
+                    retry_after: None,
+                },
+                None,
+            );
+
+            let actual =
+                serde_json::from_str::<Value>(&payload.to_payload("request0123456789")).unwrap();
+
+            assert_eq!(actual.get("raw_body").and_then(Value::as_str), raw_body);
+        }
+    }
+
+    #[tokio::test]
+    async fn should_truncate_response_body_exceeding_maximum_size() {
+        let body =
+            json!({ "issues": [{ "severity": "error", "message": "x".repeat(2000) }] }).to_string();
+
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("POST", "/MTBFile")
+            .with_status(422)
+            .with_header("Content-Type", "application/json")
+            .with_body(&body)
+            .create_async()
+            .await;
+
+        let mut config = test_config(server.url().as_str());
+        config.max_response_body_bytes = 100;
+
+        let (request_id, payload) = handle(config, &request_with_consent_status("active"))
+            .await
+            .unwrap();
+        let actual = serde_json::from_str::<Value>(&payload.to_payload(&request_id)).unwrap();
+
+        assert_eq!(actual["status_code"], json!(422));
+        assert_eq!(actual["truncated"], json!(true));
+        assert_eq!(actual["raw_body"], json!(body[..100]));
+        assert_eq!(
+            actual["status_body"]["issues"],
+            json!([{ "severity": "warning", "message": "Response body truncated" }])
+        );
+    }
+
+    #[tokio::test]
+    async fn should_not_mark_response_body_within_maximum_size_as_truncated() {
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("POST", "/MTBFile")
+            .with_status(201)
+            .with_body(r#"{"issues":[]}"#)
+            .create_async()
+            .await;
+
+        let (request_id, payload) = handle(
+            test_config(server.url().as_str()),
+            &request_with_consent_status("active"),
+        )
+        .await
+        .unwrap();
+        let actual = serde_json::from_str::<Value>(&payload.to_payload(&request_id)).unwrap();
+
+        assert!(actual.get("truncated").is_none());
+        assert_eq!(actual["status_body"], json!({ "issues": [] }));
+    }
+
+    #[tokio::test]
+    async fn should_respond_to_accepted_request_with_callback_url() {
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("POST", "/MTBFile")
+            .with_status(202)
+            .with_header("Location", "/MTBFile/status/1")
+            .create_async()
+            .await;
+
+        let (request_id, payload) = handle(
+            test_config(server.url().as_str()),
+            &request_with_consent_status("active"),
+        )
+        .await
+        .unwrap();
+        let actual = serde_json::from_str::<Value>(&payload.to_payload(&request_id)).unwrap();
+
+        assert_eq!(actual["status_code"], json!(202));
+        assert_eq!(actual["async"], json!(true));
+        assert_eq!(actual["callback_url"], json!("/MTBFile/status/1"));
+    }
+
+    #[tokio::test]
+    async fn should_respond_to_accepted_request_with_callback_url_of_body() {
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("POST", "/MTBFile")
+            .with_status(202)
+            .with_header("Content-Type", "application/json")
+            .with_body(r#"{"statusUrl": "/MTBFile/status/2"}"#)
+            .create_async()
+            .await;
+
+        let (request_id, payload) = handle(
+            test_config(server.url().as_str()),
+            &request_with_consent_status("active"),
+        )
+        .await
+        .unwrap();
+        let actual = serde_json::from_str::<Value>(&payload.to_payload(&request_id)).unwrap();
+
+        assert_eq!(actual["async"], json!(true));
+        assert_eq!(actual["callback_url"], json!("/MTBFile/status/2"));
+    }
+
+    #[tokio::test]
+    async fn should_not_mark_created_response_as_async() {
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("POST", "/MTBFile")
+            .with_status(201)
+            .with_header("Location", "/MTBFile/TESTPATIENT1234")
+            .create_async()
+            .await;
+
+        let (request_id, payload) = handle(
+            test_config(server.url().as_str()),
+            &request_with_consent_status("active"),
+        )
+        .await
+        .unwrap();
+        let actual = serde_json::from_str::<Value>(&payload.to_payload(&request_id)).unwrap();
+
+        assert!(actual.get("async").is_none());
+        assert!(actual.get("callback_url").is_none());
+    }
+
+    #[test]
+    fn should_include_content_hash_or_full_content() {
+        for (full, expected) in [
+            (
+                false,
+                json!({"sha256": "a27901ff6ef8e19313c501a38875741ffcce7fd8e074394447fd83e04937ab83"}),
+            ),
+            (
+                true,
+                json!({
+                    "sha256": "a27901ff6ef8e19313c501a38875741ffcce7fd8e074394447fd83e04937ab83",
+                    "full": {"consent": {}}
+                }),
+            ),
+        ] {
+            let payload = KafkaResponsePayload::SuccessfulConnection(
+                HttpResponse {
+                    status_code: 200,
+                    status_body: String::new(),
+                    headers: BTreeMap::new(),
+                    endpoint: None,
+                    content_type: None,
+                    original_status_code: None,
+                    truncated: false,
+                    retry_after: None,
+                },
+                None,
+            )
+            .with_content(r#"{"consent":{}}"#, full);
+
+            let actual =
+                serde_json::from_str::<Value>(&payload.to_payload("request0123456789")).unwrap();
+
+            assert_eq!(actual["content"], expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn should_delete_multiple_patients_and_report_partial_failures() {
+        let jsonstr = r#"
+           {
+                "requestId": "request0123456789",
+                "type": "DELETE",
+                "content": {
+                    "patients": ["TESTPATIENT1234", "TESTPATIENT5678"]
+                }
+           }
+        "#;
+
+        let mut server = mockito::Server::new_async().await;
+        let deleted = server
+            .mock("DELETE", "/MTBFile/TESTPATIENT1234")
+            .with_status(200)
+            .expect(1)
+            .create_async()
+            .await;
+        let failed = server
+            .mock("DELETE", "/MTBFile/TESTPATIENT5678")
+            .with_status(404)
+            .expect(1)
+            .create_async()
+            .await;
+
+        let actual = handle(test_config(server.url().as_str()), jsonstr)
+            .await
+            .unwrap();
+
+        let actual = serde_json::from_str::<Value>(&actual.1.to_payload(&actual.0)).unwrap();
+        assert_eq!(actual["status_code"], 404);
+        assert_eq!(
+            actual["patients"],
+            json!([
+                {"patient_id": hashed_patient_id("TESTPATIENT1234"), "status_code": 200},
+                {"patient_id": hashed_patient_id("TESTPATIENT5678"), "status_code": 404}
+            ])
+        );
+        assert_eq!(actual["status_body"]["issues"].as_array().unwrap().len(), 1);
+        deleted.assert_async().await;
+        failed.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn should_respond_pending_if_delete_of_multiple_patients_partially_fails() {
+        let jsonstr = r#"
+           {
+                "requestId": "request0123456789",
+                "type": "DELETE",
+                "content": {
+                    "patients": ["TESTPATIENT1234", "TESTPATIENT5678"]
+                }
+           }
+        "#;
+
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("DELETE", "/MTBFile/TESTPATIENT1234")
+            .with_status(200)
+            .create_async()
+            .await;
+        server
+            .mock("DELETE", "/MTBFile/TESTPATIENT5678")
+            .with_status(503)
+            .create_async()
+            .await;
+
+        let mut config = test_config(server.url().as_str());
+        config.kafka_delete_retry_topic = Some("etl-processor_delete-retry".into());
+
+        let actual = handle(config, jsonstr).await;
+
+        assert!(matches!(
+            actual,
+            Some((_, KafkaResponsePayload::DeletePending))
+        ))
+    }
+
+    #[tokio::test]
+    async fn should_not_delete_without_any_patient_ids() {
+        let jsonstr = r#"
+           {
+                "requestId": "request0123456789",
+                "type": "DELETE",
+                "content": {
+                    "patients": []
+                }
+           }
+        "#;
+
+        let actual = handle(test_config(URI), jsonstr).await;
+
+        assert!(matches!(
+            actual,
+            Some((_, KafkaResponsePayload::InvalidPatientId))
+        ))
+    }
+
+    #[tokio::test]
+    async fn should_delete_multiple_patients_of_rejected_consent() {
+        let jsonstr = r#"
+           {
+                "requestId": "request0123456789",
+                "content": {
+                    "consent": {"id": "TESTID1234", "patient": "TESTPATIENT1234", "status": "rejected"},
+                    "patients": ["TESTPATIENT1234", "TESTPATIENT5678"]
+                }
+           }
+        "#;
+
+        let mut server = mockito::Server::new_async().await;
+        let deleted = server
+            .mock(
+                "DELETE",
+                mockito::Matcher::Regex("^/MTBFile/TESTPATIENT".into()),
+            )
+            .with_status(200)
+            .expect(2)
+            .create_async()
+            .await;
+
+        let actual = handle(test_config(server.url().as_str()), jsonstr).await;
+
+        assert!(matches!(
+            actual,
+            Some((_, KafkaResponsePayload::MultiPatientDelete(_)))
+        ));
+        deleted.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn should_only_include_hashed_patient_ids_in_multi_patient_delete_response() {
+        let jsonstr = r#"
+           {
+                "requestId": "request0123456789",
+                "content": {
+                    "consent": {"id": "TESTID1234", "patient": "TESTPATIENT1234", "status": "rejected"},
+                    "patients": ["TESTPATIENT1234", "TESTPATIENT5678"]
+                }
+           }
+        "#;
+
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("DELETE", "/MTBFile/TESTPATIENT1234")
+            .with_status(200)
+            .create_async()
+            .await;
+        server
+            .mock("DELETE", "/MTBFile/TESTPATIENT5678")
+            .with_status(404)
+            .create_async()
+            .await;
+
+        let (request_id, response) = handle(test_config(server.url().as_str()), jsonstr)
+            .await
+            .unwrap();
+        let actual = response.to_payload(&request_id);
+
+        assert!(!actual.contains("TESTPATIENT"), "{}", actual);
+        let actual = serde_json::from_str::<Value>(&actual).unwrap();
+        assert_eq!(
+            actual["patients"][1]["patient_id"],
+            json!(hashed_patient_id("TESTPATIENT5678"))
+        );
+        assert_eq!(
+            actual["status_body"]["issues"][0]["message"],
+            json!(format!(
+                "Delete of patient '{}' failed with status 404",
+                hashed_patient_id("TESTPATIENT5678")
+            ))
+        );
+    }
+
+    #[tokio::test]
+    async fn should_never_delete_multiple_patients_of_mtb_file_or_active_consent() {
+        for jsonstr in [
+            r#"{ "requestId": "request0123456789", "content": { "consent": { "patient": "TESTPATIENT1234", "status": "active" }, "patient": { "id": "TESTPATIENT1234" }, "patients": ["TESTPATIENT1234", "TESTPATIENT5678"] } }"#,
+            r#"{ "requestId": "request0123456789", "type": "MTB_FILE", "content": { "consent": { "patient": "TESTPATIENT1234", "status": "rejected" }, "patients": ["TESTPATIENT1234", "TESTPATIENT5678"] } }"#,
+            r#"{ "requestId": "request0123456789", "type": "MTB_FILE", "content": { "patient": { "id": "TESTPATIENT1234" }, "patients": ["TESTPATIENT1234", "TESTPATIENT5678"] } }"#,
+            r#"{ "requestId": "request0123456789", "content": { "patients": ["TESTPATIENT1234", "TESTPATIENT5678"] } }"#,
+        ] {
+            let mut server = mockito::Server::new_async().await;
+            let deleted = server
+                .mock("DELETE", mockito::Matcher::Any)
+                .expect(0)
+                .create_async()
+                .await;
+            server
+                .mock("POST", "/MTBFile")
+                .with_status(201)
+                .create_async()
+                .await;
+
+            let actual = handle(test_config(server.url().as_str()), jsonstr).await;
+
+            assert!(
+                !matches!(
+                    actual,
+                    Some((_, KafkaResponsePayload::MultiPatientDelete(_)))
+                ),
+                "{}",
+                jsonstr
+            );
+            deleted.assert_async().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn should_not_delete_any_patient_if_patient_ids_contain_other_values() {
+        let jsonstr = r#"
+           {
+                "requestId": "request0123456789",
+                "type": "DELETE",
+                "content": {
+                    "patients": [123, "TESTPATIENT1234"]
+                }
+           }
+        "#;
+
+        let mut server = mockito::Server::new_async().await;
+        let deleted = server
+            .mock("DELETE", mockito::Matcher::Any)
+            .expect(0)
+            .create_async()
+            .await;
+
+        let actual = handle(test_config(server.url().as_str()), jsonstr).await;
+
+        assert!(matches!(
+            actual,
+            Some((_, KafkaResponsePayload::InvalidPatientId))
+        ));
+        deleted.assert_async().await;
+    }
+
+    #[test]
+    fn should_store_offsets_and_commit_on_interval_if_configured() {
+        let mut config = test_config(URI);
+
+        let actual = consumer_config(&config);
+        assert_eq!(actual.get("enable.auto.commit"), Some("false"));
+        assert_eq!(actual.get("enable.auto.offset.store"), Some("false"));
+        assert_eq!(actual.get("auto.commit.interval.ms"), None);
+
+        config.commit_interval_ms = Some(2500);
+
+        let actual = consumer_config(&config);
+        assert_eq!(actual.get("enable.auto.commit"), Some("true"));
+        assert_eq!(actual.get("enable.auto.offset.store"), Some("false"));
+        assert_eq!(actual.get("auto.commit.interval.ms"), Some("2500"));
+    }
+
+    /// Offset committed by consumer group of application for first partition of topic
+    fn committed_offset(config: &Config, topic: &str) -> Option<i64> {
+        let consumer: BaseConsumer = ClientConfig::new()
+            .set("bootstrap.servers", config.kafka_bootstrap_servers.as_str())
+            .set("group.id", config.kafka_group_id())
+            .create()
+            .unwrap();
+        let mut partitions = TopicPartitionList::new();
+        partitions.add_partition(topic, 0);
+        let committed = consumer
+            .committed_offsets(partitions, Duration::from_secs(1))
+            .ok()?;
+        match committed.find_partition(topic, 0)?.offset() {
+            Offset::Offset(offset) => Some(offset),
+            _ => None,
+        }
+    }
+
+    /// Produces records to topic of mock cluster
+    async fn produce(bootstrap_servers: &str, topic: &str, payloads: &[&str]) {
+        let producer: FutureProducer = ClientConfig::new()
+            .set("bootstrap.servers", bootstrap_servers)
+            .create()
+            .unwrap();
+        for payload in payloads {
+            producer
+                .send(
+                    FutureRecord::to(topic).key("key").payload(*payload),
+                    Duration::from_secs(5),
+                )
+                .await
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn should_commit_each_processed_record_using_commit_mode() {
+        let cluster = MockCluster::new(1).unwrap();
+        let mut config = test_config(URI);
+        config.kafka_bootstrap_servers = cluster.bootstrap_servers();
+        config.kafka_commit_mode = KafkaCommitMode::Sync;
+        config.sink = SinkType::Null;
+        cluster.create_topic(&config.kafka_topic, 1, 1).unwrap();
+
+        produce(
+            &config.kafka_bootstrap_servers,
+            &config.kafka_topic,
+            &[
+                r#"{"requestId": "request0123456789", "content": {"patient": {"id": "TESTPATIENT1234"}}}"#,
+                r#"{"requestId": "request9876543210", "content": {"patient": {"id": "TESTPATIENT5678"}}}"#,
+            ],
+        )
+        .await;
+
+        let committed = async {
+            while committed_offset(&config, &config.kafka_topic) != Some(2) {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        };
+        tokio::select! {
+            result = run(&config) => panic!("Application stopped: {:?}", result),
+            result = tokio::time::timeout(Duration::from_secs(30), committed) => {
+                assert!(result.is_ok(), "Offsets of processed records not committed")
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn should_stop_without_commit_on_infrastructure_failure() {
+        let cluster = MockCluster::new(1).unwrap();
+        let mut config = test_config(URI);
+        config.kafka_bootstrap_servers = cluster.bootstrap_servers();
+        config.kafka_commit_mode = KafkaCommitMode::Sync;
+        config.sink = SinkType::File;
+        config.sink_dir = Some(env::temp_dir().join("kafka-to-bwhc-missing").join("sink"));
+        cluster.create_topic(&config.kafka_topic, 1, 1).unwrap();
+
+        produce(
+            &config.kafka_bootstrap_servers,
+            &config.kafka_topic,
+            &[&request_with_consent_status("active")],
+        )
+        .await;
+
+        let actual = tokio::time::timeout(Duration::from_secs(30), run(&config)).await;
+
+        assert!(matches!(actual, Ok(Err(AppError::IoError(_)))));
+        assert_eq!(committed_offset(&config, &config.kafka_topic), None);
+    }
+
+    #[test]
+    fn should_list_changed_settings_of_config_file() {
+        let old = BTreeMap::from([
+            (
+                "APP_REST_URI".to_string(),
+                "http://bwhc:8080/api".to_string(),
+            ),
+            ("APP_REST_TIMEOUT".to_string(), "10".to_string()),
+            ("APP_STRIP_FIELDS".to_string(), "/episode".to_string()),
+        ]);
+        let new = BTreeMap::from([
+            (
+                "APP_REST_URI".to_string(),
+                "http://bwhc2:8080/api".to_string(),
+            ),
+            ("APP_REST_TIMEOUT".to_string(), "10".to_string()),
+            ("APP_REST_HTTP2".to_string(), "true".to_string()),
+        ]);
+
+        assert_eq!(
+            changed_settings(&old, &new),
+            vec!["APP_REST_HTTP2", "APP_REST_URI", "APP_STRIP_FIELDS"]
+        );
+        assert!(changed_settings(&old, &old).is_empty());
+    }
+
+    #[test]
+    fn should_select_configured_commit_mode() {
+        let mut config = test_config(URI);
+        assert!(matches!(commit_mode(&config), CommitMode::Async));
+
+        config.kafka_commit_mode = KafkaCommitMode::Sync;
+        assert!(matches!(commit_mode(&config), CommitMode::Sync));
+    }
+
+    #[test]
+    fn should_apply_fetch_sizes_if_configured() {
+        let mut config = test_config(URI);
+
+        let actual = consumer_config(&config);
+        assert_eq!(actual.get("fetch.min.bytes"), None);
+        assert_eq!(actual.get("fetch.max.bytes"), None);
+        assert_eq!(actual.get("max.partition.fetch.bytes"), None);
+
+        config.kafka_fetch_min_bytes = Some(65536);
+        config.kafka_fetch_max_bytes = Some(104857600);
+        config.kafka_max_partition_fetch_bytes = Some(10485760);
+
+        let actual = consumer_config(&config);
+        assert_eq!(actual.get("fetch.min.bytes"), Some("65536"));
+        assert_eq!(actual.get("fetch.max.bytes"), Some("104857600"));
+        assert_eq!(actual.get("max.partition.fetch.bytes"), Some("10485760"));
+    }
+
+    #[test]
+    fn should_warn_at_80_percent_of_max_poll_interval() {
+        let mut config = test_config(URI);
+
+        assert_eq!(consumer_config(&config).get("max.poll.interval.ms"), None);
+        assert_eq!(poll_interval_warning(&config), Duration::from_secs(240));
+
+        config.kafka_max_poll_interval_ms = Some(60_000);
+
+        assert_eq!(
+            consumer_config(&config).get("max.poll.interval.ms"),
+            Some("60000")
+        );
+        assert_eq!(poll_interval_warning(&config), Duration::from_secs(48));
+    }
+
+    #[tokio::test]
+    async fn should_call_deadline_handler_once_if_processing_approaches_poll_deadline() {
+        let calls = Cell::new(0);
+
+        let actual = with_poll_deadline(
+            Duration::from_millis(20),
+            |elapsed| {
+                assert!(elapsed >= Duration::from_millis(20));
+                calls.set(calls.get() + 1)
+            },
+            async {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                "processed"
+            },
+        )
+        .await;
+
+        assert_eq!(actual, "processed");
+        assert_eq!(calls.get(), 1);
+    }
+
+    #[tokio::test]
+    async fn should_only_commit_processed_records_when_approaching_poll_deadline() {
+        for commit_interval_ms in [None, Some(3_600_000)] {
+            let cluster = MockCluster::new(1).unwrap();
+            let mut config = test_config(URI);
+            config.kafka_bootstrap_servers = cluster.bootstrap_servers();
+            config.kafka_commit_mode = KafkaCommitMode::Sync;
+            config.commit_interval_ms = commit_interval_ms;
+            cluster.create_topic(&config.kafka_topic, 1, 1).unwrap();
+            produce(
+                &config.kafka_bootstrap_servers,
+                &config.kafka_topic,
+                &["processed", "in progress"],
+            )
+            .await;
+
+            let consumer: LoggingConsumer = consumer_config(&config)
+                .create_with_context(CustomContext)
+                .unwrap();
+            consumer.subscribe(&[&config.kafka_topic]).unwrap();
+            let processed = consumer.recv().await.unwrap();
+            commit_record(&config, &consumer, &processed);
+            let _in_progress = consumer.recv().await.unwrap();
+
+            approaching_poll_deadline(&config, &consumer, Duration::from_secs(240));
+
+            assert_eq!(committed_offset(&config, &config.kafka_topic), Some(1));
+        }
+    }
+
+    #[tokio::test]
+    async fn should_not_call_deadline_handler_if_processing_is_fast() {
+        let calls = Cell::new(0);
+
+        let actual = with_poll_deadline(
+            Duration::from_millis(100),
+            |_| calls.set(calls.get() + 1),
+            async { "processed" },
+        )
+        .await;
+
+        assert_eq!(actual, "processed");
+        assert_eq!(calls.get(), 0);
+    }
+
+    #[tokio::test]
+    async fn should_respond_with_timeout_if_backend_does_not_respond() {
+        let jsonstr = r#"
+           {
+                "requestId": "request0123456789",
+                "content": {
+                    "consent": {
+                        "id": "TESTID1234",
+                        "patient": "TESTPATIENT1234",
+                        "status": "rejected"
+                    }
+                }
+           }
+        "#;
+
+        // Accepts connections without ever sending a response
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut config = test_config(&format!("http://{}", listener.local_addr().unwrap()));
+        config.rest_timeout = 1;
+
+        let actual = handle(config, jsonstr).await.unwrap();
+
+        assert!(matches!(actual.1, KafkaResponsePayload::Timeout));
+        let actual = serde_json::from_str::<Value>(&actual.1.to_payload(&actual.0)).unwrap();
+        assert_eq!(actual["status_code"], 901);
+    }
+
+    #[tokio::test]
+    async fn should_respond_with_connection_refused_if_backend_is_down() {
+        let jsonstr = r#"
+           {
+                "requestId": "request0123456789",
+                "content": {
+                    "consent": {
+                        "id": "TESTID1234",
+                        "patient": "TESTPATIENT1234",
+                        "status": "active"
+                    }
+                }
+           }
+        "#;
+
+        let actual = handle(test_config("http://localhost:1/bwhc/etl/api"), jsonstr)
+            .await
+            .unwrap();
+
+        assert!(matches!(actual.1, KafkaResponsePayload::ConnectionRefused));
+        let actual = serde_json::from_str::<Value>(&actual.1.to_payload(&actual.0)).unwrap();
+        assert_eq!(actual["status_code"], 902);
+    }
+
+    #[tokio::test]
+    async fn should_use_failure_status_for_issues_above_severity_threshold() {
+        let bodies = [
+            (
+                r#"{"issues":[{"severity":"error","message":"Missing diagnosis"}]}"#,
+                422,
+            ),
+            (
+                r#"{"issues":[{"severity":"warning","message":"Missing date"}]}"#,
+                201,
+            ),
+            (r#"{"patient":"TESTPATIENT1234"}"#, 201),
+        ];
+        let jsonstr = r#"
+           {
+                "requestId": "request0123456789",
+                "content": {
+                    "consent": {
+                        "id": "TESTID1234",
+                        "patient": "TESTPATIENT1234",
+                        "status": "active"
+                    }
+                }
+           }
+        "#;
+
+        for (body, status_code) in bodies {
+            let mut server = mockito::Server::new_async().await;
+            server
+                .mock("POST", "/MTBFile")
+                .with_status(201)
+                .with_header("Content-Type", "application/json")
+                .with_body(body)
+                .create_async()
+                .await;
+            let mut config = test_config(server.url().as_str());
+            config.severity_threshold = Some(Severity::Error);
+
+            let (request_id, payload) = handle(config, jsonstr).await.unwrap();
+
+            let actual = serde_json::from_str::<Value>(&payload.to_payload(&request_id)).unwrap();
+            assert_eq!(actual["status_code"], status_code);
+            if status_code == 422 {
+                assert_eq!(actual["http_status_code"], 201);
+            } else {
+                assert!(actual.get("http_status_code").is_none());
+            }
+        }
+    }
+
+    #[test]
+    fn should_skip_records_older_than_maximum_age() {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as i64;
+        let old = Timestamp::CreateTime(now - 120_000);
+        let recent = Timestamp::CreateTime(now);
+
+        let mut config = test_config(URI);
+        assert!(!is_too_old(&config, "etl-processor", old));
+
+        config.max_record_age_seconds = Some(60);
+        assert!(is_too_old(&config, "etl-processor", old));
+        assert!(!is_too_old(&config, "etl-processor", recent));
+        assert!(!is_too_old(
+            &config,
+            "etl-processor",
+            Timestamp::NotAvailable
+        ));
+    }
+
+    #[tokio::test]
+    async fn should_not_commit_deferred_delete_retry() {
+        let cluster = MockCluster::new(1).unwrap();
+        let mut config = test_config(URI);
+        config.kafka_bootstrap_servers = cluster.bootstrap_servers();
+        config.kafka_commit_mode = KafkaCommitMode::Sync;
+        config.kafka_delete_retry_topic = Some("etl-processor_delete-retry".into());
+        config.sink = SinkType::Null;
+        let retry_topic = "etl-processor_delete-retry";
+        cluster.create_topic(&config.kafka_topic, 1, 1).unwrap();
+        cluster.create_topic(retry_topic, 1, 1).unwrap();
+
+        produce(
+            &config.kafka_bootstrap_servers,
+            retry_topic,
+            &[r#"{"requestId": "request0123456789", "type": "DELETE", "content": {"patient": {"id": "TESTPATIENT1234"}}}"#],
+        )
+        .await;
+        produce(
+            &config.kafka_bootstrap_servers,
+            &config.kafka_topic,
+            &[r#"{"requestId": "request9876543210", "content": {"patient": {"id": "TESTPATIENT5678"}}}"#],
+        )
+        .await;
+
+        let committed = async {
+            while committed_offset(&config, &config.kafka_topic) != Some(1) {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+            // Give the consumer time to receive the deferred record as well
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        };
+        tokio::select! {
+            result = run(&config) => panic!("Application stopped: {:?}", result),
+            result = tokio::time::timeout(Duration::from_secs(30), committed) => {
+                assert!(result.is_ok(), "Offsets of processed records not committed")
+            }
+        }
+        assert_eq!(committed_offset(&config, retry_topic), None);
+    }
+
+    #[test]
+    fn should_retry_deletes_regardless_of_maximum_age() {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as i64;
+        let old = Timestamp::CreateTime(now - 120_000);
+
+        let mut config = test_config(URI);
+        config.max_record_age_seconds = Some(60);
+        config.kafka_delete_retry_topic = Some("etl-processor_delete-retry".into());
+
+        assert!(is_too_old(&config, "etl-processor", old));
+        assert!(!is_too_old(&config, "etl-processor_delete-retry", old));
+    }
+
+    #[tokio::test]
+    async fn should_send_content_byte_for_byte() {
+        let content = r#"{ "patient": { "id": "TESTPATIENT1234" },
+                    "consent": {"status":"active", "patient":"TESTPATIENT1234", "id":"TESTID1234"},
+                    "score": 1.50e2, "name": "M\u00fcller" }"#;
+        let jsonstr = format!(
+            r#"{{ "requestId": "request0123456789", "content": {} }}"#,
+            content
+        );
+
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("POST", "/MTBFile")
+            .match_body(mockito::Matcher::Exact(content.into()))
+            .with_status(201)
+            .create_async()
+            .await;
+
+        let actual = handle(test_config(server.url().as_str()), &jsonstr).await;
+
+        assert!(matches!(
+            actual,
+            Some((_, KafkaResponsePayload::SuccessfulConnection(response, _))) if response.status_code == 201
+        ));
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn should_send_big_numbers_and_duplicate_keys_byte_for_byte() {
+        let content = r#"{"id":9007199254740993,"consent":{"status":"active","patient":"TESTPATIENT1234","id":"TESTID1234"},"value":0.10000000000000000555,"count":123456789012345678901234567890,"code":"A","code":"B"}"#;
+        let jsonstr = format!(
+            r#"{{ "requestId": "request0123456789", "content": {} }}"#,
+            content
+        );
+
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("POST", "/MTBFile")
+            .match_body(mockito::Matcher::Exact(content.into()))
+            .with_status(201)
+            .create_async()
+            .await;
+
+        let actual = handle(test_config(server.url().as_str()), &jsonstr).await;
+
+        assert!(matches!(
+            actual,
+            Some((_, KafkaResponsePayload::SuccessfulConnection(response, _))) if response.status_code == 201
+        ));
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn should_send_unwrapped_content_sent_as_json_string() {
+        let content = r#"{"consent":{"status":"active","patient":"TESTPATIENT1234"},"patient":"TESTPATIENT1234"}"#;
+        let jsonstr = format!(
+            r#"{{ "requestId": "request0123456789", "content": {} }}"#,
+            serde_json::to_string(content).unwrap()
+        );
+
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("POST", "/MTBFile")
+            .match_body(mockito::Matcher::Exact(content.into()))
+            .with_status(201)
+            .create_async()
+            .await;
+
+        let actual = handle(test_config(server.url().as_str()), &jsonstr).await;
+
+        assert!(matches!(
+            actual,
+            Some((_, KafkaResponsePayload::SuccessfulConnection(response, _))) if response.status_code == 201
+        ));
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn should_send_decoded_content_of_gzip_base64_encoded_content() {
+        let content = r#"{"consent":{"status":"active","patient":"TESTPATIENT1234"},"patient":"TESTPATIENT1234"}"#;
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(content.as_bytes()).unwrap();
+        let jsonstr = format!(
+            r#"{{ "requestId": "request0123456789", "contentEncoding": "gzip+base64", "content": "{}" }}"#,
+            BASE64.encode(encoder.finish().unwrap())
+        );
+
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("POST", "/MTBFile")
+            .match_body(mockito::Matcher::Exact(content.into()))
+            .with_status(201)
+            .create_async()
+            .await;
+
+        let actual = handle(test_config(server.url().as_str()), &jsonstr).await;
+
+        assert!(matches!(
+            actual,
+            Some((_, KafkaResponsePayload::SuccessfulConnection(response, _))) if response.status_code == 201
+        ));
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn should_respond_with_parse_error_if_content_cannot_be_decoded() {
+        let jsonstr = r#"{ "requestId": "request0123456789", "contentEncoding": "gzip+base64", "content": "H4sI" }"#;
+
+        let actual = handle(test_config(URI), jsonstr).await;
+
+        assert!(matches!(
+            actual,
+            Some((request_id, KafkaResponsePayload::InvalidRequest(reason)))
+                if request_id == "request0123456789" && reason == "Invalid request: content is not valid gzip"
+        ));
+    }
+
+    #[tokio::test]
+    async fn should_accept_request_with_allowed_consent_issuer() {
+        let jsonstr = r#"
+           {
+                "requestId": "request0123456789",
+                "content": {
+                    "consent": {
+                        "id": "TESTID1234",
+                        "issuer": "TESTISSUER",
+                        "patient": "TESTPATIENT1234",
+                        "status": "active"
+                    }
+                }
+           }
+        "#;
+
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("POST", "/MTBFile")
+            .with_status(201)
+            .create_async()
+            .await;
+        let mut config = test_config(server.url().as_str());
+        config.allowed_consent_issuers = vec!["OTHERISSUER".into(), "TESTISSUER".into()];
+
+        let actual = handle(config, jsonstr).await;
+
+        assert!(matches!(
+            actual,
+            Some((_, KafkaResponsePayload::SuccessfulConnection(response, _))) if response.status_code == 201
+        ));
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn should_reject_request_with_disallowed_consent_issuer() {
+        let jsonstr = r#"
+           {
+                "requestId": "request0123456789",
+                "content": {
+                    "consent": {
+                        "id": "TESTID1234",
+                        "issuer": "UNKNOWNISSUER",
+                        "patient": "TESTPATIENT1234",
+                        "status": "active"
+                    }
+                }
+           }
+        "#;
+
+        let mut config = test_config(URI);
+        config.allowed_consent_issuers = vec!["TESTISSUER".into()];
+
+        let (request_id, payload) = handle(config, jsonstr).await.unwrap();
+
+        assert!(matches!(
+            payload,
+            KafkaResponsePayload::DisallowedConsentIssuer
+        ));
+        let actual = serde_json::from_str::<Value>(&payload.to_payload(&request_id)).unwrap();
+        assert_eq!(actual["status_code"], 403);
+    }
+
+    #[tokio::test]
+    async fn should_warm_up_connection_to_backend() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("HEAD", "/")
+            .with_status(200)
+            .create_async()
+            .await;
+        let mut config = test_config(server.url().as_str());
+        config.rest_warmup = true;
+
+        let actual = warm_up(&config, &Sink::new(&config).unwrap()).await;
+
+        assert!(actual.is_ok());
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn should_fail_warm_up_only_if_backend_is_required_to_be_reachable() {
+        let mut config = test_config("http://localhost:1/bwhc/etl/api");
+        config.rest_warmup = true;
+
+        assert!(warm_up(&config, &Sink::new(&config).unwrap()).await.is_ok());
+
+        config.rest_require_reachable = true;
+
+        assert!(warm_up(&config, &Sink::new(&config).unwrap())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn should_not_be_ready_until_warm_up_completed() {
+        let mut config = test_config("http://localhost:1/bwhc/etl/api");
+        config.kafka_bootstrap_servers = "localhost:1".to_string();
+        config.rest_require_reachable = true;
+        let consumer: LoggingConsumer = consumer_config(&config)
+            .create_with_context(CustomContext)
+            .unwrap();
+        let readiness = Readiness::new();
+
+        let actual = warm_up_and_set_ready(
+            &config,
+            &Sink::new(&config).unwrap(),
+            &consumer,
+            &["etl-processor"],
+            Duration::from_millis(10),
+            &readiness,
+        )
+        .await;
+
+        assert!(actual.is_err());
+        assert!(!readiness.is_ready());
+
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("HEAD", "/")
+            .with_status(200)
+            .create_async()
+            .await;
+        let config = Config {
+            rest_uri: Some(server.url()),
+            ..config
+        };
+
+        let actual = warm_up_and_set_ready(
+            &config,
+            &Sink::new(&config).unwrap(),
+            &consumer,
+            &["etl-processor"],
+            Duration::from_millis(10),
+            &readiness,
+        )
+        .await;
+
+        assert!(actual.is_ok());
+        assert!(readiness.is_ready());
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn should_return_selftest_exit_code_of_backend_health() {
+        for (status, exit_code) in [(200, 0), (404, 0), (503, SELFTEST_BACKEND_UNAVAILABLE)] {
+            let mut server = mockito::Server::new_async().await;
+            server
+                .mock("HEAD", "/")
+                .with_status(status)
+                .create_async()
+                .await;
+
+            assert_eq!(
+                selftest_backend(&test_config(server.url().as_str())).await,
+                exit_code
+            );
+        }
+
+        assert_eq!(
+            selftest_backend(&test_config("http://localhost:1/bwhc/etl/api")).await,
+            SELFTEST_BACKEND_UNAVAILABLE
+        );
+    }
+
+    #[tokio::test]
+    async fn should_return_selftest_exit_code_if_kafka_is_not_available() {
+        let mut config = test_config(URI);
+        config.kafka_bootstrap_servers = "localhost:1".into();
+
+        assert_eq!(selftest(&config).await, SELFTEST_KAFKA_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn should_skip_request_with_duplicate_request_id() {
+        let mut server = mockito::Server::new_async().await;
+        let upload = server
+            .mock("POST", "/MTBFile")
+            .with_status(201)
+            .expect(1)
+            .create_async()
+            .await;
+        let config = test_config(server.url().as_str());
+        let sink = Sink::new(&config).unwrap();
+        let recent = RecentRequestIds::new(Some(10), None);
+        let payload = request_with_consent_status("active");
+
+        let first = handle_message(
+            &config,
+            &sink,
+            &recent,
+            &Transforms::default(),
+            &payload,
+            None,
+            Timestamp::NotAvailable,
+        )
+        .await
+        .unwrap()
+        .into_response();
+        let (request_id, second) = handle_message(
+            &config,
+            &sink,
+            &recent,
+            &Transforms::default(),
+            &payload,
+            None,
+            Timestamp::NotAvailable,
+        )
+        .await
+        .unwrap()
+        .into_response()
+        .unwrap();
+        let actual = serde_json::from_str::<Value>(&second.to_payload(&request_id)).unwrap();
+
+        assert!(matches!(
+            first,
+            Some((_, KafkaResponsePayload::SuccessfulConnection(_, _)))
+        ));
+        assert_eq!(actual["request_id"], json!("request0123456789"));
+        assert_eq!(actual["status_code"], json!(905));
+        assert_eq!(actual["skipped"], json!("duplicate request_id"));
+        let first_seen_epoch_ms = actual["first_seen_epoch_ms"].as_u64().unwrap();
+        let now_epoch_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+        assert!(first_seen_epoch_ms <= now_epoch_ms);
+        assert!(first_seen_epoch_ms > now_epoch_ms - 60_000);
+        let details = actual["status_body"]["issues"][0]["details"]
+            .as_str()
+            .unwrap();
+        assert!(details.starts_with("duplicate request_id, first seen at "));
+        assert!(details.ends_with("GMT - request ids are not kept across restarts"));
+        upload.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn should_not_skip_request_sent_again_after_ttl() {
+        let mut server = mockito::Server::new_async().await;
+        let upload = server
+            .mock("POST", "/MTBFile")
+            .with_status(201)
+            .expect(2)
+            .create_async()
+            .await;
+        let config = test_config(server.url().as_str());
+        let sink = Sink::new(&config).unwrap();
+        let recent = RecentRequestIds::new(Some(10), Some(Duration::from_millis(50)));
+        let payload = request_with_consent_status("active");
+
+        let first = handle_message(
+            &config,
+            &sink,
+            &recent,
+            &Transforms::default(),
+            &payload,
+            None,
+            Timestamp::NotAvailable,
+        )
+        .await
+        .unwrap()
+        .into_response();
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        let second = handle_message(
+            &config,
+            &sink,
+            &recent,
+            &Transforms::default(),
+            &payload,
+            None,
+            Timestamp::NotAvailable,
+        )
+        .await
+        .unwrap()
+        .into_response();
+
+        for actual in [first, second] {
+            assert!(matches!(
+                actual,
+                Some((_, KafkaResponsePayload::SuccessfulConnection(response, _))) if response.status_code == 201
+            ));
+        }
+        upload.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn should_not_skip_request_sent_again_after_failure() {
+        let mut server = mockito::Server::new_async().await;
+        let upload = server
+            .mock("POST", "/MTBFile")
+            .with_status(422)
+            .expect(2)
+            .create_async()
+            .await;
+        let config = test_config(server.url().as_str());
+        let sink = Sink::new(&config).unwrap();
+        let recent = RecentRequestIds::new(Some(10), None);
+        let payload = request_with_consent_status("active");
+
+        for _ in 0..2 {
+            let actual = handle_message(
+                &config,
+                &sink,
+                &recent,
+                &Transforms::default(),
+                &payload,
+                None,
+                Timestamp::NotAvailable,
+            )
+            .await
+            .unwrap()
+            .into_response();
+
+            assert!(matches!(
+                actual,
+                Some((_, KafkaResponsePayload::SuccessfulConnection(response, _))) if response.status_code == 422
+            ));
+        }
+        upload.assert_async().await;
+    }
+
+    const CONSENT_SCHEMA: &str = r#"{ "type": "object", "required": ["consent", "patient"] }"#;
+
+    #[tokio::test]
+    async fn should_not_send_mtb_file_violating_schema() {
+        let mut server = mockito::Server::new_async().await;
+        let upload = server
+            .mock("POST", "/MTBFile")
+            .expect(0)
+            .create_async()
+            .await;
+        let config = test_config(server.url().as_str());
+        let schema = MtbFileSchema::from_str(CONSENT_SCHEMA).unwrap();
+
+        let (request_id, payload) = handle_message(
+            &config,
+            &Sink::new(&config).unwrap(),
+            &RecentRequestIds::new(None, None),
+            &Transforms {
+                schema: Some(schema),
+                ..Default::default()
+            },
+            &request_with_consent_status("active"),
+            None,
+            Timestamp::NotAvailable,
+        )
+        .await
+        .unwrap()
+        .into_response()
+        .unwrap();
+        let actual = serde_json::from_str::<Value>(&payload.to_payload(&request_id)).unwrap();
+
+        assert_eq!(actual["status_code"], json!(422));
+        assert_eq!(
+            actual["status_body"]["issues"],
+            json!([{ "severity": "error", "message": "/: missing required property 'patient'" }])
+        );
+        upload.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn should_send_mtb_file_matching_schema() {
+        let mut server = mockito::Server::new_async().await;
+        let upload = server
+            .mock("POST", "/MTBFile")
+            .with_status(201)
+            .expect(1)
+            .create_async()
+            .await;
+        let config = test_config(server.url().as_str());
+        let schema = MtbFileSchema::from_str(CONSENT_SCHEMA).unwrap();
+
+        let actual = handle_message(
+            &config,
+            &Sink::new(&config).unwrap(),
+            &RecentRequestIds::new(None, None),
+            &Transforms {
+                schema: Some(schema),
+                ..Default::default()
+            },
+            r#"{ "requestId": "request0123456789", "content": { "consent": { "patient": "TESTPATIENT1234", "status": "active" }, "patient": { "id": "TESTPATIENT1234" } } }"#,
+            None,
+            Timestamp::NotAvailable,
+        )
+        .await
+        .unwrap()
+        .into_response();
+
+        assert!(matches!(
+            actual,
+            Some((_, KafkaResponsePayload::SuccessfulConnection(response, _))) if response.status_code == 201
+        ));
+        upload.assert_async().await;
+    }
+
+    #[test]
+    fn should_fail_loading_invalid_or_missing_schema() {
+        let path = env::temp_dir().join("kafka-to-bwhc-invalid-schema.json");
+        std::fs::write(&path, r#"{ "pattern": "[" }"#).unwrap();
+
+        for path in [
+            path.clone(),
+            env::temp_dir().join("kafka-to-bwhc-missing-schema.json"),
+        ] {
+            let mut config = test_config(URI);
+            config.mtbfile_schema_file = Some(path);
+
+            assert!(load_schema(&config).is_err());
+        }
+        assert!(load_schema(&test_config(URI)).unwrap().is_none());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn should_replay_requests_of_dlq() {
+        let mut server = mockito::Server::new_async().await;
+        let upload = server
+            .mock("POST", "/MTBFile")
+            .with_status(201)
+            .expect(2)
+            .create_async()
+            .await;
+        let config = test_config(server.url().as_str());
+        let sink = Sink::new(&config).unwrap();
+
+        let records = [
+            request_with_consent_status("active"),
+            r#"{ "requestId": "request0123456789" }"#.to_string(),
+            request_with_consent_status("active"),
+        ];
+        let mut results = vec![];
+        for payload in &records {
+            results.push(
+                replay_record(&config, &sink, &Transforms::default(), payload, None, false)
+                    .await
+                    .unwrap(),
+            );
+        }
+
+        assert!(matches!(
+            results.as_slice(),
+            [
+                ReplayResult::Reprocessed(Some((
+                    _,
+                    KafkaResponsePayload::SuccessfulConnection(_, _)
+                ))),
+                ReplayResult::Failed(Some((_, KafkaResponsePayload::InvalidRequest(_)))),
+                ReplayResult::Reprocessed(Some((
+                    _,
+                    KafkaResponsePayload::SuccessfulConnection(_, _)
+                )))
+            ]
+        ));
+        upload.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn should_fail_replay_if_backend_is_not_available() {
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("POST", "/MTBFile")
+            .with_status(503)
+            .create_async()
+            .await;
+
+        for uri in [server.url(), "http://localhost:1/bwhc/etl/api".to_string()] {
+            let mut config = test_config(uri.as_str());
+            config.rest_retries = 0;
+            let sink = Sink::new(&config).unwrap();
+
+            let actual = replay_record(
+                &config,
+                &sink,
+                &Transforms::default(),
+                &request_with_consent_status("active"),
+                None,
+                false,
+            )
+            .await
+            .unwrap();
+
+            assert!(matches!(actual, ReplayResult::Failed(Some(_))));
+        }
+    }
+
+    #[tokio::test]
+    async fn should_not_send_requests_on_dry_run() {
+        let mut server = mockito::Server::new_async().await;
+        let upload = server
+            .mock("POST", "/MTBFile")
+            .expect(0)
+            .create_async()
+            .await;
+        let config = test_config(server.url().as_str());
+        let sink = Sink::new(&config).unwrap();
+
+        let actual = replay_record(
+            &config,
+            &sink,
+            &Transforms::default(),
+            &request_with_consent_status("active"),
+            None,
+            true,
+        )
+        .await
+        .unwrap();
+
+        assert!(matches!(actual, ReplayResult::Skipped));
+        upload.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn should_require_dlq_topic_to_replay() {
+        let actual = replay_dlq(&test_config(URI), false).await;
+
+        assert!(matches!(actual, Err(AppError::MissingConfig(_))));
+    }
+
+    type SpanFields = BTreeMap<&'static str, String>;
+
+    /// Test subscriber layer collecting fields of spans named `record`
+    #[derive(Clone, Default)]
+    struct RecordSpans(Arc<Mutex<Vec<SpanFields>>>);
+
+    struct FieldVisitor<'a>(&'a mut SpanFields);
+
+    impl Visit for FieldVisitor<'_> {
+        fn record_str(&mut self, field: &Field, value: &str) {
+            self.0.insert(field.name(), value.to_string());
+        }
+
+        fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+            self.0.insert(field.name(), format!("{:?}", value));
+        }
+    }
+
+    impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for RecordSpans {
+        fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+            if attrs.metadata().name() != "record" {
+                return;
+            }
+            let mut spans = self.0.lock().unwrap();
+            let mut fields = SpanFields::new();
+            attrs.record(&mut FieldVisitor(&mut fields));
+            spans.push(fields);
+            ctx.span(id)
+                .unwrap()
+                .extensions_mut()
+                .insert(spans.len() - 1);
+        }
+
+        fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+            if let Some(index) = ctx.span(id).unwrap().extensions().get::<usize>() {
+                values.record(&mut FieldVisitor(&mut self.0.lock().unwrap()[*index]));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn should_create_span_per_record() {
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("POST", "/MTBFile")
+            .with_status(201)
+            .create_async()
+            .await;
+
+        let spans = RecordSpans::default();
+        let _guard =
+            tracing::subscriber::set_default(tracing_subscriber::registry().with(spans.clone()));
+
+        let config = test_config(server.url().as_str());
+        let sink = Sink::new(&config).unwrap();
+        for (offset, payload) in [
+            (41, r#"{ "requestId": "request0123456789" }"#),
+            (
+                42,
+                r#"{ "requestId": "request9876543210", "content": { "consent": { "status": "active", "patient": "TESTPATIENT1234", "id": "TESTID1234" } } }"#,
+            ),
+        ] {
+            handle_message(
+                &config,
+                &sink,
+                &RecentRequestIds::new(None, None),
+                &Transforms::default(),
+                payload,
+                None,
+                Timestamp::NotAvailable,
+            )
+            .instrument(record_span(3, offset))
+            .await
+            .unwrap();
+        }
+
+        let fields = |request_id: &str, offset: &str, outcome: &str| {
+            SpanFields::from([
+                ("partition", "3".to_string()),
+                ("offset", offset.to_string()),
+                ("request_id", request_id.to_string()),
+                ("outcome", outcome.to_string()),
+            ])
+        };
+        assert_eq!(
+            *spans.0.lock().unwrap(),
+            vec![
+                fields("request0123456789", "41", "parse_error"),
+                fields("request9876543210", "42", "posted"),
+            ]
+        );
     }
 }
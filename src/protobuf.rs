@@ -0,0 +1,171 @@
+/*
+ * This file is part of ETL-Processor
+ *
+ * Copyright (c) 2024  Comprehensive Cancer Center Mainfranken
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published
+ * by the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use prost::Message;
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+use serde_json::Value;
+
+use crate::resources::mtbfile::DEFAULT_CONSENT_POINTER;
+
+/// Request as defined in `docs/request.proto`, content is a serialized JSON document
+#[derive(Clone, PartialEq, Message)]
+pub struct ProtoRequest {
+    #[prost(string, tag = "1")]
+    pub request_id: String,
+    #[prost(string, tag = "2")]
+    pub content: String,
+    #[prost(string, tag = "3")]
+    pub consent_status: String,
+}
+
+/// Request envelope as parsed from JSON records
+#[derive(Serialize)]
+struct Envelope<'a> {
+    #[serde(rename = "requestId")]
+    request_id: &'a str,
+    content: &'a RawValue,
+}
+
+#[derive(Deserialize)]
+struct ContentConsent {
+    consent: Option<ConsentStatus>,
+}
+
+#[derive(Deserialize)]
+struct ConsentStatus {
+    status: Option<String>,
+}
+
+/// Status of the consent located by the given JSON pointer within the content
+fn content_consent_status(content: &RawValue, consent_pointer: &str) -> Option<String> {
+    if consent_pointer == DEFAULT_CONSENT_POINTER {
+        return serde_json::from_str::<ContentConsent>(content.get())
+            .ok()
+            .and_then(|content| content.consent)
+            .and_then(|consent| consent.status);
+    }
+    serde_json::from_str::<Value>(content.get())
+        .ok()
+        .and_then(|content| {
+            content
+                .pointer(consent_pointer)?
+                .get("status")?
+                .as_str()
+                .map(str::to_string)
+        })
+}
+
+/// Decodes protobuf message into a JSON request. Content is embedded byte-for-byte.
+/// Consent status, if set, must match the status of the consent located by the given JSON pointer.
+pub fn decode(payload: &[u8], consent_pointer: &str) -> Result<String, String> {
+    let request = ProtoRequest::decode(payload).map_err(|e| e.to_string())?;
+    let content = serde_json::from_str::<&RawValue>(&request.content)
+        .map_err(|_| "content is not valid JSON".to_string())?;
+
+    let consent_status = request.consent_status.trim();
+    if !consent_status.is_empty() {
+        let content_status = content_consent_status(content, consent_pointer);
+        if content_status.as_deref().map(str::trim) != Some(consent_status) {
+            return Err("consent status differs from consent within content".into());
+        }
+    }
+
+    serde_json::to_string(&Envelope {
+        request_id: &request.request_id,
+        content,
+    })
+    .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use prost::Message;
+
+    use crate::protobuf::{decode, ProtoRequest};
+    use crate::resources::request::Request;
+
+    const CONTENT: &str = r#"{"consent":{"id":"TESTID1234","patient":"TESTPATIENT1234","status":"active"},  "episode": {"id": "1.0"}}"#;
+
+    fn fixture(consent_status: &str) -> Vec<u8> {
+        ProtoRequest {
+            request_id: "request0123456789".into(),
+            content: CONTENT.into(),
+            consent_status: consent_status.into(),
+        }
+        .encode_to_vec()
+    }
+
+    #[test]
+    fn should_round_trip_request() {
+        let message = ProtoRequest::decode(fixture("active").as_slice()).unwrap();
+
+        assert_eq!(message.request_id, "request0123456789");
+        assert_eq!(message.content, CONTENT);
+        assert_eq!(message.consent_status, "active");
+    }
+
+    #[test]
+    fn should_decode_request_with_unmodified_content() {
+        let json = decode(&fixture("active"), "/consent").unwrap();
+        let request = Request::try_from(json.as_str()).unwrap();
+
+        assert_eq!(request.request_id(), "request0123456789");
+        assert_eq!(request.consent_status(), Some("active"));
+        assert_eq!(request.content_str(), CONTENT);
+    }
+
+    #[test]
+    fn should_decode_request_without_consent_status() {
+        let json = decode(&fixture(""), "/consent").unwrap();
+
+        assert!(Request::can_parse(&json));
+    }
+
+    #[test]
+    fn should_reject_differing_consent_status() {
+        assert!(decode(&fixture("rejected"), "/consent").is_err());
+    }
+
+    #[test]
+    fn should_check_consent_status_at_json_pointer() {
+        let message = ProtoRequest {
+            request_id: "request0123456789".into(),
+            content: r#"{"metadata":{"consent":{"status":"rejected"}}}"#.into(),
+            consent_status: "rejected".into(),
+        }
+        .encode_to_vec();
+
+        assert!(decode(&message, "/metadata/consent").is_ok());
+        assert!(decode(&message, "/consent").is_err());
+    }
+
+    #[test]
+    fn should_reject_invalid_messages() {
+        assert!(decode(&[0xff, 0xff, 0xff], "/consent").is_err());
+
+        let message = ProtoRequest {
+            request_id: "request0123456789".into(),
+            content: "{ invalid".into(),
+            consent_status: String::new(),
+        }
+        .encode_to_vec();
+        assert!(decode(&message, "/consent").is_err());
+    }
+}
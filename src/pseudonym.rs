@@ -0,0 +1,289 @@
+/*
+ * This file is part of ETL-Processor
+ *
+ * Copyright (c) 2024  Comprehensive Cancer Center Mainfranken
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published
+ * by the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::collections::BTreeSet;
+use std::fs;
+
+use hmac::{Hmac, Mac};
+use serde_json::Value;
+use sha2::Sha256;
+
+use crate::config::{Config, PseudonymizeMode};
+use crate::json_pointer;
+use crate::AppError;
+use crate::AppError::{IoError, MissingConfig, ValidationError};
+
+/// JSON pointers to patient ids and references to them at any depth
+pub const DEFAULT_PSEUDONYM_PATHS: &str = "/**/patient";
+
+/// Prefix of FHIR references to patients
+const PATIENT_REFERENCE: &str = "Patient/";
+
+/// Replaces patient ids by site-scoped pseudonyms before they are sent
+pub struct Pseudonymizer {
+    salt: Vec<u8>,
+    paths: Vec<String>,
+}
+
+impl Pseudonymizer {
+    /// Pseudonymizer if enabled, fails if no salt is configured or the salt file cannot be read
+    pub fn new(config: &Config) -> Result<Option<Self>, AppError> {
+        if config.pseudonymize != Some(PseudonymizeMode::Buildin) {
+            return Ok(None);
+        }
+        let salt = match (&config.pseudonym_salt, &config.pseudonym_salt_file) {
+            (Some(salt), _) => salt.trim().to_string(),
+            (None, Some(salt_file)) => fs::read_to_string(salt_file)
+                .map_err(|e| IoError(format!("Cannot read pseudonym salt file: {}", e)))?
+                .trim()
+                .to_string(),
+            (None, None) => return Err(MissingConfig("APP_PSEUDONYM_SALT".into())),
+        };
+        if salt.is_empty() {
+            return Err(ValidationError("Empty pseudonym salt".into()));
+        }
+        Ok(Some(Pseudonymizer {
+            salt: salt.into_bytes(),
+            paths: config.pseudonym_paths.clone(),
+        }))
+    }
+
+    /// Hex encoded HMAC-SHA256 of the patient id using the salt as key
+    pub fn pseudonym(&self, patient_id: &str) -> String {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.salt).expect("HMAC accepts keys of any size");
+        mac.update(patient_id.trim().as_bytes());
+        mac.finalize()
+            .into_bytes()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    /// Replaces patient ids at all configured paths of the content by pseudonyms.
+    /// Values matched by multiple paths are replaced only once.
+    pub fn pseudonymize_content(&self, content: &mut Value) {
+        let pointers = self
+            .paths
+            .iter()
+            .flat_map(|path| json_pointer::matching_pointers(content, path))
+            .collect::<BTreeSet<_>>();
+        for pointer in pointers {
+            if let Some(value) = content.pointer_mut(&pointer) {
+                self.replace(value);
+            }
+        }
+    }
+
+    /// Consent with its patient reference replaced by a pseudonym
+    pub fn pseudonymize_consent(&self, consent: &str) -> Result<String, serde_json::Error> {
+        let mut consent = serde_json::from_str::<Value>(consent)?;
+        if let Some(patient) = consent.get_mut("patient") {
+            self.replace(patient);
+        }
+        Ok(consent.to_string())
+    }
+
+    /// Replaces patient id given as string, as object with `id` or as reference `Patient/<id>`
+    fn replace(&self, value: &mut Value) {
+        match value {
+            Value::String(patient_id) => *patient_id = self.pseudonym(patient_id),
+            Value::Object(fields) => {
+                if let Some(Value::String(patient_id)) = fields.get_mut("id") {
+                    *patient_id = self.pseudonym(patient_id);
+                }
+                if let Some(Value::String(reference)) = fields.get_mut("reference") {
+                    if let Some(patient_id) = reference.strip_prefix(PATIENT_REFERENCE) {
+                        *reference = format!("{}{}", PATIENT_REFERENCE, self.pseudonym(patient_id));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use crate::config::{test_config, Config, PseudonymizeMode};
+    use crate::pseudonym::Pseudonymizer;
+
+    fn config(salt: &str) -> Config {
+        let mut config = test_config("http://localhost");
+        config.pseudonymize = Some(PseudonymizeMode::Buildin);
+        config.pseudonym_salt = Some(salt.to_string());
+        config
+    }
+
+    #[test]
+    fn should_derive_same_pseudonym_for_same_patient_id_and_salt() {
+        let (first, second) = (config("site-salt"), config("site-salt"));
+        let first = Pseudonymizer::new(&first).unwrap().unwrap();
+        let second = Pseudonymizer::new(&second).unwrap().unwrap();
+
+        // Pseudonyms must never change, otherwise deletes would no longer match sent MTB files
+        assert_eq!(
+            first.pseudonym("TESTPATIENT1234"),
+            "6c0f0d8c68a3a0a95ab387233ea15c876e2f1c0bb39e2a2252db4986f28d8c28"
+        );
+        assert_eq!(
+            first.pseudonym("TESTPATIENT1234"),
+            second.pseudonym(" TESTPATIENT1234 ")
+        );
+        assert_ne!(
+            first.pseudonym("TESTPATIENT1234"),
+            first.pseudonym("TESTPATIENT5678")
+        );
+    }
+
+    #[test]
+    fn should_derive_different_pseudonym_for_different_salt() {
+        let (first, second) = (config("site-salt"), config("other-salt"));
+
+        assert_ne!(
+            Pseudonymizer::new(&first)
+                .unwrap()
+                .unwrap()
+                .pseudonym("TESTPATIENT1234"),
+            Pseudonymizer::new(&second)
+                .unwrap()
+                .unwrap()
+                .pseudonym("TESTPATIENT1234")
+        );
+    }
+
+    #[test]
+    fn should_not_be_enabled_without_mode() {
+        let mut config = test_config("http://localhost");
+        config.pseudonym_salt = Some("site-salt".into());
+
+        assert!(Pseudonymizer::new(&config).unwrap().is_none());
+    }
+
+    #[test]
+    fn should_fail_without_salt() {
+        let mut config = config(" ");
+        assert!(Pseudonymizer::new(&config).is_err());
+
+        config.pseudonym_salt = None;
+        assert!(Pseudonymizer::new(&config).is_err());
+
+        config.pseudonym_salt_file = Some("/nonexistent/kafka-to-bwhc-salt".into());
+        assert!(Pseudonymizer::new(&config).is_err());
+    }
+
+    #[test]
+    fn should_read_salt_from_file() {
+        let salt_file = std::env::temp_dir().join(format!(
+            "kafka-to-bwhc-pseudonym-salt-{}",
+            std::process::id()
+        ));
+        std::fs::write(&salt_file, "site-salt\n").unwrap();
+        let mut config = config("site-salt");
+        let expected = Pseudonymizer::new(&config)
+            .unwrap()
+            .unwrap()
+            .pseudonym("TESTPATIENT1234");
+        config.pseudonym_salt = None;
+        config.pseudonym_salt_file = Some(salt_file.clone());
+
+        let actual = Pseudonymizer::new(&config).unwrap().unwrap();
+
+        assert_eq!(actual.pseudonym("TESTPATIENT1234"), expected);
+        let _ = std::fs::remove_file(salt_file);
+    }
+
+    #[test]
+    fn should_replace_patient_ids_and_references_at_default_paths() {
+        let config = config("site-salt");
+        let pseudonymizer = Pseudonymizer::new(&config).unwrap().unwrap();
+        let pseudonym = pseudonymizer.pseudonym("TESTPATIENT1234");
+        let mut content = json!({
+            "patient": {"id": "TESTPATIENT1234", "gender": "female"},
+            "consent": {"id": "TESTID1234", "patient": {"reference": "Patient/TESTPATIENT1234"}, "status": "active"},
+            "episode": {"id": "1", "patient": "TESTPATIENT1234"},
+            "diagnoses": [{"id": "2", "patient": "TESTPATIENT1234", "icd10": {"code": "C25.0"}}],
+            "molecularTherapies": [{"history": [{"id": "3", "patient": "TESTPATIENT1234"}]}],
+            "carePlans": [{"recommendations": [{"id": "4", "patient": "TESTPATIENT1234"}]}],
+            "claims": [{"responses": [{"items": [{"id": "5", "patient": "TESTPATIENT1234"}]}]}]
+        });
+
+        pseudonymizer.pseudonymize_content(&mut content);
+
+        assert_eq!(
+            content,
+            json!({
+                "patient": {"id": pseudonym, "gender": "female"},
+                "consent": {"id": "TESTID1234", "patient": {"reference": format!("Patient/{}", pseudonym)}, "status": "active"},
+                "episode": {"id": "1", "patient": pseudonym},
+                "diagnoses": [{"id": "2", "patient": pseudonym, "icd10": {"code": "C25.0"}}],
+                "molecularTherapies": [{"history": [{"id": "3", "patient": pseudonym}]}],
+                "carePlans": [{"recommendations": [{"id": "4", "patient": pseudonym}]}],
+                "claims": [{"responses": [{"items": [{"id": "5", "patient": pseudonym}]}]}]
+            })
+        );
+    }
+
+    #[test]
+    fn should_replace_patient_ids_at_configured_paths_only() {
+        let mut config = config("site-salt");
+        config.pseudonym_paths = vec![
+            "/metadata/subject".into(),
+            "/cases/0/patient".into(),
+            "/*/0/patient".into(),
+        ];
+        let pseudonymizer = Pseudonymizer::new(&config).unwrap().unwrap();
+        let pseudonym = pseudonymizer.pseudonym("TESTPATIENT1234");
+        let mut content = json!({
+            "patient": "TESTPATIENT1234",
+            "metadata": {"subject": "TESTPATIENT1234"},
+            "cases": [{"patient": "TESTPATIENT1234"}, {"patient": "TESTPATIENT1234"}]
+        });
+
+        pseudonymizer.pseudonymize_content(&mut content);
+
+        assert_eq!(
+            content,
+            json!({
+                "patient": "TESTPATIENT1234",
+                "metadata": {"subject": pseudonym},
+                "cases": [{"patient": pseudonym}, {"patient": "TESTPATIENT1234"}]
+            })
+        );
+    }
+
+    #[test]
+    fn should_replace_patient_reference_of_consent() {
+        let config = config("site-salt");
+        let pseudonymizer = Pseudonymizer::new(&config).unwrap().unwrap();
+
+        let actual = pseudonymizer
+            .pseudonymize_consent(
+                r#"{"id": "TESTID1234", "patient": "TESTPATIENT1234", "status": "rejected"}"#,
+            )
+            .unwrap();
+
+        assert_eq!(
+            serde_json::from_str::<Value>(&actual).unwrap(),
+            json!({"id": "TESTID1234", "patient": pseudonymizer.pseudonym("TESTPATIENT1234"), "status": "rejected"})
+        );
+    }
+}
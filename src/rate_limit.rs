@@ -0,0 +1,113 @@
+/*
+ * This file is part of ETL-Processor
+ *
+ * Copyright (c) 2024  Comprehensive Cancer Center Mainfranken
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published
+ * by the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::debug;
+
+/// Token bucket limiting outgoing requests per second
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: f64, burst: u32) -> Self {
+        let burst = f64::from(burst.max(1));
+        RateLimiter {
+            rate,
+            burst,
+            bucket: Mutex::new(Bucket {
+                tokens: burst,
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    /// Waits until a request can be sent
+    pub async fn acquire(&self) {
+        let mut waited = Duration::ZERO;
+        loop {
+            let wait = {
+                let mut bucket = self.bucket.lock().unwrap();
+                let now = Instant::now();
+                let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+                bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+                bucket.updated = now;
+
+                if bucket.tokens >= 1.0 {
+                    bucket.tokens -= 1.0;
+                    None
+                } else {
+                    Some(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+                }
+            };
+
+            match wait {
+                Some(wait) => {
+                    tokio::time::sleep(wait).await;
+                    waited += wait;
+                }
+                None => break,
+            }
+        }
+
+        if !waited.is_zero() {
+            debug!("Rate limit: waited {} ms", waited.as_millis());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::rate_limit::RateLimiter;
+
+    #[tokio::test]
+    async fn should_not_wait_within_burst() {
+        let limiter = RateLimiter::new(1.0, 3);
+
+        let start = Instant::now();
+        for _ in 0..3 {
+            limiter.acquire().await;
+        }
+
+        assert!(start.elapsed() < Duration::from_millis(100))
+    }
+
+    #[tokio::test]
+    async fn should_wait_if_rate_exceeded() {
+        let limiter = RateLimiter::new(20.0, 1);
+
+        let start = Instant::now();
+        for _ in 0..3 {
+            limiter.acquire().await;
+        }
+
+        assert!(start.elapsed() >= Duration::from_millis(100))
+    }
+}
@@ -0,0 +1,196 @@
+/*
+ * This file is part of ETL-Processor
+ *
+ * Copyright (c) 2024  Comprehensive Cancer Center Mainfranken
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published
+ * by the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::str::FromStr;
+
+use serde::Deserialize;
+
+use crate::AppError;
+use crate::AppError::ValidationError;
+
+/// Issues contained in bwHC-Backend response body
+#[derive(Deserialize)]
+pub struct Issues {
+    issues: Vec<Issue>
+}
+
+impl Issues {
+    pub fn count(&self) -> usize {
+        self.issues.len()
+    }
+
+    pub fn count_by_severity(&self, severity: &Severity) -> usize {
+        self.issues.iter().filter(|issue| &issue.severity == severity).count()
+    }
+
+    pub fn has_severity_at_least(&self, threshold: &Severity) -> bool {
+        self.issues.iter().any(|issue| issue.severity.is_at_least(threshold))
+    }
+}
+
+impl FromStr for Issues {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match serde_json::from_str(s) {
+            Ok(o) => Ok(o),
+            Err(_) => Err(())
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Issue {
+    severity: Severity
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub enum Severity {
+    #[serde(rename = "fatal")]
+    Fatal,
+    #[serde(rename = "error")]
+    Error,
+    #[serde(rename = "warning")]
+    Warning,
+    #[serde(rename = "info")]
+    Info,
+    #[serde(other)]
+    Unknown
+}
+
+impl Severity {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Severity::Fatal => "fatal",
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+            Severity::Info => "info",
+            Severity::Unknown => "unknown"
+        }
+    }
+
+    /// Unknown severities are never at least as severe as any threshold
+    pub fn is_at_least(&self, threshold: &Severity) -> bool {
+        match (self.rank(), threshold.rank()) {
+            (Some(rank), Some(threshold)) => rank >= threshold,
+            _ => false
+        }
+    }
+
+    fn rank(&self) -> Option<u8> {
+        match self {
+            Severity::Fatal => Some(3),
+            Severity::Error => Some(2),
+            Severity::Warning => Some(1),
+            Severity::Info => Some(0),
+            Severity::Unknown => None
+        }
+    }
+}
+
+impl FromStr for Severity {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "fatal" => Ok(Severity::Fatal),
+            "error" => Ok(Severity::Error),
+            "warning" => Ok(Severity::Warning),
+            "info" => Ok(Severity::Info),
+            _ => Err(ValidationError(format!("Unknown severity '{}'", s)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use crate::resources::issues::{Issues, Severity};
+
+    #[test]
+    fn should_parse_issues() {
+        let jsonstr = r#"
+           {
+                "issues": [
+                    { "severity": "error", "message": "Missing diagnosis", "path": "MTBFile" },
+                    { "severity": "warning", "message": "Missing date", "path": "MTBFile" },
+                    { "severity": "warning", "message": "Missing date", "path": "MTBFile" },
+                    { "severity": "info", "message": "Info", "path": "MTBFile" }
+                ]
+           }
+        "#;
+
+        let actual = Issues::from_str(jsonstr).unwrap();
+
+        assert_eq!(actual.count(), 4);
+        assert_eq!(actual.count_by_severity(&Severity::Error), 1);
+        assert_eq!(actual.count_by_severity(&Severity::Warning), 2);
+        assert_eq!(actual.count_by_severity(&Severity::Info), 1);
+    }
+
+    #[test]
+    fn should_parse_empty_issues() {
+        let actual = Issues::from_str(r#"{ "issues": [] }"#).unwrap();
+
+        assert_eq!(actual.count(), 0)
+    }
+
+    #[test]
+    fn should_parse_issues_with_unknown_severity() {
+        let actual = Issues::from_str(r#"{ "issues": [{ "severity": "critical" }] }"#).unwrap();
+
+        assert_eq!(actual.count(), 1);
+        assert_eq!(actual.count_by_severity(&Severity::Unknown), 1);
+    }
+
+    #[test]
+    fn should_check_severity_threshold() {
+        let jsonstr = r#"
+           {
+                "issues": [
+                    { "severity": "warning", "message": "Missing date", "path": "MTBFile" },
+                    { "severity": "critical", "message": "Unknown", "path": "MTBFile" }
+                ]
+           }
+        "#;
+
+        let actual = Issues::from_str(jsonstr).unwrap();
+
+        assert!(actual.has_severity_at_least(&Severity::Info));
+        assert!(actual.has_severity_at_least(&Severity::Warning));
+        assert!(!actual.has_severity_at_least(&Severity::Error));
+    }
+
+    #[test]
+    fn should_parse_severity() {
+        assert_eq!(Severity::from_str("error").unwrap(), Severity::Error);
+        assert_eq!(Severity::from_str(" Warning ").unwrap(), Severity::Warning);
+        assert!(Severity::from_str("critical").is_err());
+    }
+
+    #[test]
+    fn should_not_parse_malformed_issues() {
+        assert!(Issues::from_str(r#"{ "issues": {} }"#).is_err());
+        assert!(Issues::from_str(r#"{ "issues": [{ "message": "No severity" }] }"#).is_err());
+        assert!(Issues::from_str(r#"{}"#).is_err());
+        assert!(Issues::from_str("<html></html>").is_err());
+    }
+
+}
@@ -17,5 +17,6 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
+pub mod issues;
 pub mod mtbfile;
 pub mod request;
\ No newline at end of file
@@ -17,22 +17,285 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
+use std::collections::HashMap;
 use std::str::FromStr;
+use std::time::{Duration, SystemTime};
 
-use serde::Deserialize;
+use log::warn;
+use serde::de::Error;
+use serde::{Deserialize, Deserializer};
+use serde_json::Value;
+use time::format_description::well_known::Rfc3339;
+use time::{Date, Month, OffsetDateTime};
+
+use crate::AppError;
+use crate::AppError::ValidationError;
+
+/// Field of consent entries used to select the most recent one
+pub const DEFAULT_CONSENT_DATE_FIELD: &str = "dateTime";
+
+/// Consent status permitting to send MTB files
+pub const DEFAULT_CONSENT_ACTIVE_STATUS: &str = "active";
+
+/// JSON pointer locating the consent within the content
+pub const DEFAULT_CONSENT_POINTER: &str = "/consent";
+
+/// Handling of an MTB file resulting from its consent status
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConsentDecision {
+    /// Send MTB file
+    Upload,
+    /// Delete MTB file of patient
+    Delete,
+    /// Skip MTB file and respond that it was ignored
+    Ignore
+}
+
+/// Validity of a consent at a given time according to its provision period
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConsentValidity {
+    /// Within period or no period given
+    Valid,
+    /// Period ended before given time
+    Expired,
+    /// Period starts after given time
+    NotYetValid
+}
+
+/// Primary source of the patient id, the other one is used as fallback
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PatientIdSource {
+    /// `consent.patient`
+    Consent,
+    /// `patient` or `patient.id`
+    Patient
+}
+
+impl FromStr for PatientIdSource {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "consent" => Ok(PatientIdSource::Consent),
+            "patient" => Ok(PatientIdSource::Patient),
+            _ => Err(ValidationError(format!("Unknown patient id source '{}'", s)))
+        }
+    }
+}
+
+/// Result of comparing patient ids of consent and patient
+#[derive(Clone, Debug, PartialEq)]
+pub enum PatientMatch {
+    /// Both patient ids are equal
+    Match,
+    /// Patient ids of consent and patient differ
+    Mismatch {
+        consent: String,
+
+        patient: String
+    },
+    /// At least one patient id is missing or blank
+    Incomplete
+}
 
 #[derive(Deserialize)]
 pub struct MTBFileWithConsent {
-    consent: Consent
+    consent: Option<Consent>,
+    consents: Option<Vec<Consent>>,
+    patient: Option<PatientResource>,
+    metadata: Option<Metadata>,
+    patients: Option<Value>
 }
 
 impl MTBFileWithConsent {
+    /// Parses content taking the consent from the given JSON pointer instead of `consent` and `consents`.
+    /// The consent is moved within the parsed value, an array is used as multiple consents.
+    /// A pointer to a missing or null value results in no consent.
+    pub fn parse(content: &str, consent_pointer: &str) -> Result<Self, serde_json::Error> {
+        if consent_pointer == DEFAULT_CONSENT_POINTER {
+            return serde_json::from_str(content);
+        }
+        let mut value = serde_json::from_str::<Value>(content)?;
+        let consent = value.pointer_mut(consent_pointer).map(Value::take).unwrap_or(Value::Null);
+        if let Some(fields) = value.as_object_mut() {
+            fields.remove("consent");
+            fields.remove("consents");
+            match consent {
+                Value::Null => {}
+                Value::Array(_) => {
+                    fields.insert("consents".to_string(), consent);
+                }
+                _ => {
+                    fields.insert("consent".to_string(), consent);
+                }
+            }
+        }
+        MTBFileWithConsent::deserialize(&value)
+    }
+
+    /// Uses the most recent entry of `consents` by given date field if content contains no single `consent`.
+    /// Without dates the last entry is used. Entries of the same date with different status result in the safest status.
+    pub fn select_latest_consent(&mut self, date_field: &str) {
+        if self.consent.is_some() {
+            return;
+        }
+        let Some(consents) = self.consents.take() else {
+            return;
+        };
+        let date = |consent: &Consent| consent.fields.get(date_field).and_then(Value::as_str).map(str::to_string);
+        let latest = consents.iter().filter_map(date).max();
+        let mut candidates = consents.into_iter().filter(|consent| date(consent) == latest).collect::<Vec<_>>();
+        let conflicting = candidates.iter().any(|consent| consent.status != candidates[0].status);
+        if let (Some(latest), true) = (&latest, conflicting) {
+            warn!("Consents of '{}' have conflicting status - using safest status", latest);
+            self.consent = candidates.into_iter().min_by_key(|consent| consent.status.conflict_rank());
+        } else {
+            self.consent = candidates.pop();
+        }
+    }
+
+    /// Marks the consent as given if its status is one of the given statuses. Statuses other than FHIR ConsentState
+    /// values are rejected unless given.
+    pub fn apply_active_statuses(&mut self, active_statuses: &[String]) -> Result<(), String> {
+        let Some(consent) = self.consent.as_mut() else {
+            return Ok(());
+        };
+        consent.active = active_statuses.iter().any(|status| status.trim() == consent.status.as_str());
+        match &consent.status {
+            Status::Custom(status) if !consent.active => Err(format!("unknown consent status `{}`", status)),
+            _ => Ok(())
+        }
+    }
+
+    /// Checks if consent status is one of the statuses permitting to send MTB files
     pub fn has_consent(&self) -> bool {
-        self.consent.status == Status::Active
+        self.consent.as_ref().is_some_and(|consent| consent.active)
+    }
+
+    /// Decision by consent status, using given decision for status `entered-in-error`.
+    /// If content contains no consent, decision by given provision of the broad consent.
+    /// None if content contains neither consent nor provision.
+    pub fn consent_decision(&self, entered_in_error: ConsentDecision, provision: &str) -> Option<ConsentDecision> {
+        let broad_consent = self.broad_consent_decision(provision);
+        match &self.consent {
+            Some(consent) => {
+                let decision = match consent.status {
+                    _ if self.has_consent() => ConsentDecision::Upload,
+                    Status::Rejected | Status::Inactive => ConsentDecision::Delete,
+                    Status::Draft | Status::Proposed | Status::Active | Status::Custom(_) => ConsentDecision::Ignore,
+                    Status::EnteredInError => entered_in_error
+                };
+                if broad_consent.is_some_and(|broad_consent| broad_consent != decision) {
+                    warn!(
+                        "Consent status '{}' differs from broad consent provision '{}' - using consent status",
+                        consent.status.as_str(),
+                        provision
+                    );
+                }
+                Some(decision)
+            }
+            None => broad_consent
+        }
+    }
+
+    /// Decision by latest provision of model project consent with given purpose
+    fn broad_consent_decision(&self, provision: &str) -> Option<ConsentDecision> {
+        self.metadata
+            .as_ref()
+            .and_then(|metadata| metadata.model_project_consent.as_ref())
+            .and_then(|consent| {
+                consent
+                    .provisions
+                    .iter()
+                    .filter(|p| p.purpose.eq_ignore_ascii_case(provision))
+                    .max_by(|a, b| a.date.cmp(&b.date))
+            })
+            .map(|provision| match provision.provision_type {
+                ProvisionType::Permit => ConsentDecision::Upload,
+                ProvisionType::Deny => ConsentDecision::Delete
+            })
+    }
+
+    /// Consent status as sent within the request
+    pub fn consent_status(&self) -> Option<&str> {
+        self.consent.as_ref().map(|consent| consent.status.as_str())
+    }
+
+    /// Validity of the consent at given time, valid if there is no consent or period
+    pub fn consent_validity(&self, at: SystemTime) -> ConsentValidity {
+        let period = self
+            .consent
+            .as_ref()
+            .and_then(|consent| consent.provision.as_ref())
+            .and_then(|provision| provision.period.as_ref());
+        match period {
+            Some(Period { start: Some(start), .. }) if at < start.earliest => ConsentValidity::NotYetValid,
+            Some(Period { end: Some(end), .. }) if at > end.latest => ConsentValidity::Expired,
+            _ => ConsentValidity::Valid
+        }
+    }
+
+    pub fn has_consent_entry(&self) -> bool {
+        self.consent.is_some()
+    }
+
+    /// Patient id of given source or, if missing or blank, of the other source
+    pub fn patient_id(&self, primary: PatientIdSource) -> Option<String> {
+        let consent = self.consent_patient_id();
+        let patient = self.patient_resource_id();
+        match primary {
+            PatientIdSource::Consent => consent.or(patient),
+            PatientIdSource::Patient => patient.or(consent)
+        }
+        .cloned()
+    }
+
+    /// Compares patient ids of consent and patient, ignoring surrounding whitespace
+    pub fn patient_match(&self) -> PatientMatch {
+        match (self.consent_patient_id(), self.patient_resource_id()) {
+            (Some(consent), Some(patient)) if consent.trim() == patient.trim() => PatientMatch::Match,
+            (Some(consent), Some(patient)) => PatientMatch::Mismatch {
+                consent: consent.trim().to_string(),
+                patient: patient.trim().to_string()
+            },
+            _ => PatientMatch::Incomplete
+        }
+    }
+
+    fn consent_patient_id(&self) -> Option<&String> {
+        self.consent
+            .as_ref()
+            .and_then(|consent| consent.patient.as_ref())
+            .filter(|patient_id| !patient_id.trim().is_empty())
     }
 
-    pub fn patient_id(&self) -> String {
-        self.consent.patient.clone()
+    fn patient_resource_id(&self) -> Option<&String> {
+        self.patient
+            .as_ref()
+            .and_then(|patient| patient.id())
+            .filter(|patient_id| !patient_id.trim().is_empty())
+    }
+
+    pub fn site_id(&self) -> Option<String> {
+        self.patient
+            .as_ref()
+            .and_then(|patient| patient.managing_zpm())
+            .filter(|site_id| !site_id.trim().is_empty())
+            .cloned()
+    }
+
+    /// Entries of `patients` to delete multiple patients, none if missing or not an array
+    pub fn patients(&self) -> Option<&[Value]> {
+        self.patients.as_ref().and_then(Value::as_array).map(Vec::as_slice)
+    }
+
+    /// Issuer of consent, using consent id if no issuer is given
+    pub fn consent_issuer(&self) -> Option<String> {
+        self.consent
+            .as_ref()
+            .and_then(|consent| consent.issuer.as_ref().or(consent.id.as_ref()))
+            .filter(|issuer| !issuer.trim().is_empty())
+            .cloned()
     }
 }
 
@@ -40,32 +303,255 @@ impl FromStr for MTBFileWithConsent {
     type Err = ();
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match serde_json::from_str(s) {
-            Ok(o) => Ok(o),
-            Err(_) => Err(())
-        }
+        let mut mtbfile = serde_json::from_str::<MTBFileWithConsent>(s).map_err(|_| ())?;
+        mtbfile.apply_active_statuses(&[DEFAULT_CONSENT_ACTIVE_STATUS.to_string()]).map_err(|_| ())?;
+        Ok(mtbfile)
     }
 }
 
 #[derive(Deserialize)]
 struct Consent {
+    id: Option<String>,
+    issuer: Option<String>,
     status: Status,
-    patient: String
+    #[serde(default, deserialize_with = "deserialize_patient_reference")]
+    patient: Option<String>,
+    provision: Option<ConsentProvision>,
+    /// Set if status is one of the statuses permitting to send MTB files
+    #[serde(skip)]
+    active: bool,
+    #[serde(flatten)]
+    fields: HashMap<String, Value>
+}
+
+#[derive(Deserialize)]
+struct ConsentProvision {
+    period: Option<Period>
+}
+
+/// FHIR Period, both start and end are inclusive and optional
+#[derive(Deserialize)]
+struct Period {
+    #[serde(default, deserialize_with = "deserialize_period_bound")]
+    start: Option<PeriodBound>,
+
+    #[serde(default, deserialize_with = "deserialize_period_bound")]
+    end: Option<PeriodBound>
+}
+
+/// Instants covered by a FHIR dateTime. Dates without time, e.g. `2024-03` or `2024-03-01`, cover the whole
+/// period in UTC, date and time must contain a timezone.
+#[derive(Debug, PartialEq)]
+struct PeriodBound {
+    earliest: SystemTime,
+
+    latest: SystemTime
+}
+
+impl FromStr for PeriodBound {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid consent period date '{}'", s);
+        let value = s.trim();
+        if value.contains('T') {
+            let instant = OffsetDateTime::parse(value, &Rfc3339).map_err(|_| invalid())?;
+            return Ok(PeriodBound {
+                earliest: instant.into(),
+                latest: instant.into()
+            });
+        }
+        let date = |year: i32, month: u8, day: u8| {
+            Month::try_from(month)
+                .and_then(|month| Date::from_calendar_date(year, month, day))
+                .map_err(|_| invalid())
+        };
+        // Four digits of the year, two digits of month and day
+        let parts = value
+            .split('-')
+            .enumerate()
+            .map(|(index, part)| match part.len() == if index == 0 { 4 } else { 2 } {
+                true if part.chars().all(|c| c.is_ascii_digit()) => part.parse::<i32>().map_err(|_| invalid()),
+                _ => Err(invalid())
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let (first, next) = match parts[..] {
+            [year] => (date(year, 1, 1)?, date(year + 1, 1, 1)?),
+            [year, 12] => (date(year, 12, 1)?, date(year + 1, 1, 1)?),
+            [year, month] => (date(year, month as u8, 1)?, date(year, month as u8 + 1, 1)?),
+            [year, month, day] => {
+                let first = date(year, month as u8, day as u8)?;
+                (first, first.next_day().ok_or_else(invalid)?)
+            }
+            _ => return Err(invalid())
+        };
+        Ok(PeriodBound {
+            earliest: first.midnight().assume_utc().into(),
+            latest: SystemTime::from(next.midnight().assume_utc()) - Duration::from_nanos(1)
+        })
+    }
+}
+
+fn deserialize_period_bound<'de, D>(deserializer: D) -> Result<Option<PeriodBound>, D::Error>
+where
+    D: Deserializer<'de>
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|value| PeriodBound::from_str(&value).map_err(D::Error::custom))
+        .transpose()
+}
+
+/// Patient of consent given by its id, as object with id or as FHIR reference
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PatientReference {
+    Id(String),
+    Object {
+        id: Option<String>,
+
+        reference: Option<String>
+    }
+}
+
+/// Deserializes patient reference to the plain patient id, stripping the `Patient/` prefix of a reference
+fn deserialize_patient_reference<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: Deserializer<'de>
+{
+    match Option::<PatientReference>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(PatientReference::Id(id)) | Some(PatientReference::Object { id: Some(id), .. }) => Ok(Some(id)),
+        Some(PatientReference::Object { reference: Some(reference), .. }) => {
+            Ok(Some(reference.strip_prefix("Patient/").unwrap_or(&reference).to_string()))
+        }
+        Some(PatientReference::Object { .. }) => Err(D::Error::custom("consent patient without id or reference"))
+    }
+}
+
+/// Patient given by its id or as object
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PatientResource {
+    Id(String),
+    Patient(Patient)
+}
+
+impl PatientResource {
+    fn id(&self) -> Option<&String> {
+        match self {
+            PatientResource::Id(id) => Some(id),
+            PatientResource::Patient(patient) => patient.id.as_ref()
+        }
+    }
+
+    fn managing_zpm(&self) -> Option<&String> {
+        match self {
+            PatientResource::Id(_) => None,
+            PatientResource::Patient(patient) => patient.managing_zpm.as_ref()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Patient {
+    id: Option<String>,
+    #[serde(rename = "managingZPM")]
+    managing_zpm: Option<String>
+}
+
+/// Broad consent as used by GenomDE MTB files
+#[derive(Deserialize)]
+struct Metadata {
+    #[serde(rename = "modelProjectConsent")]
+    model_project_consent: Option<ModelProjectConsent>
+}
+
+#[derive(Deserialize)]
+struct ModelProjectConsent {
+    #[serde(default)]
+    provisions: Vec<Provision>
+}
+
+#[derive(Deserialize)]
+struct Provision {
+    date: Option<String>,
+    purpose: String,
+    #[serde(rename = "type")]
+    provision_type: ProvisionType
+}
+
+#[derive(Deserialize)]
+enum ProvisionType {
+    #[serde(rename = "permit")]
+    Permit,
+    #[serde(rename = "deny")]
+    Deny
 }
 
+/// FHIR ConsentState or a site-specific status
 #[derive(Deserialize, PartialEq)]
 enum Status {
+    #[serde(rename = "draft")]
+    Draft,
+    #[serde(rename = "proposed")]
+    Proposed,
     #[serde(rename = "active")]
     Active,
     #[serde(rename = "rejected")]
-    Rejected
+    Rejected,
+    #[serde(rename = "inactive")]
+    Inactive,
+    #[serde(rename = "entered-in-error")]
+    EnteredInError,
+    #[serde(untagged)]
+    Custom(String)
+}
+
+impl Status {
+    fn as_str(&self) -> &str {
+        match self {
+            Status::Draft => "draft",
+            Status::Proposed => "proposed",
+            Status::Active => "active",
+            Status::Rejected => "rejected",
+            Status::Inactive => "inactive",
+            Status::EnteredInError => "entered-in-error",
+            Status::Custom(status) => status
+        }
+    }
+
+    /// Rank to resolve conflicting consents of the same date, lowest is safest
+    fn conflict_rank(&self) -> u8 {
+        match self {
+            Status::Rejected => 0,
+            Status::Inactive => 1,
+            Status::EnteredInError => 2,
+            Status::Proposed => 3,
+            Status::Draft => 4,
+            Status::Active | Status::Custom(_) => 5
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use std::str::FromStr;
+    use std::time::SystemTime;
 
-    use crate::resources::mtbfile::MTBFileWithConsent;
+    use time::format_description::well_known::Rfc3339;
+    use time::OffsetDateTime;
+
+    use crate::resources::mtbfile::{
+        ConsentDecision, ConsentValidity, MTBFileWithConsent, PatientIdSource, PatientMatch
+    };
+
+    fn mtb_file_with_status(status: &str) -> MTBFileWithConsent {
+        let jsonstr = format!(
+            r#"{{"consent": {{"id": "TESTID1234", "patient": "TESTPATIENT1234", "status": "{}"}}}}"#,
+            status
+        );
+        MTBFileWithConsent::from_str(&jsonstr).unwrap()
+    }
 
     #[test]
     fn should_parse_mtb_file_with_active_consent() {
@@ -101,4 +587,606 @@ mod tests {
         assert!(actual.is_ok())
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn should_return_patient_id() {
+        let jsonstr = r#"
+           {
+                "consent": {
+                    "id": "TESTID1234",
+                    "patient": "TESTPATIENT1234",
+                    "status": "rejected"
+                }
+           }
+        "#;
+
+        let actual = MTBFileWithConsent::from_str(jsonstr).unwrap();
+
+        assert_eq!(actual.patient_id(PatientIdSource::Consent), Some("TESTPATIENT1234".to_string()))
+    }
+
+    fn consent_patient_id(patient: &str) -> Option<String> {
+        let jsonstr = format!(r#"{{"consent": {{"id": "TESTID1234", "patient": {}, "status": "active"}}}}"#, patient);
+        MTBFileWithConsent::from_str(&jsonstr)
+            .unwrap()
+            .patient_id(PatientIdSource::Consent)
+    }
+
+    #[test]
+    fn should_return_patient_id_of_consent_patient_object() {
+        assert_eq!(consent_patient_id(r#"{"id": "TESTPATIENT1234"}"#), Some("TESTPATIENT1234".to_string()))
+    }
+
+    #[test]
+    fn should_return_patient_id_of_consent_patient_reference() {
+        assert_eq!(
+            consent_patient_id(r#"{"reference": "Patient/TESTPATIENT1234"}"#),
+            Some("TESTPATIENT1234".to_string())
+        );
+        assert_eq!(consent_patient_id(r#"{"reference": "TESTPATIENT1234"}"#), Some("TESTPATIENT1234".to_string()))
+    }
+
+    #[test]
+    fn should_prefer_id_of_consent_patient_object_over_reference() {
+        assert_eq!(
+            consent_patient_id(r#"{"id": "TESTPATIENT1234", "reference": "Patient/TESTPATIENT5678"}"#),
+            Some("TESTPATIENT1234".to_string())
+        )
+    }
+
+    #[test]
+    fn should_accept_null_consent_patient() {
+        assert_eq!(consent_patient_id("null"), None)
+    }
+
+    #[test]
+    fn should_not_parse_consent_patient_object_without_id() {
+        let jsonstr = r#"{"consent": {"id": "TESTID1234", "patient": {"display": "Max"}, "status": "active"}}"#;
+
+        assert!(MTBFileWithConsent::from_str(jsonstr).is_err())
+    }
+
+    #[test]
+    fn should_return_site_id() {
+        let jsonstr = r#"
+           {
+                "consent": {
+                    "id": "TESTID1234",
+                    "patient": "TESTPATIENT1234",
+                    "status": "rejected"
+                },
+                "patient": {
+                    "id": "TESTPATIENT1234",
+                    "managingZPM": "TESTSITE"
+                }
+           }
+        "#;
+
+        let actual = MTBFileWithConsent::from_str(jsonstr).unwrap();
+
+        assert_eq!(actual.site_id(), Some("TESTSITE".to_string()))
+    }
+
+    #[test]
+    fn should_return_no_patient_id_if_missing() {
+        let jsonstr = r#"
+           {
+                "consent": {
+                    "id": "TESTID1234",
+                    "status": "rejected"
+                }
+           }
+        "#;
+
+        let actual = MTBFileWithConsent::from_str(jsonstr).unwrap();
+
+        assert_eq!(actual.patient_id(PatientIdSource::Consent), None)
+    }
+
+    #[test]
+    fn should_return_no_patient_id_if_blank() {
+        let jsonstr = r#"
+           {
+                "consent": {
+                    "id": "TESTID1234",
+                    "patient": "   ",
+                    "status": "rejected"
+                }
+           }
+        "#;
+
+        let actual = MTBFileWithConsent::from_str(jsonstr).unwrap();
+
+        assert_eq!(actual.patient_id(PatientIdSource::Consent), None)
+    }
+
+    #[test]
+    fn should_return_consent_issuer() {
+        let jsonstr = r#"
+           {
+                "consent": {
+                    "id": "TESTID1234",
+                    "issuer": "TESTISSUER",
+                    "patient": "TESTPATIENT1234",
+                    "status": "active"
+                }
+           }
+        "#;
+
+        let actual = MTBFileWithConsent::from_str(jsonstr).unwrap();
+
+        assert_eq!(actual.consent_issuer(), Some("TESTISSUER".to_string()))
+    }
+
+    #[test]
+    fn should_return_consent_id_as_issuer_if_missing() {
+        let jsonstr = r#"
+           {
+                "consent": {
+                    "id": "TESTID1234",
+                    "patient": "TESTPATIENT1234",
+                    "status": "active"
+                }
+           }
+        "#;
+
+        let actual = MTBFileWithConsent::from_str(jsonstr).unwrap();
+
+        assert_eq!(actual.consent_issuer(), Some("TESTID1234".to_string()))
+    }
+
+    #[test]
+    fn should_upload_with_active_consent() {
+        let actual = mtb_file_with_status("active");
+
+        assert_eq!(actual.consent_decision(ConsentDecision::Delete, "sequencing"), Some(ConsentDecision::Upload));
+        assert_eq!(actual.consent_status(), Some("active"))
+    }
+
+    #[test]
+    fn should_delete_with_rejected_consent() {
+        let actual = mtb_file_with_status("rejected");
+
+        assert_eq!(actual.consent_decision(ConsentDecision::Ignore, "sequencing"), Some(ConsentDecision::Delete))
+    }
+
+    #[test]
+    fn should_delete_with_inactive_consent() {
+        let actual = mtb_file_with_status("inactive");
+
+        assert_eq!(actual.consent_decision(ConsentDecision::Ignore, "sequencing"), Some(ConsentDecision::Delete))
+    }
+
+    #[test]
+    fn should_ignore_draft_consent() {
+        let actual = mtb_file_with_status("draft");
+
+        assert_eq!(actual.consent_decision(ConsentDecision::Delete, "sequencing"), Some(ConsentDecision::Ignore));
+        assert_eq!(actual.consent_status(), Some("draft"))
+    }
+
+    #[test]
+    fn should_ignore_proposed_consent() {
+        let actual = mtb_file_with_status("proposed");
+
+        assert_eq!(actual.consent_decision(ConsentDecision::Delete, "sequencing"), Some(ConsentDecision::Ignore))
+    }
+
+    #[test]
+    fn should_use_configured_decision_for_consent_entered_in_error() {
+        let actual = mtb_file_with_status("entered-in-error");
+
+        assert_eq!(actual.consent_decision(ConsentDecision::Delete, "sequencing"), Some(ConsentDecision::Delete));
+        assert_eq!(actual.consent_decision(ConsentDecision::Ignore, "sequencing"), Some(ConsentDecision::Ignore));
+        assert_eq!(actual.consent_status(), Some("entered-in-error"))
+    }
+
+    #[test]
+    fn should_not_parse_mtb_file_with_unknown_consent_status() {
+        let jsonstr = r#"{"consent": {"id": "TESTID1234", "status": "unknown"}}"#;
+
+        assert!(MTBFileWithConsent::from_str(jsonstr).is_err())
+    }
+
+    fn statuses(statuses: &[&str]) -> Vec<String> {
+        statuses.iter().map(|status| status.to_string()).collect()
+    }
+
+    #[test]
+    fn should_upload_mtb_file_with_custom_active_status() {
+        let mut actual = serde_json::from_str::<MTBFileWithConsent>(r#"{"consent": {"status": "provisional"}}"#).unwrap();
+
+        assert!(actual.apply_active_statuses(&statuses(&["active", " provisional "])).is_ok());
+        assert!(actual.has_consent());
+        assert_eq!(actual.consent_decision(ConsentDecision::Delete, "sequencing"), Some(ConsentDecision::Upload));
+        assert_eq!(actual.consent_status(), Some("provisional"))
+    }
+
+    #[test]
+    fn should_ignore_mtb_file_with_active_consent_if_not_configured_as_active_status() {
+        let mut actual = serde_json::from_str::<MTBFileWithConsent>(r#"{"consent": {"status": "active"}}"#).unwrap();
+
+        assert!(actual.apply_active_statuses(&statuses(&["provisional"])).is_ok());
+        assert!(!actual.has_consent());
+        assert_eq!(actual.consent_decision(ConsentDecision::Delete, "sequencing"), Some(ConsentDecision::Ignore))
+    }
+
+    #[test]
+    fn should_keep_decision_of_other_statuses_with_custom_active_statuses() {
+        let mut actual = serde_json::from_str::<MTBFileWithConsent>(r#"{"consent": {"status": "rejected"}}"#).unwrap();
+
+        assert!(actual.apply_active_statuses(&statuses(&["active", "provisional"])).is_ok());
+        assert!(!actual.has_consent());
+        assert_eq!(actual.consent_decision(ConsentDecision::Ignore, "sequencing"), Some(ConsentDecision::Delete))
+    }
+
+    #[test]
+    fn should_reject_custom_status_not_configured_as_active_status() {
+        let mut actual = serde_json::from_str::<MTBFileWithConsent>(r#"{"consent": {"status": "provisional"}}"#).unwrap();
+
+        assert_eq!(
+            actual.apply_active_statuses(&statuses(&["active"])),
+            Err("unknown consent status `provisional`".to_string())
+        )
+    }
+
+    #[test]
+    fn should_parse_mtb_file_without_consent() {
+        let jsonstr = r#"{"patient": {"id": "TESTPATIENT1234", "managingZPM": "TESTSITE"}}"#;
+
+        let actual = MTBFileWithConsent::from_str(jsonstr).unwrap();
+
+        assert_eq!(actual.consent_decision(ConsentDecision::Delete, "sequencing"), None);
+        assert_eq!(actual.consent_status(), None);
+        assert_eq!(actual.consent_issuer(), None);
+        assert!(!actual.has_consent_entry())
+    }
+
+    fn mtb_file_with_provisions(provisions: &str) -> MTBFileWithConsent {
+        let jsonstr = format!(
+            r#"{{"patient": {{"id": "TESTPATIENT1234"}}, "metadata": {{"researchConsents": [], "modelProjectConsent": {{"version": "1", "provisions": {}}}}}}}"#,
+            provisions
+        );
+        MTBFileWithConsent::from_str(&jsonstr).unwrap()
+    }
+
+    #[test]
+    fn should_decide_by_configured_provision_of_broad_consent() {
+        let actual = mtb_file_with_provisions(
+            r#"[{"date": "2025-01-01", "purpose": "sequencing", "type": "permit"}, {"date": "2025-01-01", "purpose": "case-identification", "type": "deny"}]"#
+        );
+
+        assert_eq!(actual.consent_decision(ConsentDecision::Delete, "sequencing"), Some(ConsentDecision::Upload));
+        assert_eq!(actual.consent_decision(ConsentDecision::Delete, "case-identification"), Some(ConsentDecision::Delete));
+        assert_eq!(actual.consent_decision(ConsentDecision::Delete, "reidentification"), None);
+        assert_eq!(actual.patient_id(PatientIdSource::Consent), Some("TESTPATIENT1234".to_string()));
+        assert!(!actual.has_consent_entry())
+    }
+
+    #[test]
+    fn should_decide_by_latest_provision_of_broad_consent() {
+        let actual = mtb_file_with_provisions(
+            r#"[{"date": "2025-06-01", "purpose": "sequencing", "type": "deny"}, {"date": "2025-01-01", "purpose": "sequencing", "type": "permit"}]"#
+        );
+
+        assert_eq!(actual.consent_decision(ConsentDecision::Delete, "sequencing"), Some(ConsentDecision::Delete))
+    }
+
+    #[test]
+    fn should_prefer_consent_status_over_broad_consent() {
+        let jsonstr = r#"
+           {
+                "consent": {"id": "TESTID1234", "patient": "TESTPATIENT1234", "status": "active"},
+                "metadata": {"modelProjectConsent": {"provisions": [{"purpose": "sequencing", "type": "deny"}]}}
+           }
+        "#;
+
+        let actual = MTBFileWithConsent::from_str(jsonstr).unwrap();
+
+        assert_eq!(actual.consent_decision(ConsentDecision::Delete, "sequencing"), Some(ConsentDecision::Upload))
+    }
+
+    #[test]
+    fn should_not_parse_broad_consent_with_unknown_provision_type() {
+        let jsonstr = r#"{"metadata": {"modelProjectConsent": {"provisions": [{"purpose": "sequencing", "type": "unknown"}]}}}"#;
+
+        assert!(MTBFileWithConsent::from_str(jsonstr).is_err())
+    }
+
+    #[test]
+    fn should_return_patient_id_of_patient_without_consent() {
+        let jsonstr = r#"{"patient": {"id": "TESTPATIENT1234"}}"#;
+
+        let actual = MTBFileWithConsent::from_str(jsonstr).unwrap();
+
+        assert_eq!(actual.patient_id(PatientIdSource::Consent), Some("TESTPATIENT1234".to_string()))
+    }
+
+    #[test]
+    fn should_return_patient_id_of_patient_given_as_string_or_object() {
+        for jsonstr in [
+            r#"{"consent": {"status": "active"}, "patient": "TESTPATIENT1234"}"#,
+            r#"{"consent": {"status": "active"}, "patient": {"id": "TESTPATIENT1234"}}"#
+        ] {
+            let actual = MTBFileWithConsent::from_str(jsonstr).unwrap();
+
+            assert_eq!(actual.patient_id(PatientIdSource::Consent), Some("TESTPATIENT1234".to_string()));
+            assert_eq!(actual.patient_id(PatientIdSource::Patient), Some("TESTPATIENT1234".to_string()));
+            assert_eq!(actual.patient_match(), PatientMatch::Incomplete)
+        }
+    }
+
+    #[test]
+    fn should_return_patient_id_of_configured_primary_source() {
+        let jsonstr = r#"{"consent": {"patient": "CONSENTPATIENT", "status": "active"}, "patient": {"id": "TESTPATIENT1234"}}"#;
+
+        let actual = MTBFileWithConsent::from_str(jsonstr).unwrap();
+
+        assert_eq!(actual.patient_id(PatientIdSource::Consent), Some("CONSENTPATIENT".to_string()));
+        assert_eq!(actual.patient_id(PatientIdSource::Patient), Some("TESTPATIENT1234".to_string()));
+        assert!(matches!(actual.patient_match(), PatientMatch::Mismatch { .. }))
+    }
+
+    #[test]
+    fn should_fall_back_to_other_source_if_patient_id_is_blank() {
+        let jsonstr = r#"{"consent": {"patient": " ", "status": "active"}, "patient": "TESTPATIENT1234"}"#;
+
+        let actual = MTBFileWithConsent::from_str(jsonstr).unwrap();
+
+        assert_eq!(actual.patient_id(PatientIdSource::Consent), Some("TESTPATIENT1234".to_string()));
+        assert_eq!(actual.patient_match(), PatientMatch::Incomplete)
+    }
+
+    #[test]
+    fn should_match_equal_patient_ids() {
+        let jsonstr = r#"{"consent": {"patient": "TESTPATIENT1234 ", "status": "active"}, "patient": {"id": "TESTPATIENT1234"}}"#;
+
+        let actual = MTBFileWithConsent::from_str(jsonstr).unwrap();
+
+        assert_eq!(actual.patient_match(), PatientMatch::Match)
+    }
+
+    #[test]
+    fn should_not_match_different_patient_ids() {
+        let jsonstr = r#"{"consent": {"patient": "CONSENTPATIENT", "status": "active"}, "patient": "TESTPATIENT1234"}"#;
+
+        let actual = MTBFileWithConsent::from_str(jsonstr).unwrap();
+
+        assert_eq!(
+            actual.patient_match(),
+            PatientMatch::Mismatch {
+                consent: "CONSENTPATIENT".to_string(),
+                patient: "TESTPATIENT1234".to_string()
+            }
+        )
+    }
+
+    #[test]
+    fn should_not_compare_if_one_patient_id_is_missing() {
+        for jsonstr in [
+            r#"{"consent": {"status": "active"}, "patient": "TESTPATIENT1234"}"#,
+            r#"{"consent": {"patient": "TESTPATIENT1234", "status": "active"}}"#,
+            r#"{"consent": {"patient": "TESTPATIENT1234", "status": "active"}, "patient": {"id": " "}}"#
+        ] {
+            let actual = MTBFileWithConsent::from_str(jsonstr).unwrap();
+
+            assert_eq!(actual.patient_match(), PatientMatch::Incomplete)
+        }
+    }
+
+    #[test]
+    fn should_return_no_site_id_of_patient_given_as_string() {
+        let actual = MTBFileWithConsent::from_str(r#"{"patient": "TESTPATIENT1234"}"#).unwrap();
+
+        assert_eq!(actual.site_id(), None)
+    }
+
+    fn latest_consent(consents: &str) -> MTBFileWithConsent {
+        let jsonstr = format!(r#"{{"consents": {}}}"#, consents);
+        let mut actual = MTBFileWithConsent::from_str(&jsonstr).unwrap();
+        actual.select_latest_consent("dateTime");
+        actual
+    }
+
+    #[test]
+    fn should_use_most_recent_of_multiple_consents() {
+        let actual = latest_consent(
+            r#"[
+                {"id": "TESTID1", "status": "rejected", "dateTime": "2024-01-01T10:00:00Z"},
+                {"id": "TESTID2", "status": "active", "dateTime": "2024-03-01T10:00:00Z"},
+                {"id": "TESTID3", "status": "inactive", "dateTime": "2024-02-01T10:00:00Z"}
+            ]"#
+        );
+
+        assert_eq!(actual.consent_status(), Some("active"));
+        assert_eq!(actual.consent_issuer(), Some("TESTID2".to_string()))
+    }
+
+    #[test]
+    fn should_use_configured_date_field_of_multiple_consents() {
+        let jsonstr = r#"{"consents": [
+            {"status": "active", "date": "2024-03-01", "dateTime": "2024-01-01T10:00:00Z"},
+            {"status": "rejected", "date": "2024-01-01", "dateTime": "2024-03-01T10:00:00Z"}
+        ]}"#;
+        let mut actual = MTBFileWithConsent::from_str(jsonstr).unwrap();
+        actual.select_latest_consent("date");
+
+        assert_eq!(actual.consent_status(), Some("active"))
+    }
+
+    #[test]
+    fn should_use_last_of_multiple_consents_without_dates() {
+        let actual = latest_consent(r#"[{"status": "active"}, {"status": "rejected"}, {"status": "draft"}]"#);
+
+        assert_eq!(actual.consent_status(), Some("draft"))
+    }
+
+    #[test]
+    fn should_use_rejected_of_conflicting_consents_of_same_date() {
+        let actual = latest_consent(
+            r#"[
+                {"status": "active", "dateTime": "2024-03-01T10:00:00Z"},
+                {"status": "rejected", "dateTime": "2024-03-01T10:00:00Z"},
+                {"status": "active", "dateTime": "2024-03-01T10:00:00Z"}
+            ]"#
+        );
+
+        assert_eq!(actual.consent_status(), Some("rejected"))
+    }
+
+    #[test]
+    fn should_prefer_single_consent_over_multiple_consents() {
+        let jsonstr = r#"{
+            "consent": {"status": "rejected"},
+            "consents": [{"status": "active", "dateTime": "2024-03-01T10:00:00Z"}]
+        }"#;
+        let mut actual = MTBFileWithConsent::from_str(jsonstr).unwrap();
+        actual.select_latest_consent("dateTime");
+
+        assert_eq!(actual.consent_status(), Some("rejected"))
+    }
+
+    #[test]
+    fn should_return_no_consent_of_empty_consents() {
+        let actual = latest_consent("[]");
+
+        assert!(!actual.has_consent_entry());
+        assert_eq!(actual.consent_status(), None)
+    }
+
+    fn consent_with_period(period: &str) -> MTBFileWithConsent {
+        let jsonstr = format!(r#"{{"consent": {{"status": "active", "provision": {{"period": {}}}}}}}"#, period);
+        MTBFileWithConsent::from_str(&jsonstr).unwrap()
+    }
+
+    fn at(datetime: &str) -> SystemTime {
+        OffsetDateTime::parse(datetime, &Rfc3339).unwrap().into()
+    }
+
+    #[test]
+    fn should_compare_consent_period_with_timezone() {
+        let actual = consent_with_period(r#"{"start": "2024-03-01T00:00:00+02:00", "end": "2024-03-31T23:59:59-05:00"}"#);
+
+        assert_eq!(actual.consent_validity(at("2024-02-29T21:59:59Z")), ConsentValidity::NotYetValid);
+        assert_eq!(actual.consent_validity(at("2024-02-29T22:00:00Z")), ConsentValidity::Valid);
+        assert_eq!(actual.consent_validity(at("2024-04-01T04:59:59Z")), ConsentValidity::Valid);
+        assert_eq!(actual.consent_validity(at("2024-04-01T05:00:00Z")), ConsentValidity::Expired)
+    }
+
+    #[test]
+    fn should_include_whole_day_of_consent_period_dates_in_utc() {
+        let actual = consent_with_period(r#"{"start": "2024-03-01", "end": "2024-03-31"}"#);
+
+        assert_eq!(actual.consent_validity(at("2024-02-29T23:59:59Z")), ConsentValidity::NotYetValid);
+        assert_eq!(actual.consent_validity(at("2024-03-01T00:00:00Z")), ConsentValidity::Valid);
+        assert_eq!(actual.consent_validity(at("2024-03-31T23:59:59Z")), ConsentValidity::Valid);
+        assert_eq!(actual.consent_validity(at("2024-04-01T00:00:00Z")), ConsentValidity::Expired)
+    }
+
+    #[test]
+    fn should_include_whole_month_or_year_of_consent_period() {
+        let month = consent_with_period(r#"{"end": "2024-02"}"#);
+        let december = consent_with_period(r#"{"end": "2024-12"}"#);
+        let year = consent_with_period(r#"{"start": "2024"}"#);
+
+        assert_eq!(month.consent_validity(at("2024-02-29T12:00:00Z")), ConsentValidity::Valid);
+        assert_eq!(month.consent_validity(at("2024-03-01T00:00:00Z")), ConsentValidity::Expired);
+        assert_eq!(december.consent_validity(at("2024-12-31T23:59:59Z")), ConsentValidity::Valid);
+        assert_eq!(december.consent_validity(at("2025-01-01T00:00:00Z")), ConsentValidity::Expired);
+        assert_eq!(year.consent_validity(at("2023-12-31T23:59:59Z")), ConsentValidity::NotYetValid);
+        assert_eq!(year.consent_validity(at("2024-01-01T00:00:00Z")), ConsentValidity::Valid)
+    }
+
+    #[test]
+    fn should_treat_consent_period_without_end_as_open_ended() {
+        let actual = consent_with_period(r#"{"start": "2024-03-01T00:00:00Z"}"#);
+
+        assert_eq!(actual.consent_validity(at("2999-12-31T23:59:59Z")), ConsentValidity::Valid);
+        assert_eq!(actual.consent_validity(at("2024-02-29T23:59:59Z")), ConsentValidity::NotYetValid)
+    }
+
+    #[test]
+    fn should_treat_consent_without_period_as_valid() {
+        for jsonstr in [
+            r#"{"consent": {"status": "active"}}"#,
+            r#"{"consent": {"status": "active", "provision": {}}}"#,
+            r#"{"consent": {"status": "active", "provision": {"period": {}}}}"#,
+            r#"{"patient": "TESTPATIENT1234"}"#
+        ] {
+            let actual = MTBFileWithConsent::from_str(jsonstr).unwrap();
+
+            assert_eq!(actual.consent_validity(at("2024-03-01T00:00:00Z")), ConsentValidity::Valid)
+        }
+    }
+
+    #[test]
+    fn should_not_parse_invalid_consent_period() {
+        for period in [
+            r#"{"start": "2024-03-01T00:00:00"}"#,
+            r#"{"start": "2024-02-30"}"#,
+            r#"{"end": "2024-13"}"#,
+            r#"{"end": "2024-268"}"#,
+            r#"{"end": "2024-3-1"}"#,
+            r#"{"end": "01.03.2024"}"#,
+            r#"{"end": ""}"#
+        ] {
+            let jsonstr = format!(r#"{{"consent": {{"status": "active", "provision": {{"period": {}}}}}}}"#, period);
+
+            assert!(MTBFileWithConsent::from_str(&jsonstr).is_err(), "{}", period)
+        }
+    }
+
+    #[test]
+    fn should_parse_patient_id_source() {
+        assert_eq!(PatientIdSource::from_str("consent").unwrap(), PatientIdSource::Consent);
+        assert_eq!(PatientIdSource::from_str("Patient").unwrap(), PatientIdSource::Patient);
+        assert!(PatientIdSource::from_str("other").is_err())
+    }
+
+    #[test]
+    fn should_parse_consent_at_json_pointer() {
+        let jsonstr = r#"
+           {
+                "consent": {"patient": "TOPLEVEL", "status": "rejected"},
+                "episodesOfCare": [
+                    {"consent": {"patient": "TESTPATIENT1234", "status": "active"}}
+                ]
+           }
+        "#;
+
+        let actual = MTBFileWithConsent::parse(jsonstr, "/episodesOfCare/0/consent").unwrap();
+
+        assert_eq!(actual.consent_status(), Some("active"));
+        assert_eq!(actual.patient_id(PatientIdSource::Consent), Some("TESTPATIENT1234".to_string()))
+    }
+
+    #[test]
+    fn should_use_array_at_json_pointer_as_multiple_consents() {
+        let jsonstr = r#"
+           {
+                "metadata": {
+                    "consent": [
+                        {"status": "rejected", "dateTime": "2024-01-01"},
+                        {"status": "active", "dateTime": "2024-02-01"}
+                    ]
+                }
+           }
+        "#;
+
+        let mut actual = MTBFileWithConsent::parse(jsonstr, "/metadata/consent").unwrap();
+        actual.select_latest_consent("dateTime");
+
+        assert_eq!(actual.consent_status(), Some("active"))
+    }
+
+    #[test]
+    fn should_have_no_consent_if_json_pointer_misses() {
+        let jsonstr = r#"{"consent": {"status": "active"}, "metadata": {"consent": null}}"#;
+
+        for pointer in ["/metadata/consent", "/metadata/other", "/other/0"] {
+            let actual = MTBFileWithConsent::parse(jsonstr, pointer).unwrap();
+
+            assert_eq!(actual.consent_status(), None, "{}", pointer)
+        }
+    }
+
+}
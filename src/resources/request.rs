@@ -17,72 +17,534 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
+use std::borrow::Cow;
+use std::fmt::{Display, Formatter};
+use std::io::Read;
 use std::str::FromStr;
+use std::time::SystemTime;
 
-use serde::Deserialize;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use flate2::read::GzDecoder;
+use regex::Regex;
+use serde::de::{Error, Unexpected, Visitor};
+use serde::{Deserialize, Deserializer};
+use serde_json::value::RawValue;
 use serde_json::Value;
-use crate::resources::mtbfile::MTBFileWithConsent;
+use crate::AppError;
+use crate::AppError::ValidationError;
+use crate::resources::mtbfile::{
+    ConsentDecision, ConsentValidity, MTBFileWithConsent, PatientIdSource, PatientMatch, DEFAULT_CONSENT_ACTIVE_STATUS,
+    DEFAULT_CONSENT_DATE_FIELD, DEFAULT_CONSENT_POINTER
+};
+
+/// Maximum number of characters of an invalid request id used in responses and logs
+const MAX_REQUEST_ID_EXCERPT: usize = 64;
+
+/// Default maximum size in bytes of decompressed content
+pub const DEFAULT_MAX_DECODED_CONTENT_BYTES: u64 = 16 * 1024 * 1024;
+
+/// Encoding of content sent as base64 string of gzipped JSON
+const GZIP_BASE64: &str = "gzip+base64";
+
+/// Format request ids must have
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RequestIdFormat {
+    Uuid
+}
+
+impl RequestIdFormat {
+    fn is_match(&self, request_id: &str) -> bool {
+        match self {
+            RequestIdFormat::Uuid => {
+                request_id.len() == 36
+                    && request_id.char_indices().all(|(index, c)| match index {
+                        8 | 13 | 18 | 23 => c == '-',
+                        _ => c.is_ascii_hexdigit()
+                    })
+            }
+        }
+    }
+}
+
+impl FromStr for RequestIdFormat {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "uuid" => Ok(RequestIdFormat::Uuid),
+            _ => Err(ValidationError(format!("Unknown request id format '{}'", s)))
+        }
+    }
+}
+
+/// Request id truncated and with control characters replaced, to be used if the request id
+/// is invalid or the request cannot be parsed
+fn sanitize_request_id(request_id: &str) -> String {
+    let mut sanitized = request_id
+        .chars()
+        .take(MAX_REQUEST_ID_EXCERPT)
+        .map(|c| if c.is_control() { '?' } else { c })
+        .collect::<String>();
+    if request_id.chars().nth(MAX_REQUEST_ID_EXCERPT).is_some() {
+        sanitized.push_str("...");
+    }
+    sanitized
+}
+
+/// Explicit type of request, taking precedence over the consent status
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+pub enum RequestType {
+    #[serde(rename = "MTB_FILE")]
+    MtbFile,
+    #[serde(rename = "DELETE")]
+    Delete
+}
+
+/// Request borrowing its content from the consumed message to avoid copies of large MTB files.
+/// Only encoded content is owned after decoding. The MTB file is parsed once and reused by all accessors.
+pub struct Request<'a> {
+
+    request_id: String,
+
+    version: Option<u32>,
+
+    tenant: Option<String>,
+
+    request_type: Option<RequestType>,
+
+    content: Cow<'a, RawValue>,
+
+    consent_pointer: &'a str,
+
+    mtbfile: Result<MTBFileWithConsent, ParseError>
+
+}
 
 #[derive(Deserialize)]
-pub struct Request {
+struct Envelope<'a> {
 
-    #[serde(alias = "requestId")]
+    #[serde(alias = "requestId", deserialize_with = "deserialize_request_id")]
     request_id: String,
 
-    content: Value
+    #[serde(default)]
+    version: Option<u32>,
+
+    tenant: Option<String>,
+
+    #[serde(rename = "type")]
+    request_type: Option<RequestType>,
+
+    #[serde(borrow)]
+    content: &'a RawValue,
+
+    #[serde(rename = "contentEncoding")]
+    content_encoding: Option<String>
+
+}
 
+/// Reason why a message or its content cannot be parsed.
+/// Values quoted in the error message are redacted as they might contain patient data.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParseError {
+    message: String,
+    request_id: Option<String>
 }
 
-impl FromStr for Request {
-    type Err = ();
+impl ParseError {
+    fn new(context: &str, error: serde_json::Error) -> Self {
+        let message = error
+            .to_string()
+            .split('"')
+            .enumerate()
+            .map(|(index, part)| if index % 2 == 1 { "***" } else { part })
+            .collect::<Vec<_>>()
+            .join("\"");
+        ParseError {
+            message: format!("{}: {}", context, message),
+            request_id: None
+        }
+    }
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match serde_json::from_str(s) {
-            Ok(o) => Ok(o),
-            Err(_) => Err(())
+    fn invalid_request(request_id: &str, reason: &str) -> Self {
+        ParseError {
+            message: format!("Invalid request: {}", reason),
+            request_id: Some(request_id.to_string())
         }
     }
+
+    fn invalid_content(request_id: &str, json_type: &str) -> Self {
+        Self::invalid_request(request_id, &format!("content must be a JSON object, found {}", json_type))
+    }
+
+    /// Sanitized request id if it could be extracted from the message
+    pub fn request_id(&self) -> Option<String> {
+        self.request_id.as_deref().map(sanitize_request_id)
+    }
 }
 
-impl Request {
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
 
-    pub fn can_parse(s: &str) -> bool {
-        match Self::from_str(s) {
-            Ok(request) => {
-                MTBFileWithConsent::from_str(request.content_string().as_str()).is_ok()
-            },
-            Err(_) => false
+/// Request id only, used to respond to requests that cannot be parsed
+#[derive(Deserialize)]
+struct RequestId {
+    #[serde(alias = "requestId")]
+    request_id: Option<RequestIdValue>
+}
+
+/// Request id sent as string or, by legacy senders, as integer. Integers are used in decimal form,
+/// floating point numbers are rejected as their textual form is ambiguous.
+struct RequestIdValue(String);
+
+impl<'de> Deserialize<'de> for RequestIdValue {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct RequestIdVisitor;
+
+        impl Visitor<'_> for RequestIdVisitor {
+            type Value = RequestIdValue;
+
+            fn expecting(&self, formatter: &mut Formatter) -> std::fmt::Result {
+                formatter.write_str("a string or integer")
+            }
+
+            fn visit_str<E: Error>(self, v: &str) -> Result<Self::Value, E> {
+                Ok(RequestIdValue(v.to_string()))
+            }
+
+            fn visit_string<E: Error>(self, v: String) -> Result<Self::Value, E> {
+                Ok(RequestIdValue(v))
+            }
+
+            fn visit_i64<E: Error>(self, v: i64) -> Result<Self::Value, E> {
+                Ok(RequestIdValue(v.to_string()))
+            }
+
+            fn visit_u64<E: Error>(self, v: u64) -> Result<Self::Value, E> {
+                Ok(RequestIdValue(v.to_string()))
+            }
+
+            fn visit_f64<E: Error>(self, v: f64) -> Result<Self::Value, E> {
+                Err(E::invalid_type(Unexpected::Float(v), &self))
+            }
+        }
+
+        deserializer.deserialize_any(RequestIdVisitor)
+    }
+}
+
+fn deserialize_request_id<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    RequestIdValue::deserialize(deserializer).map(|request_id| request_id.0)
+}
+
+#[derive(Deserialize)]
+struct ContentConsent<'a> {
+    #[serde(borrow)]
+    consent: Option<&'a RawValue>
+}
+
+/// Name of the JSON type of a raw value
+fn json_type(value: &RawValue) -> &'static str {
+    match value.get().trim_start().chars().next() {
+        Some('{') => "object",
+        Some('[') => "array",
+        Some('"') => "string",
+        Some('t' | 'f') => "boolean",
+        Some('n') => "null",
+        _ => "number"
+    }
+}
+
+/// Decodes base64 string of gzipped JSON, failing if decompressed content exceeds maximum size
+fn decode_gzip_base64(encoded: &RawValue, max_bytes: u64) -> Result<Box<RawValue>, String> {
+    let encoded = serde_json::from_str::<String>(encoded.get())
+        .map_err(|_| format!("encoded content must be a JSON string, found {}", json_type(encoded)))?;
+    let compressed = BASE64
+        .decode(encoded.trim())
+        .map_err(|_| "content is not valid base64".to_string())?;
+    let mut decoded = Vec::new();
+    GzDecoder::new(compressed.as_slice())
+        .take(max_bytes.saturating_add(1))
+        .read_to_end(&mut decoded)
+        .map_err(|_| "content is not valid gzip".to_string())?;
+    if decoded.len() as u64 > max_bytes {
+        return Err(format!("decoded content exceeds {} bytes", max_bytes));
+    }
+    let decoded = String::from_utf8(decoded).map_err(|_| "decoded content is not valid UTF-8".to_string())?;
+    RawValue::from_string(decoded).map_err(|_| "decoded content is not valid JSON".to_string())
+}
+
+/// Unwraps content serialized as JSON string by the producer. Only one level is unwrapped.
+fn unwrap_json_string(content: &RawValue) -> Result<Box<RawValue>, String> {
+    let serialized = serde_json::from_str::<String>(content.get())
+        .map_err(|_| "content is not a valid JSON string".to_string())?;
+    RawValue::from_string(serialized).map_err(|_| "content string is not valid JSON".to_string())
+}
+
+/// Removes top-level fields of content not allowed or denied. An empty allowlist allows all fields.
+pub fn sanitize_content(content: &mut Value, allow: &[String], deny: &[String]) {
+    let is_listed = |list: &[String], key: &str| list.iter().any(|item| item.trim() == key);
+
+    if let Some(fields) = content.as_object_mut() {
+        fields.retain(|key, _| (allow.is_empty() || is_listed(allow, key)) && !is_listed(deny, key));
+    }
+}
+
+impl<'a> TryFrom<&'a str> for Request<'a> {
+    type Error = ParseError;
+
+    fn try_from(s: &'a str) -> Result<Self, Self::Error> {
+        Request::parse(
+            s,
+            DEFAULT_MAX_DECODED_CONTENT_BYTES,
+            DEFAULT_CONSENT_DATE_FIELD,
+            &[DEFAULT_CONSENT_ACTIVE_STATUS.to_string()],
+            DEFAULT_CONSENT_POINTER
+        )
+    }
+}
+
+impl<'a> Request<'a> {
+
+    /// Parses request, decoding content if `contentEncoding` is `gzip+base64`
+    /// or unwrapping content sent as JSON string.
+    /// Decoded content must not exceed the given maximum size in bytes.
+    /// Of multiple consents, the most recent one by the given date field is used.
+    /// Consent statuses other than FHIR ConsentState values must be one of the given active statuses.
+    /// The consent is located by the given JSON pointer.
+    pub fn parse(
+        s: &'a str,
+        max_decoded_bytes: u64,
+        consent_date_field: &str,
+        active_statuses: &[String],
+        consent_pointer: &'a str
+    ) -> Result<Self, ParseError> {
+        let envelope = serde_json::from_str::<Envelope>(s).map_err(|e| ParseError {
+            request_id: serde_json::from_str::<RequestId>(s).ok().and_then(|id| id.request_id).map(|id| id.0),
+            ..ParseError::new("Invalid request", e)
+        })?;
+        let content = match envelope.content_encoding.as_deref().map(str::trim) {
+            None if json_type(envelope.content) == "string" => {
+                let unwrapped = unwrap_json_string(envelope.content)
+                    .map_err(|reason| ParseError::invalid_request(&envelope.request_id, &reason))?;
+                Cow::Owned(unwrapped)
+            }
+            None => Cow::Borrowed(envelope.content),
+            Some(encoding) if encoding.eq_ignore_ascii_case(GZIP_BASE64) => {
+                let decoded = decode_gzip_base64(envelope.content, max_decoded_bytes)
+                    .map_err(|reason| ParseError::invalid_request(&envelope.request_id, &reason))?;
+                Cow::Owned(decoded)
+            }
+            Some(_) => {
+                return Err(ParseError::invalid_request(
+                    &envelope.request_id,
+                    &format!("unsupported content encoding, expected '{}'", GZIP_BASE64)
+                ))
+            }
+        };
+        match json_type(&content) {
+            "object" => {}
+            json_type => return Err(ParseError::invalid_content(&envelope.request_id, json_type))
         }
+        let mtbfile = MTBFileWithConsent::parse(content.get(), consent_pointer)
+            .and_then(|mut mtbfile| {
+                mtbfile.select_latest_consent(consent_date_field);
+                mtbfile.apply_active_statuses(active_statuses).map_err(serde_json::Error::custom)?;
+                Ok(mtbfile)
+            })
+            .map_err(|e| ParseError::new("Invalid MTB file consent", e));
+        Ok(Request {
+            request_id: envelope.request_id,
+            version: envelope.version,
+            tenant: envelope.tenant,
+            request_type: envelope.request_type,
+            content,
+            consent_pointer,
+            mtbfile
+        })
+    }
+
+    #[cfg(test)]
+    pub fn can_parse(s: &str) -> bool {
+        Request::try_from(s).is_ok_and(|request| request.mtbfile.is_ok())
     }
 
     pub fn request_id(&self) -> String {
         self.request_id.to_string()
     }
 
-    pub fn content_string(&self) -> String {
-        self.content.to_string()
+    /// Request id must not be blank, must not exceed maximum length in characters
+    /// and must match format and pattern if present
+    pub fn has_valid_request_id(
+        &self,
+        max_length: u64,
+        format: Option<RequestIdFormat>,
+        pattern: Option<&Regex>
+    ) -> bool {
+        !self.request_id.trim().is_empty()
+            && self.request_id.chars().count() as u64 <= max_length
+            && format.is_none_or(|format| format.is_match(&self.request_id))
+            && pattern.is_none_or(|pattern| pattern.is_match(&self.request_id))
+    }
+
+    /// Request id truncated to 64 characters and with control characters replaced
+    pub fn sanitized_request_id(&self) -> String {
+        sanitize_request_id(&self.request_id)
+    }
+
+    /// Version of request envelope, defaults to 1
+    pub fn version(&self) -> u32 {
+        self.version.unwrap_or(1)
+    }
+
+    pub fn tenant(&self) -> Option<String> {
+        self.tenant.clone()
     }
 
-    pub fn has_consent(&self) -> bool {
-        match MTBFileWithConsent::from_str(self.content.to_string().as_str()) {
-            Ok(mtbfile) => mtbfile.has_consent(),
-            _ => false
+    /// Explicit type of request, none to decide by consent status
+    pub fn request_type(&self) -> Option<RequestType> {
+        self.request_type
+    }
+
+    /// Content as sent within the request without any copy, or as decoded if encoded.
+    /// Key order, number formatting and whitespace are kept byte-for-byte.
+    pub fn content_str(&self) -> &str {
+        self.content.get()
+    }
+
+    /// Content parsed once to be transformed before sending.
+    /// Fails if content cannot be parsed, e.g. if nested too deeply.
+    pub fn content_value(&self) -> Result<Value, serde_json::Error> {
+        serde_json::from_str(self.content.get())
+    }
+
+    /// Consent as sent within the request, kept byte-for-byte if located by the default JSON pointer
+    pub fn consent_string(&self) -> Option<String> {
+        if self.consent_pointer != DEFAULT_CONSENT_POINTER {
+            return serde_json::from_str::<Value>(self.content.get())
+                .ok()
+                .and_then(|content| {
+                    content.pointer(self.consent_pointer).filter(|consent| !consent.is_null()).map(Value::to_string)
+                });
+        }
+        match serde_json::from_str::<ContentConsent>(self.content.get()) {
+            Ok(content) => content.consent.map(|consent| consent.get().to_string()),
+            _ => None
         }
     }
 
-    pub fn patient_id(&self) -> String {
-        match MTBFileWithConsent::from_str(self.content.to_string().as_str()) {
-            Ok(mtbfile) => mtbfile.patient_id(),
-            _ => String::new()
+    /// Decision by consent status, using given decision for status `entered-in-error`.
+    /// If content contains no consent, decision by given provision of the broad consent.
+    /// None if content contains neither, fails if consent status is missing or unknown.
+    pub fn consent_decision(&self, entered_in_error: ConsentDecision, provision: &str) -> Result<Option<ConsentDecision>, &ParseError> {
+        self.mtbfile.as_ref().map(|mtbfile| mtbfile.consent_decision(entered_in_error, provision))
+    }
+
+    /// Validity of the consent period at given time, valid if content cannot be parsed
+    pub fn consent_validity(&self, at: SystemTime) -> ConsentValidity {
+        self.mtbfile.as_ref().map_or(ConsentValidity::Valid, |mtbfile| mtbfile.consent_validity(at))
+    }
+
+    pub fn consent_status(&self) -> Option<&str> {
+        self.mtbfile.as_ref().ok().and_then(|mtbfile| mtbfile.consent_status())
+    }
+
+    pub fn patient_id(&self, primary: PatientIdSource) -> Option<String> {
+        self.mtbfile.as_ref().ok().and_then(|mtbfile| mtbfile.patient_id(primary))
+    }
+
+    /// Comparison of patient ids of consent and patient, incomplete if content cannot be parsed
+    pub fn patient_match(&self) -> PatientMatch {
+        self.mtbfile.as_ref().map_or(PatientMatch::Incomplete, |mtbfile| mtbfile.patient_match())
+    }
+
+    /// Patient ids if content contains `patients` array to delete multiple patients.
+    /// Fails if any entry is not a string or blank, as it could not be deleted.
+    pub fn patient_ids(&self) -> Result<Option<Vec<String>>, ParseError> {
+        let Some(patients) = self.mtbfile.as_ref().ok().and_then(|mtbfile| mtbfile.patients()) else {
+            return Ok(None);
+        };
+        patients
+            .iter()
+            .map(|patient_id| match patient_id.as_str() {
+                Some(patient_id) if !patient_id.trim().is_empty() => Ok(patient_id.to_string()),
+                _ => Err(ParseError::invalid_request(&self.request_id, "patients must contain patient ids only"))
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map(Some)
+    }
+
+    pub fn site_id(&self) -> Option<String> {
+        self.mtbfile.as_ref().ok().and_then(|mtbfile| mtbfile.site_id())
+    }
+
+    /// Consent issuer must be listed if content contains consent. An empty allowlist allows all issuers.
+    pub fn has_allowed_consent_issuer(&self, allowed: &[String]) -> bool {
+        if allowed.is_empty() {
+            return true;
+        }
+        match &self.mtbfile {
+            Ok(mtbfile) if mtbfile.has_consent_entry() => mtbfile.consent_issuer().is_some_and(|issuer| {
+                allowed.iter().any(|allowed| allowed.trim() == issuer)
+            }),
+            _ => true
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+    use std::io::Write;
     use std::str::FromStr;
 
-    use crate::resources::request::Request;
+    use base64::engine::general_purpose::STANDARD as BASE64;
+    use base64::Engine;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use regex::Regex;
+
+    use crate::resources::mtbfile::{ConsentDecision, PatientIdSource};
+    use crate::resources::request::{sanitize_content, Request, RequestIdFormat, RequestType, DEFAULT_MAX_DECODED_CONTENT_BYTES};
+
+    fn gzip_base64(content: &str) -> String {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(content.as_bytes()).unwrap();
+        BASE64.encode(encoder.finish().unwrap())
+    }
+
+    fn encoded_request(content: &str) -> String {
+        format!(r#"{{"requestId": "request0123456789", "contentEncoding": "gzip+base64", "content": "{}"}}"#, content)
+    }
+
+    /// Counts bytes allocated by current thread to verify content is not copied
+    struct CountingAllocator;
+
+    thread_local! {
+        static ALLOCATED: Cell<usize> = const { Cell::new(0) };
+    }
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let _ = ALLOCATED.try_with(|allocated| allocated.set(allocated.get() + layout.size()));
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    fn allocated() -> usize {
+        ALLOCATED.with(|allocated| allocated.get())
+    }
 
     #[test]
     fn should_return_that_request_can_be_parsed() {
@@ -120,6 +582,38 @@ mod tests {
         assert!(Request::can_parse(jsonstr))
     }
 
+    #[test]
+    fn should_accept_integer_request_ids_as_decimal_string() {
+        for (request_id, expected) in [("4711", "4711"), ("-42", "-42"), ("18446744073709551615", "18446744073709551615"), (r#""4711""#, "4711")] {
+            for field in ["request_id", "requestId"] {
+                let jsonstr = format!(r#"{{"{}": {}, "content": {{"consent": {{"status": "active"}}}}}}"#, field, request_id);
+
+                let actual = Request::try_from(jsonstr.as_str()).unwrap();
+
+                assert_eq!(actual.request_id(), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn should_reject_floating_point_request_ids() {
+        for request_id in ["4711.0", "4711.5", "1e3"] {
+            let jsonstr = format!(r#"{{"requestId": {}, "content": {{}}}}"#, request_id);
+
+            let actual = Request::try_from(jsonstr.as_str()).err().unwrap();
+
+            assert!(actual.to_string().starts_with("Invalid request: invalid type: floating point"));
+            assert_eq!(actual.request_id(), None);
+        }
+    }
+
+    #[test]
+    fn should_return_integer_request_id_of_parse_error() {
+        let actual = Request::try_from(r#"{"requestId": 4711, "version": "1", "content": {}}"#).err().unwrap();
+
+        assert_eq!(actual.request_id(), Some("4711".to_string()));
+    }
+
     #[test]
     fn should_return_that_request_without_request_id_cannot_be_parsed() {
         let jsonstr = r#"
@@ -134,7 +628,7 @@ mod tests {
            }
         "#;
 
-        assert_eq!(Request::can_parse(jsonstr), false)
+        assert!(!Request::can_parse(jsonstr))
     }
 
     #[test]
@@ -145,9 +639,149 @@ mod tests {
            }
         "#;
 
-        assert_eq!(Request::can_parse(jsonstr), false)
+        assert!(!Request::can_parse(jsonstr))
+    }
+
+
+    #[test]
+    fn should_return_reason_if_request_or_consent_cannot_be_parsed() {
+        let without_content = Request::try_from(r#"{"request_id": "request0123456789"}"#);
+        let without_status = Request::try_from(r#"{"request_id": "request0123456789", "content": {"consent": {}}}"#).unwrap();
+
+        assert!(without_content.err().unwrap().to_string().starts_with("Invalid request: missing field `content`"));
+        assert!(without_status.consent_decision(ConsentDecision::Delete, "sequencing").unwrap_err().to_string().starts_with("Invalid MTB file consent: missing field `status`"))
+    }
+
+    #[test]
+    fn should_parse_request_type() {
+        for (request_type, expected) in [(r#""MTB_FILE""#, Some(RequestType::MtbFile)), (r#""DELETE""#, Some(RequestType::Delete)), ("null", None)] {
+            let jsonstr = format!(r#"{{"request_id": "request0123456789", "type": {}, "content": {{}}}}"#, request_type);
+
+            assert_eq!(Request::try_from(jsonstr.as_str()).unwrap().request_type(), expected);
+        }
+        let without_type = Request::try_from(r#"{"request_id": "request0123456789", "content": {}}"#).unwrap();
+
+        assert_eq!(without_type.request_type(), None)
+    }
+
+    #[test]
+    fn should_not_parse_request_with_unknown_type() {
+        let actual = Request::try_from(r#"{"request_id": "request0123456789", "type": "UPDATE", "content": {}}"#);
+
+        assert!(actual.err().unwrap().to_string().starts_with("Invalid request: unknown variant"))
+    }
+
+    #[test]
+    fn should_reject_content_that_is_not_an_object() {
+        for (content, json_type) in [(r#""\"{}\"""#, "string"), ("[{}]", "array"), ("42", "number"), ("null", "null")] {
+            let jsonstr = format!(r#"{{"request_id": "request0123456789", "content": {}}}"#, content);
+            let actual = Request::try_from(jsonstr.as_str()).err().unwrap();
+
+            assert_eq!(actual.to_string(), format!("Invalid request: content must be a JSON object, found {}", json_type));
+            assert_eq!(actual.request_id(), Some("request0123456789".to_string()));
+        }
+    }
+
+    #[test]
+    fn should_decode_gzip_base64_content() {
+        let content = r#"{"consent": {"patient": "TESTPATIENT1234", "status": "active"}, "patient": "TESTPATIENT1234"}"#;
+        let jsonstr = encoded_request(&gzip_base64(content));
+
+        let actual = Request::try_from(jsonstr.as_str()).unwrap();
+
+        assert_eq!(actual.content_str(), content);
+        assert_eq!(actual.consent_decision(ConsentDecision::Delete, "sequencing"), Ok(Some(ConsentDecision::Upload)));
+        assert_eq!(actual.consent_string(), Some(r#"{"patient": "TESTPATIENT1234", "status": "active"}"#.to_string()))
+    }
+
+    #[test]
+    fn should_not_parse_invalid_encoded_content() {
+        for (jsonstr, reason) in [
+            (encoded_request("not base64!"), "content is not valid base64"),
+            (encoded_request(&BASE64.encode("not gzip")), "content is not valid gzip"),
+            (encoded_request(&gzip_base64("{")), "decoded content is not valid JSON"),
+            (encoded_request(&gzip_base64("[]")), "content must be a JSON object, found array"),
+            (
+                r#"{"requestId": "request0123456789", "contentEncoding": "gzip+base64", "content": {}}"#.to_string(),
+                "encoded content must be a JSON string, found object"
+            ),
+            (
+                r#"{"requestId": "request0123456789", "contentEncoding": "br", "content": "e30="}"#.to_string(),
+                "unsupported content encoding, expected 'gzip+base64'"
+            )
+        ] {
+            let actual = Request::try_from(jsonstr.as_str()).err().unwrap();
+
+            assert_eq!(actual.to_string(), format!("Invalid request: {}", reason));
+            assert_eq!(actual.request_id(), Some("request0123456789".to_string()));
+        }
+    }
+
+    #[test]
+    fn should_unwrap_content_sent_as_json_string() {
+        let content = r#"{"consent": {"patient": "TESTPATIENT1234", "status": "active"}, "patient": "TESTPATIENT1234"}"#;
+        let jsonstr = format!(
+            r#"{{"requestId": "request0123456789", "content": {}}}"#,
+            serde_json::to_string(content).unwrap()
+        );
+
+        let actual = Request::try_from(jsonstr.as_str()).unwrap();
+
+        assert_eq!(actual.content_str(), content);
+        assert_eq!(actual.consent_decision(ConsentDecision::Delete, "sequencing"), Ok(Some(ConsentDecision::Upload)));
+    }
+
+    #[test]
+    fn should_unwrap_content_sent_as_json_string_only_once() {
+        let content = r#"{"consent": {"patient": "TESTPATIENT1234", "status": "active"}}"#;
+        let twice = serde_json::to_string(&serde_json::to_string(content).unwrap()).unwrap();
+        let jsonstr = format!(r#"{{"requestId": "request0123456789", "content": {}}}"#, twice);
+
+        let actual = Request::try_from(jsonstr.as_str()).err().unwrap();
+
+        assert_eq!(actual.to_string(), "Invalid request: content must be a JSON object, found string");
+        assert_eq!(actual.request_id(), Some("request0123456789".to_string()));
+    }
+
+    #[test]
+    fn should_not_parse_content_string_that_is_not_json() {
+        let actual = Request::try_from(r#"{"requestId": "request0123456789", "content": "{ not json"}"#).err().unwrap();
+
+        assert_eq!(actual.to_string(), "Invalid request: content string is not valid JSON");
+        assert_eq!(actual.request_id(), Some("request0123456789".to_string()));
     }
 
+    #[test]
+    fn should_not_decode_content_exceeding_maximum_size() {
+        let content = format!(r#"{{"consent": {{"status": "active"}}, "padding": "{}"}}"#, " ".repeat(1000));
+        let jsonstr = encoded_request(&gzip_base64(&content));
+
+        assert!(Request::parse(jsonstr.as_str(), content.len() as u64, "dateTime", &["active".to_string()], "/consent").is_ok());
+
+        let actual = Request::parse(jsonstr.as_str(), content.len() as u64 - 1, "dateTime", &["active".to_string()], "/consent").err().unwrap();
+
+        assert_eq!(actual.to_string(), format!("Invalid request: decoded content exceeds {} bytes", content.len() - 1));
+    }
+
+    #[test]
+    fn should_not_decide_on_unknown_consent_status() {
+        let request = Request::try_from(r#"{"request_id": "request0123456789", "content": {"consent": {"patient": "TESTPATIENT1234", "status": "withdrawn"}}}"#).unwrap();
+
+        assert!(request.consent_decision(ConsentDecision::Delete, "sequencing").is_err());
+        assert_eq!(request.consent_status(), None)
+    }
+
+    #[test]
+    fn should_return_request_id_and_redact_values_of_parse_error() {
+        let with_request_id = Request::try_from(r#"{"requestId": "request0123456789", "version": "TESTPATIENT1234", "content": {}}"#);
+        let without_request_id = Request::try_from(r#"{"version": 1, "content": {}}"#);
+
+        let actual = with_request_id.err().unwrap();
+        assert_eq!(actual.request_id(), Some("request0123456789".to_string()));
+        assert!(actual.to_string().contains(r#"invalid type: string "***""#));
+        assert!(!actual.to_string().contains("TESTPATIENT1234"));
+        assert_eq!(without_request_id.err().unwrap().request_id(), None)
+    }
 
     #[test]
     fn should_parse_request_and_return_mtb_file_consent() {
@@ -164,13 +798,10 @@ mod tests {
            }
         "#;
 
-        let actual = Request::from_str(jsonstr);
+        let actual = Request::try_from(jsonstr);
 
         assert!(actual.is_ok());
-        assert_eq!(
-            actual.unwrap().has_consent(),
-            true
-        )
+        assert_eq!(actual.unwrap().consent_decision(ConsentDecision::Delete, "sequencing"), Ok(Some(ConsentDecision::Upload)))
     }
 
     #[test]
@@ -188,17 +819,14 @@ mod tests {
            }
         "#;
 
-        let actual = Request::from_str(jsonstr);
+        let actual = Request::try_from(jsonstr);
 
         assert!(actual.is_ok());
-        assert_eq!(
-            actual.unwrap().has_consent(),
-            false
-        )
+        assert_eq!(actual.unwrap().consent_decision(ConsentDecision::Delete, "sequencing"), Ok(Some(ConsentDecision::Delete)))
     }
 
     #[test]
-    fn should_parse_request_and_return_content_as_string() {
+    fn should_parse_request_and_return_content_as_sent() {
         let jsonstr = r#"
            {
                 "requestId": "request0123456789",
@@ -212,15 +840,37 @@ mod tests {
            }
         "#;
 
-        let actual = Request::from_str(jsonstr);
+        let actual = Request::try_from(jsonstr);
 
         assert!(actual.is_ok());
         assert_eq!(
-            actual.unwrap().content_string(),
-            r#"{"consent":{"id":"TESTID1234","patient":"TESTPATIENT1234","status":"rejected"}}"#.to_string()
+            actual.unwrap().content_str(),
+            r#"{
+                    "consent": {
+                        "id": "TESTID1234",
+                        "patient": "TESTPATIENT1234",
+                        "status": "rejected"
+                    }
+                }"#
         )
     }
 
+    #[test]
+    fn should_keep_big_numbers_key_order_and_duplicate_keys_of_content() {
+        let consent = r#"{ "status": "active", "patient": "TESTPATIENT1234", "version": 12345678901234567890123 }"#;
+        let content = format!(
+            r#"{{"value": 9007199254740993, "ratio": 0.10000000000000000555, "consent": {}, "note": "a", "note": "b"}}"#,
+            consent
+        );
+        let jsonstr = format!(r#"{{"requestId": "request0123456789", "content": {}}}"#, content);
+
+        let actual = Request::try_from(jsonstr.as_str()).unwrap();
+
+        assert_eq!(actual.consent_decision(ConsentDecision::Delete, "sequencing"), Ok(Some(ConsentDecision::Upload)));
+        assert_eq!(actual.content_str(), content);
+        assert_eq!(actual.consent_string(), Some(consent.to_string()))
+    }
+
     #[test]
     fn should_parse_request_and_return_request_id_as_string() {
         let jsonstr = r#"
@@ -236,7 +886,7 @@ mod tests {
            }
         "#;
 
-        let actual = Request::from_str(jsonstr);
+        let actual = Request::try_from(jsonstr);
 
         assert!(actual.is_ok());
         assert_eq!(
@@ -245,4 +895,319 @@ mod tests {
         )
     }
 
+    #[test]
+    fn should_parse_request_and_return_tenant() {
+        let jsonstr = r#"
+           {
+                "requestId": "request0123456789",
+                "tenant": "tenant1",
+                "content": {
+                    "consent": {
+                        "id": "TESTID1234",
+                        "patient": "TESTPATIENT1234",
+                        "status": "active"
+                    }
+                }
+           }
+        "#;
+
+        let actual = Request::try_from(jsonstr);
+
+        assert!(actual.is_ok());
+        assert_eq!(
+            actual.unwrap().tenant(),
+            Some("tenant1".to_string())
+        )
+    }
+
+    #[test]
+    fn should_parse_request_and_return_consent_as_string() {
+        let jsonstr = r#"
+           {
+                "requestId": "request0123456789",
+                "content": {
+                    "consent": {"id":"TESTID1234","patient":"TESTPATIENT1234","status":"rejected"}
+                }
+           }
+        "#;
+
+        let actual = Request::try_from(jsonstr);
+
+        assert!(actual.is_ok());
+        assert_eq!(
+            actual.unwrap().consent_string(),
+            Some(r#"{"id":"TESTID1234","patient":"TESTPATIENT1234","status":"rejected"}"#.to_string())
+        )
+    }
+
+    #[test]
+    fn should_return_normalized_sanitized_content() {
+        let jsonstr = r#"
+           {
+                "request_id": "request0123456789",
+                "content": {
+                    "patient": { "id": "TESTPATIENT1234" },
+                    "consent": {
+                        "status": "active",
+                        "patient": "TESTPATIENT1234",
+                        "id": "TESTID1234"
+                    }
+                }
+           }
+        "#;
+
+        let mut actual = Request::try_from(jsonstr).unwrap().content_value().unwrap();
+        sanitize_content(&mut actual, &[], &[]);
+
+        assert_eq!(
+            actual.to_string(),
+            r#"{"consent":{"id":"TESTID1234","patient":"TESTPATIENT1234","status":"active"},"patient":{"id":"TESTPATIENT1234"}}"#
+        )
+    }
+
+    #[test]
+    fn should_remove_fields_from_sanitized_content() {
+        let jsonstr = r#"
+           {
+                "request_id": "request0123456789",
+                "content": {
+                    "consent": { "id": "TESTID1234", "status": "active" },
+                    "patient": { "id": "TESTPATIENT1234" },
+                    "debug": { "trace": "0123456789" },
+                    "debugInfo": "test"
+                }
+           }
+        "#;
+
+        let request = Request::try_from(jsonstr).unwrap();
+        let allow = ["consent".to_string(), "patient".to_string(), "debugInfo".to_string()];
+        let deny = ["debugInfo".to_string()];
+
+        let mut denied = request.content_value().unwrap();
+        sanitize_content(&mut denied, &[], &deny);
+        let mut allowed = request.content_value().unwrap();
+        sanitize_content(&mut allowed, &allow, &deny);
+
+        assert_eq!(
+            denied.to_string(),
+            r#"{"consent":{"id":"TESTID1234","status":"active"},"debug":{"trace":"0123456789"},"patient":{"id":"TESTPATIENT1234"}}"#
+        );
+        assert_eq!(
+            allowed.to_string(),
+            r#"{"consent":{"id":"TESTID1234","status":"active"},"patient":{"id":"TESTPATIENT1234"}}"#
+        )
+    }
+
+    #[test]
+    fn should_fail_parsing_content_nested_too_deeply() {
+        let jsonstr = format!(
+            r#"{{"request_id": "request0123456789", "content": {{"nested": {}{}}}}}"#,
+            "[".repeat(200),
+            "]".repeat(200)
+        );
+
+        let actual = Request::try_from(jsonstr.as_str()).unwrap().content_value();
+
+        assert!(actual.is_err())
+    }
+
+    #[test]
+    fn should_reject_empty_request_id() {
+        for request_id in ["", "   "] {
+            let jsonstr = format!(r#"{{"request_id": "{}", "content": {{}}}}"#, request_id);
+
+            let actual = Request::try_from(jsonstr.as_str()).unwrap();
+
+            assert!(!actual.has_valid_request_id(64, None, None))
+        }
+    }
+
+    #[test]
+    fn should_reject_request_id_exceeding_maximum_length() {
+        let request = Request::try_from(r#"{"request_id": "request0123456789", "content": {}}"#).unwrap();
+
+        assert!(request.has_valid_request_id(17, None, None));
+        assert!(!request.has_valid_request_id(16, None, None))
+    }
+
+    #[test]
+    fn should_validate_request_id_using_uuid_format() {
+        let uuid = Request::try_from(r#"{"request_id": "5a4e7c63-9f0b-4d2a-8c1e-0b6f3d2a1e9F", "content": {}}"#).unwrap();
+        let no_uuid = Request::try_from(r#"{"request_id": "5a4e7c63-9f0b-4d2a-8c1e-0b6f3d2a1e9g", "content": {}}"#).unwrap();
+        let without_hyphens = Request::try_from(r#"{"request_id": "5a4e7c639f0b4d2a8c1e0b6f3d2a1e9f", "content": {}}"#).unwrap();
+
+        assert!(uuid.has_valid_request_id(64, Some(RequestIdFormat::Uuid), None));
+        assert!(!no_uuid.has_valid_request_id(64, Some(RequestIdFormat::Uuid), None));
+        assert!(!without_hyphens.has_valid_request_id(64, Some(RequestIdFormat::Uuid), None));
+        assert!(no_uuid.has_valid_request_id(64, None, None))
+    }
+
+    #[test]
+    fn should_parse_request_id_format() {
+        assert!(matches!(RequestIdFormat::from_str(" UUID "), Ok(RequestIdFormat::Uuid)));
+        assert!(RequestIdFormat::from_str("ulid").is_err())
+    }
+
+    #[test]
+    fn should_truncate_and_sanitize_request_id() {
+        let jsonstr = format!(r#"{{"request_id": "request\n\u0000{}", "content": {{}}}}"#, "A".repeat(2000));
+
+        let actual = Request::try_from(jsonstr.as_str()).unwrap().sanitized_request_id();
+
+        assert_eq!(actual, format!("request??{}...", "A".repeat(55)))
+    }
+
+    #[test]
+    fn should_not_change_valid_request_id_if_sanitized() {
+        let request = Request::try_from(r#"{"request_id": "request0123456789", "content": {}}"#).unwrap();
+
+        assert_eq!(request.sanitized_request_id(), "request0123456789")
+    }
+
+    #[test]
+    fn should_validate_request_id_using_pattern() {
+        let pattern = Regex::new("^[A-Za-z0-9-]+$").unwrap();
+
+        let valid = Request::try_from(r#"{"request_id": "request-0123456789", "content": {}}"#).unwrap();
+        let malformed = Request::try_from(r#"{"request_id": "request 0123/456789", "content": {}}"#).unwrap();
+
+        assert!(valid.has_valid_request_id(64, None, Some(&pattern)));
+        assert!(!malformed.has_valid_request_id(64, None, Some(&pattern)));
+        assert!(malformed.has_valid_request_id(64, None, None))
+    }
+
+    #[test]
+    fn should_return_request_version() {
+        let without_version = Request::try_from(r#"{"request_id": "request0123456789", "content": {}}"#).unwrap();
+        let with_version = Request::try_from(r#"{"version": 2, "request_id": "request0123456789", "content": {}}"#).unwrap();
+
+        assert_eq!(without_version.version(), 1);
+        assert_eq!(with_version.version(), 2)
+    }
+
+    #[test]
+    fn should_decide_by_most_recent_of_multiple_consents() {
+        let jsonstr = r#"{
+            "requestId": "request0123456789",
+            "content": {
+                "consents": [
+                    {"patient": "TESTPATIENT1234", "status": "active", "date": "2024-03-01"},
+                    {"patient": "TESTPATIENT1234", "status": "rejected", "date": "2024-01-01"}
+                ]
+            }
+        }"#;
+
+        let actual = Request::parse(jsonstr, DEFAULT_MAX_DECODED_CONTENT_BYTES, "date", &["active".to_string()], "/consent").unwrap();
+
+        assert_eq!(actual.consent_status(), Some("active"));
+        assert_eq!(actual.patient_id(PatientIdSource::Consent), Some("TESTPATIENT1234".to_string()))
+    }
+
+    #[test]
+    fn should_locate_consent_by_json_pointer() {
+        let jsonstr = r#"{
+            "requestId": "request0123456789",
+            "content": {
+                "metadata": {"consent": {"patient": "TESTPATIENT1234", "status": "rejected"}}
+            }
+        }"#;
+
+        let actual = Request::parse(jsonstr, DEFAULT_MAX_DECODED_CONTENT_BYTES, "dateTime", &["active".to_string()], "/metadata/consent").unwrap();
+
+        assert_eq!(actual.consent_status(), Some("rejected"));
+        assert_eq!(actual.patient_id(PatientIdSource::Consent), Some("TESTPATIENT1234".to_string()));
+        assert_eq!(actual.consent_string(), Some(r#"{"patient":"TESTPATIENT1234","status":"rejected"}"#.to_string()))
+    }
+
+    #[test]
+    fn should_have_no_consent_if_json_pointer_misses() {
+        let jsonstr = r#"{
+            "requestId": "request0123456789",
+            "content": {"consent": {"patient": "TESTPATIENT1234", "status": "active"}}
+        }"#;
+
+        let actual = Request::parse(jsonstr, DEFAULT_MAX_DECODED_CONTENT_BYTES, "dateTime", &["active".to_string()], "/metadata/consent").unwrap();
+
+        assert_eq!(actual.consent_status(), None);
+        assert_eq!(actual.consent_string(), None);
+        assert_eq!(actual.consent_decision(ConsentDecision::Ignore, "sequencing"), Ok(None))
+    }
+
+    #[test]
+    fn should_return_patient_ids_of_multi_patient_request() {
+        let jsonstr = r#"
+           {
+                "request_id": "request0123456789",
+                "content": {
+                    "patients": ["TESTPATIENT1234", "TESTPATIENT5678"]
+                }
+           }
+        "#;
+
+        let actual = Request::try_from(jsonstr).unwrap();
+
+        assert_eq!(
+            actual.patient_ids(),
+            Ok(Some(vec!["TESTPATIENT1234".to_string(), "TESTPATIENT5678".to_string()]))
+        )
+    }
+
+    #[test]
+    fn should_reject_patient_ids_containing_other_values() {
+        for patients in [r#"[123, "TESTPATIENT1234"]"#, r#"["TESTPATIENT1234", " "]"#, r#"[null]"#] {
+            let jsonstr = format!(
+                r#"{{"request_id": "request0123456789", "content": {{"patients": {}}}}}"#,
+                patients
+            );
+
+            let actual = Request::try_from(jsonstr.as_str()).unwrap();
+
+            assert!(actual.patient_ids().is_err(), "{}", patients)
+        }
+    }
+
+    #[test]
+    fn should_return_no_patient_ids_for_single_patient_request() {
+        let jsonstr = r#"
+           {
+                "request_id": "request0123456789",
+                "content": {
+                    "consent": {
+                        "id": "TESTID1234",
+                        "patient": "TESTPATIENT1234",
+                        "status": "rejected"
+                    }
+                }
+           }
+        "#;
+
+        let actual = Request::try_from(jsonstr).unwrap();
+
+        assert_eq!(actual.patient_ids(), Ok(None))
+    }
+
+    #[test]
+    fn should_not_copy_large_content() {
+        let jsonstr = format!(
+            r#"{{
+                "request_id": "request0123456789",
+                "content": {{
+                    "consent": {{ "id": "TESTID1234", "patient": "TESTPATIENT1234", "status": "active" }},
+                    "data": "{}"
+                }}
+            }}"#,
+            "x".repeat(4 * 1024 * 1024)
+        );
+
+        let before = allocated();
+        let request = Request::try_from(jsonstr.as_str()).unwrap();
+        assert_eq!(request.consent_decision(ConsentDecision::Delete, "sequencing"), Ok(Some(ConsentDecision::Upload)));
+        assert_eq!(request.patient_ids(), Ok(None));
+        let content = request.content_str();
+        let actual = allocated() - before;
+
+        assert!(content.len() > 4 * 1024 * 1024);
+        assert!(actual < 64 * 1024, "{} bytes allocated", actual)
+    }
+
 }
\ No newline at end of file
@@ -0,0 +1,358 @@
+/*
+ * This file is part of ETL-Processor
+ *
+ * Copyright (c) 2024  Comprehensive Cancer Center Mainfranken
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published
+ * by the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::future::Future;
+use std::str::FromStr;
+use std::time::Duration;
+
+use log::debug;
+use rand::Rng;
+
+use crate::bwhc_client::HttpResponse;
+use crate::config::Config;
+use crate::AppError;
+use crate::AppError::ValidationError;
+
+/// Status codes of responses to be retried
+#[derive(Clone, Debug, PartialEq)]
+pub struct RetryStatus(Vec<u16>);
+
+impl FromStr for RetryStatus {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(|status_code| status_code.trim())
+            .filter(|status_code| !status_code.is_empty())
+            .map(|status_code| {
+                status_code
+                    .parse::<u16>()
+                    .map_err(|_| ValidationError(format!("Invalid status code '{}'", status_code)))
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map(RetryStatus)
+    }
+}
+
+pub struct RetryPolicy {
+    retries: u32,
+    retry_status: RetryStatus,
+    delay: Duration,
+    max_delay: Duration,
+    jitter: bool,
+    max_retry_after: Duration,
+}
+
+impl RetryPolicy {
+    pub fn new(config: &Config) -> Self {
+        RetryPolicy {
+            retries: config.rest_retries,
+            retry_status: config.rest_retry_status.clone(),
+            delay: Duration::from_millis(config.rest_retry_delay_ms),
+            max_delay: Duration::from_millis(config.rest_retry_max_delay_ms),
+            jitter: config.rest_retry_jitter,
+            max_retry_after: Duration::from_millis(
+                config
+                    .rest_retry_after_max_ms
+                    .unwrap_or(config.rest_retry_max_delay_ms),
+            ),
+        }
+    }
+
+    /// Retry policy for idempotent delete requests using delete specific retries and maximum delay
+    pub fn for_delete(config: &Config) -> Self {
+        RetryPolicy {
+            retries: config.rest_delete_retries.unwrap_or(config.rest_retries),
+            max_delay: Duration::from_millis(
+                config
+                    .rest_delete_retry_max_delay_ms
+                    .unwrap_or(config.rest_retry_max_delay_ms),
+            ),
+            ..Self::new(config)
+        }
+    }
+
+    /// Exponential backoff for given attempt, starting with 0.
+    /// Using full jitter, the delay is randomized between zero and the backoff.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let backoff = self
+            .delay
+            .saturating_mul(2_u32.saturating_pow(attempt))
+            .min(self.max_delay);
+
+        if self.jitter {
+            let millis = backoff.as_millis() as u64;
+            Duration::from_millis(rand::thread_rng().gen_range(0..=millis))
+        } else {
+            backoff
+        }
+    }
+
+    /// Delay requested by the response, limited to maximum delay for `Retry-After` headers,
+    /// or the exponential backoff for given attempt
+    fn response_delay(&self, result: &Result<HttpResponse, AppError>, attempt: u32) -> Duration {
+        match result {
+            Ok(HttpResponse {
+                retry_after: Some(retry_after),
+                ..
+            }) => (*retry_after).min(self.max_retry_after),
+            _ => self.delay(attempt),
+        }
+    }
+
+    fn should_retry(&self, result: &Result<HttpResponse, AppError>) -> bool {
+        match result {
+            Ok(response) => self.retry_status.0.contains(&response.status_code),
+            Err(e) => e.is_http_error(),
+        }
+    }
+
+    pub async fn execute<F, Fut>(&self, f: F) -> Result<HttpResponse, AppError>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<HttpResponse, AppError>>,
+    {
+        self.execute_while(
+            f,
+            |result| self.should_retry(result),
+            |result, attempt| self.response_delay(result, attempt),
+        )
+        .await
+    }
+
+    /// Executes given operation, retrying on HTTP errors only, e.g. if a service is unavailable
+    pub async fn execute_on_http_error<T, F, Fut>(&self, f: F) -> Result<T, AppError>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T, AppError>>,
+    {
+        self.execute_while(
+            f,
+            |result| result.as_ref().is_err_and(AppError::is_http_error),
+            |_, attempt| self.delay(attempt),
+        )
+        .await
+    }
+
+    async fn execute_while<T, F, Fut, R, D>(
+        &self,
+        f: F,
+        should_retry: R,
+        delay: D,
+    ) -> Result<T, AppError>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T, AppError>>,
+        R: Fn(&Result<T, AppError>) -> bool,
+        D: Fn(&Result<T, AppError>, u32) -> Duration,
+    {
+        let mut attempt = 0;
+        loop {
+            let result = f().await;
+            if attempt >= self.retries || !should_retry(&result) {
+                return result;
+            }
+            let delay = delay(&result, attempt);
+            debug!("Retrying request in {} ms", delay.as_millis());
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::collections::BTreeMap;
+    use std::str::FromStr;
+    use std::time::Duration;
+
+    use crate::bwhc_client::HttpResponse;
+    use crate::retry::{RetryPolicy, RetryStatus};
+    use crate::AppError;
+
+    fn policy(jitter: bool) -> RetryPolicy {
+        RetryPolicy {
+            retries: 3,
+            retry_status: RetryStatus::from_str("429,502,503,504").unwrap(),
+            delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(1000),
+            jitter,
+            max_retry_after: Duration::from_millis(5000),
+        }
+    }
+
+    #[test]
+    fn should_return_exponential_backoff_without_jitter() {
+        let policy = policy(false);
+
+        assert_eq!(policy.delay(0), Duration::from_millis(100));
+        assert_eq!(policy.delay(1), Duration::from_millis(200));
+        assert_eq!(policy.delay(2), Duration::from_millis(400));
+        assert_eq!(policy.delay(4), Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn should_return_jittered_delay_within_backoff() {
+        let policy = policy(true);
+
+        for attempt in 0..6 {
+            let max =
+                Duration::from_millis(100 * 2_u64.pow(attempt)).min(Duration::from_millis(1000));
+            for _ in 0..100 {
+                assert!(policy.delay(attempt) <= max);
+            }
+        }
+    }
+
+    #[test]
+    fn should_randomize_jittered_delay() {
+        let policy = policy(true);
+
+        let delays = (0..100).map(|_| policy.delay(3)).collect::<Vec<_>>();
+
+        assert!(delays.iter().any(|delay| *delay != delays[0]))
+    }
+
+    #[test]
+    fn should_parse_retry_status() {
+        assert_eq!(
+            RetryStatus::from_str(" 502, 503 ").unwrap(),
+            RetryStatus(vec![502, 503])
+        );
+        assert_eq!(RetryStatus::from_str("").unwrap(), RetryStatus(vec![]));
+        assert!(RetryStatus::from_str("502,abc").is_err());
+    }
+
+    async fn attempts(
+        policy: &RetryPolicy,
+        result: impl Fn() -> Result<HttpResponse, AppError>,
+    ) -> u32 {
+        let attempts = Cell::new(0);
+        let _ = policy
+            .execute(|| {
+                attempts.set(attempts.get() + 1);
+                let result = result();
+                async move { result }
+            })
+            .await;
+        attempts.get()
+    }
+
+    fn response(status_code: u16) -> Result<HttpResponse, AppError> {
+        Ok(HttpResponse {
+            status_code,
+            status_body: String::new(),
+            headers: BTreeMap::new(),
+            endpoint: None,
+            content_type: None,
+            original_status_code: None,
+            truncated: false,
+            retry_after: None,
+        })
+    }
+
+    #[test]
+    fn should_use_retry_after_limited_to_maximum() {
+        let policy = policy(false);
+        let retry_after = |delay| {
+            response(503).map(|response| HttpResponse {
+                retry_after: Some(delay),
+                ..response
+            })
+        };
+
+        assert_eq!(
+            policy.response_delay(&retry_after(Duration::from_secs(3)), 0),
+            Duration::from_secs(3)
+        );
+        assert_eq!(
+            policy.response_delay(&retry_after(Duration::from_secs(120)), 0),
+            Duration::from_millis(5000)
+        );
+        assert_eq!(
+            policy.response_delay(&response(503), 1),
+            Duration::from_millis(200)
+        );
+    }
+
+    #[tokio::test]
+    async fn should_retry_configured_status_codes_only() {
+        let policy = RetryPolicy {
+            retries: 2,
+            retry_status: RetryStatus::from_str("502").unwrap(),
+            delay: Duration::ZERO,
+            ..policy(false)
+        };
+
+        assert_eq!(attempts(&policy, || response(502)).await, 3);
+        assert_eq!(attempts(&policy, || response(503)).await, 1);
+    }
+
+    #[tokio::test]
+    async fn should_retry_network_errors_only_without_status_codes() {
+        let policy = RetryPolicy {
+            retries: 2,
+            retry_status: RetryStatus::from_str("").unwrap(),
+            delay: Duration::ZERO,
+            ..policy(false)
+        };
+
+        assert_eq!(attempts(&policy, || response(503)).await, 1);
+        assert_eq!(
+            attempts(&policy, || Err(AppError::HttpError("error".into()))).await,
+            3
+        );
+    }
+
+    async fn attempts_on_http_error(
+        policy: &RetryPolicy,
+        result: impl Fn() -> Result<&'static str, AppError>,
+    ) -> u32 {
+        let attempts = Cell::new(0);
+        let _ = policy
+            .execute_on_http_error(|| {
+                attempts.set(attempts.get() + 1);
+                let result = result();
+                async move { result }
+            })
+            .await;
+        attempts.get()
+    }
+
+    #[tokio::test]
+    async fn should_retry_http_errors_of_any_operation() {
+        let policy = RetryPolicy {
+            retries: 2,
+            delay: Duration::ZERO,
+            ..policy(false)
+        };
+
+        assert_eq!(
+            attempts_on_http_error(&policy, || Err(AppError::HttpTimeout("timeout".into()))).await,
+            3
+        );
+        assert_eq!(
+            attempts_on_http_error(&policy, || Err(AppError::ValidationError("invalid".into())))
+                .await,
+            1
+        );
+        assert_eq!(attempts_on_http_error(&policy, || Ok("schema")).await, 1);
+    }
+}
@@ -0,0 +1,610 @@
+/*
+ * This file is part of ETL-Processor
+ *
+ * Copyright (c) 2024  Comprehensive Cancer Center Mainfranken
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published
+ * by the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Display;
+use std::path::Path;
+use std::str::FromStr;
+
+use regex::Regex;
+use serde_json::{Map, Value};
+
+use crate::AppError;
+use crate::AppError::{IoError, ValidationError};
+
+/// JSON Schema of MTB files, compiled once and used to validate the content of each request.
+/// Supports a subset of JSON Schema: `type`, `enum`, `const`, `required`, `properties`,
+/// `additionalProperties`, `items`, `pattern`, `format` (`date` and `date-time`), length, size and
+/// range limits, `allOf`, `anyOf`, `oneOf` and local `$ref`s to `definitions` or `$defs`.
+/// Other keywords are ignored.
+pub struct MtbFileSchema {
+    root: Node,
+    definitions: HashMap<String, Node>,
+}
+
+enum Node {
+    Bool(bool),
+    Schema(Box<Keywords>),
+}
+
+#[derive(Default)]
+struct Keywords {
+    reference: Option<String>,
+    types: Vec<String>,
+    enum_values: Option<Vec<Value>>,
+    const_value: Option<Value>,
+    required: Vec<String>,
+    properties: BTreeMap<String, Node>,
+    additional_properties: Option<Node>,
+    items: Option<Node>,
+    pattern: Option<Regex>,
+    format: Option<String>,
+    min_length: Option<u64>,
+    max_length: Option<u64>,
+    min_items: Option<u64>,
+    max_items: Option<u64>,
+    minimum: Option<f64>,
+    maximum: Option<f64>,
+    all_of: Vec<Node>,
+    any_of: Vec<Node>,
+    one_of: Vec<Node>,
+}
+
+impl MtbFileSchema {
+    pub fn load(path: &Path) -> Result<Self, AppError> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| IoError(format!("Cannot read '{}': {}", path.display(), e)))?;
+        Self::from_str(&content)
+    }
+
+    /// Validates JSON content and returns violations, each with the location within the content.
+    /// Values of the content are not included as they might contain patient data.
+    pub fn validate(&self, content: &Value) -> Vec<String> {
+        let mut violations = vec![];
+        self.validate_node(&self.root, content, "", &mut violations);
+        violations
+    }
+
+    fn is_valid(&self, node: &Node, value: &Value) -> bool {
+        let mut violations = vec![];
+        self.validate_node(node, value, "", &mut violations);
+        violations.is_empty()
+    }
+
+    fn validate_node(&self, node: &Node, value: &Value, path: &str, violations: &mut Vec<String>) {
+        let keywords = match node {
+            Node::Bool(true) => return,
+            Node::Bool(false) => {
+                violation(violations, path, "not allowed");
+                return;
+            }
+            Node::Schema(keywords) => keywords,
+        };
+        if let Some(reference) = &keywords.reference {
+            match self.definitions.get(reference) {
+                Some(definition) => self.validate_node(definition, value, path, violations),
+                None if reference == "#" => self.validate_node(&self.root, value, path, violations),
+                None => violation(
+                    violations,
+                    path,
+                    format!("unknown reference '{}'", reference),
+                ),
+            }
+            return;
+        }
+
+        if !keywords.types.is_empty() && !keywords.types.iter().any(|t| has_type(value, t)) {
+            violation(
+                violations,
+                path,
+                format!("expected type {}", keywords.types.join(" or ")),
+            );
+            return;
+        }
+        if let Some(values) = &keywords.enum_values {
+            if !values.contains(value) {
+                violation(violations, path, "not one of the allowed values");
+            }
+        }
+        if let Some(const_value) = &keywords.const_value {
+            if const_value != value {
+                violation(violations, path, "not the expected constant value");
+            }
+        }
+
+        match value {
+            Value::Object(object) => self.validate_object(keywords, object, path, violations),
+            Value::Array(items) => {
+                if keywords
+                    .min_items
+                    .is_some_and(|min| (items.len() as u64) < min)
+                {
+                    violation(
+                        violations,
+                        path,
+                        format!(
+                            "expected at least {} items",
+                            keywords.min_items.unwrap_or_default()
+                        ),
+                    );
+                }
+                if keywords
+                    .max_items
+                    .is_some_and(|max| items.len() as u64 > max)
+                {
+                    violation(
+                        violations,
+                        path,
+                        format!(
+                            "expected at most {} items",
+                            keywords.max_items.unwrap_or_default()
+                        ),
+                    );
+                }
+                if let Some(item_schema) = &keywords.items {
+                    for (index, item) in items.iter().enumerate() {
+                        self.validate_node(
+                            item_schema,
+                            item,
+                            &format!("{}/{}", path, index),
+                            violations,
+                        );
+                    }
+                }
+            }
+            Value::String(s) => {
+                let length = s.chars().count() as u64;
+                if keywords.min_length.is_some_and(|min| length < min) {
+                    violation(
+                        violations,
+                        path,
+                        format!(
+                            "expected at least {} characters",
+                            keywords.min_length.unwrap_or_default()
+                        ),
+                    );
+                }
+                if keywords.max_length.is_some_and(|max| length > max) {
+                    violation(
+                        violations,
+                        path,
+                        format!(
+                            "expected at most {} characters",
+                            keywords.max_length.unwrap_or_default()
+                        ),
+                    );
+                }
+                if let Some(pattern) = &keywords.pattern {
+                    if !pattern.is_match(s) {
+                        violation(
+                            violations,
+                            path,
+                            format!("does not match pattern '{}'", pattern.as_str()),
+                        );
+                    }
+                }
+                if let Some(format) = &keywords.format {
+                    if !has_format(s, format) {
+                        violation(violations, path, format!("invalid {} format", format));
+                    }
+                }
+            }
+            Value::Number(number) => {
+                let number = number.as_f64().unwrap_or_default();
+                if keywords.minimum.is_some_and(|min| number < min) {
+                    violation(
+                        violations,
+                        path,
+                        format!("expected minimum {}", keywords.minimum.unwrap_or_default()),
+                    );
+                }
+                if keywords.maximum.is_some_and(|max| number > max) {
+                    violation(
+                        violations,
+                        path,
+                        format!("expected maximum {}", keywords.maximum.unwrap_or_default()),
+                    );
+                }
+            }
+            _ => {}
+        }
+
+        for node in &keywords.all_of {
+            self.validate_node(node, value, path, violations);
+        }
+        if !keywords.any_of.is_empty()
+            && !keywords
+                .any_of
+                .iter()
+                .any(|node| self.is_valid(node, value))
+        {
+            violation(violations, path, "does not match any allowed schema");
+        }
+        if !keywords.one_of.is_empty()
+            && keywords
+                .one_of
+                .iter()
+                .filter(|node| self.is_valid(node, value))
+                .count()
+                != 1
+        {
+            violation(
+                violations,
+                path,
+                "does not match exactly one allowed schema",
+            );
+        }
+    }
+
+    fn validate_object(
+        &self,
+        keywords: &Keywords,
+        object: &Map<String, Value>,
+        path: &str,
+        violations: &mut Vec<String>,
+    ) {
+        for name in &keywords.required {
+            if !object.contains_key(name) {
+                violation(
+                    violations,
+                    path,
+                    format!("missing required property '{}'", name),
+                );
+            }
+        }
+        for (name, value) in object {
+            let property_path = format!("{}/{}", path, name.replace('~', "~0").replace('/', "~1"));
+            match (
+                keywords.properties.get(name),
+                &keywords.additional_properties,
+            ) {
+                (Some(property), _) => {
+                    self.validate_node(property, value, &property_path, violations)
+                }
+                (None, Some(additional)) => {
+                    self.validate_node(additional, value, &property_path, violations)
+                }
+                (None, None) => {}
+            }
+        }
+    }
+}
+
+impl FromStr for MtbFileSchema {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let schema = serde_json::from_str::<Value>(s)
+            .map_err(|e| ValidationError(format!("Invalid JSON schema: {}", e)))?;
+        let mut definitions = HashMap::new();
+        for key in ["definitions", "$defs"] {
+            if let Some(Value::Object(defs)) = schema.get(key) {
+                for (name, definition) in defs {
+                    definitions.insert(format!("#/{}/{}", key, name), compile(definition)?);
+                }
+            }
+        }
+        let schema = MtbFileSchema {
+            root: compile(&schema)?,
+            definitions,
+        };
+        schema.check_reference_cycles()?;
+        Ok(schema)
+    }
+}
+
+impl MtbFileSchema {
+    /// Fails if references resolve to themselves without validating a nested value,
+    /// e.g. `{"$ref": "#/definitions/A"}` as definition `A`, which would recurse endlessly
+    fn check_reference_cycles(&self) -> Result<(), AppError> {
+        for start in self.definitions.keys().map(String::as_str).chain(["#"]) {
+            let mut visited = vec![start];
+            let mut pending = self.node(start).map(references).unwrap_or_default();
+            while let Some(reference) = pending.pop() {
+                if reference == start {
+                    return Err(ValidationError(format!(
+                        "Invalid JSON schema: cyclic reference '{}'",
+                        start
+                    )));
+                }
+                if visited.contains(&reference) {
+                    continue;
+                }
+                visited.push(reference);
+                pending.extend(self.node(reference).map(references).unwrap_or_default());
+            }
+        }
+        Ok(())
+    }
+
+    fn node(&self, reference: &str) -> Option<&Node> {
+        match self.definitions.get(reference) {
+            Some(definition) => Some(definition),
+            None if reference == "#" => Some(&self.root),
+            None => None,
+        }
+    }
+}
+
+/// References resolved for the same value as the node, not including those of nested values
+fn references(node: &Node) -> Vec<&str> {
+    let Node::Schema(keywords) = node else {
+        return vec![];
+    };
+    match &keywords.reference {
+        Some(reference) => vec![reference.as_str()],
+        None => keywords
+            .all_of
+            .iter()
+            .chain(&keywords.any_of)
+            .chain(&keywords.one_of)
+            .flat_map(references)
+            .collect(),
+    }
+}
+
+/// Adds violation at JSON pointer of the location, `/` for the content itself
+fn violation(violations: &mut Vec<String>, path: &str, message: impl Display) {
+    let location = if path.is_empty() { "/" } else { path };
+    violations.push(format!("{}: {}", location, message));
+}
+
+fn compile(schema: &Value) -> Result<Node, AppError> {
+    let object = match schema {
+        Value::Bool(b) => return Ok(Node::Bool(*b)),
+        Value::Object(object) => object,
+        _ => {
+            return Err(ValidationError(
+                "Invalid JSON schema: schema must be an object or boolean".into(),
+            ))
+        }
+    };
+    let compile_all = |key: &str| -> Result<Vec<Node>, AppError> {
+        match object.get(key) {
+            Some(Value::Array(schemas)) => schemas.iter().map(compile).collect(),
+            _ => Ok(vec![]),
+        }
+    };
+    let string = |key: &str| object.get(key).and_then(Value::as_str).map(String::from);
+    let unsigned = |key: &str| object.get(key).and_then(Value::as_u64);
+    let float = |key: &str| object.get(key).and_then(Value::as_f64);
+
+    let mut keywords = Keywords {
+        reference: string("$ref"),
+        types: match object.get("type") {
+            Some(Value::String(t)) => vec![t.clone()],
+            Some(Value::Array(types)) => types
+                .iter()
+                .filter_map(Value::as_str)
+                .map(String::from)
+                .collect(),
+            _ => vec![],
+        },
+        enum_values: object.get("enum").and_then(Value::as_array).cloned(),
+        const_value: object.get("const").cloned(),
+        required: object
+            .get("required")
+            .and_then(Value::as_array)
+            .map(|names| {
+                names
+                    .iter()
+                    .filter_map(Value::as_str)
+                    .map(String::from)
+                    .collect()
+            })
+            .unwrap_or_default(),
+        format: string("format"),
+        min_length: unsigned("minLength"),
+        max_length: unsigned("maxLength"),
+        min_items: unsigned("minItems"),
+        max_items: unsigned("maxItems"),
+        minimum: float("minimum"),
+        maximum: float("maximum"),
+        all_of: compile_all("allOf")?,
+        any_of: compile_all("anyOf")?,
+        one_of: compile_all("oneOf")?,
+        ..Keywords::default()
+    };
+    if let Some(pattern) = string("pattern") {
+        keywords.pattern = Some(Regex::new(&pattern).map_err(|e| {
+            ValidationError(format!("Invalid JSON schema pattern '{}': {}", pattern, e))
+        })?);
+    }
+    if let Some(Value::Object(properties)) = object.get("properties") {
+        for (name, property) in properties {
+            keywords.properties.insert(name.clone(), compile(property)?);
+        }
+    }
+    if let Some(additional) = object.get("additionalProperties") {
+        keywords.additional_properties = Some(compile(additional)?);
+    }
+    if let Some(items) = object.get("items") {
+        keywords.items = Some(compile(items)?);
+    }
+    Ok(Node::Schema(Box::new(keywords)))
+}
+
+fn has_type(value: &Value, json_type: &str) -> bool {
+    match json_type {
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "string" => value.is_string(),
+        "boolean" => value.is_boolean(),
+        "null" => value.is_null(),
+        "number" => value.is_number(),
+        "integer" => {
+            value.is_i64() || value.is_u64() || value.as_f64().is_some_and(|n| n.fract() == 0.0)
+        }
+        _ => true,
+    }
+}
+
+/// Checks `date` and `date-time` formats, other formats are not checked
+fn has_format(s: &str, format: &str) -> bool {
+    let is_date = |date: &str| {
+        let parts = date.split('-').collect::<Vec<_>>();
+        parts.len() == 3
+            && [4, 2, 2]
+                .iter()
+                .zip(&parts)
+                .all(|(len, part)| part.len() == *len && part.chars().all(|c| c.is_ascii_digit()))
+            && (1..=12).contains(&parts[1].parse::<u8>().unwrap_or_default())
+            && (1..=31).contains(&parts[2].parse::<u8>().unwrap_or_default())
+    };
+    match format {
+        "date" => is_date(s),
+        "date-time" => s.split_once(['T', 't']).is_some_and(|(date, time)| {
+            is_date(date)
+                && time.len() >= 8
+                && time[..8].chars().enumerate().all(|(i, c)| {
+                    if i == 2 || i == 5 {
+                        c == ':'
+                    } else {
+                        c.is_ascii_digit()
+                    }
+                })
+        }),
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use serde_json::json;
+
+    use crate::schema::MtbFileSchema;
+
+    const SCHEMA: &str = r##"{
+        "type": "object",
+        "required": ["patient", "diagnoses"],
+        "properties": {
+            "patient": { "$ref": "#/definitions/Patient" },
+            "diagnoses": {
+                "type": "array",
+                "minItems": 1,
+                "items": {
+                    "type": "object",
+                    "required": ["icd10"],
+                    "properties": {
+                        "icd10": { "type": "string", "pattern": "^[A-Z][0-9]{2}(\\.[0-9]+)?$" },
+                        "recordedOn": { "type": "string", "format": "date" }
+                    }
+                }
+            }
+        },
+        "definitions": {
+            "Patient": {
+                "type": "object",
+                "required": ["id"],
+                "properties": {
+                    "id": { "type": "string", "minLength": 1 },
+                    "gender": { "enum": ["male", "female", "other", "unknown"] }
+                }
+            }
+        }
+    }"##;
+
+    #[test]
+    fn should_accept_valid_content() {
+        let schema = MtbFileSchema::from_str(SCHEMA).unwrap();
+        let content = json!({
+            "patient": { "id": "TESTPATIENT1234", "gender": "female" },
+            "diagnoses": [{ "icd10": "C25.0", "recordedOn": "2024-02-29" }]
+        });
+
+        assert!(schema.validate(&content).is_empty());
+    }
+
+    #[test]
+    fn should_return_violations_without_values() {
+        let schema = MtbFileSchema::from_str(SCHEMA).unwrap();
+        let content = json!({
+            "patient": { "id": "", "gender": "TESTGENDER" },
+            "diagnoses": [{ "icd10": "TESTCODE", "recordedOn": "29.02.2024" }, {}]
+        });
+
+        assert_eq!(
+            schema.validate(&content),
+            vec![
+                "/diagnoses/0/icd10: does not match pattern '^[A-Z][0-9]{2}(\\.[0-9]+)?$'",
+                "/diagnoses/0/recordedOn: invalid date format",
+                "/diagnoses/1: missing required property 'icd10'",
+                "/patient/gender: not one of the allowed values",
+                "/patient/id: expected at least 1 characters",
+            ]
+        );
+    }
+
+    #[test]
+    fn should_return_missing_required_properties_and_types() {
+        let schema = MtbFileSchema::from_str(SCHEMA).unwrap();
+
+        assert_eq!(
+            schema.validate(&json!({ "diagnoses": "C25.0" })),
+            vec![
+                "/: missing required property 'patient'",
+                "/diagnoses: expected type array",
+            ]
+        );
+    }
+
+    #[test]
+    fn should_validate_date_time_format() {
+        let schema =
+            MtbFileSchema::from_str(r#"{ "type": "string", "format": "date-time" }"#).unwrap();
+
+        assert!(schema.validate(&json!("2024-02-29T12:30:00Z")).is_empty());
+        assert!(!schema.validate(&json!("2024-02-29 12:30")).is_empty());
+    }
+
+    #[test]
+    fn should_reject_cyclic_references() {
+        for schema in [
+            r##"{ "definitions": { "A": { "$ref": "#/definitions/A" } } }"##,
+            r##"{ "$defs": { "A": { "$ref": "#/$defs/B" }, "B": { "allOf": [{ "$ref": "#/$defs/A" }] } } }"##,
+            r##"{ "anyOf": [{ "$ref": "#" }] }"##,
+        ] {
+            assert!(MtbFileSchema::from_str(schema).is_err(), "{}", schema);
+        }
+    }
+
+    #[test]
+    fn should_accept_recursive_references_for_nested_values() {
+        let schema = MtbFileSchema::from_str(
+            r##"{ "$ref": "#/definitions/Node", "definitions": { "Node": { "type": "object", "properties": { "children": { "type": "array", "items": { "$ref": "#/definitions/Node" } } } } } }"##,
+        )
+        .unwrap();
+
+        assert!(schema
+            .validate(&json!({ "children": [{ "children": [] }] }))
+            .is_empty());
+        assert!(!schema
+            .validate(&json!({ "children": [{ "children": "none" }] }))
+            .is_empty());
+    }
+
+    #[test]
+    fn should_reject_invalid_schema() {
+        assert!(MtbFileSchema::from_str("{").is_err());
+        assert!(MtbFileSchema::from_str(r#"{ "pattern": "[" }"#).is_err());
+        assert!(MtbFileSchema::from_str("[]").is_err());
+    }
+}
@@ -0,0 +1,298 @@
+/*
+ * This file is part of ETL-Processor
+ *
+ * Copyright (c) 2024  Comprehensive Cancer Center Mainfranken
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published
+ * by the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use clap::ValueEnum;
+use log::debug;
+
+use crate::bwhc_client::{BwhcClient, DeleteMode, HttpResponse};
+use crate::config::Config;
+use crate::AppError;
+use crate::AppError::{IoError, MissingConfig};
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum SinkType {
+    /// Send requests to bwHC-Backend
+    Http,
+    /// Write requests to files in sink directory
+    File,
+    /// Accept all requests without any I/O
+    Null,
+}
+
+/// Target of requests
+pub enum Sink {
+    Http(Box<BwhcClient>),
+    File(FileSink),
+    Null(NullSink),
+}
+
+impl Sink {
+    pub fn new(config: &Config) -> Result<Self, AppError> {
+        match config.sink {
+            SinkType::Http => Ok(Sink::Http(Box::new(BwhcClient::new(config)?))),
+            SinkType::File => match &config.sink_dir {
+                Some(dir) => Ok(Sink::File(FileSink::new(dir.clone(), config.delete_mode))),
+                None => Err(MissingConfig("APP_SINK_DIR".into())),
+            },
+            SinkType::Null => Ok(Sink::Null(NullSink::new(
+                config.sink_null_status,
+                Duration::from_millis(config.sink_null_delay_ms),
+                config.delete_mode,
+            ))),
+        }
+    }
+
+    pub async fn send_mtb_file(
+        &self,
+        request_id: &str,
+        patient_id: Option<&str>,
+        content: &str,
+        tenant: Option<&str>,
+    ) -> Result<HttpResponse, AppError> {
+        match self {
+            Sink::Http(client) => {
+                client
+                    .send_mtb_file(request_id, patient_id, content, tenant)
+                    .await
+            }
+            Sink::File(sink) => sink.write(format!("{}.json", request_id), content).await,
+            Sink::Null(sink) => sink.accept().await,
+        }
+    }
+
+    pub async fn send_delete(
+        &self,
+        request_id: &str,
+        patient_id: &str,
+        site_id: Option<&str>,
+        tenant: Option<&str>,
+    ) -> Result<HttpResponse, AppError> {
+        match self {
+            Sink::Http(client) => {
+                client
+                    .send_delete(request_id, patient_id, site_id, tenant)
+                    .await
+            }
+            Sink::File(sink) => sink.write(format!("{}.delete", patient_id), "").await,
+            Sink::Null(sink) => sink.accept().await,
+        }
+    }
+
+    pub async fn send_consent(
+        &self,
+        request_id: &str,
+        consent: &str,
+        tenant: Option<&str>,
+    ) -> Result<HttpResponse, AppError> {
+        match self {
+            Sink::Http(client) => client.send_consent(request_id, consent, tenant).await,
+            Sink::File(sink) => {
+                sink.write(format!("{}.consent.json", request_id), consent)
+                    .await
+            }
+            Sink::Null(sink) => sink.accept().await,
+        }
+    }
+
+    pub fn delete_mode(&self) -> DeleteMode {
+        match self {
+            Sink::Http(client) => client.delete_mode(),
+            Sink::File(sink) => sink.delete_mode,
+            Sink::Null(sink) => sink.delete_mode,
+        }
+    }
+}
+
+/// Writes requests to files instead of sending them to bwHC-Backend
+pub struct FileSink {
+    dir: PathBuf,
+    delete_mode: DeleteMode,
+}
+
+impl FileSink {
+    pub fn new(dir: PathBuf, delete_mode: DeleteMode) -> Self {
+        FileSink { dir, delete_mode }
+    }
+
+    async fn write(&self, file_name: String, content: &str) -> Result<HttpResponse, AppError> {
+        let path = self.dir.join(Self::sanitize(file_name.as_str()));
+        tokio::fs::write(&path, content)
+            .await
+            .map_err(|e| IoError(format!("{}: {}", path.display(), e)))?;
+
+        debug!("Request written to '{}'", path.display());
+        Ok(HttpResponse {
+            status_code: 200,
+            status_body: String::new(),
+            headers: BTreeMap::new(),
+            endpoint: None,
+            content_type: None,
+            original_status_code: None,
+            truncated: false,
+            retry_after: None,
+        })
+    }
+
+    /// Replaces all characters that might not be used in file names
+    fn sanitize(file_name: &str) -> String {
+        file_name
+            .chars()
+            .map(|c| match c {
+                'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '-' | '_' => c,
+                _ => '_',
+            })
+            .collect::<String>()
+            .trim_start_matches('.')
+            .to_string()
+    }
+}
+
+/// Accepts all requests with configured status code after configured delay
+pub struct NullSink {
+    status_code: u16,
+    delay: Duration,
+    delete_mode: DeleteMode,
+}
+
+impl NullSink {
+    pub fn new(status_code: u16, delay: Duration, delete_mode: DeleteMode) -> Self {
+        NullSink {
+            status_code,
+            delay,
+            delete_mode,
+        }
+    }
+
+    async fn accept(&self) -> Result<HttpResponse, AppError> {
+        if !self.delay.is_zero() {
+            tokio::time::sleep(self.delay).await;
+        }
+        Ok(HttpResponse {
+            status_code: self.status_code,
+            status_body: String::new(),
+            headers: BTreeMap::new(),
+            endpoint: None,
+            content_type: None,
+            original_status_code: None,
+            truncated: false,
+            retry_after: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::time::{Duration, Instant};
+
+    use crate::bwhc_client::DeleteMode;
+    use crate::sink::{FileSink, NullSink, Sink};
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("kafka-to-bwhc-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn should_write_mtb_file() {
+        let dir = test_dir("mtbfile");
+        let sink = Sink::File(FileSink::new(dir.clone(), DeleteMode::Delete));
+
+        let actual = sink
+            .send_mtb_file("request0123456789", None, r#"{"consent":{}}"#, None)
+            .await;
+
+        assert_eq!(actual.unwrap().status_code, 200);
+        assert_eq!(
+            std::fs::read_to_string(dir.join("request0123456789.json")).unwrap(),
+            r#"{"consent":{}}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn should_write_delete_file() {
+        let dir = test_dir("delete");
+        let sink = Sink::File(FileSink::new(dir.clone(), DeleteMode::Delete));
+
+        let actual = sink
+            .send_delete("request0123456789", "TESTPATIENT1234", None, None)
+            .await;
+
+        assert_eq!(actual.unwrap().status_code, 200);
+        assert!(dir.join("TESTPATIENT1234.delete").exists());
+    }
+
+    #[tokio::test]
+    async fn should_return_error_if_file_cannot_be_written() {
+        let dir = test_dir("missing").join("missing");
+        let sink = Sink::File(FileSink::new(dir, DeleteMode::Delete));
+
+        let actual = sink
+            .send_delete("request0123456789", "TESTPATIENT1234", None, None)
+            .await;
+
+        assert!(actual.is_err());
+    }
+
+    #[test]
+    fn should_sanitize_file_name() {
+        assert_eq!(
+            FileSink::sanitize("../TEST/PATIENT 1234.delete"),
+            "_TEST_PATIENT_1234.delete"
+        );
+        assert_eq!(
+            FileSink::sanitize("request0123456789.json"),
+            "request0123456789.json"
+        );
+    }
+
+    #[tokio::test]
+    async fn should_accept_requests_with_configured_status_code() {
+        let sink = Sink::Null(NullSink::new(201, Duration::ZERO, DeleteMode::Delete));
+
+        let actual = sink
+            .send_mtb_file("request0123456789", None, r#"{"consent":{}}"#, None)
+            .await;
+
+        assert_eq!(actual.unwrap().status_code, 201);
+    }
+
+    #[tokio::test]
+    async fn should_accept_requests_after_delay() {
+        let sink = Sink::Null(NullSink::new(
+            200,
+            Duration::from_millis(50),
+            DeleteMode::Delete,
+        ));
+
+        let start = Instant::now();
+        let actual = sink
+            .send_delete("request0123456789", "TESTPATIENT1234", None, None)
+            .await;
+
+        assert_eq!(actual.unwrap().status_code, 200);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+}
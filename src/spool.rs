@@ -0,0 +1,403 @@
+/*
+ * This file is part of ETL-Processor
+ *
+ * Copyright (c) 2024  Comprehensive Cancer Center Mainfranken
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published
+ * by the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use log::{error, info, warn};
+use metrics::counter;
+use rdkafka::message::{Header, Headers, OwnedHeaders};
+use rdkafka::producer::{FutureProducer, FutureRecord};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+
+use crate::AppError;
+use crate::AppError::IoError;
+
+/// Extension of spooled records, temporary and invalid files use other extensions
+const EXTENSION: &str = "json";
+
+/// Record that could not be sent to Kafka, with payload and header values encoded as base64
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct SpooledRecord {
+    pub topic: String,
+    pub key: String,
+    pub payload: String,
+    #[serde(default)]
+    pub headers: Vec<SpooledHeader>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct SpooledHeader {
+    pub key: String,
+    pub value: Option<String>,
+}
+
+impl SpooledRecord {
+    pub fn new<H: Headers>(topic: &str, key: &str, payload: &[u8], headers: Option<&H>) -> Self {
+        SpooledRecord {
+            topic: topic.to_string(),
+            key: key.to_string(),
+            payload: BASE64.encode(payload),
+            headers: headers
+                .map(|headers| {
+                    headers
+                        .iter()
+                        .map(|header| SpooledHeader {
+                            key: header.key.to_string(),
+                            value: header.value.map(|value| BASE64.encode(value)),
+                        })
+                        .collect()
+                })
+                .unwrap_or_default(),
+        }
+    }
+
+    fn payload_bytes(&self) -> Result<Vec<u8>, String> {
+        BASE64
+            .decode(&self.payload)
+            .map_err(|_| "payload is not valid base64".to_string())
+    }
+
+    fn owned_headers(&self) -> Result<OwnedHeaders, String> {
+        let mut headers = OwnedHeaders::new_with_capacity(self.headers.len());
+        for header in &self.headers {
+            let value = header
+                .value
+                .as_deref()
+                .map(|value| BASE64.decode(value))
+                .transpose()
+                .map_err(|_| format!("header '{}' is not valid base64", header.key))?;
+            headers = headers.insert(Header {
+                key: &header.key,
+                value: value.as_deref(),
+            });
+        }
+        Ok(headers)
+    }
+}
+
+/// Local directory for records that could not be sent to Kafka, replayed in order of spooling
+#[derive(Clone)]
+pub struct Spool {
+    dir: PathBuf,
+    sequence: Arc<AtomicU64>,
+}
+
+impl Spool {
+    /// Creates the spool directory if it does not exist
+    pub fn new(dir: PathBuf) -> Result<Self, AppError> {
+        std::fs::create_dir_all(&dir).map_err(|e| IoError(format!("{}: {}", dir.display(), e)))?;
+        Ok(Spool {
+            dir,
+            sequence: Arc::new(AtomicU64::new(0)),
+        })
+    }
+
+    /// Writes record to a new file. The file is synced and renamed once completely written
+    /// so that replay never reads partially written records and spooled records survive a crash.
+    pub async fn store(&self, record: &SpooledRecord) -> Result<PathBuf, AppError> {
+        let result = self.write(record).await;
+        match result {
+            Ok(_) => counter!("kafka_spooled_records_total").increment(1),
+            Err(_) => counter!("kafka_spool_failures_total").increment(1),
+        }
+        result
+    }
+
+    async fn write(&self, record: &SpooledRecord) -> Result<PathBuf, AppError> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let sequence = self.sequence.fetch_add(1, Ordering::Relaxed);
+        let name = format!("{:020}-{:010}", timestamp, sequence);
+        let temporary = self.dir.join(format!("{}.tmp", name));
+        let path = self.dir.join(format!("{}.{}", name, EXTENSION));
+
+        let content = serde_json::to_vec(record).map_err(|e| IoError(e.to_string()))?;
+        let io_error = |e: std::io::Error| IoError(format!("{}: {}", temporary.display(), e));
+        let mut file = tokio::fs::File::create(&temporary)
+            .await
+            .map_err(io_error)?;
+        file.write_all(&content).await.map_err(io_error)?;
+        file.sync_all().await.map_err(io_error)?;
+        drop(file);
+        tokio::fs::rename(&temporary, &path)
+            .await
+            .map_err(|e| IoError(format!("{}: {}", path.display(), e)))?;
+        // Sync directory so that the rename is persisted
+        let dir_error = |e: std::io::Error| IoError(format!("{}: {}", self.dir.display(), e));
+        tokio::fs::File::open(&self.dir)
+            .await
+            .map_err(dir_error)?
+            .sync_all()
+            .await
+            .map_err(dir_error)?;
+
+        Ok(path)
+    }
+
+    /// Spooled files ordered by time of spooling
+    async fn files(&self) -> Result<Vec<PathBuf>, AppError> {
+        let mut entries = tokio::fs::read_dir(&self.dir)
+            .await
+            .map_err(|e| IoError(format!("{}: {}", self.dir.display(), e)))?;
+        let mut files = Vec::new();
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .map_err(|e| IoError(format!("{}: {}", self.dir.display(), e)))?
+        {
+            let path = entry.path();
+            if path
+                .extension()
+                .is_some_and(|extension| extension == EXTENSION)
+            {
+                files.push(path);
+            }
+        }
+        files.sort();
+        Ok(files)
+    }
+
+    /// Sends spooled records in order and removes each file once sent.
+    /// Stops at the first record that cannot be sent to keep the order.
+    /// Files that cannot be parsed are renamed to `.invalid` and skipped.
+    /// Returns the number of records sent.
+    pub async fn replay<F, Fut>(&self, send: F) -> Result<usize, AppError>
+    where
+        F: Fn(SpooledRecord) -> Fut,
+        Fut: Future<Output = Result<(), String>>,
+    {
+        let mut sent = 0;
+        for path in self.files().await? {
+            let record = match read_record(&path).await {
+                Ok(record) => record,
+                Err(reason) => {
+                    error!(
+                        "Cannot replay spooled record '{}': {}",
+                        path.display(),
+                        reason
+                    );
+                    let _ = tokio::fs::rename(&path, path.with_extension("invalid")).await;
+                    continue;
+                }
+            };
+            if let Err(reason) = send(record).await {
+                warn!(
+                    "Spooled record '{}' not sent, retrying later: {}",
+                    path.display(),
+                    reason
+                );
+                break;
+            }
+            tokio::fs::remove_file(&path)
+                .await
+                .map_err(|e| IoError(format!("{}: {}", path.display(), e)))?;
+            sent += 1;
+        }
+        Ok(sent)
+    }
+}
+
+/// Reads spooled record, failing if payload or header values are not valid base64
+async fn read_record(path: &Path) -> Result<SpooledRecord, String> {
+    let content = tokio::fs::read(path).await.map_err(|e| e.to_string())?;
+    let record = serde_json::from_slice::<SpooledRecord>(&content).map_err(|e| e.to_string())?;
+    record.payload_bytes()?;
+    record.owned_headers()?;
+    Ok(record)
+}
+
+/// Sends spooled record to its topic
+pub async fn produce(producer: &FutureProducer, record: SpooledRecord) -> Result<(), String> {
+    let payload = record.payload_bytes()?;
+    let headers = record.owned_headers()?;
+    producer
+        .send(
+            FutureRecord::to(&record.topic)
+                .key(&record.key)
+                .payload(&payload)
+                .headers(headers),
+            Duration::from_secs(1),
+        )
+        .await
+        .map(|_| ())
+        .map_err(|e| e.0.to_string())
+}
+
+/// Replays spooled records in given interval, starting immediately
+pub async fn replay_periodically(spool: Spool, producer: FutureProducer, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        match spool.replay(|record| produce(&producer, record)).await {
+            Ok(0) => {}
+            Ok(sent) => info!("Replayed {} spooled records", sent),
+            Err(e) => error!("Cannot replay spooled records: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::sync::Mutex;
+
+    use rdkafka::message::{Header, OwnedHeaders};
+
+    use crate::spool::{Spool, SpooledHeader, SpooledRecord};
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "kafka-to-bwhc-spool-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn record(key: &str) -> SpooledRecord {
+        SpooledRecord::new::<OwnedHeaders>("test-response", key, b"{}", None)
+    }
+
+    fn files(dir: &PathBuf) -> Vec<String> {
+        let mut files = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn should_encode_payload_and_headers() {
+        let headers = OwnedHeaders::new()
+            .insert(Header {
+                key: "tenant",
+                value: Some("site-a"),
+            })
+            .insert(Header::<&[u8]> {
+                key: "empty",
+                value: None,
+            });
+
+        let actual = SpooledRecord::new("test-dlq", "key", &[0xff, 0x00], Some(&headers));
+
+        assert_eq!(actual.payload, "/wA=");
+        assert_eq!(actual.payload_bytes().unwrap(), vec![0xff, 0x00]);
+        assert_eq!(
+            actual.headers,
+            vec![
+                SpooledHeader {
+                    key: "tenant".into(),
+                    value: Some("c2l0ZS1h".into())
+                },
+                SpooledHeader {
+                    key: "empty".into(),
+                    value: None
+                }
+            ]
+        );
+        assert!(actual.owned_headers().is_ok());
+    }
+
+    #[tokio::test]
+    async fn should_store_and_replay_records_in_order() {
+        let dir = test_dir("replay");
+        let spool = Spool::new(dir.clone()).unwrap();
+        for key in ["first", "second", "third"] {
+            spool.store(&record(key)).await.unwrap();
+        }
+        assert_eq!(files(&dir).len(), 3);
+
+        let sent = Mutex::new(Vec::new());
+        let actual = spool
+            .replay(|record| {
+                sent.lock().unwrap().push(record.key);
+                async { Ok(()) }
+            })
+            .await;
+
+        assert_eq!(actual.unwrap(), 3);
+        assert_eq!(*sent.lock().unwrap(), vec!["first", "second", "third"]);
+        assert!(files(&dir).is_empty());
+    }
+
+    #[tokio::test]
+    async fn should_return_error_if_record_cannot_be_stored() {
+        let dir = test_dir("store-failure");
+        let spool = Spool::new(dir.clone()).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let actual = spool.store(&record("first")).await;
+
+        assert!(actual.is_err());
+    }
+
+    #[tokio::test]
+    async fn should_keep_records_if_not_sent() {
+        let dir = test_dir("failure");
+        let spool = Spool::new(dir.clone()).unwrap();
+        for key in ["first", "second"] {
+            spool.store(&record(key)).await.unwrap();
+        }
+
+        let sent = Mutex::new(Vec::new());
+        let actual = spool
+            .replay(|record| {
+                sent.lock().unwrap().push(record.key);
+                async { Err("Kafka unavailable".to_string()) }
+            })
+            .await;
+
+        assert_eq!(actual.unwrap(), 0);
+        assert_eq!(*sent.lock().unwrap(), vec!["first"]);
+        assert_eq!(files(&dir).len(), 2);
+    }
+
+    #[tokio::test]
+    async fn should_skip_invalid_files_and_ignore_temporary_files() {
+        let dir = test_dir("invalid");
+        let spool = Spool::new(dir.clone()).unwrap();
+        std::fs::write(dir.join("0-invalid.json"), "{ invalid").unwrap();
+        std::fs::write(
+            dir.join("1-invalid.json"),
+            r#"{"topic": "test-response", "key": "key", "payload": "not base64!"}"#,
+        )
+        .unwrap();
+        std::fs::write(dir.join("0-partial.tmp"), "{").unwrap();
+        spool.store(&record("valid")).await.unwrap();
+
+        let actual = spool.replay(|_| async { Ok(()) }).await;
+
+        assert_eq!(actual.unwrap(), 1);
+        assert_eq!(
+            files(&dir),
+            vec!["0-invalid.invalid", "0-partial.tmp", "1-invalid.invalid"]
+        );
+    }
+}
@@ -0,0 +1,171 @@
+/*
+ * This file is part of ETL-Processor
+ *
+ * Copyright (c) 2024  Comprehensive Cancer Center Mainfranken
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published
+ * by the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use log::info;
+use tracing::Span;
+
+use crate::health::{BACKEND_HEALTH, READINESS};
+
+/// Counts of processed records since start
+pub static STATS: Stats = Stats::new();
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Outcome {
+    Posted,
+    Deleted,
+    Ignored,
+    Failed,
+    ParseError,
+}
+
+impl Outcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Outcome::Posted => "posted",
+            Outcome::Deleted => "deleted",
+            Outcome::Ignored => "ignored",
+            Outcome::Failed => "failed",
+            Outcome::ParseError => "parse_error",
+        }
+    }
+}
+
+pub struct Stats {
+    consumed: AtomicU64,
+    posted: AtomicU64,
+    deleted: AtomicU64,
+    ignored: AtomicU64,
+    failed: AtomicU64,
+    parse_errors: AtomicU64,
+}
+
+impl Stats {
+    pub const fn new() -> Self {
+        Stats {
+            consumed: AtomicU64::new(0),
+            posted: AtomicU64::new(0),
+            deleted: AtomicU64::new(0),
+            ignored: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+            parse_errors: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record_consumed(&self) {
+        self.consumed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts outcome and records it in the span of the current record, if any
+    pub fn record(&self, outcome: Outcome) {
+        Span::current().record("outcome", outcome.as_str());
+        let counter = match outcome {
+            Outcome::Posted => &self.posted,
+            Outcome::Deleted => &self.deleted,
+            Outcome::Ignored => &self.ignored,
+            Outcome::Failed => &self.failed,
+            Outcome::ParseError => &self.parse_errors,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl Display for Stats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "consumed: {}, posted: {}, deleted: {}, ignored: {}, failed: {}, parse errors: {}",
+            self.consumed.load(Ordering::Relaxed),
+            self.posted.load(Ordering::Relaxed),
+            self.deleted.load(Ordering::Relaxed),
+            self.ignored.load(Ordering::Relaxed),
+            self.failed.load(Ordering::Relaxed),
+            self.parse_errors.load(Ordering::Relaxed)
+        )
+    }
+}
+
+/// Logs counts of processed records in given interval
+pub async fn log_periodically(interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    // First tick completes immediately
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        if !READINESS.is_ready() {
+            info!("Application not ready - warm-up pending");
+        }
+        info!("Processed records since start - {}", STATS);
+        if let Some(health) = BACKEND_HEALTH.last() {
+            info!(
+                "Last bwHC-Backend health probe {}s ago: {} ({}, {}ms)",
+                health.timestamp.elapsed().unwrap_or_default().as_secs(),
+                if health.healthy {
+                    "healthy"
+                } else {
+                    "unhealthy"
+                },
+                health
+                    .status_code
+                    .map(|status_code| format!("HTTP {}", status_code))
+                    .unwrap_or("not reachable".into()),
+                health.latency.as_millis()
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::stats::{Outcome, Stats};
+
+    #[test]
+    fn should_start_with_zero_counts() {
+        let stats = Stats::new();
+
+        assert_eq!(
+            stats.to_string(),
+            "consumed: 0, posted: 0, deleted: 0, ignored: 0, failed: 0, parse errors: 0"
+        )
+    }
+
+    #[test]
+    fn should_increment_counts() {
+        let stats = Stats::new();
+
+        stats.record_consumed();
+        stats.record_consumed();
+        stats.record_consumed();
+        stats.record_consumed();
+        stats.record_consumed();
+        stats.record(Outcome::Posted);
+        stats.record(Outcome::Posted);
+        stats.record(Outcome::Deleted);
+        stats.record(Outcome::Ignored);
+        stats.record(Outcome::ParseError);
+
+        assert_eq!(
+            stats.to_string(),
+            "consumed: 5, posted: 2, deleted: 1, ignored: 1, failed: 0, parse errors: 1"
+        )
+    }
+}
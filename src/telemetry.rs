@@ -0,0 +1,126 @@
+/*
+ * This file is part of ETL-Processor
+ *
+ * Copyright (c) 2024  Comprehensive Cancer Center Mainfranken
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published
+ * by the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use opentelemetry::global;
+use opentelemetry::propagation::{Injector, TextMapPropagator};
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::{runtime, trace, Resource};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::layer::SubscriberExt;
+
+use crate::config::Config;
+use crate::AppError;
+use crate::AppError::ConnectionError;
+
+/// Exports tracing spans to `APP_OTLP_ENDPOINT` if configured.
+/// Spans are not recorded otherwise.
+pub fn init(config: &Config) -> Result<(), AppError> {
+    let endpoint = match &config.otlp_endpoint {
+        Some(endpoint) => endpoint,
+        None => return Ok(()),
+    };
+
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .http()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(trace::config().with_resource(Resource::new([KeyValue::new(
+            "service.name",
+            env!("CARGO_PKG_NAME"),
+        )])))
+        .install_batch(runtime::TokioCurrentThread)
+        .map_err(|e| ConnectionError(format!("Cannot export spans: {}", e)))?;
+
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    tracing::subscriber::set_global_default(
+        tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer)),
+    )
+    .map_err(|e| ConnectionError(format!("Cannot export spans: {}", e)))
+}
+
+/// Exports remaining spans
+pub fn shutdown() {
+    global::shutdown_tracer_provider();
+}
+
+/// Headers to propagate the context of the current span, e.g. `traceparent`.
+/// Empty if spans are not exported.
+pub fn trace_context_headers() -> HeaderMap {
+    global::get_text_map_propagator(inject)
+}
+
+fn inject(propagator: &dyn TextMapPropagator) -> HeaderMap {
+    let mut injector = HeaderInjector(HeaderMap::new());
+    propagator.inject_context(&Span::current().context(), &mut injector);
+    injector.0
+}
+
+struct HeaderInjector(HeaderMap);
+
+impl Injector for HeaderInjector {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(key.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            self.0.insert(name, value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use opentelemetry::trace::TracerProvider;
+    use opentelemetry_sdk::propagation::TraceContextPropagator;
+    use opentelemetry_sdk::trace::TracerProvider as SdkTracerProvider;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use crate::telemetry::inject;
+
+    #[test]
+    fn should_inject_traceparent_of_current_span() {
+        let provider = SdkTracerProvider::builder().build();
+        let tracer = provider.tracer("test");
+        let subscriber =
+            tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer));
+
+        let headers = tracing::subscriber::with_default(subscriber, || {
+            let _span = tracing::info_span!("record").entered();
+            inject(&TraceContextPropagator::new())
+        });
+
+        let traceparent = headers.get("traceparent").unwrap().to_str().unwrap();
+        assert!(traceparent.starts_with("00-"));
+        assert_eq!(traceparent.len(), 55)
+    }
+
+    #[test]
+    fn should_not_inject_headers_without_span() {
+        let headers = inject(&TraceContextPropagator::new());
+
+        assert!(headers.is_empty())
+    }
+}
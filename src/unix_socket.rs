@@ -0,0 +1,198 @@
+/*
+ * This file is part of ETL-Processor
+ *
+ * Copyright (c) 2024  Comprehensive Cancer Center Mainfranken
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published
+ * by the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use bytes::Bytes;
+use hyper::client::Client;
+use hyper::header::HOST;
+use hyper::{Body, Request};
+use hyperlocal::UnixConnector;
+use reqwest::header::{HeaderMap, HeaderValue};
+use reqwest::{RequestBuilder, Response};
+
+use crate::AppError;
+use crate::AppError::{HttpConnectError, HttpError, HttpTimeout};
+
+/// Scheme of `APP_REST_URI` to send requests using a unix domain socket
+pub const UNIX_SCHEME: &str = "unix://";
+
+/// Base URI of requests sent using a unix domain socket. Only path and query are used.
+pub const UNIX_BASE_URI: &str = "http://localhost";
+
+/// Sends requests built by reqwest using a unix domain socket,
+/// e.g. if bwHC-Backend runs within the same pod without a TCP listener
+pub struct UnixSocket {
+    path: PathBuf,
+    client: Client<UnixConnector>,
+    default_headers: HeaderMap,
+}
+
+impl UnixSocket {
+    pub fn new(path: PathBuf, default_headers: HeaderMap) -> Self {
+        UnixSocket {
+            path,
+            client: Client::builder().build(UnixConnector),
+            default_headers,
+        }
+    }
+
+    /// Socket path of URI using scheme `unix://`, e.g. `unix:///run/bwhc/api.sock`
+    pub fn path_of(uri: &str) -> Option<PathBuf> {
+        uri.trim()
+            .strip_prefix(UNIX_SCHEME)
+            .filter(|path| !path.is_empty())
+            .map(PathBuf::from)
+    }
+
+    /// Sends request using path and query of its URL. Default headers of the client are added
+    /// unless set on the request. Redirects are not followed.
+    pub async fn send(&self, request: RequestBuilder) -> Result<Response, AppError> {
+        let request = request.build().map_err(|e| HttpError(e.to_string()))?;
+        let url = request.url();
+        let path = match url.query() {
+            Some(query) => format!("{}?{}", url.path(), query),
+            None => url.path().to_string(),
+        };
+
+        let mut builder = Request::builder()
+            .method(request.method().clone())
+            .uri(hyperlocal::Uri::new(&self.path, &path));
+        if let Some(headers) = builder.headers_mut() {
+            headers.insert(HOST, HeaderValue::from_static("localhost"));
+            headers.extend(request.headers().clone());
+            for (name, value) in &self.default_headers {
+                if !headers.contains_key(name) {
+                    headers.insert(name, value.clone());
+                }
+            }
+        }
+        let body = match request.body().and_then(|body| body.as_bytes()) {
+            Some(body) => Body::from(Bytes::copy_from_slice(body)),
+            None => Body::empty(),
+        };
+        let hyper_request = builder.body(body).map_err(|e| HttpError(e.to_string()))?;
+
+        let response = async {
+            let response = self.client.request(hyper_request).await.map_err(|e| {
+                if e.is_connect() {
+                    HttpConnectError(format!("{}: {}", self.path.display(), e))
+                } else {
+                    HttpError(e.to_string())
+                }
+            })?;
+            let (parts, body) = response.into_parts();
+            let body = hyper::body::to_bytes(body)
+                .await
+                .map_err(|e| HttpError(e.to_string()))?;
+            Ok(hyper::Response::from_parts(parts, body))
+        };
+
+        let response = match request.timeout() {
+            Some(timeout) => Self::with_timeout(*timeout, response).await?,
+            None => response.await?,
+        };
+        Ok(Response::from(response))
+    }
+
+    async fn with_timeout<T>(
+        timeout: Duration,
+        future: impl std::future::Future<Output = Result<T, AppError>>,
+    ) -> Result<T, AppError> {
+        tokio::time::timeout(timeout, future)
+            .await
+            .map_err(|_| HttpTimeout(format!("No response within {}s", timeout.as_secs())))?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use reqwest::header::HeaderMap;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::UnixListener;
+
+    use crate::unix_socket::UnixSocket;
+    use crate::AppError;
+
+    fn socket_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "kafka-to-bwhc-{}-{}.sock",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn should_parse_socket_path() {
+        assert_eq!(
+            UnixSocket::path_of("unix:///run/bwhc/api.sock"),
+            Some(PathBuf::from("/run/bwhc/api.sock"))
+        );
+        assert_eq!(UnixSocket::path_of("unix://"), None);
+        assert_eq!(UnixSocket::path_of("http://localhost:9000"), None);
+    }
+
+    #[tokio::test]
+    async fn should_send_request_using_unix_socket() {
+        let path = socket_path("send");
+        let listener = UnixListener::bind(&path).unwrap();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = vec![0; 4096];
+            let len = stream.read(&mut request).await.unwrap();
+            stream
+                .write_all(b"HTTP/1.1 201 Created\r\ncontent-length: 2\r\n\r\n{}")
+                .await
+                .unwrap();
+            String::from_utf8_lossy(&request[..len]).to_string()
+        });
+
+        let socket = UnixSocket::new(path, HeaderMap::new());
+        let response = socket
+            .send(
+                reqwest::Client::new()
+                    .post("http://localhost/bwhc/etl/api/MTBFile")
+                    .body(r#"{"consent":{}}"#),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status().as_u16(), 201);
+        assert_eq!(response.text().await.unwrap(), "{}");
+        let request = server.await.unwrap();
+        assert!(request.starts_with("POST /bwhc/etl/api/MTBFile HTTP/1.1\r\n"));
+        assert!(request.ends_with(r#"{"consent":{}}"#));
+    }
+
+    #[tokio::test]
+    async fn should_return_connect_error_if_socket_does_not_exist() {
+        let socket = UnixSocket::new(socket_path("missing"), HeaderMap::new());
+
+        let actual = socket
+            .send(reqwest::Client::new().get("http://localhost/bwhc/etl/api"))
+            .await;
+
+        assert!(matches!(actual, Err(AppError::HttpConnectError(_))));
+    }
+}